@@ -0,0 +1,302 @@
+//! Minimal listing of the entries of zip and (uncompressed) tar archives,
+//! without extracting them, to count card dumps as backlog items.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+const ZIP_EOCD_SIG: u32 = 0x0605_4b50;
+const ZIP64_EOCD_LOCATOR_SIG: u32 = 0x0706_4b50;
+const ZIP64_EOCD_SIG: u32 = 0x0606_4b50;
+const ZIP_CENTRAL_SIG: u32 = 0x0201_4b50;
+const ZIP_EOCD_SIZE: u64 = 22;
+const ZIP_MAX_COMMENT: u64 = 0xffff;
+const TAR_BLOCK: u64 = 512;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn u16_at(buf: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([buf[pos], buf[pos + 1]])
+}
+
+fn u32_at(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(buf[pos..pos + 8].try_into().unwrap())
+}
+
+fn read_at(file: &mut File, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+/// Returns the offset and size of the zip central directory.
+fn zip_central_directory(file: &mut File) -> io::Result<(u64, u64)> {
+    let len = file.metadata()?.len();
+    // The end of central directory record is followed by a comment of at
+    // most 64KiB, so search backwards for its signature.
+    let tail_len = len.min(ZIP_EOCD_SIZE + ZIP_MAX_COMMENT);
+    let mut tail = vec![0; tail_len as usize];
+    read_at(file, len - tail_len, &mut tail)?;
+    let eocd = (0..tail.len().saturating_sub(ZIP_EOCD_SIZE as usize - 1))
+        .rev()
+        .find(|&pos| u32_at(&tail, pos) == ZIP_EOCD_SIG)
+        .ok_or_else(|| invalid("no zip end of central directory"))?;
+    let size = u32_at(&tail, eocd + 12);
+    let offset = u32_at(&tail, eocd + 16);
+    if size != u32::MAX && offset != u32::MAX {
+        return Ok((offset.into(), size.into()));
+    }
+    // Zip64, with the real values in a separate record, found via the
+    // locator just before the end of central directory record.
+    let locator = (len - tail_len + eocd as u64)
+        .checked_sub(20)
+        .ok_or_else(|| invalid("no zip64 locator"))?;
+    let mut buf = [0; 20];
+    read_at(file, locator, &mut buf)?;
+    if u32_at(&buf, 0) != ZIP64_EOCD_LOCATOR_SIG {
+        return Err(invalid("no zip64 locator"));
+    }
+    let mut record = [0; 56];
+    read_at(file, u64_at(&buf, 8), &mut record)?;
+    if u32_at(&record, 0) != ZIP64_EOCD_SIG {
+        return Err(invalid("no zip64 end of central directory"));
+    }
+    Ok((u64_at(&record, 48), u64_at(&record, 40)))
+}
+
+fn zip_entries(file: &mut File) -> io::Result<Vec<String>> {
+    let (offset, size) = zip_central_directory(file)?;
+    // The sizes come from the archive, so check them before allocating.
+    let len = file.metadata()?.len();
+    if offset.checked_add(size).is_none_or(|end| end > len) {
+        return Err(invalid("zip central directory past the end of the file"));
+    }
+    let mut directory = vec![0; size as usize];
+    read_at(file, offset, &mut directory)?;
+    let mut names = Vec::new();
+    let mut pos = 0;
+    while pos + 46 <= directory.len() && u32_at(&directory, pos) == ZIP_CENTRAL_SIG {
+        let name_len = usize::from(u16_at(&directory, pos + 28));
+        let extra_len = usize::from(u16_at(&directory, pos + 30));
+        let comment_len = usize::from(u16_at(&directory, pos + 32));
+        let name = directory
+            .get(pos + 46..pos + 46 + name_len)
+            .ok_or_else(|| invalid("truncated zip central directory"))?;
+        let name = String::from_utf8_lossy(name);
+        if !name.ends_with('/') {
+            names.push(name.into_owned());
+        }
+        pos += 46 + name_len + extra_len + comment_len;
+    }
+    Ok(names)
+}
+
+/// Parses a NUL-terminated (or full-width) header field.
+fn tar_string(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// Parses a tar size field, in octal or (for large sizes) base-256.
+fn tar_size(field: &[u8]) -> io::Result<u64> {
+    if field[0] & 0x80 != 0 {
+        return field[1..]
+            .iter()
+            .try_fold(0u64, |size, &b| {
+                size.checked_mul(256).map(|size| size | u64::from(b))
+            })
+            .ok_or_else(|| invalid("invalid tar entry size"));
+    }
+    let size = tar_string(field);
+    let size = size.trim_matches(|c: char| c == ' ' || c == '\0');
+    if size.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(size, 8).map_err(|_| invalid("invalid tar entry size"))
+}
+
+fn tar_entries(file: &mut File) -> io::Result<Vec<String>> {
+    let len = file.metadata()?.len();
+    let mut names = Vec::new();
+    let mut long_name = None;
+    let mut pos = 0;
+    let mut header = [0; TAR_BLOCK as usize];
+    while pos + TAR_BLOCK <= len {
+        read_at(file, pos, &mut header)?;
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        let size = tar_size(&header[124..136])?;
+        let next = size
+            .div_ceil(TAR_BLOCK)
+            .checked_mul(TAR_BLOCK)
+            .and_then(|data| pos.checked_add(TAR_BLOCK + data))
+            .ok_or_else(|| invalid("invalid tar entry size"))?;
+        match header[156] {
+            // GNU long name, stored as the data of this entry.
+            b'L' => {
+                if next > len {
+                    return Err(invalid("tar long name past the end of the file"));
+                }
+                let mut name = vec![0; size as usize];
+                read_at(file, pos + TAR_BLOCK, &mut name)?;
+                long_name = Some(tar_string(&name));
+            }
+            b'0' | b'\0' | b'7' => {
+                let name = long_name.take().unwrap_or_else(|| {
+                    let prefix = tar_string(&header[345..500]);
+                    let name = tar_string(&header[..100]);
+                    if prefix.is_empty() {
+                        name
+                    } else {
+                        format!("{}/{}", prefix, name)
+                    }
+                });
+                names.push(name);
+            }
+            _ => long_name = None,
+        }
+        pos = next;
+    }
+    Ok(names)
+}
+
+/// Returns the names of the file entries (not directories) of the given
+/// zip or uncompressed tar archive.
+pub fn entry_names(path: &Path) -> io::Result<Vec<String>> {
+    let mut file = File::open(path)?;
+    let mut magic = [0; 4];
+    file.read_exact(&mut magic)?;
+    if &magic[..2] == b"PK" {
+        return zip_entries(&mut file);
+    }
+    let mut ustar = [0; 5];
+    if read_at(&mut file, 257, &mut ustar).is_ok() && &ustar == b"ustar" {
+        return tar_entries(&mut file);
+    }
+    Err(invalid("not a zip or tar archive"))
+}
+
+/// Builds a minimal zip, with only the central directory.
+#[cfg(test)]
+pub(crate) fn zip_with(names: &[&str]) -> Vec<u8> {
+    let mut zip = b"PK\x03\x04".to_vec();
+    let offset = zip.len() as u32;
+    let mut directory = Vec::new();
+    for name in names {
+        directory.extend(ZIP_CENTRAL_SIG.to_le_bytes());
+        directory.extend([0; 24]);
+        directory.extend((name.len() as u16).to_le_bytes());
+        directory.extend([0; 16]);
+        directory.extend(name.as_bytes());
+    }
+    zip.extend(&directory);
+    zip.extend(ZIP_EOCD_SIG.to_le_bytes());
+    zip.extend([0; 4]);
+    zip.extend((names.len() as u16).to_le_bytes());
+    zip.extend((names.len() as u16).to_le_bytes());
+    zip.extend((directory.len() as u32).to_le_bytes());
+    zip.extend(offset.to_le_bytes());
+    zip.extend(3u16.to_le_bytes());
+    zip.extend(b"abc");
+    zip
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use rstest::rstest;
+    use speculoos::prelude::*;
+    use tempfile::tempdir;
+
+    use super::{entry_names, zip_with};
+
+    #[test]
+    fn test_zip_entries() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("card.zip");
+        std::fs::write(&path, zip_with(&["DCIM/", "DCIM/1.NEF", "DCIM/1.JPG"])).unwrap();
+        assert_that!(entry_names(&path).unwrap())
+            .is_equal_to(vec!["DCIM/1.NEF".to_string(), "DCIM/1.JPG".to_string()]);
+    }
+
+    #[test]
+    fn test_tar_entries() {
+        let temp_dir = tempdir().unwrap();
+        let dcim = temp_dir.path().join("DCIM");
+        std::fs::create_dir(&dcim).unwrap();
+        std::fs::write(dcim.join("1.nef"), vec![1; 1000]).unwrap();
+        let long_name = format!("{}.jpg", "x".repeat(120));
+        std::fs::write(dcim.join(&long_name), "").unwrap();
+        let path = temp_dir.path().join("card.tar");
+        let status = Command::new("tar")
+            .arg("-cf")
+            .arg(&path)
+            .arg("-C")
+            .arg(temp_dir.path())
+            .arg("DCIM")
+            .status();
+        // Only run if tar is available.
+        if !status.is_ok_and(|s| s.success()) {
+            return;
+        }
+        let mut names = entry_names(&path).unwrap();
+        names.sort();
+        assert_that!(names).is_equal_to(vec![
+            "DCIM/1.nef".to_string(),
+            format!("DCIM/{}", long_name),
+        ]);
+    }
+
+    /// Builds a tar header for the given name, size field and type.
+    fn tar_header(name: &str, size: &[u8], kind: u8) -> Vec<u8> {
+        let mut header = vec![0; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..124 + size.len()].copy_from_slice(size);
+        header[156] = kind;
+        header[257..262].copy_from_slice(b"ustar");
+        header
+    }
+
+    #[rstest]
+    #[case::long_name(tar_header("././@LongLink", b"77777777777", b'L'))]
+    #[case::max_size(tar_header("1.nef", &[0x80, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff], b'0'))]
+    #[case::overflow(tar_header("1.nef", &[0x80, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], b'0'))]
+    fn test_tar_bad_sizes(#[case] header: Vec<u8>) {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("card.tar");
+        std::fs::write(&path, [header, vec![0; 1024]].concat()).unwrap();
+        assert_that!(entry_names(&path))
+            .is_err()
+            .matches(|e| e.kind() == std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_zip_bad_directory_size() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("card.zip");
+        let mut zip = zip_with(&["DCIM/1.NEF"]);
+        // The central directory size, in the end of central directory
+        // record before the 3-byte comment.
+        let size_pos = zip.len() - 3 - 2 - 4 - 4;
+        zip[size_pos..size_pos + 4].copy_from_slice(&0xfff0_0000u32.to_le_bytes());
+        std::fs::write(&path, zip).unwrap();
+        assert_that!(entry_names(&path))
+            .is_err()
+            .matches(|e| e.to_string().contains("past the end"));
+    }
+
+    #[test]
+    fn test_not_an_archive() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("card.zip");
+        std::fs::write(&path, "not really").unwrap();
+        assert_that!(entry_names(&path)).is_err();
+    }
+}
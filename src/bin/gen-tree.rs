@@ -0,0 +1,16 @@
+use std::path::Path;
+
+use photo_backlog_exporter::fixture;
+
+// Builds a photo tree under the given root, from the specification in the
+// given file or, if missing, from stdin.
+fn main() -> Result<(), String> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (root, spec) = match args.as_slice() {
+        [root] => (root, std::io::read_to_string(std::io::stdin())),
+        [root, spec] => (root, std::fs::read_to_string(spec)),
+        _ => return Err("Usage: gen-tree ROOT [SPEC-FILE]".to_string()),
+    };
+    let spec = spec.map_err(|e| format!("Can't read specification: {}", e))?;
+    fixture::build_tree(Path::new(root), &spec)
+}
@@ -1,13 +1,201 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
 use photo_backlog_exporter::*;
 
+// Compares two snapshots written via --snapshot-file.
+fn compare(args: &[String]) -> Result<(), String> {
+    let (old, new) = match args {
+        [old, new] => (old, new),
+        _ => return Err("Usage: oneshot compare OLD.json NEW.json".to_string()),
+    };
+    let old = snapshot::Snapshot::load(Path::new(old))?;
+    let new = snapshot::Snapshot::load(Path::new(new))?;
+    for line in snapshot::compare(&old, &new, &collation::FolderOrder::from_env()) {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+// Compares the normalised metrics against the given golden file, printing
+// the differences.
+fn compare_golden(path: &Path, buffer: &str, summary: &mut summary::Summary) -> Result<(), String> {
+    let expected = std::fs::read_to_string(path)
+        .map_err(|e| format!("Can't read golden file '{}': {}", path.display(), e))?;
+    let differences = golden::diff(&expected, &golden::normalize(buffer));
+    for line in &differences {
+        println!("{}", line);
+    }
+    if differences.is_empty() {
+        Ok(())
+    } else {
+        summary.exit_reason = summary::ExitReason::GoldenMismatch;
+        Err(format!(
+            "Metrics differ from golden file '{}' in {} lines",
+            path.display(),
+            differences.len()
+        ))
+    }
+}
+
+// Scans each of the given roots, printing a summary table.
+fn scan_roots(
+    collector: &prometheus::PhotoBacklogCollector,
+    roots: &[PathBuf],
+    summary: &mut summary::Summary,
+) {
+    let now = SystemTime::now();
+    println!("{:>8} {:>8} {:>8}  ROOT", "FILES", "FOLDERS", "ERRORS");
+    for root in roots {
+        let backlog = collector.scan_root(root, now);
+        summary.record(&backlog);
+        if let Some(failure) = &backlog.scan_failure {
+            println!(
+                "{:>8} {:>8} {:>8}  {} ({})",
+                "-",
+                "-",
+                "-",
+                root.display(),
+                failure
+            );
+            continue;
+        }
+        println!(
+            "{:>8} {:>8} {:>8}  {}",
+            backlog.total_files,
+            backlog.folder_count(),
+            backlog.total_errors.values().sum::<i64>(),
+            root.display()
+        );
+    }
+}
+
+// Rescans the root every interval, re-rendering the folders table, until
+// interrupted.
+fn watch(collector: &prometheus::PhotoBacklogCollector, interval: Duration) -> ! {
+    let order = collation::FolderOrder::from_env();
+    loop {
+        let backlog = collector.scan_root(&collector.scan_path, SystemTime::now());
+        print!(
+            "{}{}",
+            watch::CLEAR_SCREEN,
+            watch::render(&backlog, &collector.scan_path, interval, &order)
+        );
+        std::thread::sleep(interval);
+    }
+}
+
+// Scans once and prints suggested age buckets, with the age quantiles
+// they're based on.
+fn suggest_buckets(collector: &prometheus::PhotoBacklogCollector, summary: &mut summary::Summary) {
+    // The files' ages are only kept for the quantiles.
+    let collector = prometheus::PhotoBacklogCollector {
+        age_metrics: prometheus::AgeMetrics::Quantiles,
+        ..collector.clone()
+    };
+    let backlog = collector.scan_root(&collector.scan_path, SystemTime::now());
+    summary.record(&backlog);
+    let weeks = |q| quantile(&backlog.ages, q).map_or(0.0, |age| age / (7.0 * 86400.0));
+    println!(
+        "# {} files, age quantiles in weeks: 10%: {:.1}, 50%: {:.1}, 90%: {:.1}, 99%: {:.1}",
+        backlog.ages.len(),
+        weeks(0.1),
+        weeks(0.5),
+        weeks(0.9),
+        weeks(0.99)
+    );
+    let buckets: Vec<String> = cli::suggest_age_buckets(&backlog.ages)
+        .iter()
+        .map(f64::to_string)
+        .collect();
+    println!("--age-buckets {}", buckets.join(","));
+}
+
+// Scans once and prints the commands fixing the ownership and permissions
+// violations.
+fn fix_plan(
+    collector: prometheus::PhotoBacklogCollector,
+    summary: &mut summary::Summary,
+) -> Result<(), String> {
+    let collector = prometheus::PhotoBacklogCollector {
+        keep_violations: true,
+        ..collector
+    };
+    let backlog = collector.scan_root(&collector.scan_path, SystemTime::now());
+    summary.record(&backlog);
+    if let Some(failure) = &backlog.scan_failure {
+        return Err(failure.clone());
+    }
+    print!("{}", fix_plan::render(&backlog, &collector.scan_path));
+    Ok(())
+}
+
+// Does the scan(s) and outputs, recording the results in the summary.
+fn run(opts: cli::CliOptions, summary: &mut summary::Summary) -> Result<(), String> {
+    let roots = cli::several_roots(&opts)?;
+    cli::check_readonly(&opts, roots.as_deref())?;
+    if let Some(roots) = roots {
+        scan_roots(&cli::collector_from_args(opts), &roots, summary);
+        return Ok(());
+    }
+
+    if opts.suggest_buckets {
+        suggest_buckets(&cli::collector_from_args(opts), summary);
+        return Ok(());
+    }
+
+    if opts.fix_plan {
+        return fix_plan(cli::collector_from_args(opts), summary);
+    }
+
+    if let Some(interval) = opts.watch {
+        watch(&cli::collector_from_args(opts), interval);
+    }
+
+    let snapshot_file = opts.snapshot_file.clone();
+    let golden = opts.golden;
+    let golden_file = opts.golden_file.clone();
+    let textfile_output = opts.textfile_output.clone();
+    let collector = cli::collector_from_args(opts);
+    let state = Arc::clone(&collector.state);
+    let buffer = prometheus::encode_to_text(collector).map_err(|e| e.to_string())?;
+    let last_good = state.lock().unwrap().last_good();
+    match &last_good {
+        Some(backlog) => summary.record(backlog),
+        None => summary.exit_reason = summary::ExitReason::ScanFailed,
+    }
+    match (&golden_file, &textfile_output) {
+        (Some(path), _) => compare_golden(path, &buffer, summary)?,
+        (None, _) if golden => print!("{}", golden::normalize(&buffer)),
+        (None, Some(path)) => textfile::write(path, &textfile::from_openmetrics(&buffer))?,
+        (None, None) => println!("{}", buffer),
+    }
+    if let Some(path) = snapshot_file {
+        let backlog = last_good.ok_or_else(|| "Scan failed, not writing a snapshot".to_string())?;
+        snapshot::Snapshot::from(backlog.as_ref()).save(&path)?;
+    }
+    Ok(())
+}
+
 fn main() -> Result<(), String> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().is_some_and(|a| a == "compare") {
+        return compare(&args[1..]).map_err(cli::log_error);
+    }
+
     let opts = match cli::init_binary()? {
         None => return Ok(()),
         Some(opts) => opts,
     };
 
-    let collector = cli::collector_from_args(opts);
-    let buffer = prometheus::encode_to_text(collector).map_err(|e| e.to_string())?;
-    println!("{}", buffer);
-    Ok(())
+    let started = Instant::now();
+    let summary_file = opts.summary_file.clone();
+    let mut summary = summary::Summary::default();
+    let result = run(opts, &mut summary);
+    if let Some(path) = summary_file {
+        summary.finish(started.elapsed(), &result);
+        summary.save(&path).map_err(cli::log_error)?;
+    }
+    result.map_err(cli::log_error)
 }
@@ -0,0 +1,327 @@
+//! Age histogram buckets, possibly aligned to calendar units (months,
+//! quarters, years), which don't have a fixed length.
+//!
+//! Calendar buckets are exported with their average length as bound, so
+//! that the `le` labels are stable, while files are counted against the
+//! actual calendar cutoff (e.g. the same day three months ago, in UTC):
+//! ages are mapped linearly between consecutive cutoffs onto the nominal
+//! bounds to pick their bucket, while the histogram sums the actual ages.
+
+use std::time::{Duration, SystemTime};
+
+use prometheus_client::encoding::{EncodeMetric, MetricEncoder};
+use prometheus_client::metrics::{MetricType, TypedMetric};
+
+const DAY: f64 = 86400.0;
+pub(crate) const WEEK: f64 = 7.0 * DAY;
+/// Average month length, over the 400-year Gregorian cycle.
+const MONTH: f64 = 365.2425 / 12.0 * DAY;
+
+/// A bucket bound.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AgeBound {
+    /// A fixed age, in seconds.
+    Seconds(f64),
+    /// The given number of calendar months before the reference time.
+    Months(u32),
+}
+
+impl AgeBound {
+    /// The bound as exported, in seconds.
+    pub fn nominal(self) -> f64 {
+        match self {
+            AgeBound::Seconds(seconds) => seconds,
+            AgeBound::Months(months) => f64::from(months) * MONTH,
+        }
+    }
+
+    /// The actual age of the bound at the given reference time.
+    fn cutoff(self, reference: SystemTime) -> f64 {
+        match self {
+            AgeBound::Seconds(seconds) => seconds,
+            AgeBound::Months(months) => {
+                let now = reference
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or(Duration::ZERO)
+                    .as_secs() as i64;
+                (now - months_before(now, months)) as f64
+            }
+        }
+    }
+}
+
+/// Parses comma-separated bucket bounds, in weeks unless suffixed by a
+/// unit: `d`, `w`, `M` (months), `q` (quarters) or `y` (years).
+/// Example:
+/// ```
+/// use photo_backlog_exporter::buckets::{parse_age_buckets, AgeBound};
+/// assert_eq!(
+///     parse_age_buckets("1,3d,1M,1q,1y"),
+///     Ok(vec![
+///         AgeBound::Seconds(7.0 * 86400.0),
+///         AgeBound::Seconds(3.0 * 86400.0),
+///         AgeBound::Months(1),
+///         AgeBound::Months(3),
+///         AgeBound::Months(12),
+///     ])
+/// );
+/// assert!(parse_age_buckets("1.5M").is_err());
+/// assert!(parse_age_buckets("1h").is_err());
+/// assert!(parse_age_buckets("2000000000y").is_err());
+/// ```
+pub fn parse_age_buckets(s: &str) -> Result<Vec<AgeBound>, String> {
+    s.split(',')
+        .filter(|c| !c.is_empty())
+        .map(|c| {
+            let (number, unit) = match c.find(|ch: char| ch.is_ascii_alphabetic()) {
+                Some(pos) => c.split_at(pos),
+                None => (c, "w"),
+            };
+            let months = |factor: u32| {
+                let n = number.parse::<u32>().map_err(|_| {
+                    format!("Invalid calendar bucket '{}', expected whole units", c)
+                })?;
+                n.checked_mul(factor)
+                    .map(AgeBound::Months)
+                    .ok_or_else(|| format!("Calendar bucket '{}' is too large", c))
+            };
+            let seconds = |factor: f64| {
+                number
+                    .parse::<f64>()
+                    .map(|n| AgeBound::Seconds(n * factor))
+                    .map_err(|e| format!("Invalid bucket '{}': {}", c, e))
+            };
+            match unit {
+                "d" => seconds(DAY),
+                "w" => seconds(WEEK),
+                "M" => months(1),
+                "q" => months(3),
+                "y" => months(12),
+                _ => Err(format!(
+                    "Invalid bucket unit in '{}', expected d, w, M, q or y",
+                    c
+                )),
+            }
+        })
+        .collect()
+}
+
+/// Returns the nominal bounds, as used for the histogram buckets.
+pub fn nominal(bounds: &[AgeBound]) -> Vec<f64> {
+    bounds.iter().map(|b| b.nominal()).collect()
+}
+
+/// Maps actual ages onto the nominal bucket bounds; the identity if no
+/// bucket is calendar-aligned.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AgeScale {
+    /// The actual cutoff and nominal bound of each bucket, sorted.
+    points: Vec<(f64, f64)>,
+}
+
+impl AgeScale {
+    pub fn new(bounds: &[AgeBound], reference: SystemTime) -> Self {
+        if bounds.iter().all(|b| matches!(b, AgeBound::Seconds(_))) {
+            return AgeScale::default();
+        }
+        let mut points: Vec<(f64, f64)> = bounds
+            .iter()
+            .map(|b| (b.cutoff(reference), b.nominal()))
+            .collect();
+        points.sort_by(|a, b| a.1.total_cmp(&b.1));
+        // Mixed fixed and calendar bounds can be out of order in some
+        // months (e.g. 30d and 1M in February); keep the mapping monotonic.
+        for i in 1..points.len() {
+            points[i].0 = points[i].0.max(points[i - 1].0);
+        }
+        AgeScale { points }
+    }
+
+    /// Returns the age to observe for the given actual age.
+    pub fn apply(&self, age: f64) -> f64 {
+        let mut previous = (0.0, 0.0);
+        for &(cutoff, bound) in &self.points {
+            if age <= cutoff {
+                let span = cutoff - previous.0;
+                if span <= 0.0 {
+                    return bound;
+                }
+                return previous.1 + (age - previous.0) / span * (bound - previous.1);
+            }
+            previous = (cutoff, bound);
+        }
+        // Past the last bucket, only shifted.
+        age - previous.0 + previous.1
+    }
+}
+
+/// A histogram of file ages; unlike the client's histogram, its buckets
+/// can be read back, so that scrapes can drop the ones they don't need.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AgeHistogram {
+    /// The upper bound and (non-cumulative) count of each bucket, the last
+    /// one being `+Inf`.
+    buckets: Vec<(f64, u64)>,
+    sum: f64,
+    count: u64,
+}
+
+impl AgeHistogram {
+    pub fn new(bounds: impl Iterator<Item = f64>) -> Self {
+        AgeHistogram {
+            buckets: bounds.chain([f64::MAX]).map(|bound| (bound, 0)).collect(),
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    /// Observes an age, counted in the bucket of its mapped value (see
+    /// [`AgeScale`]).
+    pub fn observe(&mut self, age: f64, scaled: f64) {
+        self.sum += age;
+        self.count += 1;
+        if let Some((_, count)) = self.buckets.iter_mut().find(|(bound, _)| *bound >= scaled) {
+            *count += 1;
+        }
+    }
+
+    /// The number of observed ages.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The sum of the observed ages.
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    /// Returns this histogram without the buckets above the given age,
+    /// their files only counting in `+Inf`.
+    pub fn truncated(&self, max: f64) -> Self {
+        let mut buckets: Vec<(f64, u64)> = self
+            .buckets
+            .iter()
+            .copied()
+            .filter(|(bound, _)| *bound <= max)
+            .collect();
+        let kept: u64 = buckets.iter().map(|(_, count)| count).sum();
+        buckets.push((f64::MAX, self.count - kept));
+        AgeHistogram { buckets, ..*self }
+    }
+}
+
+impl TypedMetric for AgeHistogram {
+    const TYPE: MetricType = MetricType::Histogram;
+}
+
+impl EncodeMetric for AgeHistogram {
+    fn encode(&self, mut encoder: MetricEncoder) -> Result<(), std::fmt::Error> {
+        encoder.encode_histogram::<()>(self.sum, self.count, &self.buckets, None)
+    }
+
+    fn metric_type(&self) -> MetricType {
+        Self::TYPE
+    }
+}
+
+/// Days since the Unix epoch of the given proleptic Gregorian date (see
+/// Howard Hinnant's date algorithms).
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = i64::from((month + 9) % 12);
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// The date of the given day since the Unix epoch.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    let (next_year, next_month) = if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    };
+    (days_from_civil(next_year, next_month, 1) - days_from_civil(year, month, 1)) as u32
+}
+
+/// Returns the Unix time the given number of months before, at the same
+/// time of day, on the same day of the month or the last one if shorter.
+fn months_before(timestamp: i64, months: u32) -> i64 {
+    let (days, time) = (timestamp.div_euclid(86400), timestamp.rem_euclid(86400));
+    let (year, month, day) = civil_from_days(days);
+    let index = year * 12 + i64::from(month) - 1 - i64::from(months);
+    let (year, month) = (index.div_euclid(12), index.rem_euclid(12) as u32 + 1);
+    let day = day.min(days_in_month(year, month));
+    days_from_civil(year, month, day) * 86400 + time
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use speculoos::prelude::*;
+
+    use super::{days_from_civil, months_before, AgeBound, AgeHistogram, AgeScale, DAY};
+
+    fn at(year: i64, month: u32, day: u32) -> i64 {
+        days_from_civil(year, month, day) * 86400
+    }
+
+    #[test]
+    fn test_months_before() {
+        assert_that!(at(1970, 1, 1)).is_equal_to(0);
+        assert_that!(months_before(at(2024, 5, 15) + 3600, 1)).is_equal_to(at(2024, 4, 15) + 3600);
+        assert_that!(months_before(at(2024, 3, 31), 1)).is_equal_to(at(2024, 2, 29));
+        assert_that!(months_before(at(2024, 2, 10), 3)).is_equal_to(at(2023, 11, 10));
+        assert_that!(months_before(at(2024, 2, 29), 12)).is_equal_to(at(2023, 2, 28));
+    }
+
+    #[test]
+    fn test_age_scale() {
+        let reference = SystemTime::UNIX_EPOCH + Duration::from_secs(at(2024, 3, 31) as u64);
+        // A month back is 2024-02-29, i.e. 31 days.
+        let bounds = [AgeBound::Seconds(7.0 * DAY), AgeBound::Months(1)];
+        let scale = AgeScale::new(&bounds, reference);
+        let month = AgeBound::Months(1).nominal();
+        assert_that!(scale.apply(3.0 * DAY)).is_equal_to(3.0 * DAY);
+        assert_that!(scale.apply(31.0 * DAY)).is_close_to(month, 1e-6);
+        assert_that!(scale.apply(30.5 * DAY)).is_less_than(month);
+        assert_that!(scale.apply(31.5 * DAY)).is_greater_than(month);
+        assert_that!(scale.apply(41.0 * DAY)).is_close_to(month + 10.0 * DAY, 1e-6);
+        // Without calendar bounds, ages are unchanged.
+        let scale = AgeScale::new(&bounds[..1], reference);
+        assert_that!(scale.apply(31.0 * DAY)).is_equal_to(31.0 * DAY);
+    }
+
+    #[test]
+    fn test_age_histogram_truncated() {
+        let mut histogram = AgeHistogram::new([1.0, 2.0, 3.0].into_iter());
+        for age in [0.5, 1.5, 2.5, 4.0] {
+            histogram.observe(age, age);
+        }
+        let truncated = histogram.truncated(2.0);
+        assert_that!(truncated.buckets).is_equal_to(vec![(1.0, 1), (2.0, 1), (f64::MAX, 2)]);
+        assert_that!(truncated.count()).is_equal_to(4);
+        assert_that!(truncated.sum()).is_equal_to(8.5);
+        assert_that!(histogram.truncated(10.0)).is_equal_to(histogram.clone());
+        // The bucket is picked by the mapped age, the sum is of the actual one.
+        histogram.observe(0.9, 1.5);
+        assert_that!(histogram.buckets[1]).is_equal_to((2.0, 2));
+        assert_that!(histogram.sum()).is_equal_to(9.4);
+    }
+}
@@ -4,8 +4,10 @@ use std::net::IpAddr;
 use std::num::{ParseFloatError, ParseIntError};
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
 use gumdrop::Options;
+use http::{HeaderName, HeaderValue};
 
 const WEEK: f64 = 7.0 * 86400.0;
 
@@ -29,8 +31,162 @@ pub fn parse_exts(s: &str) -> Vec<OsString> {
         .collect()
 }
 
-/// Simple conversion of a list of comma-separated week numbers into a vector of second values,
-/// with failure handling.
+/// Simple conversion of a comma-separated string into a vector of String values.
+/// Example:
+/// ```
+/// use photo_backlog_exporter::cli::parse_list;
+/// assert_eq!(parse_list(""), Vec::<String>::new());
+/// assert_eq!(parse_list("a,,b"), vec!["a".to_string(), "b".to_string()]);
+/// ```
+pub fn parse_list(s: &str) -> Vec<String> {
+    s.split(',')
+        .filter(|c| !c.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Conversion of a comma-separated list of folder tag keys, which must be
+/// valid label names other than `path`, with failure handling.
+/// Example:
+/// ```
+/// use photo_backlog_exporter::cli::parse_tag_keys;
+/// assert_eq!(parse_tag_keys("client,priority"),
+///   Ok(vec!["client".to_string(), "priority".to_string()]));
+/// assert!(parse_tag_keys("path").is_err());
+/// assert!(parse_tag_keys("due-date").is_err());
+/// ```
+pub fn parse_tag_keys(s: &str) -> Result<Vec<String>, String> {
+    let keys = parse_list(s);
+    for key in &keys {
+        let valid = key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && !key.starts_with("__");
+        if !valid || key == "path" {
+            return Err(format!(
+                "Invalid tag key '{}', expected a label name other than path",
+                key
+            ));
+        }
+    }
+    Ok(keys)
+}
+
+/// Conversion of a comma-separated list of `companion:primary` extension
+/// pairs, with failure handling.
+/// Example:
+/// ```
+/// use std::ffi::OsString;
+/// use photo_backlog_exporter::cli::parse_companions;
+/// assert_eq!(parse_companions("thm:mp4,"),
+///   Ok(vec![(OsString::from("thm"), OsString::from("mp4"))]));
+/// assert_eq!(parse_companions(""), Ok(vec![]));
+/// assert!(parse_companions("thm").is_err());
+/// ```
+pub fn parse_companions(s: &str) -> Result<Vec<(OsString, OsString)>, String> {
+    s.split(',')
+        .filter(|c| !c.is_empty())
+        .map(|pair| {
+            pair.split_once(':')
+                .map(|(companion, primary)| (OsString::from(companion), OsString::from(primary)))
+                .ok_or_else(|| format!("Invalid companion '{}', expected COMPANION:PRIMARY", pair))
+        })
+        .collect()
+}
+
+/// An extra HTTP response header.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResponseHeader {
+    pub name: HeaderName,
+    pub value: HeaderValue,
+}
+
+/// Parses an HTTP response header given as `NAME: VALUE`.
+/// Example:
+/// ```
+/// use photo_backlog_exporter::cli::parse_header;
+/// let header = parse_header("Cache-Control: no-store").unwrap();
+/// assert_eq!(header.name, "cache-control");
+/// assert_eq!(header.value, "no-store");
+/// assert!(parse_header("Cache-Control").is_err());
+/// assert!(parse_header("Bad Name: x").is_err());
+/// ```
+pub fn parse_header(s: &str) -> Result<ResponseHeader, String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("Invalid header '{}', expected NAME:VALUE", s))?;
+    let name = HeaderName::from_str(name.trim())
+        .map_err(|e| format!("Invalid header name in '{}': {}", s, e))?;
+    let value = HeaderValue::from_str(value.trim())
+        .map_err(|e| format!("Invalid header value in '{}': {}", s, e))?;
+    Ok(ResponseHeader { name, value })
+}
+
+/// Parses an HTTP header name.
+/// Example:
+/// ```
+/// use photo_backlog_exporter::cli::parse_header_name;
+/// assert_eq!(parse_header_name("X-Forwarded-User").unwrap(), "x-forwarded-user");
+/// assert!(parse_header_name("Bad Name").is_err());
+/// ```
+pub fn parse_header_name(s: &str) -> Result<HeaderName, String> {
+    HeaderName::from_str(s.trim()).map_err(|e| format!("Invalid header name '{}': {}", s, e))
+}
+
+/// Parses a remote write endpoint URL, which must be HTTP(S).
+/// Example:
+/// ```
+/// use photo_backlog_exporter::cli::parse_remote_write_url;
+/// let url = parse_remote_write_url("https://user:pw@example.com/api/v1/write").unwrap();
+/// assert_eq!(url.host(), Some("example.com"));
+/// assert!(parse_remote_write_url("example.com/api/v1/write").is_err());
+/// assert!(parse_remote_write_url("ftp://example.com/").is_err());
+/// ```
+pub fn parse_remote_write_url(s: &str) -> Result<http::Uri, String> {
+    let url = http::Uri::from_str(s).map_err(|e| format!("Invalid URL '{}': {}", s, e))?;
+    match (url.scheme_str(), url.host()) {
+        (Some("http" | "https"), Some(_)) => Ok(url),
+        _ => Err(format!("Invalid URL '{}', expected an http(s) one", s)),
+    }
+}
+
+/// A label added to the exported metrics.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Label {
+    pub name: String,
+    pub value: String,
+}
+
+/// Parses a label given as `NAME=VALUE`, with a valid and non-reserved
+/// Prometheus label name.
+/// Example:
+/// ```
+/// use photo_backlog_exporter::cli::parse_label;
+/// let label = parse_label("instance=nas:9000").unwrap();
+/// assert_eq!((label.name.as_str(), label.value.as_str()), ("instance", "nas:9000"));
+/// assert!(parse_label("instance").is_err());
+/// assert!(parse_label("1st=a").is_err());
+/// assert!(parse_label("__name__=a").is_err());
+/// ```
+pub fn parse_label(s: &str) -> Result<Label, String> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid label '{}', expected NAME=VALUE", s))?;
+    let valid = name
+        .chars()
+        .enumerate()
+        .all(|(i, c)| c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit()));
+    if name.is_empty() || !valid || name.starts_with("__") {
+        return Err(format!("Invalid label name '{}'", name));
+    }
+    Ok(Label {
+        name: name.to_string(),
+        value: value.to_string(),
+    })
+}
+
+/// Simple conversion of a list of comma-separated week numbers into a
+/// vector of second values, with failure handling; see
+/// [`crate::buckets::parse_age_buckets`] for calendar units.
 /// Example:
 /// ```
 /// use photo_backlog_exporter::cli::parse_weeks;
@@ -46,6 +202,47 @@ pub fn parse_weeks(s: &str) -> Result<Vec<f64>, ParseFloatError> {
         .collect()
 }
 
+/// Suggests age histogram buckets, in weeks, from the sorted file ages
+/// (in seconds): quantiles of the distribution, rounded up to round
+/// values.
+/// Example:
+/// ```
+/// use photo_backlog_exporter::cli::suggest_age_buckets;
+/// let week = 7.0 * 86400.0;
+/// let ages: Vec<f64> = (1..=100).map(|w| w as f64 * week).collect();
+/// assert_eq!(suggest_age_buckets(&ages), vec![10.0, 30.0, 50.0, 80.0, 100.0]);
+/// assert_eq!(suggest_age_buckets(&[0.3 * week]), vec![0.3]);
+/// assert_eq!(suggest_age_buckets(&[]), Vec::<f64>::new());
+/// ```
+pub fn suggest_age_buckets(sorted: &[f64]) -> Vec<f64> {
+    const QUANTILES: [f64; 6] = [0.1, 0.25, 0.5, 0.75, 0.9, 0.99];
+    const STEPS: [f64; 9] = [1.0, 1.5, 2.0, 3.0, 4.0, 5.0, 6.0, 8.0, 10.0];
+    let mut buckets: Vec<f64> = QUANTILES
+        .iter()
+        .filter_map(|&q| crate::quantile(sorted, q))
+        .map(|age| age / WEEK)
+        .filter(|&weeks| weeks > 0.0)
+        .map(|weeks| {
+            let exponent = weeks.log10().floor() as i32;
+            // Dividing for negative exponents, as e.g. 0.1 isn't exact.
+            let scale = |step: f64| {
+                if exponent < 0 {
+                    step / 10f64.powi(-exponent)
+                } else {
+                    step * 10f64.powi(exponent)
+                }
+            };
+            STEPS
+                .into_iter()
+                .map(scale)
+                .find(|&bucket| weeks <= bucket)
+                .unwrap_or_else(|| scale(10.0))
+        })
+        .collect();
+    buckets.dedup();
+    buckets
+}
+
 /// Parses the string as an octal number.
 /// Example:
 /// ```
@@ -58,19 +255,128 @@ pub fn parse_octal_mode(mode_str: &str) -> Result<u32, ParseIntError> {
     u32::from_str_radix(mode_str, 8)
 }
 
+/// Longest accepted interval, in seconds (a year).
+const MAX_INTERVAL: u64 = 366 * 86400;
+
+/// Parses a non-zero interval, in seconds or with an `s`, `m` or `h`
+/// suffix, of at most a year.
+/// Example:
+/// ```
+/// use std::time::Duration;
+/// use photo_backlog_exporter::cli::parse_interval;
+/// assert_eq!(parse_interval("30s"), Ok(Duration::from_secs(30)));
+/// assert_eq!(parse_interval("2m"), Ok(Duration::from_secs(120)));
+/// assert_eq!(parse_interval("10"), Ok(Duration::from_secs(10)));
+/// assert!(parse_interval("0s").is_err());
+/// assert!(parse_interval("1d").is_err());
+/// assert_eq!(parse_interval("8784h"), Ok(Duration::from_secs(366 * 86400)));
+/// assert!(parse_interval("8785h").is_err());
+/// assert!(parse_interval("18446744073709551615h").is_err());
+/// ```
+pub fn parse_interval(s: &str) -> Result<Duration, String> {
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(pos) => s.split_at(pos),
+        None => (s, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        _ => {
+            return Err(format!(
+                "Invalid interval unit in '{}', expected s, m or h",
+                s
+            ))
+        }
+    };
+    match u64::from_str(value) {
+        Ok(value) if value > 0 => match value.checked_mul(multiplier) {
+            Some(secs) if secs <= MAX_INTERVAL => Ok(Duration::from_secs(secs)),
+            _ => Err(format!("Interval '{}' is too long, at most 8784h", s)),
+        },
+        _ => Err(format!("Invalid interval '{}'", s)),
+    }
+}
+
+/// Parses a percentage, with or without the `%` sign, as a fraction.
+/// Example:
+/// ```
+/// use photo_backlog_exporter::cli::parse_percentage;
+/// assert_eq!(parse_percentage("10%"), Ok(0.1));
+/// assert_eq!(parse_percentage("100"), Ok(1.0));
+/// assert!(parse_percentage("0%").is_err());
+/// assert!(parse_percentage("120%").is_err());
+/// assert!(parse_percentage("a").is_err());
+/// ```
+pub fn parse_percentage(s: &str) -> Result<f64, String> {
+    match f64::from_str(s.strip_suffix('%').unwrap_or(s)) {
+        Ok(percent) if percent > 0.0 && percent <= 100.0 => Ok(percent / 100.0),
+        _ => Err(format!(
+            "Invalid percentage '{}', expected more than 0 and at most 100",
+            s
+        )),
+    }
+}
+
+/// Parses the mock data specification, as number of folders and files per
+/// folder.
+/// Example:
+/// ```
+/// use photo_backlog_exporter::cli::parse_mock_data;
+/// use photo_backlog_exporter::MockData;
+/// assert_eq!(parse_mock_data("10,100"), Ok(MockData { folders: 10, files: 100 }));
+/// assert!(parse_mock_data("10").is_err());
+/// assert!(parse_mock_data("a,1").is_err());
+/// ```
+pub fn parse_mock_data(s: &str) -> Result<crate::MockData, String> {
+    let (folders, files) = s
+        .split_once(',')
+        .ok_or_else(|| format!("Expected FOLDERS,FILES, got '{}'", s))?;
+    let parse = |n: &str| {
+        n.parse::<usize>()
+            .map_err(|e| format!("Invalid number '{}': {}", n, e))
+    };
+    Ok(crate::MockData {
+        folders: parse(folders)?,
+        files: parse(files)?,
+    })
+}
+
 #[derive(Debug, Options)]
 pub struct CliOptions {
     #[options(help = "print help message")]
     pub help: bool,
 
+    #[options(
+        help = "print the optional capabilities this binary was built with, and exit",
+        no_short
+    )]
+    pub print_features: bool,
+
     #[options(help = "port to listen on", meta = "PORT", default = "8813")]
     pub port: u16,
 
     #[options(help = "address to listen on", default = "::")]
     pub listen: IpAddr,
 
-    #[options(help = "path to root of incoming photo directory", required)]
-    pub path: PathBuf,
+    // Required unless --paths-from is given, which is checked by hand.
+    #[options(
+        help = "path to root of incoming photo directory; can be repeated for several roots, as for --paths-from"
+    )]
+    pub path: Vec<PathBuf>,
+
+    #[options(
+        help = "Optional file with newline-separated roots to scan instead of --path, or - for stdin; the daemon then labels all metrics with their root",
+        meta = "FILE",
+        no_short
+    )]
+    pub paths_from: Option<PathBuf>,
+
+    #[options(
+        help = "the root is on removable media: if missing, report it as not available instead of failing",
+        no_short
+    )]
+    pub removable_root: bool,
 
     #[options(
         help = "ignored file extension",
@@ -97,13 +403,167 @@ pub struct CliOptions {
     pub editable_exts: Vec<OsString>,
 
     #[options(
-        help = "Photos age histogram buckets, in weeks",
+        help = "Optional YAML file with ignored/raw/editable extension lists overriding the above and per-class modes, re-read on each scan",
+        meta = "FILE",
+        no_short
+    )]
+    pub exts_file: Option<PathBuf>,
+
+    #[options(
+        help = "companion extensions, as COMPANION:PRIMARY pairs, neither counted nor flagged unless orphaned",
+        default = "thm:mp4,lrv:mp4,xml:mp4,dr4:cr3",
+        parse(try_from_str = "parse_companions"),
+        meta = "PAIRS",
+        no_multi,
+        no_short
+    )]
+    pub companion_exts: Vec<(OsString, OsString)>,
+
+    #[options(
+        help = "extensions of zip or (uncompressed) tar archives, e.g. card dumps, counted as pseudo-folders of their entries",
+        default = "",
+        parse(from_str = "parse_exts"),
+        meta = "EXTS",
+        no_multi,
+        no_short
+    )]
+    pub archive_exts: Vec<OsString>,
+
+    #[options(
+        help = "how to handle files without extension: ignore, count (as backlog) or error",
+        default = "ignore",
+        meta = "POLICY",
+        no_short
+    )]
+    pub no_extension: crate::NoExtension,
+
+    #[options(
+        help = "count FIFOs, sockets and devices as errors instead of skipping them",
+        no_short
+    )]
+    pub special_files_as_errors: bool,
+
+    #[options(
+        help = "how to handle symlinks: ignore, count (linked files), error or follow",
+        default = "ignore",
+        meta = "POLICY",
+        no_short
+    )]
+    pub symlinks: crate::Symlinks,
+
+    #[options(
+        help = "Optional maximum length of paths relative to the root, in bytes",
+        meta = "BYTES",
+        no_short
+    )]
+    pub max_path_length: Option<usize>,
+
+    #[options(
+        help = "Optional maximum nesting depth below the root, files in the root being at depth 1",
+        meta = "LEVELS",
+        no_short
+    )]
+    pub max_depth: Option<usize>,
+
+    #[options(
+        help = "subdirectories that each top-level folder must contain, e.g. raw,export",
+        parse(from_str = "parse_list"),
+        meta = "DIRS",
+        no_multi,
+        no_short
+    )]
+    pub required_subdirs: Vec<String>,
+
+    #[options(
+        help = "Optional subpath of the root with already archived files, which must have the immutable attribute (chattr +i)",
+        meta = "SUBPATH",
+        no_short
+    )]
+    pub immutable_subpath: Option<PathBuf>,
+
+    #[options(
+        help = "turn off a configured check, one of owner, group, dir-mode, raw-mode, editable-mode, class-modes, structure, immutable; can be repeated",
+        meta = "CHECK",
+        no_short
+    )]
+    pub disable_check: Vec<crate::Check>,
+
+    #[options(
+        help = "number of threads walking the tree; above 1, a parallel walker is used",
+        default = "1",
+        meta = "N",
+        no_short
+    )]
+    pub scan_threads: usize,
+
+    #[options(
+        help = "read the EXIF camera serial of each file and count files per camera (reads file contents)",
+        no_short
+    )]
+    pub camera_serials: bool,
+
+    #[options(
+        help = "also count the ignored files per extension, to check that the ignore rules don't skip real photos",
+        no_short
+    )]
+    pub ignored_files_per_ext: bool,
+
+    #[options(
+        help = "Optional percentage (e.g. 10%) of files to stat and account, chosen deterministically, scaling the counts accordingly; for a quick approximate view of huge trees",
+        parse(try_from_str = "parse_percentage"),
+        meta = "PERCENT",
+        no_short
+    )]
+    pub sample: Option<f64>,
+
+    #[options(
+        help = "number of entries after which the scan is aborted, guarding against a wrong --path",
+        default = "5000000",
+        meta = "N",
+        no_short
+    )]
+    pub max_files: usize,
+
+    #[options(
+        help = "serve synthetic data for FOLDERS folders of FILES files each, instead of scanning (for demos and testing)",
+        parse(try_from_str = "parse_mock_data"),
+        meta = "FOLDERS,FILES",
+        no_short
+    )]
+    pub mock_data: Option<crate::MockData>,
+
+    #[options(
+        help = "Photos age histogram buckets, in weeks, or with a d, w, M (calendar months), q (quarters) or y (years) suffix",
         default = "1,2,3,4,5,7,10,13,17,20,26,30,35,52,104",
-        parse(try_from_str = "parse_weeks"),
+        parse(try_from_str = "crate::buckets::parse_age_buckets"),
         // Sigh, I'm doing my own parsing!
         no_multi
     )]
-    pub age_buckets: Vec<f64>,
+    pub age_buckets: Vec<crate::buckets::AgeBound>,
+
+    #[options(
+        help = "Optional age histogram buckets for raw files, as for --age-buckets, defaulting to it",
+        parse(try_from_str = "crate::buckets::parse_age_buckets"),
+        meta = "WEEKS",
+        no_short
+    )]
+    pub raw_age_buckets: Option<Vec<crate::buckets::AgeBound>>,
+
+    #[options(
+        help = "Optional age histogram buckets for editable files, as for --age-buckets, defaulting to it",
+        parse(try_from_str = "crate::buckets::parse_age_buckets"),
+        meta = "WEEKS",
+        no_short
+    )]
+    pub editable_age_buckets: Option<Vec<crate::buckets::AgeBound>>,
+
+    #[options(
+        help = "age metrics to export: histogram, quantiles (exact 0.5/0.9/0.99) or both",
+        default = "histogram",
+        meta = "KIND",
+        no_short
+    )]
+    pub age_metrics: crate::prometheus::AgeMetrics,
 
     #[options(help = "Optional owner expected for all files")]
     pub owner: Option<u32>,
@@ -130,27 +590,626 @@ pub struct CliOptions {
         short = "E"
     )]
     pub editable_file_mode: Option<u32>,
+
+    #[options(help = "only serve HTTP/1, disabling HTTP/2 (h2c) support", no_short)]
+    pub http1_only: bool,
+
+    #[options(help = "close HTTP/1 connections after each response", no_short)]
+    pub no_keep_alive: bool,
+
+    #[options(
+        help = "Optional idle timeout for client connections, in seconds",
+        meta = "SECONDS",
+        no_short
+    )]
+    pub idle_timeout: Option<u64>,
+
+    #[options(
+        help = "Optional HTTP/2 keep-alive ping interval, in seconds",
+        meta = "SECONDS",
+        no_short
+    )]
+    pub http2_keep_alive: Option<u64>,
+
+    #[options(
+        help = "behave as needed when running as PID 1 in a container: shut down gracefully on SIGTERM and SIGINT and reap zombie processes; the default when running as PID 1",
+        no_short
+    )]
+    pub in_container: bool,
+
+    #[options(
+        help = "how long (e.g. 30s) to wait for in-flight requests, including scans, when shutting down in a container",
+        parse(try_from_str = "parse_interval"),
+        default = "5s",
+        meta = "INTERVAL",
+        no_short
+    )]
+    pub shutdown_grace_period: Duration,
+
+    #[options(
+        help = "only accept IPv6 connections when listening on an IPv6 address, instead of dual-stack",
+        no_short
+    )]
+    pub ipv6_only: bool,
+
+    #[options(help = "don't set SO_REUSEADDR on the listening socket", no_short)]
+    pub no_reuse_address: bool,
+
+    #[options(
+        help = "set SO_REUSEPORT on the listening socket, allowing several instances to share the port",
+        no_short
+    )]
+    pub reuse_port: bool,
+
+    #[options(help = "set TCP_NODELAY on client connections", no_short)]
+    pub tcp_nodelay: bool,
+
+    #[options(
+        help = "Optional Prometheus web config file, for TLS and basic auth",
+        long = "web.config.file",
+        meta = "FILE",
+        no_short
+    )]
+    pub web_config_file: Option<PathBuf>,
+
+    #[options(
+        help = "Optional bearer token to require on all requests (besides any basic auth users), e.g. set via the config file to keep it out of the process list",
+        meta = "TOKEN",
+        no_short
+    )]
+    pub auth_token: Option<String>,
+
+    #[options(
+        help = "Origins allowed to make cross-origin (CORS) requests, or * for any",
+        parse(from_str = "parse_list"),
+        meta = "ORIGINS",
+        no_multi,
+        no_short
+    )]
+    pub cors_origins: Vec<String>,
+
+    #[options(
+        help = "Optional header (e.g. X-Forwarded-User) with the user authenticated by a reverse proxy, for restricting the JSON and admin endpoints to --allowed-users; /metrics stays open",
+        parse(try_from_str = "parse_header_name"),
+        meta = "HEADER",
+        no_short
+    )]
+    pub trusted_user_header: Option<HeaderName>,
+
+    #[options(
+        help = "Users, as given in --trusted-user-header, allowed to use the JSON and admin endpoints",
+        parse(from_str = "parse_list"),
+        meta = "USERS",
+        no_multi,
+        no_short
+    )]
+    pub allowed_users: Vec<String>,
+
+    #[options(
+        help = "Extra header added to all HTTP responses, e.g. 'Cache-Control: no-store'; can be repeated",
+        parse(try_from_str = "parse_header"),
+        meta = "NAME:VALUE",
+        no_short
+    )]
+    pub response_header: Vec<ResponseHeader>,
+
+    #[options(
+        help = "maximum /metrics scrapes per minute, excess ones get HTTP 429; 0 disables the limit",
+        default = "4",
+        meta = "N",
+        no_short
+    )]
+    pub max_scrapes_per_minute: u32,
+
+    #[options(
+        help = "serve the last scan, including the violation paths, as gzip-compressed JSON at /api/v1/snapshot (daemon only)",
+        no_short
+    )]
+    pub snapshot_endpoint: bool,
+
+    #[options(
+        help = "keep the N largest backlog files of each scan and serve them as JSON at /api/v1/largest?n=N (daemon only); 0 disables it",
+        meta = "N",
+        no_short
+    )]
+    pub largest_files: usize,
+
+    #[options(
+        help = "serve each folder's details (files per extension, oldest and newest file, size, violations) as JSON at /api/v1/folders/NAME (daemon only)",
+        no_short
+    )]
+    pub folder_endpoint: bool,
+
+    #[options(
+        help = "stream the completion of each background scan, with a summary, as Server-Sent Events at /api/v1/events (daemon only)",
+        no_short
+    )]
+    pub events_endpoint: bool,
+
+    #[options(
+        help = "trigger a background scan of all roots on POST /-/rescan, debounced (daemon only)",
+        no_short
+    )]
+    pub rescan_endpoint: bool,
+
+    #[options(
+        help = "Optional window (e.g. 1m, default 10s) after a rescan trigger during which further triggers are coalesced into the same scan",
+        parse(try_from_str = "parse_interval"),
+        meta = "INTERVAL",
+        no_short
+    )]
+    pub rescan_debounce: Option<Duration>,
+
+    #[options(
+        help = "Optional Prometheus remote write URL (e.g. http://victoria:8428/api/v1/write), with optional user:password@ for basic auth, to push the metrics to every --remote-write-interval, for setups without scraping (daemon only)",
+        parse(try_from_str = "parse_remote_write_url"),
+        meta = "URL",
+        no_short
+    )]
+    pub remote_write_url: Option<http::Uri>,
+
+    #[options(
+        help = "interval (e.g. 5m) at which to scan and push the metrics via remote write",
+        parse(try_from_str = "parse_interval"),
+        default = "1m",
+        meta = "INTERVAL",
+        no_short
+    )]
+    pub remote_write_interval: Duration,
+
+    #[options(
+        help = "Label (e.g. 'instance=nas') added to all metrics pushed via remote write, as the scrape target labels would be; can be repeated",
+        parse(try_from_str = "parse_label"),
+        meta = "NAME=VALUE",
+        no_short
+    )]
+    pub remote_write_label: Vec<Label>,
+
+    #[options(
+        help = "Extra header (e.g. 'Authorization: Bearer TOKEN') sent with the remote write requests, e.g. set via the config file to keep it out of the process list; can be repeated",
+        parse(try_from_str = "parse_header"),
+        meta = "NAME:VALUE",
+        no_short
+    )]
+    pub remote_write_header: Vec<ResponseHeader>,
+
+    #[options(
+        help = "Optional approximate memory budget for per-folder data and file ages, in bytes; beyond it only totals are tracked",
+        meta = "BYTES",
+        no_short
+    )]
+    pub folder_memory_budget: Option<usize>,
+
+    #[options(
+        help = "don't export per-folder metrics, only global totals and histograms",
+        no_short
+    )]
+    pub no_folder_metrics: bool,
+
+    #[options(
+        help = "export per-folder and owner file counts as photo_backlog_folder_owner_files",
+        no_short
+    )]
+    pub folder_owner_metrics: bool,
+
+    #[options(
+        help = "maximum number of folder/owner series, keeping the largest ones",
+        default = "1000",
+        meta = "N",
+        no_short
+    )]
+    pub folder_owner_max_series: usize,
+
+    #[options(
+        help = "export per-folder file counts per age bucket as photo_backlog_folder_age_bucket_files, e.g. for a heatmap",
+        no_short
+    )]
+    pub folder_age_bucket_metrics: bool,
+
+    #[options(
+        help = "maximum number of folder/age bucket series, keeping the largest folders",
+        default = "5000",
+        meta = "N",
+        no_short
+    )]
+    pub folder_age_bucket_max_series: usize,
+
+    #[options(
+        help = "export the path of the oldest file as photo_backlog_oldest_file_info",
+        no_short
+    )]
+    pub oldest_file_metric: bool,
+
+    #[options(
+        help = "tag keys (e.g. client,priority) to read from the folders' .backlog-tags files and export as labels of photo_backlog_folder_tags_info",
+        parse(try_from_str = "parse_tag_keys"),
+        meta = "KEYS",
+        no_multi,
+        no_short
+    )]
+    pub folder_tag_keys: Vec<String>,
+
+    #[options(
+        help = "Optional YAML file with regex/replacement rules mapping folder names to priorities, for folders without a priority tag",
+        parse(try_from_str = "crate::relabel::load_relabel_rules"),
+        meta = "FILE",
+        no_short
+    )]
+    pub folder_priorities_file: Option<crate::relabel::RelabelRules>,
+
+    #[options(
+        help = "Optional YAML file with regex/replacement rules applied to folder labels",
+        parse(try_from_str = "crate::relabel::load_relabel_rules"),
+        meta = "FILE",
+        no_short
+    )]
+    pub folder_relabel_file: Option<crate::relabel::RelabelRules>,
+
+    #[options(
+        help = "Optional regex of folders to export individual metrics for; others only count in totals",
+        parse(try_from_str = "crate::relabel::anchored_regex"),
+        meta = "REGEX",
+        no_short
+    )]
+    pub folder_allow: Option<regex::Regex>,
+
+    #[options(
+        help = "Optional regex of folders to not export individual metrics for, applied after --folder-allow",
+        parse(try_from_str = "crate::relabel::anchored_regex"),
+        meta = "REGEX",
+        no_short
+    )]
+    pub folder_deny: Option<regex::Regex>,
+
+    #[options(
+        help = "Optional file to write a JSON snapshot of the scan to, for use with `oneshot compare` (oneshot only)",
+        meta = "FILE",
+        no_short
+    )]
+    pub snapshot_file: Option<PathBuf>,
+
+    #[options(
+        help = "print the metrics normalised for comparison, with run-dependent values zeroed and samples sorted (oneshot only)",
+        no_short
+    )]
+    pub golden: bool,
+
+    #[options(
+        help = "Optional golden file to compare the normalised metrics against, printing the differences and failing if any (oneshot only)",
+        meta = "FILE",
+        no_short
+    )]
+    pub golden_file: Option<PathBuf>,
+
+    #[options(
+        help = "also emit renamed metrics under their old names, as deprecated aliases",
+        no_short
+    )]
+    pub metric_aliases: bool,
+
+    #[options(
+        help = "Optional interval (e.g. 30s) at which to rescan and re-render a live table of the folders, until interrupted (oneshot only)",
+        parse(try_from_str = "parse_interval"),
+        meta = "INTERVAL",
+        no_short
+    )]
+    pub watch: Option<Duration>,
+
+    #[options(
+        help = "scan once and print suggested --age-buckets, based on the age distribution (oneshot only)",
+        no_short
+    )]
+    pub suggest_buckets: bool,
+
+    #[options(
+        help = "scan once and print the chown/chmod commands that would fix the ownership and permissions violations, grouped by folder, without running them (oneshot only)",
+        no_short
+    )]
+    pub fix_plan: bool,
+
+    #[options(
+        help = "Optional file (e.g. /var/lib/node_exporter/photo_backlog.prom) to atomically write the metrics to instead of stdout, in the format of node_exporter's textfile collector (oneshot only)",
+        meta = "FILE",
+        no_short
+    )]
+    pub textfile_output: Option<PathBuf>,
+
+    #[options(
+        help = "Optional file to always write a JSON summary of the run to (counts, errors, duration, exit reason), whatever the output format (oneshot only)",
+        meta = "FILE",
+        no_short
+    )]
+    pub summary_file: Option<PathBuf>,
+
+    #[options(
+        help = "reuse the last scan if the root and top-level directories' mtimes are unchanged (deeper changes and file ages aren't refreshed)",
+        no_short
+    )]
+    pub skip_unchanged: bool,
+
+    #[options(
+        help = "Optional interval (e.g. 10m) at which to scan in the background, scrapes serving the last results (daemon only)",
+        parse(try_from_str = "parse_interval"),
+        meta = "INTERVAL",
+        no_short
+    )]
+    pub scan_interval: Option<Duration>,
+
+    #[options(
+        help = "Optional percentage (e.g. 10%) by which to randomly vary each --scan-interval delay, so that instances sharing storage don't scan at the same time",
+        parse(try_from_str = "parse_percentage"),
+        meta = "PERCENT",
+        no_short
+    )]
+    pub scan_jitter: Option<f64>,
+
+    #[options(
+        help = "how far in the future (e.g. 5m) file modification times can be, e.g. due to this host's clock being behind the file server's, before the files are flagged as future-dated",
+        parse(try_from_str = "parse_interval"),
+        default = "1m",
+        meta = "INTERVAL",
+        no_short
+    )]
+    pub clock_skew_tolerance: Duration,
+
+    #[options(
+        help = "measure file ages in working time only, either `business-days` or comma-separated DAYS[@HH:MM-HH:MM] entries, e.g. `mon-fri@19:00-22:00,sat@10:00-18:00`",
+        parse(try_from_str = "crate::work_calendar::WorkCalendar::parse"),
+        meta = "CALENDAR",
+        no_short
+    )]
+    pub age_calendar: Option<crate::work_calendar::WorkCalendar>,
+
+    #[options(
+        help = "offset from UTC (e.g. +02:00) of the --age-calendar working hours",
+        parse(try_from_str = "crate::work_calendar::parse_utc_offset"),
+        default = "+00:00",
+        meta = "OFFSET",
+        no_short
+    )]
+    pub age_utc_offset: i64,
+
+    #[options(
+        help = "watch the tree via inotify, applying files added or removed between the --scan-interval scans to the served data (daemon only)",
+        no_short
+    )]
+    pub watch_tree: bool,
+
+    #[options(
+        help = "Optional lock file held while scanning, so that only one scanner (e.g. the daemon and a oneshot cron job) walks the tree at a time; the daemon serves its last good scan instead of waiting, if it has one",
+        meta = "FILE",
+        no_short
+    )]
+    pub scan_lock: Option<PathBuf>,
+
+    #[options(
+        help = "Optional hard cap (e.g. 30m) on a scan's duration, e.g. on a hung NFS server, after which the scan is cancelled and the last good data served",
+        parse(try_from_str = "parse_interval"),
+        meta = "INTERVAL",
+        no_short
+    )]
+    pub scan_watchdog: Option<Duration>,
+
+    #[options(
+        help = "Optional JSON lines file to append each violation to, with scan id and details",
+        meta = "FILE",
+        no_short
+    )]
+    pub violations_log: Option<PathBuf>,
+
+    #[options(
+        help = "size at which the violations log is rotated, in bytes",
+        default = "10485760",
+        meta = "BYTES",
+        no_short
+    )]
+    pub violations_log_max_size: u64,
+
+    #[options(
+        help = "assert at startup that the photo tree can't be modified: drop the capabilities allowing writes despite permissions, and fail if a root is writable or any written file is under one (Linux only)",
+        no_short
+    )]
+    pub assert_readonly: bool,
+
+    #[options(
+        help = "enable the counts collector (total file and folder counts), default true",
+        long = "collector.counts",
+        meta = "BOOL",
+        no_short
+    )]
+    pub collector_counts: Option<bool>,
+
+    #[options(
+        help = "enable the errors collector (error counts), default true",
+        long = "collector.errors",
+        meta = "BOOL",
+        no_short
+    )]
+    pub collector_errors: Option<bool>,
+
+    #[options(
+        help = "enable the folders collector (per-folder metrics), default true",
+        long = "collector.folders",
+        meta = "BOOL",
+        no_short
+    )]
+    pub collector_folders: Option<bool>,
+
+    #[options(
+        help = "enable the ages collector (age histograms), default true",
+        long = "collector.ages",
+        meta = "BOOL",
+        no_short
+    )]
+    pub collector_ages: Option<bool>,
+
+    #[options(
+        help = "enable the fs-stats collector (filesystem statistics), default true",
+        long = "collector.fs-stats",
+        meta = "BOOL",
+        no_short
+    )]
+    pub collector_fs_stats: Option<bool>,
+
+    #[options(
+        help = "enable the self collector (the exporter's own metrics), default true",
+        long = "collector.self",
+        meta = "BOOL",
+        no_short
+    )]
+    pub collector_self_metrics: Option<bool>,
+
+    #[options(
+        help = "Optional TOML file with default option values; if not given, $XDG_CONFIG_HOME/photo-backlog-exporter/config.toml and then /etc/photo-backlog-exporter/config.toml are used if existing",
+        meta = "FILE",
+        no_short
+    )]
+    pub config: Option<PathBuf>,
+
+    #[options(help = "don't load any config file, not even a default one", no_short)]
+    pub no_config: bool,
 }
 
 pub fn parse_args() -> Result<CliOptions, String> {
     let args: Vec<String> = std::env::args().skip(1).collect();
+    let args = with_config_file(args, crate::config_file::discover)?;
     parse_args_from(args.as_slice())
 }
 
+/// Prepends the options from the config file, given via `--config` or
+/// else discovered, unless disabled via `--no-config`.
+pub fn with_config_file(
+    args: Vec<String>,
+    discover: impl FnOnce() -> Option<PathBuf>,
+) -> Result<Vec<String>, String> {
+    if args.iter().any(|a| a == "--no-config") {
+        return Ok(args);
+    }
+    let given = args.iter().enumerate().find_map(|(i, a)| {
+        if a == "--config" {
+            args.get(i + 1).map(PathBuf::from)
+        } else {
+            a.strip_prefix("--config=").map(PathBuf::from)
+        }
+    });
+    let Some(path) = given.or_else(discover) else {
+        log::debug!("No config file given or found");
+        return Ok(args);
+    };
+    log::info!("Using config file '{}'", path.display());
+    let mut config_args = crate::config_file::load(&path)?;
+    // Roots given on the command line replace the file's, rather than
+    // being scanned in addition.
+    let cli_roots = args.iter().any(|a| {
+        a == "--path"
+            || a.starts_with("--path=")
+            || a == "--paths-from"
+            || a.starts_with("--paths-from=")
+    });
+    if cli_roots {
+        let mut file_args = config_args.into_iter();
+        config_args = Vec::new();
+        while let Some(arg) = file_args.next() {
+            if arg == "--path" {
+                file_args.next();
+            } else {
+                config_args.push(arg);
+            }
+        }
+    }
+    config_args.extend(args);
+    Ok(config_args)
+}
+
 pub fn parse_args_from<S>(args: &[S]) -> Result<CliOptions, String>
 where
     S: AsRef<str>,
 {
     let opts = CliOptions::parse_args_default(args).map_err(|e| e.to_string())?;
-    if opts.help_requested() {
+    if opts.help_requested() || opts.print_features {
         return Ok(opts);
     }
-    let path = &opts.path;
-    if !path.is_dir() {
-        return Err(format!(
-            "Given path '{}' is not a directory :(",
-            path.display()
-        ));
+    if let Some(exts_file) = &opts.exts_file {
+        crate::exts::ExtensionLists::load(exts_file)?;
+    }
+    let several_roots = opts.paths_from.is_some() || opts.path.len() > 1;
+    if opts.watch.is_some()
+        && (several_roots
+            || opts.golden
+            || opts.golden_file.is_some()
+            || opts.snapshot_file.is_some()
+            || opts.summary_file.is_some())
+    {
+        return Err(
+            "--watch can't be combined with several roots, --golden, --golden-file, --snapshot-file or --summary-file"
+                .to_string(),
+        );
+    }
+    if opts.suggest_buckets && (several_roots || opts.watch.is_some()) {
+        return Err(
+            "--suggest-buckets can't be combined with several roots or --watch".to_string(),
+        );
+    }
+    if opts.fix_plan && (several_roots || opts.watch.is_some() || opts.suggest_buckets) {
+        return Err(
+            "--fix-plan can't be combined with several roots, --watch or --suggest-buckets"
+                .to_string(),
+        );
+    }
+    if opts.textfile_output.is_some()
+        && (several_roots
+            || opts.watch.is_some()
+            || opts.golden
+            || opts.golden_file.is_some()
+            || opts.suggest_buckets
+            || opts.fix_plan)
+    {
+        return Err(
+            "--textfile-output can't be combined with several roots, --watch, --golden, --golden-file, --suggest-buckets or --fix-plan"
+                .to_string(),
+        );
+    }
+    if opts.scan_jitter.is_some() && opts.scan_interval.is_none() {
+        return Err("--scan-jitter requires --scan-interval".to_string());
+    }
+    if opts.watch_tree && opts.scan_interval.is_none() {
+        return Err("--watch-tree requires --scan-interval".to_string());
+    }
+    if opts.watch_tree && opts.sample.is_some() {
+        return Err("--watch-tree can't be combined with --sample".to_string());
+    }
+    if opts.trusted_user_header.is_some() == opts.allowed_users.is_empty() {
+        return Err("--trusted-user-header and --allowed-users must be given together".to_string());
+    }
+    if opts.events_endpoint && opts.scan_interval.is_none() && !opts.rescan_endpoint {
+        return Err("--events-endpoint requires --scan-interval or --rescan-endpoint".to_string());
+    }
+    let calendar_buckets = std::iter::once(&opts.age_buckets)
+        .chain(&opts.raw_age_buckets)
+        .chain(&opts.editable_age_buckets)
+        .flatten()
+        .any(|b| matches!(b, crate::buckets::AgeBound::Months(_)));
+    if opts.age_calendar.is_some() && calendar_buckets {
+        return Err(
+            "--age-calendar can't be combined with month, quarter or year age buckets".to_string(),
+        );
+    }
+    if opts.rescan_debounce.is_some() && !opts.rescan_endpoint {
+        return Err("--rescan-debounce requires --rescan-endpoint".to_string());
+    }
+    if opts.path.is_empty() {
+        if opts.paths_from.is_some() || opts.mock_data.is_some() {
+            return Ok(opts);
+        }
+        return Err("missing required option `--path`".to_string());
+    }
+    for path in &opts.path {
+        if !path.is_dir() && !opts.removable_root {
+            return Err(format!(
+                "Given path '{}' is not a directory :(",
+                path.display()
+            ));
+        }
     }
     Ok(opts)
 }
@@ -164,33 +1223,296 @@ pub fn init_binary() -> Result<Option<CliOptions>, String> {
             eprintln!("{}", CliOptions::usage());
             Ok(None)
         }
+        Ok(opts) if opts.print_features => {
+            print!("{}", features_report());
+            Ok(None)
+        }
         Ok(opts) => {
             log::info!("Starting up with the following options: {:?}", opts);
+            if let Some(log) = &opts.violations_log {
+                crate::violations::check_writable(log).map_err(log_error)?;
+            }
+            if opts.assert_readonly {
+                crate::readonly::drop_write_capabilities().map_err(log_error)?;
+            }
             Ok(Some(opts))
         }
     }
 }
 
+/// The optional capabilities: name, whether built in, and description.
+pub const FEATURES: &[(&str, bool, &str)] = &[
+    (
+        "daemon",
+        cfg!(feature = "daemon"),
+        "the HTTP daemon (photo-backlog-exporter)",
+    ),
+    (
+        "tls",
+        cfg!(feature = "daemon"),
+        "TLS for the daemon and remote write, via rustls",
+    ),
+    (
+        "remote-write",
+        cfg!(feature = "remote-write"),
+        "pushing the metrics via Prometheus remote write",
+    ),
+    (
+        "collation",
+        cfg!(feature = "collation"),
+        "locale-aware sorting of folder names in the reports",
+    ),
+    (
+        "assert-readonly",
+        cfg!(target_os = "linux"),
+        "dropping the write capabilities via --assert-readonly",
+    ),
+];
+
+/// Describes the build, then the capabilities, one per line, prefixed by
+/// `+` if built in and by `-` otherwise.
+pub fn features_report() -> String {
+    let target_env = if cfg!(target_env = "musl") {
+        "-musl"
+    } else if cfg!(target_env = "gnu") {
+        "-gnu"
+    } else {
+        ""
+    };
+    let linking = if cfg!(target_feature = "crt-static") {
+        "statically linked"
+    } else {
+        "dynamically linked"
+    };
+    let mut report = format!(
+        "{} {} ({}-{}{}, {})\n",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::ARCH,
+        std::env::consts::OS,
+        target_env,
+        linking
+    );
+    for (name, enabled, description) in FEATURES {
+        let sign = if *enabled { '+' } else { '-' };
+        report.push_str(&format!("{}{:<16} {}\n", sign, name, description));
+    }
+    report
+}
+
+/// Reads the list of roots given via `--paths-from`, skipping empty lines.
+pub fn read_paths_from(source: &std::path::Path) -> Result<Vec<PathBuf>, String> {
+    let contents = if source == std::path::Path::new("-") {
+        std::io::read_to_string(std::io::stdin())
+    } else {
+        std::fs::read_to_string(source)
+    }
+    .map_err(|e| format!("Can't read paths from '{}': {}", source.display(), e))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
 pub fn log_error(e: String) -> String {
     log::error!("{}", e);
     e
 }
 
+// Returns the sub-collectors turned off via `--collector.NAME=false`.
+fn disabled_collectors(opts: &CliOptions) -> Vec<crate::prometheus::MetricGroup> {
+    use crate::prometheus::MetricGroup;
+    [
+        (MetricGroup::Counts, opts.collector_counts),
+        (MetricGroup::Errors, opts.collector_errors),
+        (MetricGroup::Folders, opts.collector_folders),
+        (MetricGroup::Ages, opts.collector_ages),
+        (MetricGroup::FsStats, opts.collector_fs_stats),
+        (MetricGroup::SelfMetrics, opts.collector_self_metrics),
+    ]
+    .into_iter()
+    .filter(|(_, enabled)| *enabled == Some(false))
+    .map(|(group, _)| group)
+    .collect()
+}
+
 pub fn collector_from_args(opts: CliOptions) -> crate::prometheus::PhotoBacklogCollector {
+    let disabled_collectors = disabled_collectors(&opts);
     crate::prometheus::PhotoBacklogCollector {
-        scan_path: opts.path,
+        scan_path: opts.path.first().cloned().unwrap_or_default(),
         ignored_exts: opts.ignored_exts,
         raw_exts: opts.raw_exts,
         editable_exts: opts.editable_exts,
+        companion_exts: opts.companion_exts,
+        archive_exts: opts.archive_exts,
+        exts_file: opts.exts_file,
         age_buckets: opts.age_buckets,
+        raw_age_buckets: opts.raw_age_buckets,
+        editable_age_buckets: opts.editable_age_buckets,
+        age_metrics: opts.age_metrics,
         owner: opts.owner,
         group: opts.group,
         dir_mode: opts.dir_mode,
         raw_file_mode: opts.raw_file_mode,
         editable_file_mode: opts.editable_file_mode,
+        folder_memory_budget: opts.folder_memory_budget,
+        no_folder_metrics: opts.no_folder_metrics,
+        oldest_file_metric: opts.oldest_file_metric,
+        folder_tag_keys: opts.folder_tag_keys,
+        folder_priorities: opts.folder_priorities_file,
+        folder_owner_metrics: opts.folder_owner_metrics,
+        folder_owner_max_series: opts.folder_owner_max_series,
+        folder_age_bucket_metrics: opts.folder_age_bucket_metrics,
+        folder_age_bucket_max_series: opts.folder_age_bucket_max_series,
+        folder_relabel: opts.folder_relabel_file.unwrap_or_default(),
+        folder_allow: opts.folder_allow,
+        folder_deny: opts.folder_deny,
+        removable_root: opts.removable_root,
+        no_extension: opts.no_extension,
+        special_files_as_errors: opts.special_files_as_errors,
+        symlinks: opts.symlinks,
+        max_path_length: opts.max_path_length,
+        max_depth: opts.max_depth,
+        scan_threads: opts.scan_threads,
+        camera_serials: opts.camera_serials,
+        ignored_files_per_ext: opts.ignored_files_per_ext,
+        sample: opts.sample,
+        max_files: Some(opts.max_files),
+        required_subdirs: opts.required_subdirs,
+        immutable_subpath: opts.immutable_subpath,
+        mock_data: opts.mock_data,
+        skip_unchanged: opts.skip_unchanged,
+        scan_interval: opts.scan_interval,
+        scan_jitter: opts.scan_jitter.unwrap_or_default(),
+        scan_lock: opts.scan_lock,
+        scan_watchdog: opts.scan_watchdog,
+        watch_tree: opts.watch_tree,
+        clock_skew_tolerance: Some(opts.clock_skew_tolerance),
+        age_calendar: opts
+            .age_calendar
+            .map(|calendar| calendar.with_utc_offset(opts.age_utc_offset)),
+        violations_log: opts
+            .violations_log
+            .map(|path| crate::violations::ViolationsLog::new(path, opts.violations_log_max_size))
+            .map(std::sync::Arc::new),
+        keep_violations: false,
+        largest_files: 0,
+        folder_details: false,
+        disabled_collectors,
+        disabled_checks: opts.disable_check,
+        metric_aliases: if opts.metric_aliases {
+            crate::prometheus::METRIC_ALIASES
+        } else {
+            &[]
+        },
+        state: Default::default(),
+        scheduled_done: Default::default(),
+        #[cfg(feature = "daemon")]
+        scan_completions: None,
+    }
+}
+
+/// Returns the roots to scan if there are several, given via `--paths-from`
+/// or a repeated `--path`.
+pub fn several_roots(opts: &CliOptions) -> Result<Option<Vec<PathBuf>>, String> {
+    match &opts.paths_from {
+        Some(source) => Ok(Some(read_paths_from(source)?)),
+        None if opts.path.len() > 1 => Ok(Some(opts.path.clone())),
+        None => Ok(None),
     }
 }
 
+/// With `--assert-readonly`, checks that the given roots (by default
+/// `--path`) aren't writable, nor have any of the written files under them.
+pub fn check_readonly(opts: &CliOptions, roots: Option<&[PathBuf]>) -> Result<(), String> {
+    if !opts.assert_readonly {
+        return Ok(());
+    }
+    let outputs = [
+        &opts.scan_lock,
+        &opts.violations_log,
+        &opts.snapshot_file,
+        &opts.summary_file,
+        &opts.textfile_output,
+    ];
+    crate::readonly::check_roots(
+        roots.unwrap_or(&opts.path),
+        outputs.into_iter().filter_map(Option::as_deref),
+    )
+}
+
+/// Builds the daemon's collectors: one for `--path`, or one per root given
+/// via `--paths-from` or a repeated `--path`.
+pub fn collectors_from_args(
+    opts: CliOptions,
+) -> Result<Vec<crate::prometheus::PhotoBacklogCollector>, String> {
+    let roots = several_roots(&opts)?;
+    check_readonly(&opts, roots.as_deref())?;
+    let collector = collector_from_args(opts);
+    match roots {
+        None => Ok(vec![collector]),
+        Some(roots) if roots.is_empty() => Err("No roots given via --paths-from".to_string()),
+        Some(roots) => Ok(roots
+            .into_iter()
+            .map(|root| collector.for_root(root))
+            .collect()),
+    }
+}
+
+#[cfg(feature = "daemon")]
+pub fn server_config_from_args(opts: &CliOptions) -> Result<crate::daemon::ServerConfig, String> {
+    let web_config = match &opts.web_config_file {
+        None => None,
+        Some(path) => Some(crate::web_config::WebConfig::load(path)?),
+    };
+    if opts.ipv6_only && opts.listen.is_ipv4() {
+        return Err(format!(
+            "--ipv6-only requires an IPv6 listen address, not {}",
+            opts.listen
+        ));
+    }
+    if opts
+        .auth_token
+        .as_ref()
+        .is_some_and(|token| token.is_empty())
+    {
+        return Err("--auth-token can't be empty".to_string());
+    }
+    let http2 = web_config.as_ref().is_none_or(|c| c.http2());
+    Ok(crate::daemon::ServerConfig {
+        http1_only: opts.http1_only || !http2,
+        no_keep_alive: opts.no_keep_alive,
+        idle_timeout: opts.idle_timeout.map(Duration::from_secs),
+        http2_keep_alive: opts.http2_keep_alive.map(Duration::from_secs),
+        shutdown_grace_period: (opts.in_container || std::process::id() == 1)
+            .then_some(opts.shutdown_grace_period),
+        web_config: web_config.map(std::sync::Arc::new),
+        auth_token: opts.auth_token.clone(),
+        ipv6_only: opts.ipv6_only,
+        no_reuse_address: opts.no_reuse_address,
+        reuse_port: opts.reuse_port,
+        tcp_nodelay: opts.tcp_nodelay,
+    })
+}
+
+#[cfg(feature = "remote-write")]
+pub fn remote_write_from_args(opts: &CliOptions) -> Option<crate::remote_write::RemoteWriteConfig> {
+    opts.remote_write_url
+        .clone()
+        .map(|url| crate::remote_write::RemoteWriteConfig {
+            url,
+            interval: opts.remote_write_interval,
+            labels: opts
+                .remote_write_label
+                .iter()
+                .map(|l| (l.name.clone(), l.value.clone()))
+                .collect(),
+            headers: opts.remote_write_header.clone(),
+        })
+}
+
 // Enables logging with support for systemd (if enabled).
 // Adopted from https://github.com/rust-cli/env_logger/issues/157.
 pub fn enable_logging() {
@@ -219,6 +1541,8 @@ pub fn enable_logging() {
 #[cfg(test)]
 mod tests {
     use std::ffi::OsString;
+    use std::path::PathBuf;
+    use std::time::Duration;
 
     use speculoos::prelude::*;
     use tempfile::tempdir;
@@ -233,6 +1557,243 @@ mod tests {
         assert_that!(opts).is_err().contains("not a directory");
     }
 
+    #[test]
+    fn test_paths_from_without_path() {
+        let opts = super::parse_args_from(&["--paths-from", "-"]);
+        assert_that!(opts).is_ok();
+        let opts = super::parse_args_from(&["--http1-only"]);
+        assert_that!(opts)
+            .is_err()
+            .contains("missing required option `--path`");
+    }
+
+    #[test]
+    fn test_watch_conflicts() {
+        let opts = super::parse_args_from(&["--watch", "30s", "--paths-from", "-"]);
+        assert_that!(opts).is_err().contains("can't be combined");
+        let opts = super::parse_args_from(&["--watch", "1d", "--paths-from", "-"]);
+        assert_that!(opts)
+            .is_err()
+            .contains("Invalid interval unit");
+        let opts = super::parse_args_from(&["--suggest-buckets", "--paths-from", "-"]);
+        assert_that!(opts).is_err().contains("can't be combined");
+    }
+
+    #[test]
+    fn test_collectors_from_args() {
+        let temp_dir = tempdir().unwrap();
+        let roots = temp_dir.path().join("roots");
+        std::fs::write(&roots, "/a\n\n/b\n").unwrap();
+        let opts = super::parse_args_from(&["--paths-from", roots.to_str().unwrap()]).unwrap();
+        let collectors = super::collectors_from_args(opts).unwrap();
+        let paths: Vec<_> = collectors.iter().map(|c| c.scan_path.clone()).collect();
+        assert_that!(paths).is_equal_to(vec![PathBuf::from("/a"), PathBuf::from("/b")]);
+        std::fs::write(&roots, "\n").unwrap();
+        let opts = super::parse_args_from(&["--paths-from", roots.to_str().unwrap()]).unwrap();
+        assert_that!(super::collectors_from_args(opts))
+            .is_err()
+            .contains("No roots");
+    }
+
+    #[test]
+    fn test_repeated_path() {
+        let first = tempdir().unwrap();
+        let second = tempdir().unwrap();
+        let (first, second) = (first.path(), second.path());
+        let args = ["--path", first.to_str().unwrap()];
+        let opts =
+            super::parse_args_from(&[&args[..], &["--path", second.to_str().unwrap()]].concat())
+                .unwrap();
+        let collectors = super::collectors_from_args(opts).unwrap();
+        let paths: Vec<_> = collectors.iter().map(|c| c.scan_path.as_path()).collect();
+        assert_that!(paths).is_equal_to(vec![first, second]);
+        // A single one isn't labeled.
+        let opts = super::parse_args_from(&args).unwrap();
+        assert_that!(super::several_roots(&opts)).is_ok().is_none();
+    }
+
+    #[test]
+    fn test_scan_jitter() {
+        let opts = super::parse_args_from(&["--mock-data", "1,1", "--scan-jitter", "10%"]);
+        assert_that!(opts)
+            .is_err()
+            .contains("requires --scan-interval");
+        let opts = super::parse_args_from(&[
+            "--mock-data",
+            "1,1",
+            "--scan-interval",
+            "10m",
+            "--scan-jitter",
+            "10%",
+        ])
+        .unwrap();
+        let collector = super::collector_from_args(opts);
+        assert_that!(collector.scan_interval).is_equal_to(Some(Duration::from_secs(600)));
+        assert_that!(collector.scan_jitter).is_equal_to(0.1);
+    }
+
+    #[test]
+    fn test_violations_log_not_opened() {
+        let temp_dir = tempdir().unwrap();
+        let log = temp_dir.path().join("violations.jsonl");
+        let opts = super::parse_args_from(&[
+            "--mock-data",
+            "1,1",
+            "--violations-log",
+            log.to_str().unwrap(),
+        ])
+        .unwrap();
+        // Only opened once the binary starts up, not when parsing.
+        assert_that!(opts.violations_log).is_equal_to(Some(log.clone()));
+        assert_that!(log.exists()).is_false();
+    }
+
+    #[test]
+    fn test_age_calendar() {
+        let args = ["--mock-data", "1,1", "--age-calendar", "business-days"];
+        let opts = super::parse_args_from(&[&args[..], &["--age-buckets", "1,1M"]].concat());
+        assert_that!(opts)
+            .is_err()
+            .contains("can't be combined with month");
+        let opts =
+            super::parse_args_from(&[&args[..], &["--age-utc-offset", "+01:00"]].concat()).unwrap();
+        let collector = super::collector_from_args(opts);
+        let expected = crate::work_calendar::WorkCalendar::parse("mon-fri")
+            .unwrap()
+            .with_utc_offset(3600);
+        assert_that!(collector.age_calendar).is_equal_to(Some(expected));
+    }
+
+    #[test]
+    fn test_watch_tree() {
+        let opts = super::parse_args_from(&["--mock-data", "1,1", "--watch-tree"]);
+        assert_that!(opts)
+            .is_err()
+            .contains("requires --scan-interval");
+        let opts = super::parse_args_from(&[
+            "--mock-data",
+            "1,1",
+            "--scan-interval",
+            "1h",
+            "--watch-tree",
+            "--sample",
+            "10%",
+        ]);
+        assert_that!(opts).is_err().contains("--sample");
+    }
+
+    #[test]
+    fn test_config_file() {
+        let temp_dir = tempdir().unwrap();
+        let temp_dir_str = temp_dir.path().to_str().unwrap();
+        let config = temp_dir.path().join("config.toml");
+        std::fs::write(
+            &config,
+            format!(
+                "path = \"{}\"\nmax-depth = 3\ncollector.folders = false\n",
+                temp_dir_str
+            ),
+        )
+        .unwrap();
+        let discovered = || Some(config.clone());
+        let args =
+            super::with_config_file(vec!["--max-depth".to_string(), "5".to_string()], discovered)
+                .unwrap();
+        let opts = super::parse_args_from(&args).unwrap();
+        assert_that!(opts.path).is_equal_to(vec![temp_dir.path().to_path_buf()]);
+        // Command line options take precedence.
+        assert_that!(opts.max_depth).is_equal_to(Some(5));
+        assert_that!(opts.collector_folders).is_equal_to(Some(false));
+        // Roots given on the command line replace the file's.
+        let other = tempdir().unwrap();
+        let other_str = other.path().to_str().unwrap().to_string();
+        let args =
+            super::with_config_file(vec!["--path".to_string(), other_str], discovered).unwrap();
+        let opts = super::parse_args_from(&args).unwrap();
+        assert_that!(opts.path).is_equal_to(vec![other.path().to_path_buf()]);
+        assert_that!(opts.max_depth).is_equal_to(Some(3));
+
+        let args = vec!["--no-config".to_string()];
+        assert_that!(super::with_config_file(args.clone(), discovered)).is_ok_containing(args);
+        let missing = temp_dir.path().join("missing.toml");
+        let args = vec![
+            "--config".to_string(),
+            missing.to_str().unwrap().to_string(),
+        ];
+        assert_that!(super::with_config_file(args, || None))
+            .is_err()
+            .contains("Can't read config file");
+    }
+
+    #[test]
+    fn test_collector_flags() {
+        let temp_dir = tempdir().unwrap();
+        let temp_dir_str = temp_dir.path().to_str().unwrap();
+        let opts = super::parse_args_from(&[
+            "--path",
+            temp_dir_str,
+            "--collector.folders=false",
+            "--collector.fs-stats",
+            "false",
+            "--collector.ages=true",
+        ])
+        .expect("parse args");
+        let collector = super::collector_from_args(opts);
+        assert_that!(collector.disabled_collectors).is_equal_to(vec![
+            crate::prometheus::MetricGroup::Folders,
+            crate::prometheus::MetricGroup::FsStats,
+        ]);
+        let opts = super::parse_args_from(&["--path", temp_dir_str, "--collector.ages=no"]);
+        assert_that!(opts).is_err();
+    }
+
+    #[test]
+    #[cfg(feature = "daemon")]
+    fn test_ipv6_only_listen() {
+        let temp_dir = tempdir().unwrap();
+        let temp_dir_str = temp_dir.path().to_str().unwrap();
+        let opts = super::parse_args_from(&["--path", temp_dir_str, "--ipv6-only"]).unwrap();
+        assert_that!(super::server_config_from_args(&opts)).is_ok();
+        let opts = super::parse_args_from(&[
+            "--path",
+            temp_dir_str,
+            "--ipv6-only",
+            "--listen",
+            "127.0.0.1",
+        ])
+        .unwrap();
+        assert_that!(super::server_config_from_args(&opts))
+            .is_err()
+            .contains("requires an IPv6 listen address");
+    }
+
+    #[test]
+    fn test_disable_check() {
+        let temp_dir = tempdir().unwrap();
+        let temp_dir_str = temp_dir.path().to_str().unwrap();
+        std::fs::File::create(temp_dir.path().join("1.nef")).unwrap();
+        let opts = super::parse_args_from(&[
+            "--path",
+            temp_dir_str,
+            "--raw-file-mode",
+            "0",
+            "--dir-mode",
+            "0",
+            "--disable-check",
+            "raw-mode",
+        ])
+        .expect("parse args");
+        let collector = super::collector_from_args(opts);
+        let backlog = collector.scan_root(temp_dir.path(), std::time::SystemTime::now());
+        // Only the directory's mode is checked.
+        assert_that!(backlog.total_errors).contains_entry(crate::ErrorType::Permissions, 1);
+        let opts =
+            super::parse_args_from(&["--path", temp_dir_str, "--disable-check", "ownership"]);
+        assert_that!(opts)
+            .is_err()
+            .contains("Invalid check 'ownership', expected one of owner, group");
+    }
+
     #[test]
     fn test_cli_error() {
         let opts = super::parse_args_from(&["--no-such-arg"]);
@@ -260,4 +1821,15 @@ mod tests {
         let expected_exts = vec![OsString::from("xmp"), OsString::from("info")];
         assert_that!(opts.ignored_exts).is_equal_to(expected_exts);
     }
+
+    #[test]
+    fn test_scan_threads() {
+        let temp_dir = tempdir().unwrap();
+        let temp_dir_str = temp_dir.path().to_str().unwrap();
+        for (args, expected) in [(vec![], 1), (vec!["--scan-threads", "4"], 4)] {
+            let opts = super::parse_args_from(&[&["--path", temp_dir_str][..], &args].concat())
+                .expect("parse args");
+            assert_that!(super::collector_from_args(opts).scan_threads).is_equal_to(expected);
+        }
+    }
 }
@@ -0,0 +1,194 @@
+//! Ordering and display of folder names in the human-readable reports
+//! (`oneshot --watch` and `oneshot compare`). Names are sorted by Unicode
+//! collation, as per the locale, and shown composed (NFC), so that e.g.
+//! umlauts sort next to their base letters, and names written by macOS,
+//! which stores them decomposed (NFD), sort and print like the others.
+//!
+//! The metrics and APIs keep the names as they are on disk. Without the
+//! `collation` feature, names are sorted by bytes and shown as they are.
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+
+#[cfg(feature = "collation")]
+use icu_collator::{Collator, CollatorOptions};
+#[cfg(feature = "collation")]
+use icu_locid::Locale;
+#[cfg(feature = "collation")]
+use icu_normalizer::ComposingNormalizer;
+
+pub struct FolderOrder {
+    #[cfg(feature = "collation")]
+    collator: Collator,
+}
+
+impl FolderOrder {
+    /// Uses the collation of the given locale, e.g. `de` or `sv-SE`.
+    #[cfg(feature = "collation")]
+    pub fn new(locale: &str) -> Result<Self, String> {
+        let locale: Locale = locale
+            .parse()
+            .map_err(|e| format!("Invalid locale '{}': {}", locale, e))?;
+        let collator = Collator::try_new(&(&locale).into(), CollatorOptions::new())
+            .map_err(|e| format!("Can't collate for locale '{}': {}", locale, e))?;
+        Ok(FolderOrder { collator })
+    }
+
+    #[cfg(not(feature = "collation"))]
+    pub fn new(_locale: &str) -> Result<Self, String> {
+        Err("Built without the collation feature".to_string())
+    }
+
+    /// Uses the collation locale of the environment, as `sort` does (but
+    /// with the root collation instead of byte order for `C`).
+    #[cfg(feature = "collation")]
+    pub fn from_env() -> Self {
+        let var = |name| std::env::var(name).ok();
+        let locale = env_locale(var("LC_ALL"), var("LC_COLLATE"), var("LANG"));
+        locale
+            .and_then(|locale| {
+                FolderOrder::new(&locale)
+                    .inspect_err(|e| log::debug!("{}, using the root collation", e))
+                    .ok()
+            })
+            .unwrap_or_default()
+    }
+
+    #[cfg(not(feature = "collation"))]
+    pub fn from_env() -> Self {
+        FolderOrder::default()
+    }
+
+    /// Compares names by collation, then by bytes, for a total order.
+    #[cfg(feature = "collation")]
+    pub fn compare(&self, a: &str, b: &str) -> Ordering {
+        self.collator.compare(a, b).then_with(|| a.cmp(b))
+    }
+
+    #[cfg(not(feature = "collation"))]
+    pub fn compare(&self, a: &str, b: &str) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+impl Default for FolderOrder {
+    /// The root collation, i.e. the Unicode default order.
+    #[cfg(feature = "collation")]
+    fn default() -> Self {
+        FolderOrder::new("und").expect("the root collation is built in")
+    }
+
+    #[cfg(not(feature = "collation"))]
+    fn default() -> Self {
+        FolderOrder {}
+    }
+}
+
+/// Returns the name in composed form, for display.
+/// Example:
+/// ```
+/// use photo_backlog_exporter::collation::display;
+/// assert_eq!(display("Mu\u{308}ller"), "M\u{fc}ller");
+/// assert_eq!(display("plain"), "plain");
+/// ```
+#[cfg(feature = "collation")]
+pub fn display(name: &str) -> Cow<'_, str> {
+    const NFC: ComposingNormalizer = ComposingNormalizer::new_nfc();
+    if NFC.is_normalized(name) {
+        Cow::Borrowed(name)
+    } else {
+        Cow::Owned(NFC.normalize(name))
+    }
+}
+
+#[cfg(not(feature = "collation"))]
+pub fn display(name: &str) -> Cow<'_, str> {
+    Cow::Borrowed(name)
+}
+
+// Returns the collation locale as per the POSIX variables, in precedence
+// order, converted to a BCP 47 tag (e.g. `de_DE.UTF-8` to `de-DE`).
+#[cfg(feature = "collation")]
+fn env_locale(
+    lc_all: Option<String>,
+    lc_collate: Option<String>,
+    lang: Option<String>,
+) -> Option<String> {
+    let value = [lc_all, lc_collate, lang]
+        .into_iter()
+        .flatten()
+        .find(|v| !v.is_empty())?;
+    let name = value.split(['.', '@']).next().unwrap_or_default();
+    match name {
+        "" | "C" | "POSIX" => None,
+        name => Some(name.replace('_', "-")),
+    }
+}
+
+#[cfg(all(test, feature = "collation"))]
+mod tests {
+    use rstest::rstest;
+    use speculoos::prelude::*;
+
+    use super::{env_locale, FolderOrder};
+
+    fn sorted(order: &FolderOrder, names: &[&str]) -> Vec<String> {
+        let mut names: Vec<_> = names.iter().map(|n| n.to_string()).collect();
+        names.sort_by(|a, b| order.compare(a, b));
+        names
+    }
+
+    #[test]
+    fn test_compare() {
+        let names = [
+            "Zürich",
+            "Ziegel",
+            "Äpfel",
+            "apfel",
+            "Mu\u{308}ller",
+            "Mueller",
+            "Möbel",
+        ];
+        let german = FolderOrder::new("de").unwrap();
+        assert_that!(sorted(&german, &names)).is_equal_to(
+            [
+                "apfel",
+                "Äpfel",
+                "Möbel",
+                "Mueller",
+                "Mu\u{308}ller",
+                "Ziegel",
+                "Zürich",
+            ]
+            .map(String::from)
+            .to_vec(),
+        );
+        // Swedish sorts the umlauts after z.
+        let swedish = FolderOrder::new("sv").unwrap();
+        assert_that!(sorted(&swedish, &["Äpfel", "Zebra", "apfel"]))
+            .is_equal_to(["apfel", "Zebra", "Äpfel"].map(String::from).to_vec());
+        // Composed and decomposed forms sort together, in byte order.
+        let default = FolderOrder::default();
+        assert_that!(sorted(&default, &["Mü", "Mv", "Mu\u{308}", "Mu"]))
+            .is_equal_to(["Mu", "Mu\u{308}", "Mü", "Mv"].map(String::from).to_vec());
+        assert_that!(FolderOrder::new("not a locale!").is_err()).is_true();
+    }
+
+    #[rstest]
+    #[case(None, None, None, None)]
+    #[case(None, None, Some("de_DE.UTF-8"), Some("de-DE"))]
+    #[case(None, Some("sv_SE.UTF-8"), Some("de_DE.UTF-8"), Some("sv-SE"))]
+    #[case(Some("C"), Some("sv_SE"), None, None)]
+    #[case(Some(""), None, Some("de_AT@euro"), Some("de-AT"))]
+    #[case(None, None, Some("POSIX"), None)]
+    fn test_env_locale(
+        #[case] lc_all: Option<&str>,
+        #[case] lc_collate: Option<&str>,
+        #[case] lang: Option<&str>,
+        #[case] expected: Option<&str>,
+    ) {
+        let owned = |v: Option<&str>| v.map(String::from);
+        assert_that!(env_locale(owned(lc_all), owned(lc_collate), owned(lang)))
+            .is_equal_to(owned(expected));
+    }
+}
@@ -0,0 +1,165 @@
+//! Optional TOML configuration file, holding default values for the
+//! command line options, so that interactive runs pick up the standard
+//! settings without flags.
+//!
+//! Keys are the long option names, and values are given as on the command
+//! line; arrays are for repeatable options, and `false` for the options
+//! taking a boolean (e.g. `collector.folders`):
+//!
+//! ```toml
+//! path = "/photos/incoming"
+//! raw-exts = "nef,cr3"
+//! disable-check = ["group", "structure"]
+//! collector.fs-stats = false
+//! ```
+//!
+//! The options are prepended to the command line ones, which thus take
+//! precedence, except for repeatable options, which are combined (other
+//! than `path`, see `cli::with_config_file`).
+
+use std::path::{Path, PathBuf};
+
+use toml_edit::{DocumentMut, Item, Table, Value};
+
+/// Name of the directory holding the configuration file.
+const APP_DIR: &str = "photo-backlog-exporter";
+const FILE_NAME: &str = "config.toml";
+
+/// Returns the default locations of the configuration file, in search
+/// order: the user's XDG configuration directory, then `/etc`.
+pub fn default_locations(xdg_config_home: Option<&Path>, home: Option<&Path>) -> Vec<PathBuf> {
+    // Per the XDG spec, an empty or relative value is to be ignored.
+    let user_dir = match xdg_config_home.filter(|p| p.is_absolute()) {
+        Some(dir) => Some(dir.to_path_buf()),
+        None => home.map(|home| home.join(".config")),
+    };
+    user_dir
+        .into_iter()
+        .chain([PathBuf::from("/etc")])
+        .map(|dir| dir.join(APP_DIR).join(FILE_NAME))
+        .collect()
+}
+
+/// Finds the first existing configuration file in the default locations.
+pub fn discover() -> Option<PathBuf> {
+    let xdg_config_home = std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from);
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    default_locations(xdg_config_home.as_deref(), home.as_deref())
+        .into_iter()
+        .find(|path| path.is_file())
+}
+
+fn value_args(name: &str, value: &Value, args: &mut Vec<String>) -> Result<(), String> {
+    let option = format!("--{}", name);
+    match value {
+        Value::Boolean(b) if *b.value() => args.push(option),
+        Value::Boolean(_) => args.push(format!("{}=false", option)),
+        Value::String(s) => args.extend([option, s.value().clone()]),
+        Value::Integer(i) => args.extend([option, i.value().to_string()]),
+        Value::Float(f) => args.extend([option, f.value().to_string()]),
+        Value::Array(values) => {
+            for value in values {
+                if value.is_array() {
+                    return Err(format!("Nested arrays are not supported for '{}'", name));
+                }
+                value_args(name, value, args)?;
+            }
+        }
+        _ => return Err(format!("Unsupported value for '{}'", name)),
+    }
+    Ok(())
+}
+
+fn table_args(prefix: &str, table: &Table, args: &mut Vec<String>) -> Result<(), String> {
+    for (key, item) in table.iter() {
+        let name = format!("{}{}", prefix, key);
+        match item {
+            Item::Value(value) => value_args(&name, value, args)?,
+            // Dotted option names, e.g. `collector.folders`.
+            Item::Table(table) => table_args(&format!("{}.", name), table, args)?,
+            _ => return Err(format!("Unsupported value for '{}'", name)),
+        }
+    }
+    Ok(())
+}
+
+/// Converts the configuration file contents to command line arguments.
+/// Example:
+/// ```
+/// use photo_backlog_exporter::config_file::to_args;
+/// assert_eq!(
+///     to_args("path = \"/photos\"\nhttp1-only = true\n").unwrap(),
+///     vec!["--path", "/photos", "--http1-only"]
+/// );
+/// ```
+pub fn to_args(contents: &str) -> Result<Vec<String>, String> {
+    let document: DocumentMut = contents.parse().map_err(|e| format!("{}", e))?;
+    let mut args = Vec::new();
+    table_args("", document.as_table(), &mut args)?;
+    Ok(args)
+}
+
+/// Loads the given configuration file as command line arguments.
+pub fn load(path: &Path) -> Result<Vec<String>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Can't read config file '{}': {}", path.display(), e))?;
+    to_args(&contents).map_err(|e| format!("Can't parse config file '{}': {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use speculoos::prelude::*;
+
+    use super::{default_locations, to_args};
+
+    #[test]
+    fn test_default_locations() {
+        let etc = PathBuf::from("/etc/photo-backlog-exporter/config.toml");
+        assert_that!(default_locations(
+            Some(Path::new("/xdg")),
+            Some(Path::new("/home/u"))
+        ))
+        .is_equal_to(vec![
+            PathBuf::from("/xdg/photo-backlog-exporter/config.toml"),
+            etc.clone(),
+        ]);
+        assert_that!(default_locations(
+            Some(Path::new("xdg")),
+            Some(Path::new("/home/u"))
+        ))
+        .is_equal_to(vec![
+            PathBuf::from("/home/u/.config/photo-backlog-exporter/config.toml"),
+            etc.clone(),
+        ]);
+        assert_that!(default_locations(None, None)).is_equal_to(vec![etc]);
+    }
+
+    #[test]
+    fn test_to_args() {
+        let args = to_args(
+            "disable-check = [\"group\", \"structure\"]\n\
+             max-depth = 3\n\
+             collector.fs-stats = false\n",
+        )
+        .unwrap();
+        assert_that!(args).is_equal_to(
+            [
+                "--disable-check",
+                "group",
+                "--disable-check",
+                "structure",
+                "--max-depth",
+                "3",
+                "--collector.fs-stats=false",
+            ]
+            .map(String::from)
+            .to_vec(),
+        );
+        assert_that!(to_args("path = [[\"a\"]]\n"))
+            .is_err()
+            .contains("Nested arrays");
+        assert_that!(to_args("path = ")).is_err();
+    }
+}
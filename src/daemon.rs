@@ -1,57 +1,1261 @@
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
 
-use axum::{routing::get, Router};
-use prometheus_client::{encoding::text::encode, registry::Registry};
+use axum::extract::{Query, RawQuery, Request, State};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{middleware, Json, Router};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::service::TowerToHyperService;
+use prometheus_client::encoding::text::encode_registry;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::registry::Registry;
+#[cfg(target_os = "linux")]
+use rustix::process::{waitpid, WaitOptions};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{broadcast, watch};
+use tokio::time::Instant;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
 
 use crate::cli;
+use crate::prometheus::{encode_roots, PhotoBacklogCollector, ScanCompletion, ScrapeOptions};
+use crate::snapshot::ViolationSnapshot;
+use crate::summary::{ExitReason, Summary};
+use crate::tree_watch;
+use crate::web_config::{self, WebConfig};
+use crate::Backlog;
 
-pub fn build_app(opts: cli::CliOptions) -> (SocketAddr, Router) {
+/// The first descriptor of the sockets passed by systemd.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Connection-level settings for the HTTP server.
+#[derive(Clone, Debug, Default)]
+pub struct ServerConfig {
+    /// Only serve HTTP/1, refusing HTTP/2 (h2c) connections.
+    pub http1_only: bool,
+    /// Close HTTP/1 connections after each response.
+    pub no_keep_alive: bool,
+    /// Close connections that have been idle for this long.
+    pub idle_timeout: Option<Duration>,
+    /// Interval for HTTP/2 keep-alive pings; unanswered pings close the connection.
+    pub http2_keep_alive: Option<Duration>,
+    /// If set, shut down on SIGTERM and SIGINT, waiting at most this long
+    /// for in-flight requests, and reap zombie processes, as needed when
+    /// running as PID 1 in a container; otherwise the signals' default
+    /// actions apply.
+    pub shutdown_grace_period: Option<Duration>,
+    /// TLS and authentication settings, in the Prometheus web config format.
+    pub web_config: Option<Arc<WebConfig>>,
+    /// Bearer token accepted for all requests, besides any basic auth
+    /// users from the web config.
+    pub auth_token: Option<String>,
+    /// Only accept IPv6 connections on IPv6 addresses, instead of dual-stack.
+    pub ipv6_only: bool,
+    /// Don't set SO_REUSEADDR on the listener (which is set by default).
+    pub no_reuse_address: bool,
+    /// Set SO_REUSEPORT, allowing several instances to share the port.
+    pub reuse_port: bool,
+    /// Set TCP_NODELAY on accepted connections.
+    pub tcp_nodelay: bool,
+}
+
+/// Builds the exporter's routes (currently `/metrics`), e.g. for mounting
+/// inside another axum application, together with a handle on the
+/// collectors.
+/// Example:
+/// ```
+/// use axum::Router;
+/// use photo_backlog_exporter::buckets::AgeBound;
+/// use photo_backlog_exporter::daemon::AppBuilder;
+/// use photo_backlog_exporter::prometheus::PhotoBacklogCollector;
+/// use photo_backlog_exporter::MockData;
+///
+/// let collector = PhotoBacklogCollector {
+///     mock_data: Some(MockData { folders: 1, files: 2 }),
+///     age_buckets: vec![AgeBound::Seconds(1.0)],
+///     ..Default::default()
+/// };
+/// let (routes, handle) = AppBuilder::new(vec![collector])
+///     .max_scrapes_per_minute(10)
+///     .build();
+/// let app: Router = Router::new().nest("/photo-backlog", routes);
+/// assert_eq!(handle.scan()[0].total_files, 2);
+/// ```
+#[derive(Debug, Default)]
+pub struct AppBuilder {
+    collectors: Vec<PhotoBacklogCollector>,
+    max_scrapes_per_minute: u32,
+    cors_origins: Vec<String>,
+    response_headers: Vec<cli::ResponseHeader>,
+    registry: Option<Arc<Registry>>,
+    snapshot_endpoint: bool,
+    largest_files: usize,
+    folder_endpoint: bool,
+    events_endpoint: bool,
+    rescan_debounce: Option<Duration>,
+    trusted_users: Option<Arc<TrustedUsers>>,
+}
+
+/// Users allowed to use the JSON and admin endpoints, as authenticated by
+/// a reverse proxy setting the header.
+#[derive(Debug)]
+struct TrustedUsers {
+    header: HeaderName,
+    users: Vec<String>,
+}
+
+impl AppBuilder {
+    /// Serves the given collectors, labeling the metrics with their root
+    /// if more than one.
+    pub fn new(collectors: Vec<PhotoBacklogCollector>) -> Self {
+        AppBuilder {
+            collectors,
+            ..Default::default()
+        }
+    }
+
+    /// Configures the app as per the command line options.
+    pub fn from_args(opts: cli::CliOptions) -> Result<Self, String> {
+        let max_scrapes = opts.max_scrapes_per_minute;
+        let cors_origins = opts.cors_origins.clone();
+        let response_headers = opts.response_header.clone();
+        let snapshot_endpoint = opts.snapshot_endpoint;
+        let largest_files = opts.largest_files;
+        let folder_endpoint = opts.folder_endpoint;
+        let events_endpoint = opts.events_endpoint;
+        let trusted_user_header = opts.trusted_user_header.clone();
+        let allowed_users = opts.allowed_users.clone();
+        let rescan_debounce = opts
+            .rescan_endpoint
+            .then(|| opts.rescan_debounce.unwrap_or(DEFAULT_RESCAN_DEBOUNCE));
+        let builder = AppBuilder::new(cli::collectors_from_args(opts)?);
+        let builder = match trusted_user_header {
+            Some(header) => builder.trusted_users(header, allowed_users),
+            None => builder,
+        };
+        Ok(builder
+            .snapshot_endpoint(snapshot_endpoint)
+            .largest_files_endpoint(largest_files)
+            .folder_endpoint(folder_endpoint)
+            .events_endpoint(events_endpoint)
+            .rescan_endpoint(rescan_debounce)
+            .max_scrapes_per_minute(max_scrapes)
+            .cors_origins(cors_origins)
+            .response_headers(response_headers))
+    }
+
+    /// Rate limits scrapes, zero meaning unlimited.
+    pub fn max_scrapes_per_minute(mut self, n: u32) -> Self {
+        self.max_scrapes_per_minute = n;
+        self
+    }
+
+    /// Allows cross-origin requests from the given origins.
+    pub fn cors_origins(mut self, origins: Vec<String>) -> Self {
+        self.cors_origins = origins;
+        self
+    }
+
+    /// Adds the given headers to all responses.
+    pub fn response_headers(mut self, headers: Vec<cli::ResponseHeader>) -> Self {
+        self.response_headers = headers;
+        self
+    }
+
+    /// Serves the last scan of each root, including the violation paths,
+    /// as gzip-compressed JSON at `/api/v1/snapshot`.
+    pub fn snapshot_endpoint(mut self, enabled: bool) -> Self {
+        self.snapshot_endpoint = enabled;
+        self
+    }
+
+    /// Keeps the `n` largest backlog files of each scan and serves them
+    /// as JSON at `/api/v1/largest`, if `n` isn't zero.
+    pub fn largest_files_endpoint(mut self, n: usize) -> Self {
+        self.largest_files = n;
+        self
+    }
+
+    /// Keeps the per-folder details of each scan and serves them, with the
+    /// folder's violations, as JSON at `/api/v1/folders/NAME`.
+    pub fn folder_endpoint(mut self, enabled: bool) -> Self {
+        self.folder_endpoint = enabled;
+        self
+    }
+
+    /// Streams the completion of each background scan, with a summary of
+    /// its results, as Server-Sent Events at `/api/v1/events`.
+    pub fn events_endpoint(mut self, enabled: bool) -> Self {
+        self.events_endpoint = enabled;
+        self
+    }
+
+    /// Restricts the JSON and admin endpoints (but not `/metrics`) to the
+    /// given users, as found in the header set by an authenticating
+    /// reverse proxy, which must not let clients set it themselves.
+    pub fn trusted_users(mut self, header: HeaderName, users: Vec<String>) -> Self {
+        self.trusted_users = Some(Arc::new(TrustedUsers { header, users }));
+        self
+    }
+
+    /// Triggers a background scan of all roots on `POST /-/rescan`, if a
+    /// debounce window is given: the scan starts after the window, and
+    /// further triggers until then are suppressed.
+    pub fn rescan_endpoint(mut self, debounce: Option<Duration>) -> Self {
+        self.rescan_debounce = debounce;
+        self
+    }
+
+    /// Also serves the metrics of the given registry, e.g. holding the
+    /// application's own collectors, before the backlog ones.
+    pub fn registry(mut self, registry: Registry) -> Self {
+        self.registry = Some(Arc::new(registry));
+        self
+    }
+
+    /// Builds the routes, starting the background scans of collectors
+    /// scanning on a schedule.
+    pub fn build(self) -> (Router, AppHandle) {
+        let snapshot_endpoint = self.snapshot_endpoint;
+        let largest_files = self.largest_files;
+        let folder_endpoint = self.folder_endpoint;
+        let completions = self
+            .events_endpoint
+            .then(|| broadcast::channel(SCAN_EVENTS_CAPACITY).0);
+        let collectors: Vec<_> = self
+            .collectors
+            .into_iter()
+            .map(|collector| {
+                Arc::new(PhotoBacklogCollector {
+                    keep_violations: collector.keep_violations
+                        || snapshot_endpoint
+                        || folder_endpoint,
+                    largest_files: collector.largest_files.max(largest_files),
+                    folder_details: collector.folder_details || folder_endpoint,
+                    scan_completions: completions.clone().or(collector.scan_completions),
+                    ..collector
+                })
+            })
+            .collect();
+        for collector in collectors.iter().filter(|c| c.scan_interval.is_some()) {
+            let collector = Arc::clone(collector);
+            std::thread::spawn(move || collector.run_scheduled_scans());
+        }
+        // Rescans caused by the tree watches are throttled like the
+        // endpoint's ones.
+        let min_rescan_interval = self.rescan_debounce.unwrap_or(DEFAULT_RESCAN_DEBOUNCE);
+        for collector in collectors.iter().filter(|c| c.watch_tree) {
+            let collector = Arc::clone(collector);
+            std::thread::spawn(move || tree_watch::run(&collector, min_rescan_interval));
+        }
+        let handle = AppHandle {
+            collectors: Arc::new(collectors),
+        };
+
+        let rescanner = self.rescan_debounce.map(|debounce| {
+            Arc::new(Rescanner {
+                handle: handle.clone(),
+                debounce,
+                pending: Mutex::new(false),
+                scanning: tokio::sync::Mutex::new(()),
+                triggers: Family::default(),
+            })
+        });
+        let mut registries: Vec<_> = self.registry.into_iter().collect();
+        if let Some(rescanner) = &rescanner {
+            let mut registry = Registry::default();
+            registry.register(
+                "photo_backlog_rescan_triggers",
+                "Rescan requests, either queued or suppressed as one was already queued",
+                rescanner.triggers.clone(),
+            );
+            registries.push(Arc::new(registry));
+        }
+
+        // build our application with a route
+        let collectors = Arc::clone(&handle.collectors);
+        let registries = Arc::new(registries);
+        let app = Router::new().route(
+            "/metrics",
+            get(move |headers: HeaderMap, query: RawQuery| {
+                metrics(
+                    Arc::clone(&collectors),
+                    Arc::clone(&registries),
+                    headers,
+                    query,
+                )
+            }),
+        );
+        let app = if self.max_scrapes_per_minute == 0 {
+            app
+        } else {
+            let limiter = Arc::new(RateLimiter::per_minute(self.max_scrapes_per_minute));
+            app.route_layer(middleware::from_fn_with_state(limiter, rate_limit))
+        };
+        // The JSON and admin endpoints, which can be restricted to some
+        // users; not rate limited, as they don't scan.
+        let mut api = Router::new();
+        if snapshot_endpoint {
+            let handle = handle.clone();
+            api = api.route(
+                "/api/v1/snapshot",
+                get(move |params: Query<SnapshotParams>, headers: HeaderMap| {
+                    snapshot(handle.clone(), params, headers)
+                }),
+            );
+        }
+        if largest_files > 0 {
+            let handle = handle.clone();
+            api = api.route(
+                "/api/v1/largest",
+                get(move |params: Query<LargestParams>| largest(handle.clone(), params)),
+            );
+        }
+        if folder_endpoint {
+            let handle = handle.clone();
+            api = api.route(
+                "/api/v1/folders/:name",
+                get(
+                    move |name: axum::extract::Path<String>, params: Query<SnapshotParams>| {
+                        folder(handle.clone(), name, params)
+                    },
+                ),
+            );
+        }
+        if let Some(completions) = &completions {
+            let completions = completions.clone();
+            api = api.route(
+                "/api/v1/events",
+                get(move || events(completions.subscribe())),
+            );
+        }
+        // Debounced instead.
+        if let Some(rescanner) = rescanner {
+            api = api.route("/-/rescan", post(move || rescan(Arc::clone(&rescanner))));
+        }
+        let api_enabled = snapshot_endpoint
+            || largest_files > 0
+            || folder_endpoint
+            || completions.is_some()
+            || self.rescan_debounce.is_some();
+        let app = match self.trusted_users {
+            Some(users) if api_enabled => {
+                app.merge(api.route_layer(middleware::from_fn_with_state(users, authorize)))
+            }
+            _ => app.merge(api),
+        };
+        let app = if self.cors_origins.is_empty() {
+            app
+        } else {
+            app.layer(middleware::from_fn_with_state(
+                Arc::new(self.cors_origins),
+                cors,
+            ))
+        };
+        let app = if self.response_headers.is_empty() {
+            app
+        } else {
+            app.layer(middleware::from_fn_with_state(
+                Arc::new(self.response_headers),
+                add_headers,
+            ))
+        };
+        (app, handle)
+    }
+}
+
+/// Handle on the collectors of a built app, for scanning outside of
+/// scrapes and inspecting the results.
+#[derive(Clone, Debug)]
+pub struct AppHandle {
+    collectors: Arc<Vec<Arc<PhotoBacklogCollector>>>,
+}
+
+impl AppHandle {
+    /// Scans all roots now, returning the resulting backlogs (or the last
+    /// good ones, if a scan failed). As this blocks on the filesystem, call
+    /// it via e.g. `tokio::task::spawn_blocking` from async code.
+    pub fn scan(&self) -> Vec<Arc<Backlog>> {
+        self.collectors.iter().map(|c| c.scan_now()).collect()
+    }
+
+    /// Returns each root with its last good scan, if any, without
+    /// scanning.
+    pub fn last_good(&self) -> Vec<(PathBuf, Option<Arc<Backlog>>)> {
+        self.collectors
+            .iter()
+            .map(|c| (c.scan_path.clone(), c.state.lock().unwrap().last_good()))
+            .collect()
+    }
+
+    /// Scans all roots as for a scrape, returning the metrics in the text
+    /// format.
+    pub fn encode(&self) -> Result<String, String> {
+        encode_roots(&self.collectors, &ScrapeOptions::default()).map_err(|e| e.to_string())
+    }
+}
+
+pub fn build_app(opts: cli::CliOptions) -> Result<(SocketAddr, Router), String> {
     let addr = SocketAddr::from((opts.listen, opts.port));
-    let collector = Box::new(cli::collector_from_args(opts));
-    let mut registry = Registry::default();
-    registry.register_collector(collector);
-    let r2 = Arc::new(registry);
-
-    // build our application with a route
-    let app = Router::new().route(
-        "/metrics",
-        get({
-            let req_registry = Arc::clone(&r2);
-            move || metrics(req_registry)
-        }),
-    );
-    (addr, app)
+    #[cfg(not(feature = "remote-write"))]
+    if opts.remote_write_url.is_some() {
+        return Err("--remote-write-url requires the remote-write feature".to_string());
+    }
+    #[cfg(feature = "remote-write")]
+    let remote_write = cli::remote_write_from_args(&opts);
+    let (app, _handle) = AppBuilder::from_args(opts)?.build();
+    #[cfg(feature = "remote-write")]
+    if let Some(config) = remote_write {
+        let handle = _handle.clone();
+        tokio::spawn(async move {
+            if let Err(e) = crate::remote_write::run(config, handle).await {
+                log::error!("Remote write disabled: {}", e);
+            }
+        });
+    }
+    Ok((addr, app))
 }
 
-pub async fn run_daemon(addr: SocketAddr, app: Router) -> Result<(), String> {
-    let listener = TcpListener::bind(&addr)
-        .await
-        .map_err(|e| format!("Failed to bind to {}: {}", addr, e))?;
-    axum::serve(listener, app)
+/// A token bucket, refilled continuously up to its capacity.
+#[derive(Debug)]
+pub struct RateLimiter {
+    capacity: f64,
+    refill_per_sec: f64,
+    // Available tokens, as of the given time.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    /// Allows `n` requests per minute, with bursts of up to `n` requests.
+    pub fn per_minute(n: u32) -> Self {
+        let capacity = f64::from(n);
+        Self {
+            capacity,
+            refill_per_sec: capacity / 60.0,
+            state: Mutex::new((capacity, Instant::now())),
+        }
+    }
+
+    /// Takes a token if available, returning whether the request is allowed.
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let (tokens, last) = *state;
+        let tokens = (tokens + now.duration_since(last).as_secs_f64() * self.refill_per_sec)
+            .min(self.capacity);
+        if tokens >= 1.0 {
+            *state = (tokens - 1.0, now);
+            true
+        } else {
+            *state = (tokens, now);
+            false
+        }
+    }
+}
+
+async fn rate_limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !limiter.try_acquire() {
+        log::info!("Rate limit exceeded, rejecting scrape");
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, "60")],
+            "Too many scrapes, try again later\n",
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+// Adds CORS headers for allowed origins, and answers preflight requests.
+async fn cors(State(origins): State<Arc<Vec<String>>>, request: Request, next: Next) -> Response {
+    let origin = match request.headers().get(header::ORIGIN) {
+        Some(origin)
+            if origins
+                .iter()
+                .any(|o| o == "*" || o.as_bytes() == origin.as_bytes()) =>
+        {
+            origin.clone()
+        }
+        _ => return next.run(request).await,
+    };
+    let preflight = request.method() == Method::OPTIONS
+        && request
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+    let mut response = if preflight {
+        let mut response = StatusCode::NO_CONTENT.into_response();
+        let headers = response.headers_mut();
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            HeaderValue::from_static("GET, OPTIONS"),
+        );
+        if let Some(req_headers) = request
+            .headers()
+            .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+        {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, req_headers.clone());
+        }
+        headers.insert(
+            header::ACCESS_CONTROL_MAX_AGE,
+            HeaderValue::from_static("3600"),
+        );
+        response
+    } else {
+        next.run(request).await
+    };
+    let headers = response.headers_mut();
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    response
+}
+
+// Only lets through requests from the allowed users, as given in the
+// header set by the authenticating reverse proxy.
+async fn authorize(
+    State(trusted): State<Arc<TrustedUsers>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let user = request
+        .headers()
+        .get(&trusted.header)
+        .and_then(|value| value.to_str().ok());
+    match user {
+        None => (StatusCode::UNAUTHORIZED, "Not authenticated\n").into_response(),
+        Some(user) if trusted.users.iter().any(|u| u == user) => next.run(request).await,
+        Some(user) => {
+            log::info!("Rejecting request from user '{}', not allowed", user);
+            (StatusCode::FORBIDDEN, "Not allowed\n").into_response()
+        }
+    }
+}
+
+// Adds the configured extra headers to all responses, replacing any
+// existing ones of the same name.
+async fn add_headers(
+    State(extra_headers): State<Arc<Vec<cli::ResponseHeader>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    for extra in extra_headers.iter() {
+        headers.insert(extra.name.clone(), extra.value.clone());
+    }
+    response
+}
+
+pub async fn run_daemon(
+    addr: SocketAddr,
+    app: Router,
+    server_config: ServerConfig,
+) -> Result<(), String> {
+    let listener = match systemd_listener()? {
+        Some(listener) => listener,
+        None => {
+            bind(addr, &server_config).map_err(|e| format!("Failed to bind to {}: {}", addr, e))?
+        }
+    };
+    run_daemon_on(listener, app, server_config).await
+}
+
+// Returns the number of sockets passed via systemd socket activation, as
+// per sd_listen_fds(3), or zero if they're not meant for this process
+// (e.g. inherited from a wrapper that didn't exec).
+fn listen_fds(
+    listen_pid: Option<String>,
+    listen_fds: Option<String>,
+    pid: u32,
+) -> Result<usize, String> {
+    let (Some(listen_pid), Some(listen_fds)) = (listen_pid, listen_fds) else {
+        return Ok(0);
+    };
+    let listen_pid: u32 = listen_pid
+        .parse()
+        .map_err(|_| format!("Invalid LISTEN_PID '{}'", listen_pid))?;
+    if listen_pid != pid {
+        return Ok(0);
+    }
+    listen_fds
+        .parse()
+        .map_err(|_| format!("Invalid LISTEN_FDS '{}'", listen_fds))
+}
+
+// Returns the listening socket passed by systemd, if any, in which case
+// the listen address and socket options are those of the socket unit.
+fn systemd_listener() -> Result<Option<TcpListener>, String> {
+    let var = |name| std::env::var(name).ok();
+    match listen_fds(var("LISTEN_PID"), var("LISTEN_FDS"), std::process::id())? {
+        0 => Ok(None),
+        1 => {
+            // SAFETY: the passed sockets start at this descriptor, and
+            // belong to this process; nothing else takes ownership of it.
+            let fd = unsafe { OwnedFd::from_raw_fd(SD_LISTEN_FDS_START) };
+            inherited_listener(fd).map(Some)
+        }
+        n => Err(format!(
+            "Got {} sockets from systemd, expected a single one",
+            n
+        )),
+    }
+}
+
+fn inherited_listener(fd: OwnedFd) -> Result<TcpListener, String> {
+    let socket = Socket::from(fd);
+    let error = |e: std::io::Error| format!("Invalid socket from systemd: {}", e);
+    let addr = socket.local_addr().map_err(error)?;
+    let Some(addr) = addr.as_socket() else {
+        return Err("Invalid socket from systemd: not a TCP socket".to_string());
+    };
+    if socket.r#type().map_err(error)? != Type::STREAM {
+        return Err("Invalid socket from systemd: not a TCP socket".to_string());
+    }
+    // Passed connections (Accept=yes in the socket unit) aren't supported.
+    #[cfg(target_os = "linux")]
+    if !socket.is_listener().map_err(error)? {
+        return Err("Invalid socket from systemd: not listening, is Accept=yes set?".to_string());
+    }
+    socket.set_nonblocking(true).map_err(error)?;
+    log::info!("Using the socket from systemd, listening on {}", addr);
+    TcpListener::from_std(socket.into()).map_err(error)
+}
+
+// Equivalent to TcpListener::bind, but with the socket options from the
+// server config.
+fn bind(addr: SocketAddr, server_config: &ServerConfig) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(server_config.ipv6_only)?;
+    }
+    socket.set_reuse_address(!server_config.no_reuse_address)?;
+    socket.set_reuse_port(server_config.reuse_port)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+async fn run_daemon_on(
+    listener: TcpListener,
+    app: Router,
+    server_config: ServerConfig,
+) -> Result<(), String> {
+    let tls_acceptor = match &server_config.web_config {
+        None => None,
+        Some(web_config) => web_config.tls_acceptor()?,
+    };
+    let app = apply_auth(app, &server_config);
+    // Also on authentication failures, as the official exporters do.
+    let headers: Vec<_> = match &server_config.web_config {
+        None => vec![],
+        Some(web_config) => web_config
+            .headers()?
+            .into_iter()
+            .map(|(name, value)| cli::ResponseHeader { name, value })
+            .collect(),
+    };
+    let app = if headers.is_empty() {
+        app
+    } else {
+        app.layer(middleware::from_fn_with_state(
+            Arc::new(headers),
+            add_headers,
+        ))
+    };
+    serve(listener, app, server_config, tls_acceptor).await;
+    Ok(())
+}
+
+fn apply_auth(app: Router, server_config: &ServerConfig) -> Router {
+    let credentials = web_config::Credentials {
+        users: server_config
+            .web_config
+            .as_ref()
+            .map(|web_config| web_config.basic_auth_users.clone())
+            .unwrap_or_default(),
+        token: server_config.auth_token.clone(),
+    };
+    if credentials.is_empty() {
+        return app;
+    }
+    app.layer(middleware::from_fn_with_state(
+        Arc::new(credentials),
+        web_config::authenticate,
+    ))
+}
+
+// Accept loop, equivalent to axum::serve but with the connection builder
+// configured from the server config.
+async fn serve(
+    listener: TcpListener,
+    app: Router,
+    server_config: ServerConfig,
+    tls_acceptor: Option<TlsAcceptor>,
+) {
+    let mut builder = Builder::new(TokioExecutor::new());
+    builder.http1().keep_alive(!server_config.no_keep_alive);
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .keep_alive_interval(server_config.http2_keep_alive);
+    if server_config.http1_only {
+        builder = builder.http1_only();
+    }
+    let builder = Arc::new(builder);
+    // Connections shut down gracefully once this is set, and the sender is
+    // closed once they're all done.
+    let (shutdown, _) = watch::channel(false);
+    let handle_signals = server_config.shutdown_grace_period.is_some();
+    if handle_signals {
+        tokio::spawn(reap_zombies());
+    }
+    let signal = async {
+        if handle_signals {
+            shutdown_signal().await
+        } else {
+            std::future::pending().await
+        }
+    };
+    tokio::pin!(signal);
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            name = &mut signal => {
+                log::info!("Received {}, shutting down", name);
+                break;
+            }
+        };
+        let stream = match accepted {
+            Ok((stream, _)) => {
+                if server_config.tcp_nodelay {
+                    if let Err(e) = stream.set_nodelay(true) {
+                        log::debug!("Failed to set TCP_NODELAY: {}", e);
+                    }
+                }
+                stream
+            }
+            Err(e) => {
+                // Mostly transient errors (e.g. out of file descriptors), so
+                // back off a bit instead of spinning.
+                log::warn!("Failed to accept connection: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let builder = Arc::clone(&builder);
+        let app = app.clone();
+        let tls_acceptor = tls_acceptor.clone();
+        let idle_timeout = server_config.idle_timeout;
+        let shutdown = shutdown.subscribe();
+        tokio::spawn(async move {
+            match tls_acceptor {
+                None => serve_connection(&builder, stream, app, idle_timeout, shutdown).await,
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => {
+                        serve_connection(&builder, stream, app, idle_timeout, shutdown).await
+                    }
+                    Err(e) => log::debug!("TLS handshake failed: {}", e),
+                },
+            }
+        });
+    }
+    drop(listener);
+    shutdown.send_replace(true);
+    let grace_period = server_config.shutdown_grace_period.unwrap_or_default();
+    // Background scans are simply abandoned on exit.
+    if tokio::time::timeout(grace_period, shutdown.closed())
         .await
-        .map_err(|e| format!("Server error: {}", e))
+        .is_err()
+    {
+        log::warn!(
+            "Requests still in flight after {:?}, exiting anyway",
+            grace_period
+        );
+    }
+}
+
+// Waits for SIGTERM or SIGINT, returning its name.
+async fn shutdown_signal() -> &'static str {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            log::warn!("Can't handle SIGTERM: {}", e);
+            return std::future::pending().await;
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+    }
+}
+
+// Reaps the exited child processes, e.g. orphans inherited as PID 1, which
+// would otherwise linger as zombies.
+#[cfg(target_os = "linux")]
+async fn reap_zombies() {
+    let mut children = match signal(SignalKind::child()) {
+        Ok(children) => children,
+        Err(e) => {
+            log::warn!("Can't handle SIGCHLD, not reaping zombies: {}", e);
+            return;
+        }
+    };
+    while children.recv().await.is_some() {
+        while let Ok(Some(status)) = waitpid(None, WaitOptions::NOHANG) {
+            log::debug!("Reaped a child process, {:?}", status);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn reap_zombies() {}
+
+// Serves a single connection, gracefully shutting it down once no data has
+// been read or written for the idle timeout (if any). Note that hyper's own
+// header read timeout doesn't fire for idle keep-alive connections, hence
+// the activity tracking here.
+async fn serve_connection<S>(
+    builder: &Builder<TokioExecutor>,
+    stream: S,
+    app: Router,
+    idle_timeout: Option<Duration>,
+    mut shutdown: watch::Receiver<bool>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let last_activity = Arc::new(Mutex::new(Instant::now()));
+    let stream = ActivityStream {
+        inner: stream,
+        last_activity: Arc::clone(&last_activity),
+    };
+    let conn =
+        builder.serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(app));
+    tokio::pin!(conn);
+    let mut shutting_down = false;
+    let result = loop {
+        let deadline = idle_timeout.map(|timeout| *last_activity.lock().unwrap() + timeout);
+        tokio::select! {
+            result = conn.as_mut() => break result,
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)),
+                if deadline.is_some() && !shutting_down =>
+            {
+                let timeout = idle_timeout.unwrap_or_default();
+                if last_activity.lock().unwrap().elapsed() >= timeout {
+                    // Let in-flight requests finish, but don't accept new ones.
+                    conn.as_mut().graceful_shutdown();
+                    shutting_down = true;
+                }
+            }
+            _ = shutdown.wait_for(|&shutdown| shutdown), if !shutting_down => {
+                conn.as_mut().graceful_shutdown();
+                shutting_down = true;
+            }
+        }
+    };
+    if let Err(e) = result {
+        log::debug!("Connection error: {}", e);
+    }
+}
+
+// A stream wrapper recording the time of the last successful read or write.
+struct ActivityStream<S> {
+    inner: S,
+    last_activity: Arc<Mutex<Instant>>,
+}
+
+impl<S> ActivityStream<S> {
+    fn record<T>(&self, poll: &Poll<std::io::Result<T>>) {
+        if let Poll::Ready(Ok(_)) = poll {
+            *self.last_activity.lock().unwrap() = Instant::now();
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ActivityStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.record(&poll);
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ActivityStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.record(&poll);
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
 }
 
 // metrics handler
-async fn metrics(registry: Arc<Registry>) -> String {
-    let mut buffer = String::new();
-    encode(&mut buffer, &registry).unwrap();
-    buffer
+async fn metrics(
+    collectors: Arc<Vec<Arc<PhotoBacklogCollector>>>,
+    registries: Arc<Vec<Arc<Registry>>>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+) -> Response {
+    let options = match ScrapeOptions::from_query(query.as_deref().unwrap_or_default()) {
+        Ok(options) => options,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("{}\n", e)).into_response(),
+    };
+    let protobuf = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(crate::protobuf::negotiated);
+    // Scans block (up to the watchdog, if any), so keep them off the
+    // runtime for other requests to still be served.
+    let response = tokio::task::spawn_blocking(move || {
+        let mut buffer = String::new();
+        for registry in registries.iter() {
+            // Without the EOF marker, which ends the backlog metrics.
+            encode_registry(&mut buffer, registry).unwrap();
+        }
+        buffer.push_str(&encode_roots(&collectors, &options).unwrap());
+        if !protobuf {
+            return Ok(buffer.into_response());
+        }
+        crate::protobuf::from_text(&buffer).map(|body| {
+            (
+                [(header::CONTENT_TYPE, crate::protobuf::CONTENT_TYPE)],
+                body,
+            )
+                .into_response()
+        })
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|response| response);
+    let mut response = response.unwrap_or_else(|e| {
+        (StatusCode::INTERNAL_SERVER_ERROR, format!("{}\n", e)).into_response()
+    });
+    // The format depends on the Accept header, which caches must key on.
+    response
+        .headers_mut()
+        .append(header::VARY, HeaderValue::from_static("accept"));
+    response
+}
+
+/// Debounce window of the rescan endpoint, unless configured.
+pub const DEFAULT_RESCAN_DEBOUNCE: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RescanLabels {
+    outcome: &'static str,
+}
+
+/// Coalesces rescan triggers into scans, protecting the disk from
+/// trigger-happy automation.
+#[derive(Debug)]
+struct Rescanner {
+    handle: AppHandle,
+    debounce: Duration,
+    /// Whether a scan is queued but not yet started.
+    pending: Mutex<bool>,
+    /// Held while scanning, so that scans queued during one run after it.
+    scanning: tokio::sync::Mutex<()>,
+    triggers: Family<RescanLabels, Counter>,
+}
+
+impl Rescanner {
+    fn count(&self, outcome: &'static str) {
+        self.triggers.get_or_create(&RescanLabels { outcome }).inc();
+    }
+}
+
+// Queues a scan of all roots after the debounce window, unless one is
+// already queued; triggers during a running scan queue another one.
+async fn rescan(rescanner: Arc<Rescanner>) -> Response {
+    if std::mem::replace(&mut *rescanner.pending.lock().unwrap(), true) {
+        rescanner.count("suppressed");
+        return (StatusCode::ACCEPTED, "Rescan already queued\n").into_response();
+    }
+    rescanner.count("queued");
+    tokio::spawn(async move {
+        tokio::time::sleep(rescanner.debounce).await;
+        let _scanning = rescanner.scanning.lock().await;
+        *rescanner.pending.lock().unwrap() = false;
+        let handle = rescanner.handle.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || handle.scan()).await {
+            log::warn!("Rescan failed: {}", e);
+        }
+    });
+    (StatusCode::ACCEPTED, "Rescan queued\n").into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct SnapshotParams {
+    root: Option<PathBuf>,
+}
+
+/// Returns the last good scan of the root selected via `root=PATH`, which
+/// can be omitted if there's a single one, or the error status and message.
+fn selected_last_good(
+    handle: &AppHandle,
+    root: Option<&Path>,
+) -> Result<Arc<Backlog>, (StatusCode, &'static str)> {
+    let last_good = handle.last_good();
+    let backlog = match (last_good.as_slice(), root) {
+        ([(_, backlog)], None) => backlog.clone(),
+        (_, None) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Several roots are served, select one via root=PATH\n",
+            ))
+        }
+        (_, Some(root)) => match last_good.iter().find(|(path, _)| path == root) {
+            Some((_, backlog)) => backlog.clone(),
+            None => return Err((StatusCode::NOT_FOUND, "Unknown root\n")),
+        },
+    };
+    backlog.ok_or((StatusCode::SERVICE_UNAVAILABLE, "No good scan yet\n"))
+}
+
+#[derive(Debug, Deserialize)]
+struct LargestParams {
+    root: Option<PathBuf>,
+    n: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct LargestFile {
+    path: String,
+    size: u64,
+    age_seconds: f64,
+}
+
+// Serves the `n` (by default all kept) largest backlog files of the last
+// good scan of a root, largest first.
+async fn largest(handle: AppHandle, Query(params): Query<LargestParams>) -> Response {
+    let backlog = match selected_last_good(&handle, params.root.as_deref()) {
+        Ok(backlog) => backlog,
+        Err(error) => return error.into_response(),
+    };
+    let files: Vec<_> = backlog
+        .largest_files
+        .iter()
+        .take(params.n.unwrap_or(usize::MAX))
+        .map(|f| LargestFile {
+            path: f.path.to_string_lossy().into_owned(),
+            size: f.size,
+            age_seconds: f.age,
+        })
+        .collect();
+    Json(files).into_response()
+}
+
+#[derive(Debug, Serialize)]
+struct FolderFile {
+    path: String,
+    age_seconds: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct FolderResponse {
+    name: String,
+    files: i64,
+    raw_files: i64,
+    editable_files: i64,
+    size: u64,
+    exts: BTreeMap<String, i64>,
+    oldest: Option<FolderFile>,
+    newest: Option<FolderFile>,
+    violations: Vec<ViolationSnapshot>,
+}
+
+// Serves the details of a folder of the last good scan of a root, with
+// the violations found in it.
+async fn folder(
+    handle: AppHandle,
+    axum::extract::Path(name): axum::extract::Path<String>,
+    Query(params): Query<SnapshotParams>,
+) -> Response {
+    let root = params.root;
+    let backlog = match selected_last_good(&handle, root.as_deref()) {
+        Ok(backlog) => backlog,
+        Err(error) => return error.into_response(),
+    };
+    let Some((files, _)) = backlog.folders.get(&name) else {
+        return (StatusCode::NOT_FOUND, "Unknown folder\n").into_response();
+    };
+    let root = match root {
+        Some(root) => root,
+        None => handle.collectors[0].scan_path.clone(),
+    };
+    let details = backlog
+        .folder_details
+        .get(&name)
+        .cloned()
+        .unwrap_or_default();
+    let (raw_files, editable_files) = backlog.folder_kinds.get(&name).copied().unwrap_or_default();
+    let file = |(path, age): (PathBuf, f64)| FolderFile {
+        path: path.to_string_lossy().into_owned(),
+        age_seconds: age,
+    };
+    let violations = backlog
+        .violations
+        .iter()
+        .filter(|v| {
+            v.path
+                .strip_prefix(&root)
+                .is_ok_and(|relative| relative.iter().next() == Some(name.as_ref()))
+        })
+        .map(ViolationSnapshot::from)
+        .collect();
+    Json(FolderResponse {
+        name,
+        files: *files,
+        raw_files,
+        editable_files,
+        size: details.size,
+        exts: details.exts,
+        oldest: details.oldest.map(file),
+        newest: details.newest.map(file),
+        violations,
+    })
+    .into_response()
+}
+
+/// Scan events buffered for clients of the events endpoint, beyond which
+/// slow ones miss the oldest.
+const SCAN_EVENTS_CAPACITY: usize = 16;
+
+#[derive(Debug, Serialize)]
+struct ScanEvent {
+    root: String,
+    #[serde(flatten)]
+    summary: Summary,
+}
+
+// Streams the background scans as `scan` events, with the scan id as event
+// id; slow clients missing some only get the later ones.
+async fn events(
+    completions: broadcast::Receiver<ScanCompletion>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let stream = BroadcastStream::new(completions).filter_map(|completion| {
+        let completion = completion.ok()?;
+        let mut summary = Summary::default();
+        summary.record(&completion.backlog);
+        summary.finish(completion.duration, &Ok(()));
+        if completion.failed {
+            summary.exit_reason = ExitReason::ScanFailed;
+        }
+        let event = ScanEvent {
+            root: completion.root.to_string_lossy().into_owned(),
+            summary,
+        };
+        Some(
+            Event::default()
+                .event("scan")
+                .id(completion.backlog.scan_id.to_string())
+                .json_data(event),
+        )
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+// Serves the last good scan of a root (selected via `root=PATH` if there
+// are several) as gzip-compressed JSON, with the scan id as ETag.
+async fn snapshot(
+    handle: AppHandle,
+    Query(params): Query<SnapshotParams>,
+    headers: HeaderMap,
+) -> Response {
+    let backlog = match selected_last_good(&handle, params.root.as_deref()) {
+        Ok(backlog) => backlog,
+        Err(error) => return error.into_response(),
+    };
+    let etag = format!("\"{}\"", backlog.scan_id);
+    if headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|value| value.as_bytes() == etag.as_bytes())
+    {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+    }
+    let filename = format!(
+        "attachment; filename=\"snapshot-{}.json.gz\"",
+        backlog.scan_id
+    );
+    // Snapshots of large trees are large too, so compress off the runtime.
+    let body = tokio::task::spawn_blocking(move || {
+        crate::snapshot::Snapshot::from(backlog.as_ref()).to_gzip_json()
+    })
+    .await
+    .map_err(std::io::Error::other)
+    .and_then(|result| result);
+    match body {
+        Ok(body) => (
+            [
+                (header::ETAG, etag),
+                (header::CONTENT_TYPE, "application/gzip".to_string()),
+                (header::CONTENT_DISPOSITION, filename),
+            ],
+            body,
+        )
+            .into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{}\n", e)).into_response(),
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::sync::Arc;
+    use std::time::Duration;
 
     use ::axum_test::TestServer;
+    use axum::http::{HeaderValue, Method, StatusCode};
+    use rstest::rstest;
     use speculoos::prelude::*;
 
     use tempfile::tempdir;
-    use tokio::net::TcpListener;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::rustls;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::TlsConnector;
 
-    use crate::{cli, daemon::run_daemon};
+    use crate::cli;
+    use crate::daemon::{run_daemon, ServerConfig};
+    use crate::web_config::WebConfig;
 
     #[tokio::test]
     async fn test_metrics() {
@@ -61,7 +1265,7 @@ mod tests {
         std::fs::File::create(temp_dir.path().join("test2.nef")).unwrap();
 
         let opts = cli::parse_args_from(&["--path", temp_dir_str]).expect("parse_args");
-        let (_addr, app) = super::build_app(opts);
+        let (_addr, app) = super::build_app(opts).unwrap();
         let server = TestServer::new(app).unwrap();
         let response = server.get("/metrics").await;
         response.assert_status_ok();
@@ -69,7 +1273,482 @@ mod tests {
         assert_that!(raw_text).contains("photo_backlog_counts{kind=\"folders\"} 1");
         assert_that!(raw_text).contains("photo_backlog_counts{kind=\"photos\"} 2");
         assert_that!(raw_text).contains("photo_backlog_ages_count 2");
-        assert_that!(raw_text).contains("photo_backlog_processing_time_seconds ");
+        assert_that!(raw_text).contains("photo_backlog_processing_time_seconds{phase=\"walk\"} ");
+    }
+
+    #[tokio::test]
+    async fn test_metrics_protobuf() {
+        let temp_dir = tempdir().unwrap();
+        let temp_dir_str = temp_dir.path().to_str().expect("convert tempdir to str");
+        std::fs::File::create(temp_dir.path().join("test1.nef")).unwrap();
+        let opts = cli::parse_args_from(&["--path", temp_dir_str]).expect("parse_args");
+        let (_addr, app) = super::build_app(opts).unwrap();
+        let server = TestServer::new(app).unwrap();
+        let text = server.get("/metrics").await.text();
+        let response = server
+            .get("/metrics")
+            .add_header(
+                axum::http::header::ACCEPT,
+                HeaderValue::from_static("application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited;q=0.7,text/plain;version=0.0.4;q=0.3"),
+            )
+            .await;
+        response.assert_status_ok();
+        assert_that!(response.header(axum::http::header::CONTENT_TYPE))
+            .is_equal_to(HeaderValue::from_static(crate::protobuf::CONTENT_TYPE));
+        assert_that!(response.header(axum::http::header::VARY))
+            .is_equal_to(HeaderValue::from_static("accept"));
+        // One length-delimited message per family, exactly covering the body.
+        let body = response.as_bytes();
+        let mut rest = &body[..];
+        let mut families = 0;
+        while !rest.is_empty() {
+            let (mut len, mut shift) = (0, 0);
+            while rest[0] >= 0x80 {
+                len |= usize::from(rest[0] & 0x7f) << shift;
+                shift += 7;
+                rest = &rest[1..];
+            }
+            len |= usize::from(rest[0]) << shift;
+            rest = &rest[1 + len..];
+            families += 1;
+        }
+        assert_that!(families).is_equal_to(text.matches("# HELP ").count());
+        assert_that!(body.windows(20).any(|w| w == b"photo_backlog_counts")).is_true();
+    }
+
+    #[tokio::test]
+    async fn test_metrics_multiple_roots() {
+        let temp_dir = tempdir().unwrap();
+        let present = temp_dir.path().join("present");
+        let missing = temp_dir.path().join("missing");
+        std::fs::create_dir(&present).unwrap();
+        std::fs::File::create(present.join("test1.nef")).unwrap();
+        let roots = temp_dir.path().join("roots");
+        std::fs::write(
+            &roots,
+            format!("{}\n{}\n", present.display(), missing.display()),
+        )
+        .unwrap();
+        let opts =
+            cli::parse_args_from(&["--paths-from", roots.to_str().unwrap()]).expect("parse_args");
+        let (_addr, app) = super::build_app(opts).unwrap();
+        let server = TestServer::new(app).unwrap();
+        let response = server.get("/metrics").await;
+        response.assert_status_ok();
+        let raw_text = response.text();
+        // The missing root doesn't affect the present one.
+        assert_that!(raw_text).contains(
+            format!(
+                "photo_backlog_counts{{root=\"{}\",kind=\"photos\"}} 1",
+                present.display()
+            )
+            .as_str(),
+        );
+        assert_that!(raw_text).contains(
+            format!(
+                "photo_backlog_root_available{{root=\"{}\"}} 0",
+                missing.display()
+            )
+            .as_str(),
+        );
+        assert_that!(raw_text
+            .matches("# TYPE photo_backlog_counts gauge")
+            .count())
+        .is_equal_to(1);
+    }
+
+    #[tokio::test]
+    async fn test_app_builder_nested() {
+        let temp_dir = tempdir().unwrap();
+        std::fs::File::create(temp_dir.path().join("test1.nef")).unwrap();
+        let opts = cli::parse_args_from(&["--path", temp_dir.path().to_str().unwrap()])
+            .expect("parse_args");
+        let (routes, handle) = super::AppBuilder::from_args(opts).unwrap().build();
+        assert_that!(handle.last_good()[0].1).is_none();
+        let app = axum::Router::new().nest("/photo-backlog", routes);
+        let server = TestServer::new(app).unwrap();
+        let response = server.get("/photo-backlog/metrics").await;
+        response.assert_status_ok();
+        assert_that!(response.text()).contains("photo_backlog_counts{kind=\"photos\"} 1");
+        server.get("/metrics").await.assert_status_not_found();
+
+        std::fs::File::create(temp_dir.path().join("test2.nef")).unwrap();
+        assert_that!(handle.scan()[0].total_files).is_equal_to(2);
+        let (root, last_good) = &handle.last_good()[0];
+        assert_that!(root).is_equal_to(&temp_dir.path().to_path_buf());
+        assert_that!(last_good.as_ref().map(|b| b.total_files)).is_equal_to(Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_app_builder_registry() {
+        let mut registry = prometheus_client::registry::Registry::default();
+        let uploads = prometheus_client::metrics::counter::Counter::<u64>::default();
+        registry.register("uploads", "Uploaded photos", uploads.clone());
+        uploads.inc();
+        let collector = crate::prometheus::PhotoBacklogCollector {
+            mock_data: Some(crate::MockData {
+                folders: 1,
+                files: 2,
+            }),
+            age_buckets: vec![crate::buckets::AgeBound::Seconds(1.0)],
+            ..Default::default()
+        };
+        let (app, _handle) = super::AppBuilder::new(vec![collector])
+            .registry(registry)
+            .build();
+        let server = TestServer::new(app).unwrap();
+        let raw_text = server.get("/metrics?collect[]=counts").await.text();
+        assert_that!(raw_text).contains("uploads_total 1");
+        assert_that!(raw_text).contains("photo_backlog_counts{kind=\"photos\"} 2");
+        assert_that!(raw_text).does_not_contain("photo_backlog_ages");
+        assert_that!(raw_text.matches("# EOF").count()).is_equal_to(1);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_endpoint() {
+        let temp_dir = tempdir().unwrap();
+        std::fs::File::create(temp_dir.path().join("test1.nef")).unwrap();
+        // No file is owned by this user, so there's a violation.
+        let opts = cli::parse_args_from(&[
+            "--path",
+            temp_dir.path().to_str().unwrap(),
+            "--owner",
+            "4242",
+            "--snapshot-endpoint",
+        ])
+        .expect("parse_args");
+        let (_addr, app) = super::build_app(opts).unwrap();
+        let server = TestServer::new(app).unwrap();
+        server
+            .get("/api/v1/snapshot")
+            .await
+            .assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        server.get("/metrics").await.assert_status_ok();
+
+        let response = server.get("/api/v1/snapshot").await;
+        response.assert_status_ok();
+        let etag = response.header("etag");
+        let mut json = String::new();
+        let mut decoder = flate2::read::GzDecoder::new(response.as_bytes().as_ref());
+        std::io::Read::read_to_string(&mut decoder, &mut json).unwrap();
+        let snapshot: crate::snapshot::Snapshot = serde_json::from_str(&json).unwrap();
+        assert_that!(snapshot.total_files).is_equal_to(1);
+        let violations = snapshot
+            .errors
+            .get("ownership")
+            .copied()
+            .unwrap_or_default();
+        assert_that!(snapshot.violations).has_length(violations as usize);
+        assert_that!(snapshot
+            .violations
+            .iter()
+            .any(|v| v.path.ends_with("test1.nef") && v.kind == "ownership"))
+        .is_true();
+
+        server
+            .get("/api/v1/snapshot")
+            .add_header(axum::http::header::IF_NONE_MATCH, etag)
+            .await
+            .assert_status(StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn test_largest_endpoint() {
+        let temp_dir = tempdir().unwrap();
+        for (name, size) in [("small.nef", 10), ("dump.mov", 1000), ("big.nef", 100)] {
+            std::fs::write(temp_dir.path().join(name), vec![0u8; size]).unwrap();
+        }
+        let opts = cli::parse_args_from(&[
+            "--path",
+            temp_dir.path().to_str().unwrap(),
+            "--editable-exts",
+            "mov",
+            "--largest-files",
+            "5",
+        ])
+        .expect("parse_args");
+        let (_addr, app) = super::build_app(opts).unwrap();
+        let server = TestServer::new(app).unwrap();
+        server
+            .get("/api/v1/largest")
+            .await
+            .assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        server.get("/metrics").await.assert_status_ok();
+        let response = server.get("/api/v1/largest?n=2").await;
+        response.assert_status_ok();
+        let files: serde_json::Value = response.json();
+        assert_that!(files.as_array().unwrap()).has_length(2);
+        assert_that!(files[0]["path"].as_str()).is_equal_to(Some("dump.mov"));
+        assert_that!(files[0]["size"].as_u64()).is_equal_to(Some(1000));
+        assert_that!(files[1]["path"].as_str()).is_equal_to(Some("big.nef"));
+        assert_that!(files[1]["age_seconds"].as_f64()).is_some();
+    }
+
+    #[tokio::test]
+    async fn test_folder_endpoint() {
+        let temp_dir = tempdir().unwrap();
+        let folder = temp_dir.path().join("trip");
+        std::fs::create_dir(&folder).unwrap();
+        std::fs::write(folder.join("1.nef"), vec![0u8; 10]).unwrap();
+        std::fs::write(folder.join("2.jpg"), vec![0u8; 5]).unwrap();
+        let opts = cli::parse_args_from(&[
+            "--path",
+            temp_dir.path().to_str().unwrap(),
+            "--owner",
+            "4242",
+            "--folder-endpoint",
+        ])
+        .expect("parse_args");
+        let (_addr, app) = super::build_app(opts).unwrap();
+        let server = TestServer::new(app).unwrap();
+        server
+            .get("/api/v1/folders/trip")
+            .await
+            .assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        server.get("/metrics").await.assert_status_ok();
+        server
+            .get("/api/v1/folders/other")
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+        let response = server.get("/api/v1/folders/trip").await;
+        response.assert_status_ok();
+        let details: serde_json::Value = response.json();
+        assert_that!(details["files"].as_i64()).is_equal_to(Some(2));
+        assert_that!(details["raw_files"].as_i64()).is_equal_to(Some(1));
+        assert_that!(details["size"].as_u64()).is_equal_to(Some(15));
+        assert_that!(details["exts"]["nef"].as_i64()).is_equal_to(Some(1));
+        assert_that!(details["oldest"]["path"].as_str()).is_some();
+        // The files and the folder itself aren't owned by that user.
+        assert_that!(details["violations"].as_array().unwrap()).has_length(3);
+    }
+
+    #[tokio::test]
+    async fn test_trusted_users() {
+        let temp_dir = tempdir().unwrap();
+        let opts = cli::parse_args_from(&[
+            "--path",
+            temp_dir.path().to_str().unwrap(),
+            "--snapshot-endpoint",
+            "--trusted-user-header",
+            "X-Forwarded-User",
+            "--allowed-users",
+            "alice,carol",
+        ])
+        .expect("parse_args");
+        let (_addr, app) = super::build_app(opts).unwrap();
+        let server = TestServer::new(app).unwrap();
+        server.get("/metrics").await.assert_status_ok();
+        server
+            .get("/api/v1/snapshot")
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        let as_user = |user: &'static str| {
+            server.get("/api/v1/snapshot").add_header(
+                axum::http::HeaderName::from_static("x-forwarded-user"),
+                HeaderValue::from_static(user),
+            )
+        };
+        as_user("bob").await.assert_status(StatusCode::FORBIDDEN);
+        as_user("alice").await.assert_status_ok();
+
+        let opts = cli::parse_args_from(&["--path", "/", "--allowed-users", "alice"]);
+        assert_that!(opts)
+            .is_err()
+            .contains("must be given together");
+    }
+
+    #[tokio::test]
+    async fn test_rescan_endpoint() {
+        let temp_dir = tempdir().unwrap();
+        std::fs::File::create(temp_dir.path().join("test1.nef")).unwrap();
+        let opts = cli::parse_args_from(&[
+            "--path",
+            temp_dir.path().to_str().unwrap(),
+            "--rescan-endpoint",
+            "--rescan-debounce",
+            "1s",
+        ])
+        .expect("parse_args");
+        let (app, handle) = super::AppBuilder::from_args(opts).unwrap().build();
+        let server = TestServer::new(app).unwrap();
+        for _ in 0..3 {
+            server
+                .post("/-/rescan")
+                .await
+                .assert_status(StatusCode::ACCEPTED);
+        }
+        // Coalesced into a single scan, after the debounce window.
+        assert_that!(handle.last_good()[0].1).is_none();
+        for _ in 0..100 {
+            if handle.last_good()[0].1.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let last_good = handle.last_good()[0].1.clone();
+        assert_that!(last_good.map(|b| b.total_files)).is_equal_to(Some(1));
+        let raw_text = server.get("/metrics").await.text();
+        assert_that!(raw_text)
+            .contains("photo_backlog_rescan_triggers_total{outcome=\"queued\"} 1");
+        assert_that!(raw_text)
+            .contains("photo_backlog_rescan_triggers_total{outcome=\"suppressed\"} 2");
+        server
+            .get("/-/rescan")
+            .await
+            .assert_status(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_events_endpoint() {
+        let temp_dir = tempdir().unwrap();
+        std::fs::File::create(temp_dir.path().join("test1.nef")).unwrap();
+        let root = temp_dir.path().to_str().unwrap();
+        let opts = cli::parse_args_from(&["--path", root, "--events-endpoint"]);
+        assert_that!(opts)
+            .is_err()
+            .contains("requires --scan-interval or --rescan-endpoint");
+        let opts =
+            cli::parse_args_from(&["--path", root, "--rescan-endpoint", "--events-endpoint"])
+                .expect("parse_args");
+        let (app, handle) = super::AppBuilder::from_args(opts).unwrap().build();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(super::run_daemon_on(listener, app, ServerConfig::default()));
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /api/v1/events HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        // Reads until the response contains the given text.
+        let mut response = String::new();
+        let mut read_until = async |text: &str| {
+            let mut buf = [0u8; 4096];
+            while !response.contains(text) {
+                let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+                    .await
+                    .expect("server answers in time")
+                    .unwrap();
+                assert_that!(n).is_greater_than(0);
+                response.push_str(&String::from_utf8_lossy(&buf[..n]));
+            }
+        };
+        read_until("text/event-stream").await;
+        let backlog = tokio::task::spawn_blocking(move || handle.scan())
+            .await
+            .unwrap();
+        read_until("\n\n").await;
+        assert_that!(response).contains("event: scan\n");
+        assert_that!(response).contains(format!("id: {}\n", backlog[0].scan_id));
+        assert_that!(response).contains("\"total_files\":1");
+        assert_that!(response).contains("\"exit_reason\":\"ok\"");
+    }
+
+    #[tokio::test]
+    async fn test_metrics_collect_query() {
+        let temp_dir = tempdir().unwrap();
+        let temp_dir_str = temp_dir.path().to_str().expect("convert tempdir to str");
+        let opts = cli::parse_args_from(&["--path", temp_dir_str]).expect("parse_args");
+        let (_addr, app) = super::build_app(opts).unwrap();
+        let server = TestServer::new(app).unwrap();
+        let response = server.get("/metrics?collect[]=counts").await;
+        response.assert_status_ok();
+        let raw_text = response.text();
+        assert_that!(raw_text).contains("photo_backlog_counts{kind=\"photos\"} 0");
+        assert_that!(raw_text).does_not_contain("photo_backlog_errors");
+        let response = server.get("/metrics?collect[]=bogus").await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+        assert_that!(response.text()).contains("Unknown metric group 'bogus'");
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = super::RateLimiter::per_minute(2);
+        assert_that!(limiter.try_acquire()).is_true();
+        assert_that!(limiter.try_acquire()).is_true();
+        assert_that!(limiter.try_acquire()).is_false();
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_metrics() {
+        let temp_dir = tempdir().unwrap();
+        let temp_dir_str = temp_dir.path().to_str().expect("convert tempdir to str");
+        let opts = cli::parse_args_from(&["--path", temp_dir_str, "--max-scrapes-per-minute", "1"])
+            .expect("parse_args");
+        let (_addr, app) = super::build_app(opts).unwrap();
+        let server = TestServer::new(app).unwrap();
+        server.get("/metrics").await.assert_status_ok();
+        let response = server.get("/metrics").await;
+        response.assert_status(StatusCode::TOO_MANY_REQUESTS);
+        assert_that!(response.header("retry-after")).is_equal_to(HeaderValue::from_static("60"));
+    }
+
+    #[tokio::test]
+    async fn test_cors() {
+        let temp_dir = tempdir().unwrap();
+        let temp_dir_str = temp_dir.path().to_str().expect("convert tempdir to str");
+        let opts = cli::parse_args_from(&[
+            "--path",
+            temp_dir_str,
+            "--cors-origins",
+            "https://dash.example.com",
+        ])
+        .expect("parse_args");
+        let (_addr, app) = super::build_app(opts).unwrap();
+        let server = TestServer::new(app).unwrap();
+
+        let response = server
+            .get("/metrics")
+            .add_header("origin", "https://dash.example.com")
+            .await;
+        response.assert_status_ok();
+        assert_that!(response.header("access-control-allow-origin"))
+            .is_equal_to(HeaderValue::from_static("https://dash.example.com"));
+
+        let response = server
+            .get("/metrics")
+            .add_header("origin", "https://evil.example.com")
+            .await;
+        response.assert_status_ok();
+        assert_that!(response.maybe_header("access-control-allow-origin")).is_none();
+
+        let response = server
+            .method(Method::OPTIONS, "/metrics")
+            .add_header("origin", "https://dash.example.com")
+            .add_header("access-control-request-method", "GET")
+            .await;
+        response.assert_status(StatusCode::NO_CONTENT);
+        assert_that!(response.header("access-control-allow-methods"))
+            .is_equal_to(HeaderValue::from_static("GET, OPTIONS"));
+    }
+
+    #[tokio::test]
+    async fn test_response_headers() {
+        let temp_dir = tempdir().unwrap();
+        let temp_dir_str = temp_dir.path().to_str().expect("convert tempdir to str");
+        let opts = cli::parse_args_from(&[
+            "--path",
+            temp_dir_str,
+            "--response-header",
+            "Cache-Control: no-store",
+            "--response-header",
+            "X-Scraped-By: prometheus",
+            "--max-scrapes-per-minute",
+            "1",
+        ])
+        .expect("parse_args");
+        let (_addr, app) = super::build_app(opts).unwrap();
+        let server = TestServer::new(app).unwrap();
+        let response = server.get("/metrics").await;
+        response.assert_status_ok();
+        assert_that!(response.header("cache-control"))
+            .is_equal_to(HeaderValue::from_static("no-store"));
+        assert_that!(response.header("x-scraped-by"))
+            .is_equal_to(HeaderValue::from_static("prometheus"));
+        // Also on error responses.
+        let response = server.get("/metrics").await;
+        response.assert_status(StatusCode::TOO_MANY_REQUESTS);
+        assert_that!(response.header("cache-control"))
+            .is_equal_to(HeaderValue::from_static("no-store"));
     }
 
     #[tokio::test]
@@ -78,7 +1757,7 @@ mod tests {
         let temp_dir = tempdir().unwrap();
         let temp_dir_str = temp_dir.path().to_str().expect("convert tempdir to str");
         let opts = cli::parse_args_from(&["--path", temp_dir_str]).expect("parse_args");
-        let (_addr, app) = super::build_app(opts);
+        let (_addr, app) = super::build_app(opts).unwrap();
 
         // Bind to a random localhost port, and remember the full address,
         // including port.
@@ -90,7 +1769,237 @@ mod tests {
 
         // Now try to run a demon against the same address/port combination,
         // which should fail.
-        let result = run_daemon(*addr_with_port, app).await;
+        let result = run_daemon(*addr_with_port, app, ServerConfig::default()).await;
         assert_that!(result).is_err().contains("Failed to bind to");
     }
+
+    #[tokio::test]
+    async fn test_reuse_port() {
+        let server_config = ServerConfig {
+            reuse_port: true,
+            ..Default::default()
+        };
+        let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0);
+        let first = super::bind(socket, &server_config).expect("bind first listener");
+        let addr = first.local_addr().expect("get local address");
+        assert_that!(super::bind(addr, &server_config)).is_ok();
+        assert_that!(super::bind(addr, &ServerConfig::default())).is_err();
+    }
+
+    #[tokio::test]
+    async fn test_ipv6_only() {
+        let server_config = ServerConfig {
+            ipv6_only: true,
+            ..Default::default()
+        };
+        let socket = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0);
+        // IPv6 might not be available in the test environment.
+        let Ok(listener) = super::bind(socket, &server_config) else {
+            return;
+        };
+        let port = listener.local_addr().expect("get local address").port();
+        let v4 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+        assert_that!(TcpStream::connect(v4).await).is_err();
+    }
+
+    #[rstest]
+    #[case::unset(None, None, Ok(0))]
+    #[case::ours(Some("42"), Some("1"), Ok(1))]
+    #[case::other_process(Some("41"), Some("1"), Ok(0))]
+    #[case::no_pid(None, Some("1"), Ok(0))]
+    #[case::invalid_pid(Some("self"), Some("1"), Err("Invalid LISTEN_PID 'self'".to_string()))]
+    #[case::invalid_fds(Some("42"), Some("one"), Err("Invalid LISTEN_FDS 'one'".to_string()))]
+    fn test_listen_fds(
+        #[case] listen_pid: Option<&str>,
+        #[case] listen_fds: Option<&str>,
+        #[case] expected: Result<usize, String>,
+    ) {
+        let owned = |v: Option<&str>| v.map(String::from);
+        assert_that!(super::listen_fds(owned(listen_pid), owned(listen_fds), 42))
+            .is_equal_to(expected);
+    }
+
+    #[tokio::test]
+    async fn test_inherited_listener() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind listener");
+        let addr = listener.local_addr().expect("get local address");
+        let listener = super::inherited_listener(listener.into()).expect("inherit listener");
+        let (accepted, connected) = tokio::join!(listener.accept(), TcpStream::connect(addr));
+        assert_that!(accepted).is_ok();
+        assert_that!(connected).is_ok();
+
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").expect("bind UDP socket");
+        assert_that!(super::inherited_listener(udp.into()))
+            .is_err()
+            .contains("not a TCP socket");
+        let not_listening = std::net::TcpStream::connect(addr).expect("connect");
+        assert_that!(super::inherited_listener(not_listening.into()))
+            .is_err()
+            .contains("not listening");
+    }
+
+    // Starts the server on a random localhost port, returning its address.
+    async fn start_server(server_config: ServerConfig) -> SocketAddr {
+        let temp_dir = tempdir().unwrap();
+        let temp_dir_str = temp_dir.path().to_str().expect("convert tempdir to str");
+        let opts = cli::parse_args_from(&["--path", temp_dir_str]).expect("parse_args");
+        let (_addr, app) = super::build_app(opts).unwrap();
+        let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0);
+        let listener = TcpListener::bind(&socket).await.expect("bind listener");
+        let addr = listener.local_addr().expect("get local address");
+        tokio::spawn(super::run_daemon_on(listener, app, server_config));
+        addr
+    }
+
+    // Starts the server and returns a connected client stream.
+    async fn connect_to_server(server_config: ServerConfig) -> TcpStream {
+        let addr = start_server(server_config).await;
+        TcpStream::connect(addr).await.expect("connect to server")
+    }
+
+    #[tokio::test]
+    async fn test_http2_prior_knowledge() {
+        let mut stream = connect_to_server(ServerConfig::default()).await;
+        // Connection preface followed by an empty SETTINGS frame.
+        stream
+            .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0")
+            .await
+            .unwrap();
+        let mut frame_header = [0u8; 9];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut frame_header))
+            .await
+            .expect("server answers in time")
+            .expect("read server frame");
+        // The server must answer with its own SETTINGS frame.
+        assert_that!(frame_header[3]).is_equal_to(0x04);
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let mut stream = connect_to_server(ServerConfig {
+            idle_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        })
+        .await;
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        // The connection is kept alive after the response, but closed once
+        // no new request comes in within the idle timeout.
+        let mut buf = Vec::new();
+        let result =
+            tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut buf)).await;
+        assert_that!(result).is_ok();
+        assert_that!(String::from_utf8_lossy(&buf).to_string()).starts_with("HTTP/1.1 200 OK");
+    }
+
+    #[tokio::test]
+    async fn test_no_keep_alive() {
+        let mut stream = connect_to_server(ServerConfig {
+            no_keep_alive: true,
+            ..Default::default()
+        })
+        .await;
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = Vec::new();
+        let result =
+            tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut buf)).await;
+        assert_that!(result).is_ok();
+        let response = String::from_utf8_lossy(&buf).to_string();
+        assert_that!(response).starts_with("HTTP/1.1 200 OK");
+        assert_that!(response).contains("photo_backlog_counts");
+    }
+
+    #[tokio::test]
+    async fn test_tls_with_basic_auth() {
+        let temp_dir = tempdir().unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_file = temp_dir.path().join("server.crt");
+        let key_file = temp_dir.path().join("server.key");
+        std::fs::write(&cert_file, cert.cert.pem()).unwrap();
+        std::fs::write(&key_file, cert.key_pair.serialize_pem()).unwrap();
+        let web_config = WebConfig::parse(&format!(
+            "tls_server_config:\n  cert_file: {}\n  key_file: {}\nbasic_auth_users:\n  alice: '{}'\nhttp_server_config:\n  headers:\n    X-Frame-Options: deny\n",
+            cert_file.display(),
+            key_file.display(),
+            bcrypt::hash("secret", 4).unwrap()
+        ))
+        .expect("parse web config");
+        let addr = start_server(ServerConfig {
+            web_config: Some(Arc::new(web_config)),
+            ..Default::default()
+        })
+        .await;
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let client_config = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(client_config));
+
+        let get_metrics = |auth: &'static str| {
+            let connector = connector.clone();
+            async move {
+                let stream = TcpStream::connect(addr).await.unwrap();
+                let domain = ServerName::try_from("localhost").unwrap();
+                let mut stream = connector
+                    .connect(domain, stream)
+                    .await
+                    .expect("TLS handshake");
+                let request = format!(
+                    "GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}\r\n",
+                    auth
+                );
+                stream.write_all(request.as_bytes()).await.unwrap();
+                let mut buf = Vec::new();
+                // A TLS close_notify is not always sent, so ignore read errors.
+                let _ = stream.read_to_end(&mut buf).await;
+                String::from_utf8_lossy(&buf).to_string()
+            }
+        };
+        let response = get_metrics("").await;
+        assert_that!(response).starts_with("HTTP/1.1 401 Unauthorized");
+        assert_that!(response).contains("x-frame-options: deny");
+        // "alice:secret", base64-encoded.
+        let response = get_metrics("Authorization: Basic YWxpY2U6c2VjcmV0\r\n").await;
+        assert_that!(response).starts_with("HTTP/1.1 200 OK");
+        assert_that!(response).contains("photo_backlog_counts");
+    }
+
+    #[tokio::test]
+    async fn test_auth_token() {
+        let addr = start_server(ServerConfig {
+            auth_token: Some("s3cret".to_string()),
+            ..Default::default()
+        })
+        .await;
+        let get_metrics = |auth: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let request = format!(
+                "GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}\r\n",
+                auth
+            );
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).await.unwrap();
+            String::from_utf8_lossy(&buf).to_string()
+        };
+        let response = get_metrics("").await;
+        assert_that!(response).starts_with("HTTP/1.1 401 Unauthorized");
+        assert_that!(response.to_lowercase()).contains("www-authenticate: bearer");
+        assert_that!(get_metrics("Authorization: Bearer wrong\r\n").await)
+            .starts_with("HTTP/1.1 401 Unauthorized");
+        let response = get_metrics("Authorization: Bearer s3cret\r\n").await;
+        assert_that!(response).starts_with("HTTP/1.1 200 OK");
+        assert_that!(response).contains("photo_backlog_counts");
+    }
 }
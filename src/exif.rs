@@ -0,0 +1,222 @@
+//! Minimal EXIF reading, for the few tags the scan needs, from TIFF-based
+//! raw files (NEF, CR2, ARW, DNG, …) and JPEGs.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Pointer from IFD0 to the EXIF IFD.
+const TAG_EXIF_IFD: u16 = 0x8769;
+/// The camera body's serial number, as ASCII.
+const TAG_BODY_SERIAL_NUMBER: u16 = 0xa431;
+const TYPE_ASCII: u16 = 2;
+/// Sanity limits, to not go astray on corrupt files.
+const MAX_IFD_ENTRIES: u16 = 1024;
+const MAX_STRING_LEN: u32 = 256;
+
+#[derive(Clone, Copy)]
+enum ByteOrder {
+    Little,
+    Big,
+}
+
+struct Tiff<'a> {
+    file: &'a mut File,
+    /// Offset of the TIFF header in the file, which IFD offsets are
+    /// relative to.
+    base: u64,
+    order: ByteOrder,
+}
+
+impl Tiff<'_> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.base + offset))?;
+        self.file.read_exact(buf)
+    }
+
+    fn u16(&self, b: [u8; 2]) -> u16 {
+        match self.order {
+            ByteOrder::Little => u16::from_le_bytes(b),
+            ByteOrder::Big => u16::from_be_bytes(b),
+        }
+    }
+
+    fn u32(&self, b: [u8; 4]) -> u32 {
+        match self.order {
+            ByteOrder::Little => u32::from_le_bytes(b),
+            ByteOrder::Big => u32::from_be_bytes(b),
+        }
+    }
+
+    /// Finds the given tag in the IFD at `offset`, returning its type,
+    /// count and raw value (or offset) field.
+    fn find_tag(&mut self, offset: u32, tag: u16) -> io::Result<Option<(u16, u32, [u8; 4])>> {
+        let mut count = [0; 2];
+        self.read_at(offset.into(), &mut count)?;
+        let count = self.u16(count).min(MAX_IFD_ENTRIES);
+        let mut entries = vec![0; usize::from(count) * 12];
+        self.read_at(u64::from(offset) + 2, &mut entries)?;
+        for entry in entries.chunks_exact(12) {
+            if self.u16([entry[0], entry[1]]) == tag {
+                let kind = self.u16([entry[2], entry[3]]);
+                let count = self.u32([entry[4], entry[5], entry[6], entry[7]]);
+                return Ok(Some((
+                    kind,
+                    count,
+                    [entry[8], entry[9], entry[10], entry[11]],
+                )));
+            }
+        }
+        Ok(None)
+    }
+
+    fn body_serial(&mut self) -> io::Result<Option<String>> {
+        let mut header = [0; 8];
+        self.read_at(0, &mut header)?;
+        self.order = match &header[..2] {
+            b"II" => ByteOrder::Little,
+            b"MM" => ByteOrder::Big,
+            _ => return Ok(None),
+        };
+        if self.u16([header[2], header[3]]) != 42 {
+            return Ok(None);
+        }
+        let ifd0 = self.u32([header[4], header[5], header[6], header[7]]);
+        let exif_ifd = match self.find_tag(ifd0, TAG_EXIF_IFD)? {
+            Some((_, _, value)) => self.u32(value),
+            None => return Ok(None),
+        };
+        let (count, value) = match self.find_tag(exif_ifd, TAG_BODY_SERIAL_NUMBER)? {
+            Some((TYPE_ASCII, count, value)) if count <= MAX_STRING_LEN => (count, value),
+            _ => return Ok(None),
+        };
+        let mut serial = vec![0; count as usize];
+        if count <= 4 {
+            serial.copy_from_slice(&value[..count as usize]);
+        } else {
+            let offset = self.u32(value);
+            self.read_at(offset.into(), &mut serial)?;
+        }
+        let serial = String::from_utf8_lossy(&serial);
+        let serial = serial.trim_end_matches('\0').trim();
+        Ok((!serial.is_empty()).then(|| serial.to_string()))
+    }
+}
+
+/// Returns the offset of the TIFF header inside a JPEG's EXIF segment.
+fn jpeg_exif_offset(file: &mut File) -> io::Result<Option<u64>> {
+    let mut pos = 2;
+    loop {
+        let mut marker = [0; 4];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut marker)?;
+        // Start of scan or end of image: no more metadata segments.
+        if marker[0] != 0xff || marker[1] == 0xda || marker[1] == 0xd9 {
+            return Ok(None);
+        }
+        let length = u64::from(u16::from_be_bytes([marker[2], marker[3]]));
+        if marker[1] == 0xe1 {
+            let mut id = [0; 6];
+            file.read_exact(&mut id)?;
+            if &id == b"Exif\0\0" {
+                return Ok(Some(pos + 10));
+            }
+        }
+        pos += 2 + length;
+    }
+}
+
+fn read_body_serial(file: &mut File) -> io::Result<Option<String>> {
+    let mut magic = [0; 2];
+    file.read_exact(&mut magic)?;
+    let base = if magic == [0xff, 0xd8] {
+        match jpeg_exif_offset(file)? {
+            Some(offset) => offset,
+            None => return Ok(None),
+        }
+    } else {
+        0
+    };
+    Tiff {
+        file,
+        base,
+        order: ByteOrder::Little,
+    }
+    .body_serial()
+}
+
+/// Reads the camera body serial number (EXIF `BodySerialNumber`) from the
+/// given file, if present.
+pub fn body_serial(path: &Path) -> io::Result<Option<String>> {
+    let mut file = File::open(path)?;
+    match read_body_serial(&mut file) {
+        // Truncated or corrupt files just don't have a serial.
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        other => other,
+    }
+}
+
+/// Builds a minimal little-endian TIFF with the given body serial number.
+#[cfg(test)]
+pub(crate) fn tiff_with_serial(serial: &str) -> Vec<u8> {
+    let mut serial = serial.as_bytes().to_vec();
+    serial.push(0);
+    let mut tiff = b"II\x2a\x00\x08\x00\x00\x00".to_vec();
+    // IFD0, at 8: one entry pointing to the EXIF IFD at 26.
+    tiff.extend(1u16.to_le_bytes());
+    tiff.extend(TAG_EXIF_IFD.to_le_bytes());
+    tiff.extend(4u16.to_le_bytes());
+    tiff.extend(1u32.to_le_bytes());
+    tiff.extend(26u32.to_le_bytes());
+    tiff.extend(0u32.to_le_bytes());
+    // EXIF IFD, at 26: the serial, inline if short or else stored at 44.
+    tiff.extend(1u16.to_le_bytes());
+    tiff.extend(TAG_BODY_SERIAL_NUMBER.to_le_bytes());
+    tiff.extend(TYPE_ASCII.to_le_bytes());
+    tiff.extend((serial.len() as u32).to_le_bytes());
+    if serial.len() <= 4 {
+        serial.resize(4, 0);
+        tiff.extend(serial);
+        tiff.extend(0u32.to_le_bytes());
+    } else {
+        tiff.extend(44u32.to_le_bytes());
+        tiff.extend(0u32.to_le_bytes());
+        tiff.extend(serial);
+    }
+    tiff
+}
+
+#[cfg(test)]
+mod tests {
+    use speculoos::prelude::*;
+    use tempfile::tempdir;
+
+    use super::{body_serial, tiff_with_serial};
+
+    #[test]
+    fn test_body_serial() {
+        let temp_dir = tempdir().unwrap();
+        let raw = temp_dir.path().join("1.nef");
+        std::fs::write(&raw, tiff_with_serial("3012345")).unwrap();
+        assert_that!(body_serial(&raw).unwrap()).is_equal_to(Some("3012345".to_string()));
+
+        // A JPEG with a JFIF segment before the EXIF one.
+        let mut jpeg = b"\xff\xd8\xff\xe0\x00\x04ab".to_vec();
+        let tiff = tiff_with_serial("A1");
+        jpeg.extend(b"\xff\xe1");
+        jpeg.extend(((tiff.len() + 8) as u16).to_be_bytes());
+        jpeg.extend(b"Exif\0\0");
+        jpeg.extend(tiff);
+        jpeg.extend(b"\xff\xd9");
+        let jpg = temp_dir.path().join("1.jpg");
+        std::fs::write(&jpg, jpeg).unwrap();
+        assert_that!(body_serial(&jpg).unwrap()).is_equal_to(Some("A1".to_string()));
+
+        let other = temp_dir.path().join("1.xmp");
+        std::fs::write(&other, "<x:xmpmeta/>").unwrap();
+        assert_that!(body_serial(&other).unwrap()).is_none();
+        let truncated = temp_dir.path().join("2.nef");
+        std::fs::write(&truncated, &tiff_with_serial("1")[..20]).unwrap();
+        assert_that!(body_serial(&truncated).unwrap()).is_none();
+    }
+}
@@ -0,0 +1,112 @@
+//! Extension lists loaded from a file, re-read on each scan so that edits
+//! take effect without restarting.
+//!
+//! The file is YAML, with any of the `ignored`, `raw` and `editable`
+//! lists; missing lists keep the values given on the command line. It can
+//! also define extension classes with their own expected (octal) mode,
+//! which take precedence over the raw and editable file modes:
+//!
+//! ```yaml
+//! raw: [nef, cr3]
+//! editable: [jpg, tif]
+//! modes:
+//!   sidecar: { exts: [xmp], mode: "640" }
+//!   video: { exts: [mp4, mov], mode: "440" }
+//! ```
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::Path;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::ModeClass;
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ExtensionLists {
+    pub ignored: Option<Vec<String>>,
+    pub raw: Option<Vec<String>>,
+    pub editable: Option<Vec<String>>,
+    #[serde(default)]
+    pub modes: BTreeMap<String, ModeSpec>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ModeSpec {
+    pub exts: Vec<String>,
+    #[serde(deserialize_with = "octal_mode")]
+    pub mode: u32,
+}
+
+fn octal_mode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let mode = String::deserialize(deserializer)?;
+    crate::cli::parse_octal_mode(&mode)
+        .map_err(|e| serde::de::Error::custom(format!("invalid mode '{}': {}", mode, e)))
+}
+
+impl ExtensionLists {
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        serde_yaml::from_str(contents).map_err(|e| e.to_string())
+    }
+
+    /// Loads and parses the given extensions file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Can't read extensions file '{}': {}", path.display(), e))?;
+        Self::parse(&contents)
+            .map_err(|e| format!("Can't parse extensions file '{}': {}", path.display(), e))
+    }
+
+    /// Returns the mode classes, in name order.
+    pub fn mode_classes(&self) -> Vec<ModeClass> {
+        self.modes
+            .iter()
+            .map(|(name, spec)| ModeClass {
+                name: name.clone(),
+                exts: spec.exts.iter().map(OsString::from).collect(),
+                mode: spec.mode,
+            })
+            .collect()
+    }
+}
+
+/// Returns the list from the file if given, or else the default one.
+pub fn or_default(list: &Option<Vec<String>>, default: &[OsString]) -> Vec<OsString> {
+    match list {
+        Some(exts) => exts.iter().map(OsString::from).collect(),
+        None => default.to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use speculoos::prelude::*;
+
+    use super::ExtensionLists;
+
+    #[test]
+    fn test_parse() {
+        let lists = ExtensionLists::parse("raw: [nef, cr3]\n").unwrap();
+        assert_that!(lists.raw).is_equal_to(Some(vec!["nef".to_string(), "cr3".to_string()]));
+        assert_that!(lists.editable).is_none();
+        assert_that!(ExtensionLists::parse("rwa: [nef]\n")).is_err();
+    }
+
+    #[test]
+    fn test_parse_modes() {
+        let lists = ExtensionLists::parse("modes:\n  video: { exts: [mp4, mov], mode: \"440\" }\n")
+            .unwrap();
+        assert_that!(lists.raw).is_none();
+        let classes = lists.mode_classes();
+        assert_that!(classes).has_length(1);
+        assert_that!(classes[0].name).is_equal_to("video".to_string());
+        assert_that!(classes[0].mode).is_equal_to(0o440);
+        assert_that!(ExtensionLists::parse(
+            "modes:\n  video: { exts: [mp4], mode: \"9\" }\n"
+        ))
+        .is_err()
+        .contains("invalid mode '9'");
+    }
+}
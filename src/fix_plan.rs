@@ -0,0 +1,165 @@
+//! Dry-run plan of the `chown`/`chmod` commands that would fix the
+//! ownership and permissions violations of a scan, printed by `oneshot
+//! --fix-plan` for review before applying them manually.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::{Component, Path};
+
+use crate::{Backlog, ErrorType, Violation};
+
+/// Quotes a path for a POSIX shell, if needed.
+/// Example:
+/// ```
+/// use photo_backlog_exporter::fix_plan::shell_quote;
+/// assert_eq!(shell_quote("/photos/a/1.nef"), "/photos/a/1.nef");
+/// assert_eq!(shell_quote("/photos/Smith wedding"), "'/photos/Smith wedding'");
+/// assert_eq!(shell_quote("/photos/Bob's"), "'/photos/Bob'\\''s'");
+/// ```
+pub fn shell_quote(s: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "/._-+,:@%".contains(c);
+    if !s.is_empty() && s.chars().all(safe) {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', "'\\''"))
+    }
+}
+
+/// Escapes the control characters of a comment, so that e.g. a newline
+/// in a folder name can't end the comment line.
+fn escape_comment(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_control() {
+                c.escape_default().to_string()
+            } else {
+                c.to_string()
+            }
+        })
+        .collect()
+}
+
+/// Returns the command fixing the given violation, if it's fixable.
+fn fix_command(violation: &Violation) -> Option<String> {
+    let expected = violation.expected.as_deref()?;
+    let path = shell_quote(&violation.path.to_string_lossy());
+    match violation.kind {
+        ErrorType::Ownership => match expected.split_once(':')? {
+            ("*", "*") => None,
+            (owner, "*") => Some(format!("chown {} {}", owner, path)),
+            ("*", group) => Some(format!("chgrp {} {}", group, path)),
+            (owner, group) => Some(format!("chown {}:{} {}", owner, group, path)),
+        },
+        ErrorType::Permissions => Some(format!("chmod {} {}", expected, path)),
+        _ => None,
+    }
+}
+
+/// Returns the top-level folder of the given path, including for the
+/// folder itself, or `.` for the root and the files directly in it.
+fn top_folder(root: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    let mut components = relative.components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(folder)), Some(_)) => folder.to_string_lossy().into_owned(),
+        (Some(Component::Normal(folder)), None) if path.is_dir() => {
+            folder.to_string_lossy().into_owned()
+        }
+        _ => ".".to_string(),
+    }
+}
+
+/// Renders the commands fixing the ownership and permissions violations,
+/// grouped by top-level folder.
+pub fn render(backlog: &Backlog, root: &Path) -> String {
+    let mut folders: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for violation in &backlog.violations {
+        if let Some(command) = fix_command(violation) {
+            folders
+                .entry(top_folder(root, &violation.path))
+                .or_default()
+                .push(command);
+        }
+    }
+    let mut out = String::new();
+    // Writing to a String can't fail.
+    let commands: usize = folders.values().map(Vec::len).sum();
+    let _ = writeln!(
+        out,
+        "# {}: {} commands in {} folders",
+        escape_comment(&root.to_string_lossy()),
+        commands,
+        folders.len()
+    );
+    for (folder, mut commands) in folders {
+        commands.sort();
+        let _ = writeln!(out, "\n# {}", escape_comment(&folder));
+        for command in commands {
+            let _ = writeln!(out, "{}", command);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use speculoos::prelude::*;
+    use tempfile::tempdir;
+
+    use crate::{Backlog, ErrorType, Violation};
+
+    fn violation(root: &Path, path: &str, kind: ErrorType, expected: &str) -> Violation {
+        Violation {
+            path: root.join(path),
+            kind,
+            expected: Some(expected.to_string()),
+            actual: None,
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path().join("photos");
+        std::fs::create_dir_all(root.join("a b")).unwrap();
+        let mut backlog = Backlog::new([].into_iter());
+        backlog.violations = vec![
+            violation(&root, "b/2.nef", ErrorType::Permissions, "640"),
+            violation(&root, "a b/1.nef", ErrorType::Ownership, "1000:*"),
+            violation(&root, "a b", ErrorType::Ownership, "1000:100"),
+            violation(&root, "b/1.nef", ErrorType::Ownership, "*:100"),
+            violation(&root, "1.nef", ErrorType::Permissions, "600"),
+            violation(&root, "b/2.nef", ErrorType::Structure, "raw"),
+        ];
+        let root_str = root.display().to_string();
+        let expected = String::from(
+            "# R: 5 commands in 3 folders\n\
+             \n# .\n\
+             chmod 600 R/1.nef\n\
+             \n# a b\n\
+             chown 1000 'R/a b/1.nef'\n\
+             chown 1000:100 'R/a b'\n\
+             \n# b\n\
+             chgrp 100 R/b/1.nef\n\
+             chmod 640 R/b/2.nef\n",
+        );
+        assert_that!(super::render(&backlog, &root).replace(&root_str, "R")).is_equal_to(expected);
+    }
+
+    #[test]
+    fn test_render_escapes_folders() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path().join("photos");
+        let mut backlog = Backlog::new([].into_iter());
+        backlog.violations = vec![violation(
+            &root,
+            "a\nrm -rf ~/1.nef",
+            ErrorType::Permissions,
+            "640",
+        )];
+        // Escaped in the comment, while the command keeps it quoted.
+        assert_that!(super::render(&backlog, &root)).contains("\n# a\\nrm -rf ~\nchmod 640 '");
+    }
+}
@@ -0,0 +1,134 @@
+//! Builds reproducible photo trees from a simple text specification, for
+//! tests and for reproducing bugs (see the `gen-tree` binary).
+//!
+//! The specification has one entry per line, as a path relative to the
+//! root, with directories ending in `/`, optionally followed by attributes:
+//!
+//! ```text
+//! # Comments and empty lines are ignored.
+//! 2024-06-15 Smith/ mode=750
+//! 2024-06-15 Smith/DSC_0001.nef mode=640 age=30
+//! 2024-06-15 Smith/DSC_0001.xmp owner=1000:1000
+//! ```
+//!
+//! `mode` is octal, `age` in days before now, and `owner` is `UID:GID`
+//! (only possible when running with the needed privileges). Parent
+//! directories are created as needed.
+
+use std::fs::File;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+#[derive(Debug, Default, PartialEq)]
+struct Attributes {
+    mode: Option<u32>,
+    age_days: Option<u64>,
+    owner: Option<(u32, u32)>,
+}
+
+/// Splits a specification line into the path and its attributes.
+fn parse_line(line: &str) -> Result<(&str, Attributes), String> {
+    let mut attrs = Attributes::default();
+    let mut path = line;
+    while let Some((rest, token)) = path.rsplit_once(' ') {
+        let (key, value) = match token.split_once('=') {
+            Some(kv) => kv,
+            None => break,
+        };
+        let invalid = |e: &dyn std::fmt::Display| format!("Invalid {} in '{}': {}", key, line, e);
+        match key {
+            "mode" => {
+                attrs.mode = Some(u32::from_str_radix(value, 8).map_err(|e| invalid(&e))?);
+            }
+            "age" => attrs.age_days = Some(value.parse().map_err(|e| invalid(&e))?),
+            "owner" => {
+                let (uid, gid) = value
+                    .split_once(':')
+                    .ok_or_else(|| invalid(&"expected UID:GID"))?;
+                attrs.owner = Some((
+                    uid.parse().map_err(|e| invalid(&e))?,
+                    gid.parse().map_err(|e| invalid(&e))?,
+                ));
+            }
+            _ => break,
+        }
+        path = rest.trim_end();
+    }
+    Ok((path, attrs))
+}
+
+/// Creates the entries described by `spec` under `root`.
+pub fn build_tree(root: &Path, spec: &str) -> Result<(), String> {
+    for line in spec.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (entry, attrs) = parse_line(line)?;
+        let path = root.join(entry);
+        let error = |e: std::io::Error| format!("Can't create '{}': {}", path.display(), e);
+        if entry.ends_with('/') {
+            std::fs::create_dir_all(&path).map_err(error)?;
+        } else {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent).map_err(error)?;
+            }
+            File::create(&path).map_err(error)?;
+        }
+        if let Some(days) = attrs.age_days {
+            let mtime = SystemTime::now() - Duration::from_secs(days * 86400);
+            File::open(&path)
+                .and_then(|f| f.set_modified(mtime))
+                .map_err(error)?;
+        }
+        if let Some((uid, gid)) = attrs.owner {
+            std::os::unix::fs::chown(&path, Some(uid), Some(gid)).map_err(error)?;
+        }
+        // Last, as it can remove our own access.
+        if let Some(mode) = attrs.mode {
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))
+                .map_err(error)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use speculoos::prelude::*;
+    use tempfile::tempdir;
+
+    use super::{build_tree, parse_line, Attributes};
+
+    #[test]
+    fn test_parse_line() {
+        assert_that!(parse_line("a b/c.nef mode=640 age=3")).is_equal_to(Ok((
+            "a b/c.nef",
+            Attributes {
+                mode: Some(0o640),
+                age_days: Some(3),
+                owner: None,
+            },
+        )));
+        assert_that!(parse_line("a=b/")).is_equal_to(Ok(("a=b/", Attributes::default())));
+        assert_that!(parse_line("a mode=9")).is_err();
+    }
+
+    #[test]
+    fn test_build_tree() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        build_tree(
+            root,
+            "# A test tree.\n\nempty/\n2024-06-15 Smith/1.nef mode=600 age=10\n",
+        )
+        .unwrap();
+        assert_that!(root.join("empty").is_dir()).is_true();
+        let m = std::fs::metadata(root.join("2024-06-15 Smith/1.nef")).unwrap();
+        assert_that!(m.permissions().mode() & 0o777).is_equal_to(0o600);
+        let age = m.modified().unwrap().elapsed().unwrap().as_secs();
+        assert_that!(age / 86400).is_equal_to(10);
+    }
+}
@@ -0,0 +1,103 @@
+//! Normalised ("golden") metrics output, for diffing against a committed
+//! file to detect unintended metric or label changes across versions.
+//!
+//! Values depending on the run rather than on the tree (timestamps,
+//! processing times, scan ids) are zeroed, and the samples of each metric
+//! are sorted, as their order isn't stable.
+
+use std::collections::HashSet;
+
+use crate::prometheus::PROCESSING_TIME_NAME;
+
+/// Metrics whose values depend on the run, including the histogram
+/// series derived from them.
+const VOLATILE: &[&str] = &[
+    PROCESSING_TIME_NAME,
+    "photo_backlog_snapshot_timestamp_seconds",
+    "photo_backlog_last_scan_id",
+    "photo_backlog_stat_duration_seconds",
+];
+
+fn normalize_sample(line: &str) -> String {
+    let name = line.split(['{', ' ']).next().unwrap_or_default();
+    match line.rsplit_once(' ') {
+        Some((series, _)) if VOLATILE.iter().any(|v| name.starts_with(v)) => {
+            format!("{} 0", series)
+        }
+        _ => line.to_string(),
+    }
+}
+
+/// Normalises the given text format output.
+/// Example:
+/// ```
+/// use photo_backlog_exporter::golden::normalize;
+/// let text = "# TYPE a gauge\na{x=\"2\"} 1\na{x=\"1\"} 2\nphoto_backlog_last_scan_id 42\n";
+/// assert_eq!(
+///     normalize(text),
+///     "# TYPE a gauge\na{x=\"1\"} 2\na{x=\"2\"} 1\nphoto_backlog_last_scan_id 0\n"
+/// );
+/// ```
+pub fn normalize(text: &str) -> String {
+    let mut lines = Vec::new();
+    let mut samples = Vec::new();
+    for line in text.lines() {
+        if line.is_empty() || line.starts_with('#') {
+            samples.sort();
+            lines.append(&mut samples);
+            lines.push(line.to_string());
+        } else {
+            samples.push(normalize_sample(line));
+        }
+    }
+    samples.sort();
+    lines.append(&mut samples);
+    let mut normalized = lines.join("\n");
+    normalized.push('\n');
+    normalized
+}
+
+/// Returns the lines only in the expected output, prefixed with `-`, and
+/// those only in the actual one, prefixed with `+`.
+pub fn diff(expected: &str, actual: &str) -> Vec<String> {
+    let expected_lines: HashSet<&str> = expected.lines().collect();
+    let actual_lines: HashSet<&str> = actual.lines().collect();
+    let removed = expected
+        .lines()
+        .filter(|line| !actual_lines.contains(line))
+        .map(|line| format!("-{}", line));
+    let added = actual
+        .lines()
+        .filter(|line| !expected_lines.contains(line))
+        .map(|line| format!("+{}", line));
+    removed.chain(added).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use speculoos::prelude::*;
+
+    use super::{diff, normalize};
+
+    #[test]
+    fn test_diff() {
+        let expected = normalize("# TYPE a gauge\na{x=\"1\"} 1\na{x=\"2\"} 1\n");
+        let actual = normalize("# TYPE a gauge\na{y=\"1\"} 1\na{x=\"2\"} 1\n");
+        assert_that!(diff(&expected, &expected)).is_empty();
+        assert_that!(diff(&expected, &actual)).is_equal_to(vec![
+            "-a{x=\"1\"} 1".to_string(),
+            "+a{y=\"1\"} 1".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn test_normalize_histogram() {
+        let text = "photo_backlog_stat_duration_seconds_sum 0.001\n\
+                    photo_backlog_stat_duration_seconds_bucket{le=\"+Inf\"} 3\n";
+        assert_that!(normalize(text)).is_equal_to(
+            "photo_backlog_stat_duration_seconds_bucket{le=\"+Inf\"} 0\n\
+             photo_backlog_stat_duration_seconds_sum 0\n"
+                .to_string(),
+        );
+    }
+}
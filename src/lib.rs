@@ -1,22 +1,68 @@
-use std::collections::HashMap;
-use std::ffi::OsString;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
 use std::fs::Metadata;
+use std::ops::ControlFlow;
 use std::option::Option;
-use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
 use std::path::{Component, Path, PathBuf};
-use std::time::{Duration, SystemTime};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
-use log::{info, warn};
-use walkdir::WalkDir;
+use log::warn;
 
 use prometheus_client::encoding::{EncodeLabelValue, LabelValueEncoder};
-use prometheus_client::metrics::histogram::Histogram;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
 
 const ROOT_FILE_DIR: &str = ".";
 
+/// Camera serial number used for files without one.
+pub const UNKNOWN_SERIAL: &str = "unknown";
+
+/// Optional file at the root with gitignore-style patterns of paths to skip.
+pub const IGNORE_FILE: &str = ".backlogignore";
+
+/// Optional file in top-level folders with `key=value` tags (e.g.
+/// `client=smith,priority=high`), read if tag keys are allowed.
+pub const TAGS_FILE: &str = ".backlog-tags";
+
+/// Logs a message about a scan, prefixed with its id, so that log lines
+/// can be correlated with the scan (and scrape) producing them.
+macro_rules! scan_log {
+    ($level:ident, $config:expr, $($arg:tt)+) => {
+        log::$level!("[scan {}] {}", $config.scan_id, format_args!($($arg)+))
+    };
+}
+
+pub mod archive;
+pub mod buckets;
 pub mod cli;
+pub mod collation;
+pub mod config_file;
+#[cfg(feature = "daemon")]
 pub mod daemon;
+pub mod exif;
+pub mod exts;
+pub mod fix_plan;
+#[cfg(any(test, feature = "gen-tree"))]
+pub mod fixture;
+pub mod golden;
 pub mod prometheus;
+pub mod protobuf;
+pub mod readonly;
+pub mod relabel;
+#[cfg(feature = "remote-write")]
+pub mod remote_write;
+pub mod scan_lock;
+pub mod snapshot;
+pub mod summary;
+pub mod textfile;
+pub mod tree_watch;
+pub mod violations;
+mod walk;
+pub mod watch;
+#[cfg(feature = "daemon")]
+pub mod web_config;
+pub mod work_calendar;
 
 /// Returns the first named directory from a given path.
 ///
@@ -63,6 +109,11 @@ pub fn relative_top(root: &Path, p: &Path) -> Option<PathBuf> {
     first_dir(relative)
 }
 
+/// How far in the future a file's modification time can be before it's
+/// flagged, by default, as files can be written while scanning and the
+/// host's clock can be slightly behind the file server's.
+pub const DEFAULT_CLOCK_SKEW_TOLERANCE: Duration = Duration::from_secs(60);
+
 /// Returns the age of a file relative to a given timestamp, or zero if the file is newer.
 pub fn relative_age(reference: SystemTime, m: &Metadata) -> Duration {
     let modified = m.modified().unwrap_or(reference);
@@ -75,131 +126,937 @@ pub enum ErrorType {
     Ownership,
     Permissions,
     Unknown,
+    /// FIFOs, sockets and devices, if configured to be errors.
+    SpecialFile,
+    /// Symlinks, if configured to be errors.
+    Symlink,
+    /// Paths longer than the configured maximum.
+    PathLength,
+    /// Paths nested deeper than the configured maximum.
+    PathDepth,
+    /// Top-level folders missing some of the required subdirectories.
+    Structure,
+    /// Companion files (e.g. video thumbnails) without their primary file.
+    OrphanCompanion,
+    /// Files in the archive subpath without the immutable attribute.
+    NotImmutable,
 }
 
-#[derive(PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum FileKind {
     Raw,
     Editable,
     Ignored,
     None,
     Unknown,
+    /// A zip or tar archive, counted via its entries.
+    Archive,
 }
 
-impl EncodeLabelValue for ErrorType {
+/// An extension class with its own expected file mode, e.g. sidecars or
+/// videos, taking precedence over the raw and editable file modes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModeClass {
+    pub name: String,
+    pub exts: Vec<OsString>,
+    pub mode: u32,
+}
+
+/// What to do with files that have no extension.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NoExtension {
+    /// Skip them, as if they were ignored files.
+    #[default]
+    Ignore,
+    /// Count them as backlog.
+    Count,
+    /// Count them as errors, like files with unknown extensions.
+    Error,
+}
+
+impl std::str::FromStr for NoExtension {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(NoExtension::Ignore),
+            "count" => Ok(NoExtension::Count),
+            "error" => Ok(NoExtension::Error),
+            _ => Err(format!(
+                "Invalid value '{}', expected one of ignore, count, error",
+                s
+            )),
+        }
+    }
+}
+
+/// Size of the synthetic backlog served instead of scanning.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MockData {
+    pub folders: usize,
+    /// Files per folder.
+    pub files: usize,
+}
+
+/// How to handle symlinks found while scanning.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Symlinks {
+    /// Skip them.
+    #[default]
+    Ignore,
+    /// Count symlinked files as backlog, without descending into
+    /// symlinked directories.
+    Count,
+    /// Count them as errors.
+    Error,
+    /// Follow them, both for files and directories; loops are detected
+    /// and reported as scan errors.
+    Follow,
+}
+
+impl std::str::FromStr for Symlinks {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(Symlinks::Ignore),
+            "count" => Ok(Symlinks::Count),
+            "error" => Ok(Symlinks::Error),
+            "follow" => Ok(Symlinks::Follow),
+            _ => Err(format!(
+                "Invalid value '{}', expected one of ignore, count, error, follow",
+                s
+            )),
+        }
+    }
+}
+
+/// A check that can be turned off while keeping its configuration.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Check {
+    /// The file and directory owner.
+    Owner,
+    Group,
+    DirMode,
+    RawMode,
+    EditableMode,
+    /// The per-class modes from the extensions file.
+    ClassModes,
+    /// The required subdirectories of top-level folders.
+    Structure,
+    /// The immutable attribute of archived files.
+    Immutable,
+}
+
+impl Check {
+    pub const ALL: [Check; 8] = [
+        Check::Owner,
+        Check::Group,
+        Check::DirMode,
+        Check::RawMode,
+        Check::EditableMode,
+        Check::ClassModes,
+        Check::Structure,
+        Check::Immutable,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Check::Owner => "owner",
+            Check::Group => "group",
+            Check::DirMode => "dir-mode",
+            Check::RawMode => "raw-mode",
+            Check::EditableMode => "editable-mode",
+            Check::ClassModes => "class-modes",
+            Check::Structure => "structure",
+            Check::Immutable => "immutable",
+        }
+    }
+
+    /// The error type of failures of this check.
+    pub fn error_type(&self) -> ErrorType {
+        match self {
+            Check::Owner | Check::Group => ErrorType::Ownership,
+            Check::DirMode | Check::RawMode | Check::EditableMode | Check::ClassModes => {
+                ErrorType::Permissions
+            }
+            Check::Structure => ErrorType::Structure,
+            Check::Immutable => ErrorType::NotImmutable,
+        }
+    }
+}
+
+impl EncodeLabelValue for Check {
     fn encode(&self, encoder: &mut LabelValueEncoder) -> Result<(), std::fmt::Error> {
-        let s = match self {
+        EncodeLabelValue::encode(&self.name().replace('-', "_").as_str(), encoder)
+    }
+}
+
+impl std::str::FromStr for Check {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Check::ALL
+            .into_iter()
+            .find(|check| check.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<_> = Check::ALL.iter().map(Check::name).collect();
+                format!(
+                    "Invalid check '{}', expected one of {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+impl ErrorType {
+    /// The name used as label value and in the violations log.
+    pub fn name(&self) -> &'static str {
+        match self {
             ErrorType::Scan => "scan",
             ErrorType::Ownership => "ownership",
             ErrorType::Permissions => "permissions",
             ErrorType::Unknown => "unknown",
-        };
-        EncodeLabelValue::encode(&s, encoder)
+            ErrorType::SpecialFile => "special_file",
+            ErrorType::Symlink => "symlink",
+            ErrorType::PathLength => "path_length",
+            ErrorType::PathDepth => "path_depth",
+            ErrorType::Structure => "structure",
+            ErrorType::OrphanCompanion => "orphan_companion",
+            ErrorType::NotImmutable => "not_immutable",
+        }
     }
 }
 
-pub fn check_ownership(config: &Config, path: &Path, m: &Metadata, kind: &str) -> bool {
-    let mut good = true;
-    if let Some(owner) = config.owner {
-        good &= owner == m.uid();
+impl EncodeLabelValue for ErrorType {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> Result<(), std::fmt::Error> {
+        EncodeLabelValue::encode(&self.name(), encoder)
+    }
+}
+
+/// Whether a directory or a file failed an ownership or permissions check.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum CheckTarget {
+    File,
+    Dir,
+}
+
+impl CheckTarget {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CheckTarget::File => "file",
+            CheckTarget::Dir => "dir",
+        }
     }
-    if let Some(group) = config.group {
-        good &= group == m.gid();
+}
+
+impl EncodeLabelValue for CheckTarget {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> Result<(), std::fmt::Error> {
+        EncodeLabelValue::encode(&self.name(), encoder)
     }
-    if !good {
+}
+
+/// Why a walked entry was skipped, i.e. neither counted nor an error.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Files with an ignored extension, or without one, if ignored.
+    IgnoredExt,
+    /// The ignore and tags files themselves.
+    Marker,
+    /// Entries matching the ignore file's patterns; ignored directories
+    /// count once, as they're not walked.
+    ExcludedGlob,
+    /// FIFOs, sockets and devices.
+    SpecialFile,
+    /// Symlinks neither followed nor counted, or counted but not pointing
+    /// to a file.
+    Symlink,
+    /// Companion files, e.g. video thumbnails.
+    Companion,
+}
+
+impl SkipReason {
+    pub const ALL: [SkipReason; 6] = [
+        SkipReason::IgnoredExt,
+        SkipReason::Marker,
+        SkipReason::ExcludedGlob,
+        SkipReason::SpecialFile,
+        SkipReason::Symlink,
+        SkipReason::Companion,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SkipReason::IgnoredExt => "ignored_ext",
+            SkipReason::Marker => "marker",
+            SkipReason::ExcludedGlob => "excluded_glob",
+            SkipReason::SpecialFile => "special_file",
+            SkipReason::Symlink => "symlink",
+            SkipReason::Companion => "companion",
+        }
+    }
+}
+
+impl EncodeLabelValue for SkipReason {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> Result<(), std::fmt::Error> {
+        EncodeLabelValue::encode(&self.name(), encoder)
+    }
+}
+
+/// A single violation found while scanning, kept for the violations log.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Violation {
+    pub path: PathBuf,
+    pub kind: ErrorType,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+}
+
+/// A file added to or removed from the tree since a scan, e.g. as seen by
+/// the daemon's tree watcher.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FileChange {
+    Added(PathBuf),
+    Removed(PathBuf),
+}
+
+/// A backlog file among the largest ones.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LargeFile {
+    /// Path relative to the root.
+    pub path: PathBuf,
+    pub size: u64,
+    /// Age, in seconds.
+    pub age: f64,
+}
+
+/// Detailed breakdown of a folder's backlog files, if recording it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FolderDetails {
+    /// File counts per extension, those without one under an empty one.
+    pub exts: BTreeMap<String, i64>,
+    /// Total size, in bytes; archives count once, not per entry.
+    pub size: u64,
+    /// The oldest and newest files, relative to the root, with their
+    /// ages in seconds.
+    pub oldest: Option<(PathBuf, f64)>,
+    pub newest: Option<(PathBuf, f64)>,
+}
+
+/// Returns the failed ownership checks (owner and/or group).
+fn failed_ownership_checks(config: &Config, m: &Metadata) -> Vec<Check> {
+    let mut failed = Vec::new();
+    if config.owner.is_some_and(|owner| owner != m.uid()) {
+        failed.push(Check::Owner);
+    }
+    if config.group.is_some_and(|group| group != m.gid()) {
+        failed.push(Check::Group);
+    }
+    failed
+}
+
+pub fn check_ownership(config: &Config, path: &Path, m: &Metadata, kind: &str) -> bool {
+    let failed = failed_ownership_checks(config, m);
+    if !failed.is_empty() {
         fn format_id(m_id: Option<u32>) -> String {
             match m_id {
                 None => "(not checked)".to_string(),
                 Some(p) => p.to_string(),
             }
         }
-        info!(
-            "{} '{}' has wrong owner:group {}:{}, expected {}:{}",
+        let checks: Vec<_> = failed.iter().map(Check::name).collect();
+        scan_log!(
+            info,
+            config,
+            "{} '{}' has wrong owner:group {}:{}, expected {}:{} (failed checks: {})",
             kind,
             path.display(),
             m.uid(),
             m.gid(),
             format_id(config.owner),
-            format_id(config.group)
+            format_id(config.group),
+            checks.join(", ")
         );
     }
-    good
+    failed.is_empty()
 }
 
-pub fn check_mode(config: &Config, path: &Path, m: &Metadata, k: FileKind) -> bool {
-    let mut good = true;
-    let mut kind = "(unknown)";
-    let mut expected = 0o0;
-    let actual = m.mode() & 0o777;
-    if m.is_dir() {
-        kind = "directory";
-        if let Some(dir_mode) = config.dir_mode {
-            expected = dir_mode;
-            good &= dir_mode == actual;
+/// Whether the extensions are the same, ignoring ASCII case, as cameras
+/// write them in upper case while the options are usually lower case.
+fn ext_eq(a: &OsStr, b: &OsStr) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+/// Classifies a file (or archive entry) by its extension.
+fn classify(config: &Config, path: &Path) -> FileKind {
+    match path.extension() {
+        None => FileKind::None,
+        Some(ext) => {
+            if config.archive_exts.iter().any(|c| c == ext) {
+                FileKind::Archive
+            } else if config.ignored_exts.iter().any(|c| c == ext) {
+                FileKind::Ignored
+            } else if config.raw_exts.iter().any(|c| c == ext) {
+                FileKind::Raw
+            } else if config.editable_exts.iter().any(|c| c == ext) {
+                FileKind::Editable
+            } else {
+                FileKind::Unknown
+            }
         }
+    }
+}
+
+/// Returns the mode class of the given file, if any.
+fn mode_class<'a>(config: &Config<'a>, path: &Path) -> Option<&'a ModeClass> {
+    let ext = path.extension()?;
+    config
+        .mode_classes
+        .iter()
+        .find(|class| class.exts.iter().any(|e| e == ext))
+}
+
+/// Returns the mode expected for the given entry, if any.
+fn expected_mode(config: &Config, path: &Path, m: &Metadata, k: FileKind) -> Option<u32> {
+    if m.is_dir() {
+        config.dir_mode
     } else if m.is_file() {
-        kind = "file";
-        let expected_mode = match k {
+        if let Some(class) = mode_class(config, path) {
+            return Some(class.mode);
+        }
+        match k {
             FileKind::Raw => config.raw_file_mode,
             FileKind::Editable => config.editable_file_mode,
             _ => None,
-        };
-        if let Some(file_mode) = expected_mode {
-            expected = file_mode;
-            good &= file_mode == actual;
         }
+    } else {
+        None
     }
+}
+
+/// Returns the check responsible for the mode of the given entry.
+fn mode_check(config: &Config, path: &Path, m: &Metadata, k: FileKind) -> Option<Check> {
+    if m.is_dir() {
+        Some(Check::DirMode)
+    } else if !m.is_file() {
+        None
+    } else if mode_class(config, path).is_some() {
+        Some(Check::ClassModes)
+    } else {
+        match k {
+            FileKind::Raw => Some(Check::RawMode),
+            FileKind::Editable => Some(Check::EditableMode),
+            _ => None,
+        }
+    }
+}
+
+pub fn check_mode(config: &Config, path: &Path, m: &Metadata, k: FileKind) -> bool {
+    let kind = if m.is_dir() {
+        "directory"
+    } else if m.is_file() {
+        "file"
+    } else {
+        "(unknown)"
+    };
+    let actual = m.mode() & 0o777;
+    let expected = expected_mode(config, path, m, k);
+    let good = expected.is_none_or(|mode| mode == actual);
     if !good {
-        info!(
-            "{} '{}' has wrong mode {:o}, expected {:o} (kind: {:?})",
+        scan_log!(
+            info,
+            config,
+            "{} '{}' has wrong mode {:o}, expected {:o} (failed check: {})",
             kind,
             path.display(),
             actual,
-            expected,
-            kind,
+            expected.unwrap_or(0),
+            mode_check(config, path, m, k).map_or("(unknown)", |check| check.name()),
         );
     }
     good
 }
 
+/// Returns whether the given file has the immutable attribute (`chattr +i`).
+#[cfg(target_os = "linux")]
+pub fn is_immutable(path: &Path) -> std::io::Result<bool> {
+    let file = std::fs::File::open(path)?;
+    let flags = rustix::fs::ioctl_getflags(&file)?;
+    Ok(flags.contains(rustix::fs::IFlags::IMMUTABLE))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_immutable(_path: &Path) -> std::io::Result<bool> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "the immutable attribute can only be checked on Linux",
+    ))
+}
+
 pub struct Config<'a> {
     pub root_path: &'a Path,
     pub ignored_exts: &'a [OsString],
     pub raw_exts: &'a [OsString],
     pub editable_exts: &'a [OsString],
+    /// Companion and primary extension pairs: companions (e.g. GoPro
+    /// thumbnails) are neither counted nor flagged, unless orphaned.
+    pub companion_exts: &'a [(OsString, OsString)],
+    /// Extensions of zip or tar archives (e.g. card dumps), counted as
+    /// pseudo-folders holding their entries.
+    pub archive_exts: &'a [OsString],
     pub owner: Option<u32>,
     pub group: Option<u32>,
     pub dir_mode: Option<u32>,
     pub raw_file_mode: Option<u32>,
     pub editable_file_mode: Option<u32>,
+    /// Extension classes with their own expected mode, checked before the
+    /// raw and editable file modes; these also apply to ignored files.
+    pub mode_classes: &'a [ModeClass],
+    /// Approximate memory budget, in bytes, for the per-folder data and
+    /// the files' ages; once exceeded, new folders are only accounted for
+    /// in the totals, and the ages (overall or per folder) are dropped.
+    pub folder_memory_budget: Option<usize>,
+    pub no_extension: NoExtension,
+    pub special_files_as_errors: bool,
+    pub symlinks: Symlinks,
+    /// Maximum length, in bytes, of paths relative to the root.
+    pub max_path_length: Option<usize>,
+    /// Maximum nesting depth below the root; files directly in the root
+    /// are at depth 1.
+    pub max_depth: Option<usize>,
+    /// Number of threads walking the tree; above one, the parallel walker
+    /// is used.
+    pub scan_threads: usize,
+    /// Subdirectories that each top-level folder must contain.
+    pub required_subdirs: &'a [String],
+    /// Subpath, relative to the root, of already archived files, which
+    /// must all have the immutable attribute.
+    pub immutable_subpath: Option<&'a Path>,
+    /// Whether to read the camera serial number of backlog files.
+    pub camera_serials: bool,
+    /// Whether to count files per folder and owner.
+    pub track_owners: bool,
+    /// Number of entries after which the scan is aborted, as a guard
+    /// against pointing at the wrong root.
+    pub max_files: Option<usize>,
+    /// Fraction of the files to stat and account, chosen deterministically,
+    /// with the counts scaled back up; directories are all walked.
+    pub sample: Option<f64>,
+    /// Whether to keep the individual violations, not only their counts.
+    pub record_violations: bool,
+    /// Identifier of the scan, for log lines.
+    pub scan_id: u64,
+    /// Number of largest backlog files to keep, if any.
+    pub largest_files: usize,
+    /// Flag cancelling the scan once set, checked before each entry.
+    pub cancel: Option<&'a AtomicBool>,
+    /// Tag keys read from the folders' tags files, other keys being
+    /// ignored; if empty, tags files aren't read.
+    pub folder_tag_keys: &'a [String],
+    /// Whether to record the per-folder details.
+    pub folder_details: bool,
+    /// How far in the future a file's modification time can be before
+    /// it's flagged as future-dated.
+    pub clock_skew_tolerance: Duration,
+    /// Calendar whose working time file ages are measured in, instead of
+    /// the wall-clock time.
+    pub age_calendar: Option<&'a work_calendar::WorkCalendar>,
+    /// Whether to keep every file's age, for the quantiles and median.
+    pub keep_ages: bool,
+    /// Whether to keep the ages of the files per folder, for the folder
+    /// medians and age buckets.
+    pub keep_folder_ages: bool,
+}
+
+impl<'a> Config<'a> {
+    /// Returns a configuration for the given root path with no extensions
+    /// and no checks enabled.
+    pub fn new(root_path: &'a Path) -> Self {
+        Self {
+            root_path,
+            ignored_exts: &[],
+            raw_exts: &[],
+            editable_exts: &[],
+            companion_exts: &[],
+            archive_exts: &[],
+            owner: None,
+            group: None,
+            dir_mode: None,
+            raw_file_mode: None,
+            editable_file_mode: None,
+            mode_classes: &[],
+            folder_memory_budget: None,
+            no_extension: NoExtension::Ignore,
+            special_files_as_errors: false,
+            symlinks: Symlinks::Ignore,
+            max_path_length: None,
+            max_depth: None,
+            scan_threads: 1,
+            required_subdirs: &[],
+            immutable_subpath: None,
+            camera_serials: false,
+            track_owners: false,
+            max_files: None,
+            sample: None,
+            record_violations: false,
+            scan_id: 0,
+            largest_files: 0,
+            cancel: None,
+            folder_tag_keys: &[],
+            folder_details: false,
+            clock_skew_tolerance: DEFAULT_CLOCK_SKEW_TOLERANCE,
+            age_calendar: None,
+            keep_ages: true,
+            keep_folder_ages: true,
+        }
+    }
+
+    /// Returns the age of a file, as per the calendar if any.
+    fn file_age(&self, reference: SystemTime, m: &Metadata) -> Duration {
+        match self.age_calendar {
+            Some(calendar) => calendar.age(reference, m.modified().unwrap_or(reference)),
+            None => relative_age(reference, m),
+        }
+    }
+}
+
+/// Approximate memory used by a single entry in the folders map.
+fn folder_entry_size(folder: &str) -> usize {
+    // Key (heap and inline parts), value, and the hash map's own overhead
+    // of roughly one word per entry.
+    folder.len()
+        + std::mem::size_of::<String>()
+        + std::mem::size_of::<(i64, f64)>()
+        + std::mem::size_of::<usize>()
+}
+
+/// Returns the name of the top-level folder owning the given path.
+fn top_folder(root: &Path, path: &Path) -> String {
+    let parent = match relative_top(root, path) {
+        Some(x) => x,
+        None => {
+            warn!("Can't determine parent path for {}", path.to_string_lossy());
+            PathBuf::from(ROOT_FILE_DIR)
+        }
+    };
+
+    // And convert to valid UTF-8 string via lossy
+    // conversion. But at least we're back in safe land.
+    String::from(parent.to_string_lossy())
+}
+
+/// Returns the modification times of the root and of the top-level
+/// directories, sorted by name. These change whenever entries are added,
+/// removed or renamed directly in them, but not for deeper changes.
+pub fn tree_mtimes(root: &Path) -> std::io::Result<Vec<(OsString, SystemTime)>> {
+    let mut mtimes = vec![(OsString::new(), root.metadata()?.modified()?)];
+    for entry in std::fs::read_dir(root)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            mtimes.push((entry.file_name(), metadata.modified()?));
+        }
+    }
+    mtimes.sort();
+    Ok(mtimes)
+}
+
+/// Returns the given quantile of the sorted values, using the nearest-rank
+/// method, so that the result is always one of the values.
+/// Example:
+/// ```
+/// use photo_backlog_exporter::quantile;
+/// let values = [1.0, 2.0, 3.0, 4.0];
+/// assert_eq!(quantile(&values, 0.5), Some(2.0));
+/// assert_eq!(quantile(&values, 0.9), Some(4.0));
+/// assert_eq!(quantile(&values, 0.0), Some(1.0));
+/// assert_eq!(quantile(&[], 0.5), None);
+/// ```
+pub fn quantile(sorted: &[f64], q: f64) -> Option<f64> {
+    let rank = (q * sorted.len() as f64).ceil() as usize;
+    sorted.get(rank.saturating_sub(1)).copied()
 }
 
-#[derive(Debug)]
+/// Cloning shares the histograms, as these can't be copied.
+#[derive(Clone, Debug)]
 pub struct Backlog {
     pub total_errors: HashMap<ErrorType, i64>,
+    /// Ownership and permissions errors, split by the failed check and by
+    /// directories and files; an entry can fail both owner and group.
+    pub check_errors: HashMap<(Check, CheckTarget), i64>,
     pub total_files: i64,
     pub folders: HashMap<String, (i64, f64)>,
-    pub ages_histogram: Histogram,
+    /// Per-folder counts of special files, if these are errors.
+    pub special_files: HashMap<String, i64>,
+    /// Backlog files with modification times in the future.
+    pub future_dated_files: i64,
+    /// Backlog files modified in the future, but within the clock skew
+    /// tolerance, respectively in the same window before the scan's start.
+    pub skewed_files: i64,
+    pub recent_files: i64,
+    /// Number of symlinks encountered.
+    pub symlinks: i64,
+    /// Number of directories walked, including the root.
+    pub dirs: i64,
+    /// Number of regular files walked, whether in the backlog or not.
+    pub walked_files: i64,
+    /// Deepest nesting level seen below the root.
+    pub max_depth: usize,
+    /// Approximate memory used by the folders map.
+    pub folders_memory: usize,
+    /// Approximate memory used by the files' ages, overall and per folder.
+    pub ages_memory: usize,
+    /// Whether the files' ages were dropped as they exceeded the memory
+    /// budget.
+    pub ages_dropped: bool,
+    /// Whether the per-folder ages were dropped as they exceeded the
+    /// memory budget.
+    pub folder_ages_dropped: bool,
+    /// Files not accounted per-folder since the memory budget was exceeded.
+    pub untracked_files: i64,
+    /// Hashes of the folders of the untracked files, so that they're still
+    /// counted, at a fraction of the memory.
+    untracked_folders: HashSet<u64>,
+    pub ages_histogram: buckets::AgeHistogram,
+    /// Per-class age histograms, whose buckets can differ from the global
+    /// one.
+    pub raw_ages_histogram: buckets::AgeHistogram,
+    pub editable_ages_histogram: buckets::AgeHistogram,
+    /// Mappings of the ages onto the buckets of the respective histograms,
+    /// for calendar-aligned buckets.
+    ages_scale: buckets::AgeScale,
+    raw_ages_scale: buckets::AgeScale,
+    editable_ages_scale: buckets::AgeScale,
+    /// Ages of all files, sorted once the scan is done, for exact
+    /// quantiles; only kept if exporting those.
+    pub ages: Vec<f64>,
+    /// Per-folder raw and editable file counts.
+    pub folder_kinds: HashMap<String, (i64, i64)>,
+    /// Per-folder ages, sorted once the scan is done, for medians.
+    pub folder_ages: HashMap<String, Vec<f64>>,
+    /// Latency of the stat calls done during the scan.
+    pub stat_histogram: Histogram,
+    /// Total time of the stat calls, i.e. waiting on the filesystem.
+    pub stat_time: Duration,
+    /// Number of threads walking the tree.
+    pub walk_workers: usize,
+    /// Peak number of walked entries waiting to be processed.
+    pub walk_queue_peak: i64,
+    /// Time each walker thread was busy, for the parallel walker.
+    pub walk_worker_busy: Vec<Duration>,
+    /// Time spent classifying and checking entries during the scan.
+    pub checks_time: Duration,
+    /// Time spent aggregating backlog files during the scan.
+    pub aggregation_time: Duration,
+    /// Individual violations, if recording them is enabled.
+    pub violations: Vec<Violation>,
+    /// Directories that couldn't be read (listed or their entries stat-ed)
+    /// due to permissions, outermost only; each counts as one scan error,
+    /// whatever it contains.
+    pub unreadable_dirs: BTreeSet<PathBuf>,
+    /// File counts per camera serial number, if reading them; files
+    /// without one are counted under `UNKNOWN_SERIAL`.
+    pub camera_files: HashMap<String, i64>,
+    /// Files skipped due to the ignored extensions (or lack of extension,
+    /// if ignored), per extension; those without one under an empty one.
+    pub ignored_files: HashMap<String, i64>,
+    /// Entries skipped, per reason.
+    pub skipped_entries: HashMap<SkipReason, i64>,
+    /// Per-folder and owner (uid) file counts, if tracking owners.
+    pub folder_owners: HashMap<(String, u32), i64>,
+    /// Backlog file counts and total sizes, in bytes, per class: the mode
+    /// class if any (e.g. video), else raw or editable.
+    pub class_sizes: HashMap<String, (i64, u64)>,
+    /// The largest backlog files, largest first, if keeping them; while
+    /// scanning, these are only pruned once in a while.
+    pub largest_files: Vec<LargeFile>,
+    /// Per-folder allowed tags, from the folders' tags files.
+    pub folder_tags: HashMap<String, BTreeMap<String, String>>,
+    /// Per-folder details, if recording them.
+    pub folder_details: HashMap<String, FolderDetails>,
+    /// The oldest backlog file, relative to the root, with its folder and
+    /// age.
+    pub oldest_file: Option<(PathBuf, String, f64)>,
+    /// The age of the oldest backlog file per extension, those without
+    /// one under an empty one.
+    pub oldest_by_ext: HashMap<String, f64>,
+    /// Number of entries walked.
+    pub entries: usize,
+    /// Whether the scan was aborted due to too many entries.
+    pub scan_aborted: bool,
+    /// Identifier of the scan producing this backlog.
+    pub scan_id: u64,
+    /// The fraction of files sampled, if the scan was sampled.
+    pub sample_ratio: Option<f64>,
+    /// Companion files seen during the scan, checked for their primary
+    /// once the scan is done.
+    companions: Vec<PathBuf>,
+    /// Per-directory stems and extensions of possible primary files.
+    primaries: HashMap<PathBuf, Vec<(OsString, OsString)>>,
+    /// Set when the scan failed as a whole, i.e. the root itself couldn't
+    /// be read, as opposed to individual entries failing.
+    pub scan_failure: Option<String>,
 }
 
 impl Backlog {
     pub fn new(buckets: impl Iterator<Item = f64>) -> Self {
+        let buckets: Vec<f64> = buckets.collect();
         Self {
             total_errors: HashMap::from([
                 (ErrorType::Scan, 0),
                 (ErrorType::Ownership, 0),
                 (ErrorType::Permissions, 0),
                 (ErrorType::Unknown, 0),
+                (ErrorType::SpecialFile, 0),
+                (ErrorType::Symlink, 0),
+                (ErrorType::PathLength, 0),
+                (ErrorType::PathDepth, 0),
+                (ErrorType::Structure, 0),
+                (ErrorType::OrphanCompanion, 0),
+                (ErrorType::NotImmutable, 0),
+            ]),
+            check_errors: HashMap::from([
+                ((Check::Owner, CheckTarget::File), 0),
+                ((Check::Owner, CheckTarget::Dir), 0),
+                ((Check::Group, CheckTarget::File), 0),
+                ((Check::Group, CheckTarget::Dir), 0),
+                ((Check::DirMode, CheckTarget::Dir), 0),
+                ((Check::RawMode, CheckTarget::File), 0),
+                ((Check::EditableMode, CheckTarget::File), 0),
+                ((Check::ClassModes, CheckTarget::File), 0),
             ]),
             total_files: 0,
             folders: HashMap::new(),
-            ages_histogram: Histogram::new(buckets),
+            special_files: HashMap::new(),
+            future_dated_files: 0,
+            skewed_files: 0,
+            recent_files: 0,
+            symlinks: 0,
+            dirs: 0,
+            walked_files: 0,
+            max_depth: 0,
+            folders_memory: 0,
+            ages_memory: 0,
+            ages_dropped: false,
+            folder_ages_dropped: false,
+            untracked_files: 0,
+            untracked_folders: HashSet::new(),
+            ages_histogram: buckets::AgeHistogram::new(buckets.iter().copied()),
+            raw_ages_histogram: buckets::AgeHistogram::new(buckets.iter().copied()),
+            editable_ages_histogram: buckets::AgeHistogram::new(buckets.into_iter()),
+            ages_scale: Default::default(),
+            raw_ages_scale: Default::default(),
+            editable_ages_scale: Default::default(),
+            ages: Vec::new(),
+            folder_kinds: HashMap::new(),
+            folder_ages: HashMap::new(),
+            // 10µs to ~160ms, which covers both local disks and slow
+            // network filesystems.
+            stat_histogram: Histogram::new(exponential_buckets(1e-5, 4.0, 8)),
+            stat_time: Duration::ZERO,
+            walk_workers: 0,
+            walk_queue_peak: 0,
+            walk_worker_busy: Vec::new(),
+            checks_time: Duration::ZERO,
+            aggregation_time: Duration::ZERO,
+            violations: Vec::new(),
+            unreadable_dirs: BTreeSet::new(),
+            camera_files: HashMap::new(),
+            ignored_files: HashMap::new(),
+            skipped_entries: HashMap::new(),
+            folder_owners: HashMap::new(),
+            class_sizes: HashMap::new(),
+            largest_files: Vec::new(),
+            folder_details: HashMap::new(),
+            folder_tags: HashMap::new(),
+            oldest_file: None,
+            oldest_by_ext: HashMap::new(),
+            entries: 0,
+            scan_aborted: false,
+            scan_id: 0,
+            sample_ratio: None,
+            companions: Vec::new(),
+            primaries: HashMap::new(),
+            scan_failure: None,
+        }
+    }
+    /// Overrides the buckets of the per-class age histograms.
+    pub fn with_class_buckets(
+        self,
+        raw_buckets: impl Iterator<Item = f64>,
+        editable_buckets: impl Iterator<Item = f64>,
+    ) -> Self {
+        Self {
+            raw_ages_histogram: buckets::AgeHistogram::new(raw_buckets),
+            editable_ages_histogram: buckets::AgeHistogram::new(editable_buckets),
+            ..self
         }
     }
+
+    /// Sets the mappings of ages for calendar-aligned buckets of the
+    /// global, raw and editable age histograms.
+    pub fn with_age_scales(
+        self,
+        all: buckets::AgeScale,
+        raw: buckets::AgeScale,
+        editable: buckets::AgeScale,
+    ) -> Self {
+        Self {
+            ages_scale: all,
+            raw_ages_scale: raw,
+            editable_ages_scale: editable,
+            ..self
+        }
+    }
+
+    /// Observes the age of a file in the age histograms.
+    fn observe_age(&mut self, age: f64, kind: FileKind) {
+        self.ages_histogram.observe(age, self.ages_scale.apply(age));
+        match kind {
+            FileKind::Raw => self
+                .raw_ages_histogram
+                .observe(age, self.raw_ages_scale.apply(age)),
+            FileKind::Editable => self
+                .editable_ages_histogram
+                .observe(age, self.editable_ages_scale.apply(age)),
+            _ => {}
+        }
+    }
+
+    /// Approximate heap memory used by the per-folder and per-file data.
+    pub fn approx_memory(&self) -> usize {
+        let f64_size = std::mem::size_of::<f64>();
+        self.folders_memory
+            + self.ages.capacity() * f64_size
+            + self
+                .folder_ages
+                .iter()
+                .map(|(folder, ages)| folder_entry_size(folder) + ages.capacity() * f64_size)
+                .sum::<usize>()
+    }
+
+    /// The number of folders with backlog files, including those not
+    /// tracked due to the folder memory budget.
+    pub fn folder_count(&self) -> usize {
+        self.folders.len() + self.untracked_folders.len()
+    }
+
     pub fn record_file(&mut self) {
         self.total_files += 1;
     }
 
+    /// Counts a file skipped due to its extension.
+    fn record_ignored(&mut self, path: &Path) {
+        let ext = path.extension().unwrap_or_default().to_string_lossy();
+        *self.ignored_files.entry(ext.into_owned()).or_insert(0) += 1;
+        self.record_skipped(SkipReason::IgnoredExt, 1);
+    }
+
+    fn record_skipped(&mut self, reason: SkipReason, count: i64) {
+        *self.skipped_entries.entry(reason).or_insert(0) += count;
+    }
+
     pub fn record_error(&mut self, err: ErrorType) {
         self.total_errors
             .entry(err)
@@ -207,108 +1064,985 @@ impl Backlog {
             .or_insert(1);
     }
 
-    pub fn scan(&mut self, config: &Config, now: SystemTime) {
-        for maybe_entry in WalkDir::new(config.root_path) {
-            let entry = match maybe_entry {
-                Err(e) => {
-                    info!("Error while scanning recursively: {}", e);
-                    self.record_error(ErrorType::Scan);
+    /// Records an error for the given path, keeping its details if
+    /// configured.
+    fn record_violation(
+        &mut self,
+        config: &Config,
+        kind: ErrorType,
+        path: &Path,
+        expected: Option<String>,
+        actual: Option<String>,
+    ) {
+        self.record_error(kind);
+        if config.record_violations {
+            self.violations.push(Violation {
+                path: path.to_path_buf(),
+                kind,
+                expected,
+                actual,
+            });
+        }
+    }
+
+    /// Records a directory that couldn't be read, unless it's inside one
+    /// already recorded, so that its contents aren't reported one by one.
+    fn record_unreadable(&mut self, config: &Config, path: &Path, message: String) {
+        if path.ancestors().any(|a| self.unreadable_dirs.contains(a)) {
+            return;
+        }
+        scan_log!(info, config, "Can't read '{}': {}", path.display(), message);
+        self.record_violation(config, ErrorType::Scan, path, None, Some(message));
+        self.unreadable_dirs.insert(path.to_path_buf());
+    }
+
+    fn record_check_error(&mut self, check: Check, m: &Metadata) {
+        let target = if m.is_dir() {
+            CheckTarget::Dir
+        } else {
+            CheckTarget::File
+        };
+        *self.check_errors.entry((check, target)).or_insert(0) += 1;
+    }
+
+    /// Records an ownership violation, with the expected and actual
+    /// owner:group.
+    fn record_ownership(&mut self, config: &Config, path: &Path, m: &Metadata) {
+        for check in failed_ownership_checks(config, m) {
+            self.record_check_error(check, m);
+        }
+        let format_id = |id: Option<u32>| id.map_or("*".to_string(), |id| id.to_string());
+        self.record_violation(
+            config,
+            ErrorType::Ownership,
+            path,
+            Some(format!(
+                "{}:{}",
+                format_id(config.owner),
+                format_id(config.group)
+            )),
+            Some(format!("{}:{}", m.uid(), m.gid())),
+        );
+    }
+
+    /// Records a permissions violation, with the expected and actual mode.
+    fn record_mode(&mut self, config: &Config, path: &Path, m: &Metadata, k: FileKind) {
+        if let Some(check) = mode_check(config, path, m, k) {
+            self.record_check_error(check, m);
+        }
+        self.record_violation(
+            config,
+            ErrorType::Permissions,
+            path,
+            expected_mode(config, path, m, k).map(|mode| format!("{:o}", mode)),
+            Some(format!("{:o}", m.mode() & 0o777)),
+        );
+    }
+
+    /// Fills the backlog with synthetic data, without touching the
+    /// filesystem, with file ages spread deterministically over a year.
+    pub fn fill_mock(&mut self, mock: MockData) {
+        const DAY: f64 = 86400.0;
+        // The root and one directory per folder, holding the files.
+        self.dirs = mock.folders as i64 + 1;
+        self.walked_files = (mock.folders * mock.files) as i64;
+        self.max_depth = match (mock.folders, mock.files) {
+            (0, _) => 0,
+            (_, 0) => 1,
+            _ => 2,
+        };
+        for folder in 0..mock.folders {
+            let name = format!("mock-folder-{:04}", folder);
+            for file in 0..mock.files {
+                let age = ((folder * 7 + file) % 365) as f64 * DAY;
+                self.record_file();
+                let (count, ages) = self.folders.entry(name.clone()).or_insert((0, 0.0));
+                *count += 1;
+                *ages += age;
+                self.folder_ages.entry(name.clone()).or_default().push(age);
+                self.ages.push(age);
+                let kind = if file % 2 == 0 {
+                    FileKind::Raw
+                } else {
+                    FileKind::Editable
+                };
+                self.observe_age(age, kind);
+                self.record_folder_kind(name.clone(), kind);
+            }
+        }
+        self.sort_ages();
+    }
+
+    /// Counts a raw or editable file in the given folder.
+    fn record_folder_kind(&mut self, folder: String, kind: FileKind) {
+        let (raw, editable) = self.folder_kinds.entry(folder).or_insert((0, 0));
+        match kind {
+            FileKind::Raw => *raw += 1,
+            FileKind::Editable => *editable += 1,
+            _ => {}
+        }
+    }
+
+    /// Applies files added or removed since the scan to the file counts,
+    /// in total and per folder. Added files are stat-ed for their age,
+    /// while removed ones are assumed to have had their folder's mean age,
+    /// and a removed top-level folder takes all its files along; the
+    /// histograms, quantiles and errors are left as scanned.
+    pub fn apply_changes(&mut self, config: &Config, now: SystemTime, changes: &[FileChange]) {
+        let ignore = walk::load_ignore_file(config);
+        for change in changes {
+            let (FileChange::Added(path) | FileChange::Removed(path)) = change;
+            if walk::is_ignored_file(config, ignore.as_ref(), path) {
+                continue;
+            }
+            if let FileChange::Removed(_) = change {
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                if path.parent() == Some(config.root_path) && self.folders.contains_key(&*name) {
+                    self.remove_folder(&name);
                     continue;
                 }
-                Ok(entry) => entry,
-            };
-            let path = entry.path();
-            let metadata = match entry.metadata() {
-                Ok(m) => m,
-                Err(e) => {
-                    info!("Can't stat '{}': {}", path.display(), e);
-                    self.record_error(ErrorType::Scan);
-                    continue;
+            }
+            let folder = top_folder(config.root_path, path);
+            // Companions and archives aren't counted by themselves.
+            let kind = classify(config, path);
+            let is_companion = path
+                .extension()
+                .is_some_and(|ext| config.companion_exts.iter().any(|(c, _)| ext_eq(c, ext)));
+            if is_companion || !matches!(kind, FileKind::Raw | FileKind::Editable) {
+                continue;
+            }
+            match change {
+                FileChange::Added(_) => {
+                    // The file can be gone again already.
+                    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+                        continue;
+                    };
+                    if !metadata.is_file() {
+                        continue;
+                    }
+                    let age = config.file_age(now, &metadata).as_secs_f64();
+                    self.total_files += 1;
+                    let (count, ages) = self.folders.entry(folder.clone()).or_insert((0, 0.0));
+                    *count += 1;
+                    *ages += age;
+                    self.record_folder_kind(folder, kind);
                 }
-            };
-            if entry.file_type().is_dir() {
-                if !check_ownership(config, path, &metadata, "Directory") {
-                    self.record_error(ErrorType::Ownership);
+                FileChange::Removed(_) => {
+                    self.total_files = (self.total_files - 1).max(0);
+                    let Some((count, ages)) = self.folders.get_mut(&folder) else {
+                        continue;
+                    };
+                    *ages -= *ages / *count as f64;
+                    *count -= 1;
+                    if let Some((raw, editable)) = self.folder_kinds.get_mut(&folder) {
+                        let kind_count = if kind == FileKind::Raw { raw } else { editable };
+                        *kind_count = (*kind_count - 1).max(0);
+                    }
+                    if *count <= 0 {
+                        self.remove_folder(&folder);
+                    }
                 }
-                if !check_mode(
+            }
+        }
+    }
+
+    /// Removes a folder and its files from the counts.
+    fn remove_folder(&mut self, folder: &str) {
+        if let Some((count, _)) = self.folders.remove(folder) {
+            self.total_files = (self.total_files - count).max(0);
+        }
+        self.folder_kinds.remove(folder);
+        self.folder_ages.remove(folder);
+        self.folder_details.remove(folder);
+    }
+
+    /// Keeps only the given number of largest files, sorted.
+    fn prune_largest_files(&mut self, n: usize) {
+        self.largest_files
+            .sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+        self.largest_files.truncate(n);
+    }
+
+    /// Sorts the recorded ages, as needed for quantiles.
+    fn sort_ages(&mut self) {
+        self.ages.sort_by(f64::total_cmp);
+        for ages in self.folder_ages.values_mut() {
+            ages.sort_by(f64::total_cmp);
+        }
+    }
+
+    /// Checks the path length and depth limits, if any.
+    fn check_path_limits(&mut self, config: &Config, path: &Path, depth: usize) {
+        if let Some(max) = config.max_path_length {
+            let relative = path.strip_prefix(config.root_path).unwrap_or(path);
+            let length = relative.as_os_str().len();
+            if length > max {
+                scan_log!(
+                    info,
+                    config,
+                    "Path '{}' is too long: {} bytes, maximum {}",
+                    path.display(),
+                    length,
+                    max
+                );
+                self.record_violation(
                     config,
+                    ErrorType::PathLength,
                     path,
-                    &metadata,
-                    FileKind::None, /* misuse, but… */
-                ) {
-                    self.record_error(ErrorType::Permissions);
-                }
-                // We don't track directories by themselves,
-                // only via file contents.
-                continue;
+                    Some(format!("<= {}", max)),
+                    Some(length.to_string()),
+                );
             }
-            if !entry.file_type().is_file() {
-                // We don't care about other file types.
-                continue;
+        }
+        if let Some(max) = config.max_depth {
+            if depth > max {
+                scan_log!(
+                    info,
+                    config,
+                    "Path '{}' is nested too deep: {} levels, maximum {}",
+                    path.display(),
+                    depth,
+                    max
+                );
+                self.record_violation(
+                    config,
+                    ErrorType::PathDepth,
+                    path,
+                    Some(format!("<= {}", max)),
+                    Some(depth.to_string()),
+                );
             }
-            let kind = match entry.path().extension() {
-                None => FileKind::None,
-                Some(ext) => {
-                    if config.ignored_exts.iter().any(|c| c == ext) {
-                        FileKind::Ignored
-                    } else if config.raw_exts.iter().any(|c| c == ext) {
-                        FileKind::Raw
-                    } else if config.editable_exts.iter().any(|c| c == ext) {
-                        FileKind::Editable
-                    } else {
-                        FileKind::Unknown
-                    }
-                }
-            };
+        }
+    }
 
-            if kind == FileKind::Ignored || kind == FileKind::None {
-                // We don't care about ignored files, or files without extension.
+    /// Flags companions with no primary in the same directory whose stem is
+    /// the same as, or a prefix of, the companion's (as for Sony's
+    /// `C0001M01.XML` next to `C0001.MP4`).
+    fn check_companions(&mut self, config: &Config) {
+        for path in std::mem::take(&mut self.companions) {
+            let (Some(dir), Some(stem), Some(ext)) =
+                (path.parent(), path.file_stem(), path.extension())
+            else {
                 continue;
+            };
+            let has_primary = self.primaries.get(dir).is_some_and(|primaries| {
+                primaries.iter().any(|(p_stem, p_ext)| {
+                    config
+                        .companion_exts
+                        .iter()
+                        .any(|(c, p)| ext_eq(c, ext) && ext_eq(p, p_ext))
+                        && stem
+                            .as_encoded_bytes()
+                            .starts_with(p_stem.as_encoded_bytes())
+                })
+            });
+            if !has_primary {
+                scan_log!(
+                    info,
+                    config,
+                    "Companion file '{}' has no primary",
+                    path.display()
+                );
+                self.record_violation(config, ErrorType::OrphanCompanion, &path, None, None);
+            }
+        }
+        self.primaries.clear();
+    }
+
+    /// Flags files in the archive subpath without the immutable attribute.
+    fn check_immutable(&mut self, config: &Config, path: &Path) {
+        let Some(subpath) = config.immutable_subpath else {
+            return;
+        };
+        if !path
+            .strip_prefix(config.root_path)
+            .is_ok_and(|rel| rel.starts_with(subpath))
+        {
+            return;
+        }
+        match is_immutable(path) {
+            Ok(true) => {}
+            Ok(false) => {
+                scan_log!(
+                    info,
+                    config,
+                    "Archived file '{}' is not immutable",
+                    path.display()
+                );
+                self.record_violation(
+                    config,
+                    ErrorType::NotImmutable,
+                    path,
+                    Some("immutable".to_string()),
+                    None,
+                );
+            }
+            Err(e) => {
+                scan_log!(
+                    info,
+                    config,
+                    "Can't get attributes of '{}': {}",
+                    path.display(),
+                    e
+                );
+                self.record_violation(config, ErrorType::Scan, path, None, Some(e.to_string()));
             }
+        }
+    }
 
-            if kind == FileKind::Unknown {
-                warn!("Unknown file type: {}", entry.path().to_string_lossy());
-                self.record_error(ErrorType::Unknown);
+    /// Checks that the given top-level folder has the required
+    /// subdirectories.
+    fn check_structure(&mut self, config: &Config, path: &Path) {
+        let missing: Vec<&str> = config
+            .required_subdirs
+            .iter()
+            .filter(|sub| !path.join(sub).is_dir())
+            .map(String::as_str)
+            .collect();
+        if missing.is_empty() {
+            return;
+        }
+        scan_log!(
+            info,
+            config,
+            "Folder '{}' is missing required subdirectories: {}",
+            path.display(),
+            missing.join(", ")
+        );
+        self.record_violation(
+            config,
+            ErrorType::Structure,
+            path,
+            Some(config.required_subdirs.join(",")),
+            Some(format!("missing {}", missing.join(","))),
+        );
+    }
+
+    /// Reads the allowed tags of a top-level folder from its tags file,
+    /// if any.
+    fn read_folder_tags(&mut self, config: &Config, path: &Path) {
+        let tags_path = path.join(TAGS_FILE);
+        let contents = match std::fs::read_to_string(&tags_path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(e) => {
+                scan_log!(info, config, "Can't read '{}': {}", tags_path.display(), e);
+                return;
+            }
+        };
+        let mut tags = BTreeMap::new();
+        for entry in contents.split([',', '\n']).map(str::trim) {
+            if entry.is_empty() || entry.starts_with('#') {
                 continue;
             }
+            match entry.split_once('=') {
+                Some((key, value)) if config.folder_tag_keys.iter().any(|k| k == key.trim()) => {
+                    tags.insert(key.trim().to_string(), value.trim().to_string());
+                }
+                Some((key, _)) => scan_log!(
+                    debug,
+                    config,
+                    "Ignoring tag '{}' in '{}', not an allowed key",
+                    key.trim(),
+                    tags_path.display()
+                ),
+                None => scan_log!(
+                    info,
+                    config,
+                    "Invalid tag '{}' in '{}', expected key=value",
+                    entry,
+                    tags_path.display()
+                ),
+            }
+        }
+        if !tags.is_empty() {
+            self.folder_tags
+                .insert(top_folder(config.root_path, &tags_path), tags);
+        }
+    }
 
-            // Here it's not an ignored entry, nor an unknown one, so let's process it.
-            self.record_file();
+    pub fn scan(&mut self, config: &Config, now: SystemTime) {
+        let stats = walk::walk(config, |item| self.process_item(config, now, item));
+        self.walk_workers = stats.workers;
+        self.walk_queue_peak = stats.queue_peak;
+        self.walk_worker_busy = stats.worker_busy;
+        self.check_companions(config);
+        if let Some(ratio) = config.sample {
+            self.scale_sample(ratio);
+        }
+        // Filtered before sampling, so not scaled.
+        for (reason, count) in stats.skipped.into_iter().filter(|(_, c)| *c > 0) {
+            self.record_skipped(reason, count);
+        }
+        self.sort_ages();
+        self.prune_largest_files(config.largest_files);
+    }
+
+    /// Scales the counts of a sampled scan up to estimates for the whole
+    /// tree; distributions (histograms, quantiles) and error counts are
+    /// left as sampled.
+    fn scale_sample(&mut self, ratio: f64) {
+        let scale = |count: &mut i64| *count = (*count as f64 / ratio).round() as i64;
+        scale(&mut self.total_files);
+        scale(&mut self.untracked_files);
+        scale(&mut self.walked_files);
+        for (count, ages) in self.folders.values_mut() {
+            scale(count);
+            *ages /= ratio;
+        }
+        for (raw, editable) in self.folder_kinds.values_mut() {
+            scale(raw);
+            scale(editable);
+        }
+        self.camera_files.values_mut().for_each(scale);
+        self.folder_owners.values_mut().for_each(scale);
+        self.ignored_files.values_mut().for_each(scale);
+        self.skipped_entries.values_mut().for_each(scale);
+        self.sample_ratio = Some(ratio);
+    }
+
+    fn process_item(
+        &mut self,
+        config: &Config,
+        now: SystemTime,
+        item: walk::WalkItem,
+    ) -> ControlFlow<()> {
+        if config.cancel.is_some_and(|c| c.load(Ordering::Relaxed)) {
+            self.scan_failure = Some(format!(
+                "Scan of '{}' cancelled",
+                config.root_path.display()
+            ));
+            return ControlFlow::Break(());
+        }
+        self.entries += 1;
+        if let Some(max) = config.max_files.filter(|max| self.entries > *max) {
+            let message = format!(
+                "More than {} entries under '{}', aborting the scan; is the path right?",
+                max,
+                config.root_path.display()
+            );
+            scan_log!(warn, config, "{}", message);
+            self.scan_failure = Some(message);
+            self.scan_aborted = true;
+            return ControlFlow::Break(());
+        }
+        let checks_start = Instant::now();
+        let file = self.check_item(config, item);
+        self.checks_time += checks_start.elapsed();
+        if let Some((path, metadata, kind)) = file {
+            let aggregation_start = Instant::now();
+            self.aggregate(config, now, &path, &metadata, kind);
+            self.aggregation_time += aggregation_start.elapsed();
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Classifies and checks a walked item, returning the backlog file
+    /// to aggregate, if any.
+    fn check_item(
+        &mut self,
+        config: &Config,
+        item: walk::WalkItem,
+    ) -> Option<(PathBuf, Metadata, FileKind)> {
+        let entry = match item {
+            walk::WalkItem::Error {
+                message,
+                root,
+                is_loop,
+                denied,
+            } => {
+                if let Some(path) = denied.filter(|_| !root) {
+                    self.record_unreadable(config, &path, message);
+                    return None;
+                }
+                scan_log!(
+                    info,
+                    config,
+                    "Error while scanning recursively: {}",
+                    message
+                );
+                self.record_error(ErrorType::Scan);
+                if root {
+                    self.scan_failure = Some(message);
+                }
+                if is_loop {
+                    // Followed symlink pointing back to an ancestor.
+                    self.symlinks += 1;
+                }
+                return None;
+            }
+            walk::WalkItem::Entry(entry) => *entry,
+        };
+        let path = entry.path.as_path();
+        let is_symlink = entry.is_symlink;
+        if is_symlink {
+            self.symlinks += 1;
+            match config.symlinks {
+                Symlinks::Ignore => {
+                    self.record_skipped(SkipReason::Symlink, 1);
+                    return None;
+                }
+                Symlinks::Error => {
+                    scan_log!(warn, config, "Symlink: {}", path.display());
+                    self.record_violation(config, ErrorType::Symlink, path, None, None);
+                    return None;
+                }
+                Symlinks::Count | Symlinks::Follow => {}
+            }
+        }
+        // Only skipped symlinks aren't stat-ed, handled above.
+        let metadata = entry.metadata?;
+        self.stat_histogram.observe(entry.stat_time.as_secs_f64());
+        self.stat_time += entry.stat_time;
+        let metadata = match metadata {
+            Ok(m) => m,
+            // Entries listed but not stat-able are due to their directory.
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                if let Some(parent) = path.parent() {
+                    self.record_unreadable(config, parent, e.to_string());
+                }
+                return None;
+            }
+            Err(e) => {
+                scan_log!(info, config, "Can't stat '{}': {}", path.display(), e);
+                self.record_violation(config, ErrorType::Scan, path, None, Some(e.to_string()));
+                return None;
+            }
+        };
+        self.check_path_limits(config, path, entry.depth);
+        self.max_depth = self.max_depth.max(entry.depth);
+        let file_type = metadata.file_type();
+        if is_symlink && config.symlinks == Symlinks::Count && !file_type.is_file() {
+            // Only symlinked files are counted.
+            self.record_skipped(SkipReason::Symlink, 1);
+            return None;
+        }
+        if file_type.is_dir() {
+            self.dirs += 1;
+            if !check_ownership(config, path, &metadata, "Directory") {
+                self.record_ownership(config, path, &metadata);
+            }
+            if !check_mode(
+                config,
+                path,
+                &metadata,
+                FileKind::None, /* misuse, but… */
+            ) {
+                self.record_mode(config, path, &metadata, FileKind::None);
+            }
+            if entry.depth == 1 && !is_symlink {
+                self.check_structure(config, path);
+                if !config.folder_tag_keys.is_empty() {
+                    self.read_folder_tags(config, path);
+                }
+            }
+            // We don't track directories by themselves,
+            // only via file contents.
+            return None;
+        }
+        if !file_type.is_file() {
+            if config.special_files_as_errors
+                && (file_type.is_fifo()
+                    || file_type.is_socket()
+                    || file_type.is_block_device()
+                    || file_type.is_char_device())
+            {
+                scan_log!(warn, config, "Special file: {}", path.display());
+                self.record_violation(config, ErrorType::SpecialFile, path, None, None);
+                *self
+                    .special_files
+                    .entry(top_folder(config.root_path, path))
+                    .or_insert(0) += 1;
+            }
+            // Otherwise we don't care about other file types.
+            self.record_skipped(SkipReason::SpecialFile, 1);
+            return None;
+        }
+        self.walked_files += 1;
+        self.check_immutable(config, path);
+        if let (Some(dir), Some(stem), Some(ext)) =
+            (path.parent(), path.file_stem(), path.extension())
+        {
+            if config.companion_exts.iter().any(|(_, p)| ext_eq(p, ext)) {
+                self.primaries
+                    .entry(dir.to_path_buf())
+                    .or_default()
+                    .push((stem.to_os_string(), ext.to_os_string()));
+            }
+            if config.companion_exts.iter().any(|(c, _)| ext_eq(c, ext)) {
+                self.companions.push(path.to_path_buf());
+                self.record_skipped(SkipReason::Companion, 1);
+                return None;
+            }
+        }
+        let kind = classify(config, path);
+        if kind == FileKind::Archive {
+            // Archives are only counted once their entries are listed.
             if !check_ownership(config, path, &metadata, "File") {
-                self.record_error(ErrorType::Ownership);
+                self.record_ownership(config, path, &metadata);
             }
             if !check_mode(config, path, &metadata, kind) {
-                self.record_error(ErrorType::Permissions);
+                self.record_mode(config, path, &metadata, kind);
+            }
+            return Some((entry.path, metadata, kind));
+        }
+
+        if kind == FileKind::Ignored
+            || (kind == FileKind::None && config.no_extension == NoExtension::Ignore)
+        {
+            // We don't care about ignored files, or (by default) files
+            // without extension, except for the mode of ignored files
+            // having their own mode class (e.g. sidecars).
+            self.record_ignored(path);
+            if kind == FileKind::Ignored
+                && mode_class(config, path).is_some()
+                && !check_mode(config, path, &metadata, kind)
+            {
+                self.record_mode(config, path, &metadata, kind);
             }
+            return None;
+        }
+
+        if kind == FileKind::None && config.no_extension == NoExtension::Error {
+            scan_log!(
+                warn,
+                config,
+                "File without extension: {}",
+                path.to_string_lossy()
+            );
+            self.record_violation(config, ErrorType::Unknown, path, None, None);
+            return None;
+        }
+
+        if kind == FileKind::Unknown {
+            scan_log!(
+                warn,
+                config,
+                "Unknown file type: {}",
+                path.to_string_lossy()
+            );
+            self.record_violation(config, ErrorType::Unknown, path, None, None);
+            return None;
+        }
+
+        // Here it's not an ignored entry, nor an unknown one, so let's process it.
+        self.record_file();
+        if !check_ownership(config, path, &metadata, "File") {
+            self.record_ownership(config, path, &metadata);
+        }
+        if !check_mode(config, path, &metadata, kind) {
+            self.record_mode(config, path, &metadata, kind);
+        }
+        Some((entry.path, metadata, kind))
+    }
+
+    /// Counts and logs files modified in the future (beyond the clock
+    /// skew tolerance), which get a zero age, and counts the files
+    /// modified around the scan's start, for detecting clock skew.
+    fn check_future_dated(
+        &mut self,
+        config: &Config,
+        now: SystemTime,
+        path: &Path,
+        metadata: &Metadata,
+    ) {
+        let Ok(modified) = metadata.modified() else {
+            return;
+        };
+        let tolerance = config.clock_skew_tolerance;
+        match modified.duration_since(now) {
+            Ok(ahead) if ahead > tolerance => {
+                scan_log!(
+                    warn,
+                    config,
+                    "File '{}' is dated {}s in the future, check the camera clock",
+                    path.display(),
+                    ahead.as_secs()
+                );
+                self.future_dated_files += 1;
+            }
+            Ok(ahead) if !ahead.is_zero() => self.skewed_files += 1,
+            Ok(_) => self.recent_files += 1,
+            Err(e) if e.duration() <= tolerance => self.recent_files += 1,
+            Err(_) => {}
+        }
+    }
+
+    /// Whether the scan suggests that the host's clock is behind the file
+    /// server's: all the files modified around its start are dated in the
+    /// future, if any.
+    pub fn clock_skewed(&self) -> bool {
+        self.skewed_files > 0 && self.recent_files == 0
+    }
 
-            // Find owner top-level dir.
-            let parent = match relative_top(config.root_path, entry.path()) {
-                Some(x) => x,
-                None => {
-                    warn!(
-                        "Can't determine parent path for {}",
-                        entry.path().to_string_lossy()
+    /// Accounts a backlog file in the totals, folders and histograms.
+    fn aggregate(
+        &mut self,
+        config: &Config,
+        now: SystemTime,
+        path: &Path,
+        metadata: &Metadata,
+        kind: FileKind,
+    ) {
+        self.check_future_dated(config, now, path, metadata);
+        let age = config.file_age(now, metadata).as_secs_f64();
+        if kind == FileKind::Archive {
+            self.aggregate_archive(config, path, metadata, age);
+            return;
+        }
+        let folder = top_folder(config.root_path, path);
+        if config.camera_serials {
+            let serial = match exif::body_serial(path) {
+                Ok(serial) => serial,
+                Err(e) => {
+                    scan_log!(
+                        info,
+                        config,
+                        "Can't read EXIF data from '{}': {}",
+                        path.display(),
+                        e
                     );
-                    PathBuf::from(ROOT_FILE_DIR)
+                    None
                 }
             };
+            *self
+                .camera_files
+                .entry(serial.unwrap_or_else(|| UNKNOWN_SERIAL.to_string()))
+                .or_insert(0) += 1;
+        }
+        self.record_class_size(config, path, metadata, kind);
+        let relative = path.strip_prefix(config.root_path).unwrap_or(path);
+        if config.largest_files > 0 {
+            self.largest_files.push(LargeFile {
+                path: relative.to_path_buf(),
+                size: metadata.len(),
+                age,
+            });
+            if self.largest_files.len() >= 2 * config.largest_files {
+                self.prune_largest_files(config.largest_files);
+            }
+        }
+        let details_folder = config.folder_details.then(|| folder.clone());
+        self.account(config, folder, relative, metadata, age, kind);
+        if let Some(folder) = details_folder {
+            self.record_folder_details(&folder, relative, metadata.len(), age);
+        }
+    }
+
+    /// Records a file in its folder's details, unless the folder isn't
+    /// tracked due to the memory budget.
+    fn record_folder_details(&mut self, folder: &str, relative: &Path, size: u64, age: f64) {
+        if !self.folders.contains_key(folder) {
+            return;
+        }
+        let details = self.folder_details.entry(folder.to_string()).or_default();
+        let ext = relative.extension().unwrap_or_default().to_string_lossy();
+        *details.exts.entry(ext.into_owned()).or_insert(0) += 1;
+        details.size += size;
+        if details.oldest.as_ref().is_none_or(|(_, a)| age > *a) {
+            details.oldest = Some((relative.to_path_buf(), age));
+        }
+        if details.newest.as_ref().is_none_or(|(_, a)| age < *a) {
+            details.newest = Some((relative.to_path_buf(), age));
+        }
+    }
+
+    fn record_class_size(
+        &mut self,
+        config: &Config,
+        path: &Path,
+        metadata: &Metadata,
+        kind: FileKind,
+    ) {
+        let class = match (mode_class(config, path), kind) {
+            (Some(class), _) => class.name.as_str(),
+            (None, FileKind::Raw) => "raw",
+            (None, FileKind::Editable) => "editable",
+            _ => return,
+        };
+        let entry = match self.class_sizes.get_mut(class) {
+            Some(entry) => entry,
+            None => self.class_sizes.entry(class.to_string()).or_default(),
+        };
+        entry.0 += 1;
+        entry.1 += metadata.len();
+    }
+
+    /// Accounts the entries of an archive, as files of a pseudo-folder
+    /// named after the archive and with its age.
+    fn aggregate_archive(&mut self, config: &Config, path: &Path, metadata: &Metadata, age: f64) {
+        let names = match archive::entry_names(path) {
+            Ok(names) => names,
+            Err(e) => {
+                scan_log!(
+                    info,
+                    config,
+                    "Can't list archive '{}': {}",
+                    path.display(),
+                    e
+                );
+                self.record_violation(config, ErrorType::Scan, path, None, Some(e.to_string()));
+                return;
+            }
+        };
+        let relative = path.strip_prefix(config.root_path).unwrap_or(path);
+        let folder = relative.to_string_lossy().into_owned();
+        for name in names {
+            let kind = classify(config, Path::new(&name));
+            if kind == FileKind::Ignored {
+                self.record_ignored(Path::new(&name));
+                continue;
+            }
+            self.record_file();
+            if config.camera_serials {
+                // Reading these would need extracting the entries.
+                *self
+                    .camera_files
+                    .entry(UNKNOWN_SERIAL.to_string())
+                    .or_insert(0) += 1;
+            }
+            self.account(
+                config,
+                folder.clone(),
+                &relative.join(&name),
+                metadata,
+                age,
+                kind,
+            );
+            if config.folder_details {
+                self.record_folder_details(&folder, &relative.join(&name), 0, age);
+            }
+        }
+        if let Some(details) = self.folder_details.get_mut(&folder) {
+            details.size += metadata.len();
+        }
+    }
+
+    /// Accounts a file of the given folder in the folders and histograms.
+    fn account(
+        &mut self,
+        config: &Config,
+        folder: String,
+        relative: &Path,
+        metadata: &Metadata,
+        age: f64,
+        kind: FileKind,
+    ) {
+        if config.track_owners {
+            *self
+                .folder_owners
+                .entry((folder.clone(), metadata.uid()))
+                .or_insert(0) += 1;
+        }
+        if self.oldest_file.as_ref().is_none_or(|(_, _, a)| age > *a) {
+            self.oldest_file = Some((relative.to_path_buf(), folder.clone(), age));
+        }
+        let ext = relative.extension().unwrap_or_default().to_string_lossy();
+        match self.oldest_by_ext.get_mut(ext.as_ref()) {
+            Some(oldest) => *oldest = oldest.max(age),
+            None => {
+                self.oldest_by_ext.insert(ext.into_owned(), age);
+            }
+        }
+        // Now update folders struct.
+        if let Some((c, a)) = self.folders.get_mut(&folder) {
+            *c += 1;
+            *a += age;
+        } else {
+            let entry_size = folder_entry_size(&folder);
+            if self.over_budget(config, entry_size) {
+                if self.untracked_files == 0 {
+                    scan_log!(
+                        warn,
+                        config,
+                        "Folder memory budget exceeded at folder '{}', only accounting totals from now on",
+                        folder
+                    );
+                }
+                self.untracked_files += 1;
+                let mut hasher = std::hash::DefaultHasher::new();
+                std::hash::Hash::hash(&folder, &mut hasher);
+                self.untracked_folders
+                    .insert(std::hash::Hasher::finish(&hasher));
+            } else {
+                self.folders_memory += entry_size;
+                self.folders.insert(folder.clone(), (1, age));
+            }
+        }
+        if self.folders.contains_key(&folder) {
+            self.keep_folder_age(config, &folder, age);
+            self.record_folder_kind(folder, kind);
+        }
+        // And observe the age for the ages histograms.
+        self.observe_age(age, kind);
+        if config.keep_ages {
+            self.keep_age(config, age);
+        }
+    }
+
+    /// Whether the given additional memory exceeds the memory budget.
+    fn over_budget(&self, config: &Config, size: usize) -> bool {
+        config
+            .folder_memory_budget
+            .is_some_and(|budget| self.folders_memory + self.ages_memory + size > budget)
+    }
 
-            // And convert to valid UTF-8 string via lossy
-            // conversion. But at least we're back in safe land.
-            let folder = String::from(parent.to_string_lossy());
-
-            // Now update folders struct.
-            let age = relative_age(now, &metadata).as_secs_f64();
-            self.folders
-                .entry(folder)
-                .and_modify(|(c, a)| {
-                    *c += 1;
-                    *a += age;
-                })
-                .or_insert((1, age));
-            // And observe the age for the ages histogram.
-            self.ages_histogram.observe(age);
+    /// Keeps a file's age for the quantiles, unless that exceeds the memory
+    /// budget, in which case all ages are dropped, as partial ones would
+    /// skew the quantiles.
+    fn keep_age(&mut self, config: &Config, age: f64) {
+        if self.ages_dropped {
+            return;
+        }
+        let size = std::mem::size_of::<f64>();
+        if self.over_budget(config, size) {
+            scan_log!(
+                warn,
+                config,
+                "Folder memory budget exceeded by the file ages, dropping the age quantiles"
+            );
+            self.ages_memory -= self.ages.len() * size;
+            self.ages = Vec::new();
+            self.ages_dropped = true;
+            return;
+        }
+        self.ages_memory += size;
+        self.ages.push(age);
+    }
+
+    /// Keeps a file's age for its folder's median, unless that exceeds the
+    /// memory budget, in which case all folders' ages are dropped.
+    fn keep_folder_age(&mut self, config: &Config, folder: &str, age: f64) {
+        if !config.keep_folder_ages || self.folder_ages_dropped {
+            return;
+        }
+        let f64_size = std::mem::size_of::<f64>();
+        let size = if self.folder_ages.contains_key(folder) {
+            f64_size
+        } else {
+            folder_entry_size(folder) + f64_size
+        };
+        if self.over_budget(config, size) {
+            scan_log!(
+                warn,
+                config,
+                "Folder memory budget exceeded by the folder ages at folder '{}', dropping the folder medians",
+                folder
+            );
+            self.ages_memory -= self
+                .folder_ages
+                .iter()
+                .map(|(folder, ages)| folder_entry_size(folder) + ages.len() * f64_size)
+                .sum::<usize>();
+            self.folder_ages = HashMap::new();
+            self.folder_ages_dropped = true;
+            return;
         }
+        self.ages_memory += size;
+        self.folder_ages
+            .entry(folder.to_string())
+            .or_default()
+            .push(age);
     }
 }
 
@@ -316,19 +2050,19 @@ impl Backlog {
 mod tests {
     use rstest::fixture;
     use rstest::rstest;
-    use std::collections::HashMap;
+    use std::collections::{BTreeMap, BTreeSet, HashMap};
     use std::ffi::OsString;
     use std::os::unix::fs::MetadataExt;
     use std::os::unix::fs::PermissionsExt;
     use std::path::{Path, PathBuf};
-    use std::time::SystemTime;
+    use std::time::{Duration, SystemTime};
     use tempfile::tempdir;
     use tempfile::TempDir;
     extern crate speculoos;
     use speculoos::prelude::*;
 
     use crate::{Backlog, ROOT_FILE_DIR};
-    use crate::{Config, ErrorType};
+    use crate::{Check, CheckTarget, Config, ErrorType, Violation};
 
     const SUBDIR: &str = "dir1";
 
@@ -355,9 +2089,8 @@ mod tests {
             dir_mode: Option<u32>,
             raw_file_mode: Option<u32>,
             editable_file_mode: Option<u32>,
-        ) -> Config {
+        ) -> Config<'_> {
             Config {
-                root_path: self.temp_dir.path(),
                 ignored_exts: &self.ignored_exts,
                 raw_exts: &self.raw_exts,
                 editable_exts: &self.editable_exts,
@@ -366,6 +2099,7 @@ mod tests {
                 dir_mode,
                 raw_file_mode,
                 editable_file_mode,
+                ..Config::new(self.temp_dir.path())
             }
         }
     }
@@ -410,6 +2144,13 @@ mod tests {
             (ErrorType::Ownership, ownership_errors),
             (ErrorType::Permissions, permissions_errors),
             (ErrorType::Unknown, unknown_errors),
+            (ErrorType::SpecialFile, 0),
+            (ErrorType::Symlink, 0),
+            (ErrorType::PathLength, 0),
+            (ErrorType::PathDepth, 0),
+            (ErrorType::Structure, 0),
+            (ErrorType::OrphanCompanion, 0),
+            (ErrorType::NotImmutable, 0),
         ]);
         assert_that!(backlog.folders).has_length(expect_folders);
         assert_that!(backlog.total_files).is_equal_to(expect_files);
@@ -455,6 +2196,25 @@ mod tests {
         check_backlog(&backlog, 0, 0, 0, 0, 0, 0);
     }
     #[rstest]
+    fn no_extension_is_counted(test_data: TestData, mut backlog: Backlog) {
+        let subdir = test_data.get_subdir();
+        add_file(&subdir, "IMG_0001");
+        let mut config = test_data.build_config(None, None, None, None, None);
+        config.no_extension = crate::NoExtension::Count;
+        backlog.scan(&config, test_data.now);
+        check_backlog(&backlog, 1, 1, 0, 0, 0, 0);
+        check_has_dir_with(&backlog, SUBDIR, 1);
+    }
+    #[rstest]
+    fn no_extension_is_error(test_data: TestData, mut backlog: Backlog) {
+        let subdir = test_data.get_subdir();
+        add_file(&subdir, "IMG_0001");
+        let mut config = test_data.build_config(None, None, None, None, None);
+        config.no_extension = crate::NoExtension::Error;
+        backlog.scan(&config, test_data.now);
+        check_backlog(&backlog, 0, 0, 0, 0, 0, 1);
+    }
+    #[rstest]
     fn ignored_extension_is_ignored(test_data: TestData, mut backlog: Backlog) {
         let subdir = test_data.get_subdir();
         add_file(&subdir, "file.nef");
@@ -472,6 +2232,7 @@ mod tests {
         backlog.scan(&config, test_data.now);
         check_backlog(&backlog, 1, 1, 0, 0, 0, 0);
         check_has_dir_with(&backlog, SUBDIR, 1);
+        assert_that!(backlog.scan_failure).is_none();
     }
     #[rstest]
     fn one_dir_two_files(test_data: TestData, mut backlog: Backlog) {
@@ -503,6 +2264,557 @@ mod tests {
         check_has_dir_with(&backlog, ROOT_FILE_DIR, 1);
     }
 
+    #[rstest]
+    fn special_files(test_data: TestData, mut backlog: Backlog) {
+        let subdir = test_data.get_subdir();
+        add_file(&subdir, "file.nef");
+        let fifo = subdir.join("fifo");
+        let status = std::process::Command::new("mkfifo")
+            .arg(&fifo)
+            .status()
+            .expect("run mkfifo");
+        assert_that!(status.success()).is_true();
+
+        // Skipped by default.
+        let config = test_data.build_config(None, None, None, None, None);
+        backlog.scan(&config, test_data.now);
+        check_backlog(&backlog, 1, 1, 0, 0, 0, 0);
+        assert_that!(backlog.skipped_entries).contains_entry(crate::SkipReason::SpecialFile, 1);
+
+        let mut backlog = Backlog::new([].into_iter());
+        let mut config = test_data.build_config(None, None, None, None, None);
+        config.special_files_as_errors = true;
+        backlog.scan(&config, test_data.now);
+        assert_that!(backlog.total_errors).contains_entry(ErrorType::SpecialFile, 1);
+        assert_that!(backlog.special_files).is_equal_to(HashMap::from([(SUBDIR.to_string(), 1)]));
+    }
+
+    #[rstest]
+    #[case::ignore(crate::Symlinks::Ignore, 1, 0, 0)]
+    #[case::count(crate::Symlinks::Count, 2, 0, 0)]
+    #[case::error(crate::Symlinks::Error, 1, 2, 0)]
+    // The loop is detected and reported, instead of being descended into.
+    #[case::follow(crate::Symlinks::Follow, 2, 0, 1)]
+    fn symlink_policy(
+        test_data: TestData,
+        mut backlog: Backlog,
+        #[case] policy: crate::Symlinks,
+        #[case] files: i64,
+        #[case] symlink_errors: i64,
+        #[case] scan_errors: i64,
+    ) {
+        let subdir = test_data.get_subdir();
+        add_file(&subdir, "file.nef");
+        std::os::unix::fs::symlink(subdir.join("file.nef"), subdir.join("link.nef")).unwrap();
+        // A loop back to the parent directory.
+        std::os::unix::fs::symlink(&subdir, subdir.join("loop")).unwrap();
+        let mut config = test_data.build_config(None, None, None, None, None);
+        config.symlinks = policy;
+        backlog.scan(&config, test_data.now);
+        assert_that!(backlog.total_files).is_equal_to(files);
+        assert_that!(backlog.symlinks).is_equal_to(2);
+        assert_that!(backlog.total_errors).contains_entry(ErrorType::Symlink, symlink_errors);
+        assert_that!(backlog.total_errors).contains_entry(ErrorType::Scan, scan_errors);
+    }
+
+    #[rstest]
+    fn path_limits(test_data: TestData, mut backlog: Backlog) {
+        let subdir = test_data.get_subdir();
+        let nested = subdir.join("a").join("b");
+        std::fs::create_dir_all(&nested).unwrap();
+        add_file(&subdir, "short.nef");
+        add_file(&nested, "file.nef");
+        let mut config = test_data.build_config(None, None, None, None, None);
+        // "dir1/short.nef" fits, "dir1/a/b/file.nef" doesn't.
+        config.max_path_length = Some("dir1/short.nef".len());
+        // Only the file in dir1/a/b is too deep.
+        config.max_depth = Some(3);
+        backlog.scan(&config, test_data.now);
+        assert_that!(backlog.total_files).is_equal_to(2);
+        assert_that!(backlog.total_errors).contains_entry(ErrorType::PathLength, 1);
+        assert_that!(backlog.total_errors).contains_entry(ErrorType::PathDepth, 1);
+    }
+
+    #[rstest]
+    fn violations(test_data: TestData, mut backlog: Backlog) {
+        let subdir = test_data.get_subdir();
+        let nested = subdir.join("a");
+        std::fs::create_dir_all(&nested).unwrap();
+        add_file(&nested, "file.nef");
+        let mut config = test_data.build_config(None, None, None, None, None);
+        config.max_depth = Some(2);
+        backlog.scan(&config, test_data.now);
+        assert_that!(backlog.total_errors).contains_entry(ErrorType::PathDepth, 1);
+        assert_that!(backlog.violations).is_empty();
+
+        config.record_violations = true;
+        let mut backlog = Backlog::new(std::iter::empty());
+        backlog.scan(&config, test_data.now);
+        assert_that!(backlog.violations).is_equal_to(vec![Violation {
+            path: nested.join("file.nef"),
+            kind: ErrorType::PathDepth,
+            expected: Some("<= 2".to_string()),
+            actual: Some("3".to_string()),
+        }]);
+    }
+
+    #[rstest]
+    fn structure(test_data: TestData, mut backlog: Backlog) {
+        let root = test_data.temp_dir.path();
+        for dir in ["good/raw", "good/export", "partial/raw", "empty"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        let required = vec!["raw".to_string(), "export".to_string()];
+        let mut config = test_data.build_config(None, None, None, None, None);
+        config.required_subdirs = &required;
+        config.record_violations = true;
+        backlog.scan(&config, test_data.now);
+        assert_that!(backlog.total_errors).contains_entry(ErrorType::Structure, 2);
+        assert_that!(backlog.violations).contains(Violation {
+            path: root.join("partial"),
+            kind: ErrorType::Structure,
+            expected: Some("raw,export".to_string()),
+            actual: Some("missing export".to_string()),
+        });
+        assert_that!(backlog
+            .violations
+            .iter()
+            .map(|v| v.path.clone())
+            .collect::<Vec<_>>())
+        .does_not_contain(root.join("good"));
+    }
+
+    #[rstest]
+    #[case::sequential(1)]
+    #[case::parallel(4)]
+    fn max_files(test_data: TestData, mut backlog: Backlog, #[case] scan_threads: usize) {
+        let subdir = test_data.get_subdir();
+        for i in 0..10 {
+            add_file(&subdir, &format!("{}.nef", i));
+        }
+        let mut config = test_data.build_config(None, None, None, None, None);
+        config.scan_threads = scan_threads;
+        config.max_files = Some(5);
+        backlog.scan(&config, test_data.now);
+        assert_that!(backlog.scan_aborted).is_true();
+        assert_that!(backlog.scan_failure)
+            .is_some()
+            .contains("aborting the scan");
+        assert_that!(backlog.entries).is_equal_to(6);
+    }
+
+    #[rstest]
+    fn folder_tags(test_data: TestData, mut backlog: Backlog) {
+        let subdir = test_data.get_subdir();
+        add_file(&subdir, "a.nef");
+        std::fs::write(
+            subdir.join(crate::TAGS_FILE),
+            "client = smith, priority=high\n# comment\nbroken\nsecret=x",
+        )
+        .unwrap();
+        let keys = vec!["client".to_string(), "priority".to_string()];
+        let mut config = test_data.build_config(None, None, None, None, None);
+        config.no_extension = crate::NoExtension::Count;
+        config.folder_tag_keys = &keys;
+        backlog.scan(&config, test_data.now);
+        // The tags file itself isn't a backlog file.
+        assert_that!(backlog.total_files).is_equal_to(1);
+        let folder = subdir.file_name().unwrap().to_string_lossy().into_owned();
+        assert_that!(backlog.folder_tags).contains_entry(
+            folder,
+            BTreeMap::from([
+                ("client".to_string(), "smith".to_string()),
+                ("priority".to_string(), "high".to_string()),
+            ]),
+        );
+    }
+
+    #[rstest]
+    fn applied_changes(test_data: TestData, mut backlog: Backlog) {
+        use crate::FileChange::{Added, Removed};
+        let subdir = test_data.get_subdir();
+        let other = test_data.temp_dir.path().join("other");
+        std::fs::create_dir(&other).unwrap();
+        add_file(&subdir, "a.nef");
+        let b = add_file(&subdir, "b.jpg");
+        add_file(&other, "c.nef");
+        let config = test_data.build_config(None, None, None, None, None);
+        backlog.scan(&config, test_data.now);
+        check_has_dir_with(&backlog, SUBDIR, 2);
+
+        let d = add_file(&subdir, "d.nef");
+        let ignored = add_file(&subdir, "d.xmp");
+        std::fs::remove_file(&b).unwrap();
+        backlog.apply_changes(
+            &config,
+            test_data.now,
+            &[Added(d), Added(ignored), Removed(b), Removed(other)],
+        );
+        assert_that!(backlog.total_files).is_equal_to(2);
+        check_has_dir_with(&backlog, SUBDIR, 2);
+        assert_that!(backlog.folders).does_not_contain_key("other".to_string());
+        assert_that!(backlog.folder_kinds).contains_entry(SUBDIR.to_string(), (2, 0));
+    }
+
+    #[rstest]
+    fn cancelled(test_data: TestData, mut backlog: Backlog) {
+        add_file(&test_data.get_subdir(), "a.nef");
+        let cancel = std::sync::atomic::AtomicBool::new(true);
+        let mut config = test_data.build_config(None, None, None, None, None);
+        config.cancel = Some(&cancel);
+        backlog.scan(&config, test_data.now);
+        assert_that!(backlog.scan_aborted).is_false();
+        assert_that!(backlog.scan_failure)
+            .is_some()
+            .contains("cancelled");
+        assert_that!(backlog.entries).is_equal_to(0);
+    }
+
+    #[rstest]
+    fn oldest_file(test_data: TestData, mut backlog: Backlog) {
+        let subdir = test_data.get_subdir();
+        for (name, days) in [("new.nef", 1), ("old.nef", 30), ("mid.nef", 10)] {
+            std::fs::File::create(subdir.join(name))
+                .and_then(|f| f.set_modified(test_data.now - Duration::from_secs(days * 86400)))
+                .unwrap();
+        }
+        let config = test_data.build_config(None, None, None, None, None);
+        backlog.scan(&config, test_data.now);
+        assert_that!(backlog.oldest_file).is_equal_to(Some((
+            PathBuf::from(SUBDIR).join("old.nef"),
+            SUBDIR.to_string(),
+            30.0 * 86400.0,
+        )));
+    }
+
+    #[rstest]
+    fn oldest_by_ext(test_data: TestData, mut backlog: Backlog) {
+        let subdir = test_data.get_subdir();
+        for (name, days) in [("a.nef", 1), ("b.nef", 5), ("a.jpg", 30), ("b.jpg", 2)] {
+            std::fs::File::create(subdir.join(name))
+                .and_then(|f| f.set_modified(test_data.now - Duration::from_secs(days * 86400)))
+                .unwrap();
+        }
+        let config = test_data.build_config(None, None, None, None, None);
+        backlog.scan(&config, test_data.now);
+        assert_that!(backlog.oldest_by_ext).is_equal_to(HashMap::from([
+            ("nef".to_string(), 5.0 * 86400.0),
+            ("jpg".to_string(), 30.0 * 86400.0),
+        ]));
+    }
+
+    #[rstest]
+    fn age_calendar(test_data: TestData, mut backlog: Backlog) {
+        let subdir = test_data.get_subdir();
+        std::fs::File::create(subdir.join("1.nef"))
+            .and_then(|f| f.set_modified(test_data.now - Duration::from_secs(14 * 86400)))
+            .unwrap();
+        let calendar = crate::work_calendar::WorkCalendar::parse("business-days").unwrap();
+        let mut config = test_data.build_config(None, None, None, None, None);
+        config.age_calendar = Some(&calendar);
+        backlog.scan(&config, test_data.now);
+        // Two weeks are ten business days, whatever the current weekday.
+        assert_that!(backlog.ages).is_equal_to(vec![10.0 * 86400.0]);
+    }
+
+    #[rstest]
+    fn camera_serials(test_data: TestData, mut backlog: Backlog) {
+        let subdir = test_data.get_subdir();
+        std::fs::write(subdir.join("1.nef"), crate::exif::tiff_with_serial("3001")).unwrap();
+        std::fs::write(subdir.join("2.nef"), crate::exif::tiff_with_serial("3001")).unwrap();
+        std::fs::write(subdir.join("3.nef"), crate::exif::tiff_with_serial("3002")).unwrap();
+        add_file(&subdir, "4.nef");
+        let mut config = test_data.build_config(None, None, None, None, None);
+        config.camera_serials = true;
+        backlog.scan(&config, test_data.now);
+        assert_that!(backlog.camera_files).is_equal_to(HashMap::from([
+            ("3001".to_string(), 2),
+            ("3002".to_string(), 1),
+            (crate::UNKNOWN_SERIAL.to_string(), 1),
+        ]));
+    }
+
+    #[rstest]
+    fn folder_kinds(test_data: TestData, mut backlog: Backlog) {
+        let subdir = test_data.get_subdir();
+        add_file(&subdir, "1.nef");
+        add_file(&subdir, "2.nef");
+        add_file(&subdir, "1.jpg");
+        let config = test_data.build_config(None, None, None, None, None);
+        backlog.scan(&config, test_data.now);
+        assert_that!(backlog.folder_kinds)
+            .is_equal_to(HashMap::from([(SUBDIR.to_string(), (2, 1))]));
+    }
+
+    #[rstest]
+    fn companions(test_data: TestData, mut backlog: Backlog) {
+        let subdir = test_data.get_subdir();
+        for file in [
+            "GH01.MP4",
+            "GH01.THM",
+            "C0001.MP4",
+            "C0001M01.XML",
+            "GH02.THM",
+            "IMG_0001.CR3",
+            "IMG_0001.DR4",
+        ] {
+            add_file(&subdir, file);
+        }
+        let companions = crate::cli::parse_companions("thm:mp4,xml:mp4,dr4:cr3").unwrap();
+        let raw_exts = [OsString::from("CR3")];
+        let editable_exts = [OsString::from("MP4")];
+        let mut config = test_data.build_config(None, None, None, None, None);
+        config.raw_exts = &raw_exts;
+        config.editable_exts = &editable_exts;
+        config.companion_exts = &companions;
+        config.record_violations = true;
+        backlog.scan(&config, test_data.now);
+        assert_that!(backlog.total_files).is_equal_to(3);
+        assert_that!(backlog.total_errors).contains_entry(ErrorType::Unknown, 0);
+        assert_that!(backlog.total_errors).contains_entry(ErrorType::OrphanCompanion, 1);
+        assert_that!(backlog.violations[0].path).is_equal_to(subdir.join("GH02.THM"));
+    }
+
+    #[rstest]
+    fn archives(test_data: TestData, mut backlog: Backlog) {
+        let subdir = test_data.get_subdir();
+        add_file(&subdir, "1.nef");
+        let zip = crate::archive::zip_with(&["DCIM/", "DCIM/1.NEF", "DCIM/1.xmp", "DCIM/2.jpg"]);
+        std::fs::write(subdir.join("card.zip"), zip).unwrap();
+        std::fs::write(subdir.join("broken.zip"), "").unwrap();
+        let archive_exts = [OsString::from("zip")];
+        let raw_exts = [OsString::from("nef"), OsString::from("NEF")];
+        let mut config = test_data.build_config(None, None, None, None, None);
+        config.archive_exts = &archive_exts;
+        config.raw_exts = &raw_exts;
+        backlog.scan(&config, test_data.now);
+        // The sidecar in the archive is ignored, as is outside.
+        assert_that!(backlog.total_files).is_equal_to(3);
+        assert_that!(backlog.total_errors).contains_entry(ErrorType::Scan, 1);
+        assert_that!(backlog.total_errors).contains_entry(ErrorType::Unknown, 0);
+        let card = format!("{}/card.zip", SUBDIR);
+        assert_that!(backlog.folders.get(&card).map(|(c, _)| *c)).is_equal_to(Some(2));
+        assert_that!(backlog.folder_kinds).is_equal_to(HashMap::from([
+            (SUBDIR.to_string(), (1, 0)),
+            (card, (1, 1)),
+        ]));
+    }
+
+    #[rstest]
+    fn sampling(test_data: TestData, mut backlog: Backlog) {
+        let subdir = test_data.get_subdir();
+        for i in 0..200 {
+            add_file(&subdir, &format!("{}.nef", i));
+            add_file(&subdir, &format!("{}.jpg", i));
+        }
+        let mut config = test_data.build_config(None, None, None, None, None);
+        config.sample = Some(0.25);
+        backlog.scan(&config, test_data.now);
+        let sampled = backlog.ages.len();
+        assert_that!(sampled).is_greater_than(50);
+        assert_that!(sampled).is_less_than(150);
+        // Raw and editable versions of a shot are sampled together.
+        let (raw, editable) = backlog.folder_kinds[SUBDIR];
+        assert_that!(raw).is_equal_to(editable);
+        assert_that!(backlog.total_files).is_equal_to(sampled as i64 * 4);
+        assert_that!(backlog.folders[SUBDIR].0).is_equal_to(sampled as i64 * 4);
+        assert_that!(backlog.sample_ratio).is_equal_to(Some(0.25));
+
+        // The sample is the same on each scan.
+        let mut again = Backlog::new([1.0].into_iter());
+        again.scan(&config, test_data.now);
+        assert_that!(again.total_files).is_equal_to(backlog.total_files);
+    }
+
+    #[rstest]
+    fn tree_shape(test_data: TestData, mut backlog: Backlog) {
+        let subdir = test_data.get_subdir();
+        let deep = subdir.join("a").join("b");
+        std::fs::create_dir_all(&deep).unwrap();
+        add_file(&subdir, "1.nef");
+        add_file(&subdir, "1.xmp");
+        add_file(&deep, "2.nef");
+        let config = test_data.build_config(None, None, None, None, None);
+        backlog.scan(&config, test_data.now);
+        // The root, the subdir and its two nested directories.
+        assert_that!(backlog.dirs).is_equal_to(4);
+        assert_that!(backlog.walked_files).is_equal_to(3);
+        assert_that!(backlog.max_depth).is_equal_to(4);
+    }
+
+    #[rstest]
+    fn future_dated(test_data: TestData, mut backlog: Backlog) {
+        let subdir = test_data.get_subdir();
+        add_file(&subdir, "1.nef");
+        let future = add_file(&subdir, "2.nef");
+        std::fs::File::options()
+            .write(true)
+            .open(&future)
+            .unwrap()
+            .set_modified(test_data.now + Duration::from_secs(86400))
+            .unwrap();
+        let config = test_data.build_config(None, None, None, None, None);
+        backlog.scan(&config, test_data.now);
+        assert_that!(backlog.total_files).is_equal_to(2);
+        assert_that!(backlog.future_dated_files).is_equal_to(1);
+    }
+
+    #[rstest]
+    fn clock_skew(test_data: TestData, mut backlog: Backlog) {
+        let subdir = test_data.get_subdir();
+        let set_modified = |path: &Path, modified: SystemTime| {
+            std::fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        };
+        let ahead = add_file(&subdir, "1.nef");
+        set_modified(&ahead, test_data.now + Duration::from_secs(30));
+        let mut config = test_data.build_config(None, None, None, None, None);
+        backlog.scan(&config, test_data.now);
+        assert_that!(backlog.future_dated_files).is_equal_to(0);
+        assert_that!(backlog.clock_skewed()).is_true();
+
+        let mut backlog = Backlog::new([].into_iter());
+        config.clock_skew_tolerance = Duration::from_secs(10);
+        backlog.scan(&config, test_data.now);
+        assert_that!(backlog.future_dated_files).is_equal_to(1);
+        assert_that!(backlog.clock_skewed()).is_false();
+
+        // A file modified just before the scan shows the clocks agree.
+        let recent = add_file(&subdir, "2.nef");
+        set_modified(&recent, test_data.now - Duration::from_secs(5));
+        let mut backlog = Backlog::new([].into_iter());
+        config.clock_skew_tolerance = crate::DEFAULT_CLOCK_SKEW_TOLERANCE;
+        backlog.scan(&config, test_data.now);
+        assert_that!(backlog.skewed_files).is_equal_to(1);
+        assert_that!(backlog.clock_skewed()).is_false();
+    }
+
+    #[rstest]
+    fn not_immutable(test_data: TestData, mut backlog: Backlog) {
+        let archive = test_data.temp_dir.path().join("archive");
+        std::fs::create_dir(&archive).unwrap();
+        let archived = add_file(&archive, "1.nef");
+        add_file(&test_data.get_subdir(), "2.nef");
+        // Not all filesystems support file attributes.
+        if crate::is_immutable(&archived).is_err() {
+            return;
+        }
+        let subpath = Path::new("archive");
+        let mut config = test_data.build_config(None, None, None, None, None);
+        config.immutable_subpath = Some(subpath);
+        config.record_violations = true;
+        backlog.scan(&config, test_data.now);
+        assert_that!(backlog.total_files).is_equal_to(2);
+        assert_that!(backlog.total_errors).contains_entry(ErrorType::NotImmutable, 1);
+        assert_that!(backlog.violations[0].path).is_equal_to(archived);
+    }
+
+    #[rstest]
+    fn folder_owners(test_data: TestData, mut backlog: Backlog) {
+        let subdir = test_data.get_subdir();
+        add_file(&subdir, "1.nef");
+        add_file(&subdir, "2.nef");
+        let mut config = test_data.build_config(None, None, None, None, None);
+        backlog.scan(&config, test_data.now);
+        assert_that!(backlog.folder_owners).is_empty();
+        config.track_owners = true;
+        let mut backlog = Backlog::new(std::iter::empty());
+        backlog.scan(&config, test_data.now);
+        let uid = std::fs::metadata(&subdir).unwrap().uid();
+        assert_that!(backlog.folder_owners)
+            .is_equal_to(HashMap::from([((SUBDIR.to_string(), uid), 2)]));
+    }
+
+    #[rstest]
+    fn ignore_file(test_data: TestData, mut backlog: Backlog) {
+        let root = test_data.temp_dir.path();
+        let subdir = test_data.get_subdir();
+        add_file(&subdir, "keep.nef");
+        add_file(&subdir, "skip.nef");
+        let exports = root.join("exports");
+        std::fs::create_dir(&exports).unwrap();
+        add_file(&exports, "file.nef");
+        add_file(&exports, "file.zip");
+        std::fs::write(
+            root.join(crate::IGNORE_FILE),
+            "# comment\nexports/\nskip.*\n",
+        )
+        .unwrap();
+        let mut config = test_data.build_config(None, None, None, None, None);
+        // The ignore file itself isn't counted, even if files without
+        // extension are.
+        config.no_extension = crate::NoExtension::Count;
+        backlog.scan(&config, test_data.now);
+        check_backlog(&backlog, 1, 1, 0, 0, 0, 0);
+        check_has_dir_with(&backlog, SUBDIR, 1);
+        // The exports directory counts once, not being walked.
+        assert_that!(backlog.skipped_entries).is_equal_to(HashMap::from([
+            (crate::SkipReason::Marker, 1),
+            (crate::SkipReason::ExcludedGlob, 2),
+        ]));
+    }
+
+    /// Compares the results of the sequential and parallel walkers.
+    #[rstest]
+    fn parallel_walk(test_data: TestData) {
+        let root = test_data.temp_dir.path();
+        for dir in ["a", "b", "c/d"] {
+            let subdir = root.join(dir);
+            std::fs::create_dir_all(&subdir).unwrap();
+            add_file(&subdir, "file1.nef");
+            add_file(&subdir, "file2.jpg");
+            add_file(&subdir, "file.xmp");
+            add_file(&subdir, "file.zip");
+        }
+        add_file(root, "root.nef");
+        std::fs::write(root.join(crate::IGNORE_FILE), "b/\n").unwrap();
+        std::os::unix::fs::symlink(root.join("a"), root.join("c").join("loop")).unwrap();
+        let scan = |scan_threads, symlinks| {
+            let mut backlog = Backlog::new([].into_iter());
+            let mut config = test_data.build_config(None, None, None, None, None);
+            config.scan_threads = scan_threads;
+            config.symlinks = symlinks;
+            config.max_depth = Some(2);
+            backlog.scan(&config, test_data.now);
+            backlog
+        };
+        for symlinks in [crate::Symlinks::Ignore, crate::Symlinks::Follow] {
+            let sequential = scan(1, symlinks);
+            let parallel = scan(4, symlinks);
+            assert_that!(parallel.total_files).is_equal_to(sequential.total_files);
+            assert_that!(parallel.folders).is_equal_to(sequential.folders);
+            assert_that!(parallel.total_errors).is_equal_to(sequential.total_errors);
+            assert_that!(parallel.symlinks).is_equal_to(sequential.symlinks);
+            assert_that!(parallel.skipped_entries).is_equal_to(sequential.skipped_entries);
+            assert_that!(sequential.walk_workers).is_equal_to(1);
+            assert_that!(sequential.walk_worker_busy).is_empty();
+            assert_that!(parallel.walk_workers).is_equal_to(4);
+            assert_that!(parallel.walk_worker_busy).has_length(4);
+            assert_that!(parallel.walk_queue_peak).is_greater_than(0);
+        }
+        let missing = test_data.temp_dir.path().join("missing");
+        let mut config = test_data.build_config(None, None, None, None, None);
+        config.root_path = &missing;
+        config.scan_threads = 4;
+        let mut backlog = Backlog::new([].into_iter());
+        backlog.scan(&config, test_data.now);
+        assert_that!(backlog.scan_failure).is_some();
+    }
+
+    #[rstest]
+    fn mock_data(mut backlog: Backlog) {
+        backlog.fill_mock(crate::MockData {
+            folders: 3,
+            files: 5,
+        });
+        check_backlog(&backlog, 3, 15, 0, 0, 0, 0);
+        check_has_dir_with(&backlog, "mock-folder-0002", 5);
+    }
+
     #[rstest]
     fn no_such_dir(test_data: TestData, mut backlog: Backlog) {
         let _subdir = test_data.get_subdir();
@@ -512,6 +2824,67 @@ mod tests {
         config.root_path = &missing_dir;
         backlog.scan(&config, test_data.now);
         check_backlog(&backlog, 0, 0, 1, 0, 0, 0);
+        assert_that!(backlog.scan_failure).is_some();
+    }
+
+    #[rstest]
+    fn folder_memory_budget(test_data: TestData, mut backlog: Backlog) {
+        for dir in ["a", "b", "c"] {
+            let subdir = test_data.temp_dir.path().join(dir);
+            std::fs::create_dir(&subdir).unwrap();
+            add_file(&subdir, "file1.nef");
+            add_file(&subdir, "file2.nef");
+        }
+        // Enough for exactly two folders.
+        let mut config = test_data.build_config(None, None, None, None, None);
+        config.folder_memory_budget = Some(2 * crate::folder_entry_size("a"));
+        config.keep_ages = false;
+        config.keep_folder_ages = false;
+        backlog.scan(&config, test_data.now);
+        check_backlog(&backlog, 2, 6, 0, 0, 0, 0);
+        assert_that!(backlog.untracked_files).is_equal_to(2);
+        assert_that!(backlog.folder_count()).is_equal_to(3);
+    }
+
+    #[rstest]
+    fn ages_memory_budget(test_data: TestData, mut backlog: Backlog) {
+        let subdir = test_data.temp_dir.path().join("a");
+        std::fs::create_dir(&subdir).unwrap();
+        for i in 0..3 {
+            add_file(&subdir, &format!("file{}.nef", i));
+        }
+        // Enough for the folder and two ages.
+        let mut config = test_data.build_config(None, None, None, None, None);
+        config.folder_memory_budget = Some(crate::folder_entry_size("a") + 16);
+        config.keep_folder_ages = false;
+        backlog.scan(&config, test_data.now);
+        check_backlog(&backlog, 1, 3, 0, 0, 0, 0);
+        assert_that!(backlog.untracked_files).is_equal_to(0);
+        assert_that!(backlog.ages_dropped).is_true();
+        assert_that!(backlog.ages).is_empty();
+        assert_that!(backlog.ages_memory).is_equal_to(0);
+    }
+
+    #[rstest]
+    fn folder_ages_memory_budget(test_data: TestData, mut backlog: Backlog) {
+        for (dir, files) in [("a", 2), ("b", 1)] {
+            let subdir = test_data.temp_dir.path().join(dir);
+            std::fs::create_dir(&subdir).unwrap();
+            for i in 0..files {
+                add_file(&subdir, &format!("file{}.nef", i));
+            }
+        }
+        // Enough for both folders, but only the first one's ages.
+        let entry_size = crate::folder_entry_size("a");
+        let mut config = test_data.build_config(None, None, None, None, None);
+        config.folder_memory_budget = Some(4 * entry_size + 23);
+        config.keep_ages = false;
+        backlog.scan(&config, test_data.now);
+        check_backlog(&backlog, 2, 3, 0, 0, 0, 0);
+        assert_that!(backlog.untracked_files).is_equal_to(0);
+        assert_that!(backlog.folder_ages_dropped).is_true();
+        assert_that!(backlog.folder_ages).is_empty();
+        assert_that!(backlog.ages_memory).is_equal_to(0);
     }
 
     enum FailMode {
@@ -622,6 +2995,117 @@ mod tests {
         check_has_dir_with(&backlog, subdir.file_name().unwrap().to_str().unwrap(), 2);
     }
 
+    #[rstest]
+    fn test_mode_classes(test_data: TestData, mut backlog: Backlog) {
+        let subdir = test_data.get_subdir();
+        let nef = add_file(&subdir, "file.nef");
+        let mp4 = add_file(&subdir, "file.mp4");
+        let xmp = add_file(&subdir, "file.xmp");
+        let jpg = add_file(&subdir, "file.jpg");
+        for path in [&nef, &mp4, &xmp] {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o640)).unwrap();
+        }
+        std::fs::set_permissions(jpg, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let editable_exts = [OsString::from("jpg"), OsString::from("mp4")];
+        let mode_classes = [
+            crate::ModeClass {
+                name: "video".to_string(),
+                exts: vec![OsString::from("mp4")],
+                mode: 0o640,
+            },
+            crate::ModeClass {
+                name: "sidecar".to_string(),
+                exts: vec![OsString::from("xmp")],
+                mode: 0o600,
+            },
+        ];
+        let config = Config {
+            editable_exts: &editable_exts,
+            mode_classes: &mode_classes,
+            ..test_data.build_config(None, None, None, Some(0o640), Some(0o600))
+        };
+        backlog.scan(&config, test_data.now);
+        // The video matches its class mode rather than the editable one,
+        // while the ignored sidecar is still checked, and fails.
+        check_backlog(&backlog, 1, 3, 0, 0, 1, 0);
+    }
+
+    #[rstest]
+    fn class_sizes(test_data: TestData, mut backlog: Backlog) {
+        let subdir = test_data.get_subdir();
+        for (name, size) in [
+            ("1.nef", 100),
+            ("2.nef", 300),
+            ("1.jpg", 50),
+            ("1.mp4", 1000),
+        ] {
+            std::fs::write(subdir.join(name), vec![0u8; size]).unwrap();
+        }
+        let editable_exts = [OsString::from("jpg"), OsString::from("mp4")];
+        let mode_classes = [crate::ModeClass {
+            name: "video".to_string(),
+            exts: vec![OsString::from("mp4")],
+            mode: 0o640,
+        }];
+        let config = Config {
+            editable_exts: &editable_exts,
+            mode_classes: &mode_classes,
+            ..test_data.build_config(None, None, None, None, None)
+        };
+        backlog.scan(&config, test_data.now);
+        assert_that!(backlog.class_sizes).is_equal_to(HashMap::from([
+            ("raw".to_string(), (2, 400)),
+            ("editable".to_string(), (1, 50)),
+            ("video".to_string(), (1, 1000)),
+        ]));
+    }
+
+    #[rstest]
+    fn largest_files(test_data: TestData, mut backlog: Backlog) {
+        let subdir = test_data.get_subdir();
+        for (i, size) in [10, 40, 20, 30, 5].into_iter().enumerate() {
+            std::fs::write(subdir.join(format!("{}.nef", i)), vec![0u8; size]).unwrap();
+        }
+        let mut config = test_data.build_config(None, None, None, None, None);
+        config.largest_files = 2;
+        backlog.scan(&config, test_data.now);
+        let folder = subdir.strip_prefix(test_data.temp_dir.path()).unwrap();
+        assert_that!(backlog
+            .largest_files
+            .iter()
+            .map(|f| (f.path.clone(), f.size))
+            .collect::<Vec<_>>())
+        .is_equal_to(vec![(folder.join("1.nef"), 40), (folder.join("3.nef"), 30)]);
+    }
+
+    #[rstest]
+    fn folder_details(test_data: TestData, mut backlog: Backlog) {
+        let subdir = test_data.get_subdir();
+        for (name, size, age) in [("1.nef", 10, 100), ("2.nef", 20, 300), ("3.jpg", 5, 200)] {
+            let path = subdir.join(name);
+            std::fs::write(&path, vec![0u8; size]).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(test_data.now - Duration::from_secs(age))
+                .unwrap();
+        }
+        let mut config = test_data.build_config(None, None, None, None, None);
+        config.folder_details = true;
+        backlog.scan(&config, test_data.now);
+        let folder = Path::new(SUBDIR);
+        assert_that!(backlog.folder_details).contains_entry(
+            SUBDIR.to_string(),
+            crate::FolderDetails {
+                exts: BTreeMap::from([("jpg".to_string(), 1), ("nef".to_string(), 2)]),
+                size: 35,
+                oldest: Some((folder.join("2.nef"), 300.0)),
+                newest: Some((folder.join("1.nef"), 100.0)),
+            },
+        );
+    }
+
     #[rstest]
     fn ignored_files_are_ignored(test_data: TestData, mut backlog: Backlog) {
         let _ = env_logger::builder().is_test(true).try_init();
@@ -653,6 +3137,17 @@ mod tests {
         // well, but the two extra files are ignored.
         let expected_errors = 3;
         check_backlog(&backlog, 1, 1, 0, expected_errors, 1, 0);
+        // Both owner and group are wrong, which is counted per check.
+        assert_that!(backlog.check_errors).is_equal_to(HashMap::from([
+            ((Check::Owner, CheckTarget::File), 1),
+            ((Check::Owner, CheckTarget::Dir), 2),
+            ((Check::Group, CheckTarget::File), 1),
+            ((Check::Group, CheckTarget::Dir), 2),
+            ((Check::DirMode, CheckTarget::Dir), 0),
+            ((Check::RawMode, CheckTarget::File), 1),
+            ((Check::EditableMode, CheckTarget::File), 0),
+            ((Check::ClassModes, CheckTarget::File), 0),
+        ]));
         check_has_dir_with(&backlog, subdir.file_name().unwrap().to_str().unwrap(), 1);
     }
 
@@ -683,6 +3178,22 @@ mod tests {
         let config = test_data.build_config(None, None, None, None, None);
         backlog.scan(&config, test_data.now);
         std::fs::set_permissions(temp_dir, std::fs::Permissions::from_mode(0o755)).unwrap();
-        check_backlog(&backlog, 0, 0, 3, 0, 0, 0);
+        // The directory is the single error, not each of its entries.
+        check_backlog(&backlog, 0, 0, 1, 0, 0, 0);
+        assert_that!(backlog.unreadable_dirs).contains(temp_dir.path().to_path_buf());
+    }
+
+    #[rstest]
+    fn unreadable_dirs(test_data: TestData, mut backlog: Backlog) {
+        let config = test_data.build_config(None, None, None, None, None);
+        let root = test_data.temp_dir.path();
+        let message = || "Permission denied".to_string();
+        backlog.record_unreadable(&config, &root.join("a"), message());
+        backlog.record_unreadable(&config, &root.join("a/b"), message());
+        backlog.record_unreadable(&config, &root.join("a"), message());
+        backlog.record_unreadable(&config, &root.join("ab"), message());
+        assert_that!(backlog.unreadable_dirs)
+            .is_equal_to(BTreeSet::from([root.join("a"), root.join("ab")]));
+        assert_that!(backlog.total_errors).contains_entry(ErrorType::Scan, 2);
     }
 }
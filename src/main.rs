@@ -1,13 +1,25 @@
 use cli::log_error;
 use photo_backlog_exporter::*;
 
-#[tokio::main]
-async fn main() -> Result<(), String> {
+fn main() -> Result<(), String> {
     let opts = match cli::init_binary()? {
         None => return Ok(()),
         Some(opts) => opts,
     };
 
-    let (addr, app) = daemon::build_app(opts);
-    daemon::run_daemon(addr, app).await.map_err(log_error)
+    // Only started now, so that its threads inherit the capabilities
+    // dropped via `--assert-readonly`.
+    let runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| log_error(format!("Can't start the runtime: {}", e)))?;
+    let result = runtime.block_on(async {
+        let server_config = cli::server_config_from_args(&opts).map_err(log_error)?;
+        let (addr, app) = daemon::build_app(opts).map_err(log_error)?;
+        daemon::run_daemon(addr, app, server_config)
+            .await
+            .map_err(log_error)
+    });
+    // The grace period is over, so don't wait for blocking tasks such as
+    // scans still running, as dropping the runtime would.
+    runtime.shutdown_background();
+    result
 }
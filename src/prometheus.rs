@@ -1,34 +1,417 @@
+use std::collections::HashMap;
 use std::ffi::OsString;
 
-use std::path::PathBuf;
-use std::sync::atomic::AtomicU64;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
 
-use std::time::{Instant, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{info, warn};
+
+use super::buckets::{self, AgeBound, AgeScale};
 
 use prometheus_client::collector::Collector;
 use prometheus_client::encoding::text::encode;
 use prometheus_client::encoding::DescriptorEncoder;
 use prometheus_client::encoding::EncodeMetric;
 use prometheus_client::encoding::{EncodeLabelSet, EncodeLabelValue, LabelValueEncoder};
+use prometheus_client::metrics::counter::{ConstCounter, Counter};
 use prometheus_client::metrics::family::Family;
 use prometheus_client::metrics::gauge::{ConstGauge, Gauge};
 use prometheus_client::registry::Registry;
+#[cfg(feature = "daemon")]
+use tokio::sync::broadcast;
 
 pub const PROCESSING_TIME_NAME: &str = "photo_backlog_processing_time_seconds";
-pub const PROCESSING_TIME_HELP: &str = "Processing time for scanning the backlog";
+pub const PROCESSING_TIME_HELP: &str = "Processing time for scanning the backlog, per phase";
 
-#[derive(Debug)]
+/// Version of the metric names and labels, to be bumped whenever metrics
+/// are renamed or removed, or labels changed.
+pub const SCHEMA_VERSION: i64 = 2;
+
+/// Folder tag holding the folder's priority.
+pub const PRIORITY_TAG: &str = "priority";
+
+/// Priority of folders without a priority tag or matching rule.
+pub const NO_PRIORITY: &str = "none";
+
+/// How long scrapes wait for the first background scan before serving
+/// an empty backlog, well within Prometheus' default scrape timeout.
+const FIRST_SCAN_WAIT: Duration = Duration::from_secs(5);
+
+/// Number of consecutive scans suggesting clock skew after which it's
+/// reported, as a single one can be due to files written while scanning.
+const CLOCK_SKEW_SCANS: u32 = 3;
+
+/// Old and current names of renamed metrics, for emitting the old names
+/// as aliases (with `--metric-aliases`) until dashboards and recording
+/// rules are migrated.
+pub const METRIC_ALIASES: &[(&str, &str)] = &[];
+
+#[derive(Clone, Debug, Default)]
 pub struct PhotoBacklogCollector {
     pub scan_path: PathBuf,
     pub ignored_exts: Vec<OsString>,
     pub raw_exts: Vec<OsString>,
     pub editable_exts: Vec<OsString>,
-    pub age_buckets: Vec<f64>,
+    /// Optional file overriding the extension lists, re-read on each scan.
+    pub exts_file: Option<PathBuf>,
+    /// Companion and primary extension pairs.
+    pub companion_exts: Vec<(OsString, OsString)>,
+    /// Extensions of archives counted via their entries.
+    pub archive_exts: Vec<OsString>,
+    pub age_buckets: Vec<AgeBound>,
+    /// Per-class bucket overrides, defaulting to `age_buckets`.
+    pub raw_age_buckets: Option<Vec<AgeBound>>,
+    pub editable_age_buckets: Option<Vec<AgeBound>>,
+    pub age_metrics: AgeMetrics,
     pub owner: Option<u32>,
     pub group: Option<u32>,
     pub dir_mode: Option<u32>,
     pub raw_file_mode: Option<u32>,
     pub editable_file_mode: Option<u32>,
+    pub folder_memory_budget: Option<usize>,
+    pub no_folder_metrics: bool,
+    /// Export per-folder and owner file counts, limited to the given
+    /// number of series.
+    pub folder_owner_metrics: bool,
+    pub folder_owner_max_series: usize,
+    /// Export per-folder file counts per age bucket, limited to the given
+    /// number of series.
+    pub folder_age_bucket_metrics: bool,
+    pub folder_age_bucket_max_series: usize,
+    /// Export the path of the oldest file, as an info metric.
+    pub oldest_file_metric: bool,
+    /// Keys of the folder tags (from the folders' tags files) exported as
+    /// labels of `photo_backlog_folder_tags_info`.
+    pub folder_tag_keys: Vec<String>,
+    /// Rules mapping folder names to priorities, for folders without a
+    /// priority tag; the replacement is the priority.
+    pub folder_priorities: Option<super::relabel::RelabelRules>,
+    pub folder_relabel: super::relabel::RelabelRules,
+    pub folder_allow: Option<regex::Regex>,
+    pub folder_deny: Option<regex::Regex>,
+    /// Treat a missing root as detached media rather than a failed scan.
+    pub removable_root: bool,
+    pub no_extension: super::NoExtension,
+    pub special_files_as_errors: bool,
+    pub symlinks: super::Symlinks,
+    pub max_path_length: Option<usize>,
+    pub max_depth: Option<usize>,
+    pub scan_threads: usize,
+    /// Read the camera serial of each file, for per-camera counts.
+    pub camera_serials: bool,
+    /// Whether to export the ignored files per extension.
+    pub ignored_files_per_ext: bool,
+    /// Fraction of the files to sample, if any.
+    pub sample: Option<f64>,
+    pub required_subdirs: Vec<String>,
+    /// Subpath of archived files that must be immutable.
+    pub immutable_subpath: Option<PathBuf>,
+    pub max_files: Option<usize>,
+    /// If set, serve synthetic data instead of scanning.
+    pub mock_data: Option<super::MockData>,
+    /// Reuse the last good scan if the root's and top-level directories'
+    /// modification times didn't change since.
+    pub skip_unchanged: bool,
+    /// If set, scan in the background (see `run_scheduled_scans`) at this
+    /// interval, and serve the last results on scrapes.
+    pub scan_interval: Option<Duration>,
+    /// Fraction of the scan interval by which to randomly vary each
+    /// delay, so that instances sharing storage don't scan in lockstep.
+    pub scan_jitter: f64,
+    /// Lock file held while scanning, so that only one scanner (possibly
+    /// in another process) walks the tree at a time.
+    pub scan_lock: Option<PathBuf>,
+    /// How far in the future modification times can be before files are
+    /// flagged as future-dated, by default `DEFAULT_CLOCK_SKEW_TOLERANCE`.
+    pub clock_skew_tolerance: Option<Duration>,
+    /// Measure file ages in this calendar's working time.
+    pub age_calendar: Option<crate::work_calendar::WorkCalendar>,
+    /// Watch the root for files added or removed between the background
+    /// scans, applying them to the served data (see `tree_watch`).
+    pub watch_tree: bool,
+    /// Hard cap on a scan's duration (e.g. on a hung network filesystem),
+    /// after which it's cancelled and the last good data served.
+    pub scan_watchdog: Option<Duration>,
+    /// If set, individual violations are logged here.
+    pub violations_log: Option<Arc<super::violations::ViolationsLog>>,
+    /// Number of largest backlog files to keep in the scan results.
+    pub largest_files: usize,
+    /// Keep the per-folder details in the scan results, e.g. for serving
+    /// them per folder.
+    pub folder_details: bool,
+    /// Keep the individual violations in the scan results, e.g. for
+    /// serving them in snapshots.
+    pub keep_violations: bool,
+    /// Sub-collectors turned off via `--collector.NAME=false`.
+    pub disabled_collectors: Vec<MetricGroup>,
+    /// Checks turned off via `--disable-check`, despite being configured.
+    pub disabled_checks: Vec<super::Check>,
+    /// Old and current names of metrics to additionally emit under their
+    /// old name.
+    pub metric_aliases: &'static [(&'static str, &'static str)],
+    /// Shared, so that the scan results can be inspected after the
+    /// collector is registered.
+    pub state: Arc<Mutex<CollectorState>>,
+    /// Signalled whenever a background scan completes.
+    pub scheduled_done: Arc<Condvar>,
+    /// If set, background scans (scheduled or via `scan_now`) are
+    /// announced here, e.g. for streaming them to clients.
+    #[cfg(feature = "daemon")]
+    pub scan_completions: Option<broadcast::Sender<ScanCompletion>>,
+}
+
+/// A completed background scan.
+#[derive(Clone, Debug)]
+pub struct ScanCompletion {
+    pub root: PathBuf,
+    /// The backlog served after the scan, i.e. the last good one if the
+    /// scan failed.
+    pub backlog: Arc<super::Backlog>,
+    pub failed: bool,
+    pub duration: Duration,
+}
+
+/// State kept by the collector across scrapes.
+#[derive(Debug, Default)]
+pub struct CollectorState {
+    /// The last successful scan, and when it was done.
+    last_good: Option<(Arc<super::Backlog>, SystemTime)>,
+    /// Whether the (removable) root was missing at the last scrape, to
+    /// only log transitions.
+    root_missing: bool,
+    /// The tree's top-level modification times at the last good scan, if
+    /// skipping unchanged scans.
+    tree_mtimes: Option<Vec<(OsString, SystemTime)>>,
+    /// The last successfully loaded extension lists.
+    exts: super::exts::ExtensionLists,
+    /// Number of scrapes served from, respectively not from, the last
+    /// good scan, if skipping unchanged scans.
+    cache_hits: u64,
+    cache_misses: u64,
+    /// The id of the last started scan.
+    last_scan_id: u64,
+    /// The results of the last background scan, if scanning on a schedule.
+    scheduled: Option<ScrapeData>,
+    /// Whether background scans are running, in which case scrapes wait
+    /// for the first one instead of walking the tree themselves.
+    scheduler_running: bool,
+    /// Whether a scan cancelled by the watchdog is still running; no new
+    /// scans are started until it returns.
+    scan_stuck: bool,
+    /// Number of scans cancelled by the watchdog.
+    watchdog_fired: u64,
+    /// Number of folders with files in a scan that were empty or gone in
+    /// the next one.
+    folders_cleared: u64,
+    /// Number of consecutive scans suggesting clock skew (see
+    /// `Backlog::clock_skewed`).
+    skewed_scans: u32,
+}
+
+impl CollectorState {
+    /// Returns the last successful scan, if any.
+    pub fn last_good(&self) -> Option<Arc<super::Backlog>> {
+        self.last_good
+            .as_ref()
+            .map(|(backlog, _)| Arc::clone(backlog))
+    }
+}
+
+/// The sub-collectors, each exporting a group of metrics, which can be
+/// disabled globally or selected per scrape.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricGroup {
+    /// Total file and folder counts.
+    Counts,
+    /// Error counts, per kind.
+    Errors,
+    /// Per-folder metrics.
+    Folders,
+    /// Age histograms.
+    Ages,
+    /// Filesystem statistics: stat latency, symlinks, memory budget.
+    FsStats,
+    /// The exporter's own state: root availability, staleness, processing
+    /// time.
+    SelfMetrics,
+}
+
+impl MetricGroup {
+    /// All groups, in export order; self-metrics come last so that the
+    /// processing time covers the other groups.
+    pub const ALL: [MetricGroup; 6] = [
+        MetricGroup::Counts,
+        MetricGroup::Errors,
+        MetricGroup::Folders,
+        MetricGroup::Ages,
+        MetricGroup::FsStats,
+        MetricGroup::SelfMetrics,
+    ];
+
+    /// The name used in flags and `collect[]` parameters.
+    pub fn name(self) -> &'static str {
+        match self {
+            MetricGroup::Counts => "counts",
+            MetricGroup::Errors => "errors",
+            MetricGroup::Folders => "folders",
+            MetricGroup::Ages => "ages",
+            MetricGroup::FsStats => "fs-stats",
+            MetricGroup::SelfMetrics => "self",
+        }
+    }
+
+    fn sub_collector(self) -> SubCollector {
+        match self {
+            MetricGroup::Counts => encode_counts,
+            MetricGroup::Errors => encode_errors,
+            MetricGroup::Folders => encode_folders,
+            MetricGroup::Ages => encode_ages,
+            MetricGroup::FsStats => encode_fs_stats,
+            MetricGroup::SelfMetrics => encode_self_metrics,
+        }
+    }
+}
+
+impl std::str::FromStr for MetricGroup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        MetricGroup::ALL
+            .into_iter()
+            .find(|g| g.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<_> = MetricGroup::ALL.iter().map(|g| g.name()).collect();
+                format!(
+                    "Unknown metric group '{}', expected one of {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// Which age metrics to export.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AgeMetrics {
+    /// Histograms only.
+    #[default]
+    Histogram,
+    /// Exact quantiles only.
+    Quantiles,
+    /// Both histograms and quantiles.
+    Both,
+}
+
+impl std::str::FromStr for AgeMetrics {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "histogram" => Ok(AgeMetrics::Histogram),
+            "quantiles" => Ok(AgeMetrics::Quantiles),
+            "both" => Ok(AgeMetrics::Both),
+            _ => Err(format!(
+                "Invalid value '{}', expected one of histogram, quantiles, both",
+                s
+            )),
+        }
+    }
+}
+
+/// The quantiles exported for file ages.
+const AGE_QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+/// Per-scrape options.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScrapeOptions {
+    /// The groups to export, or all if none given.
+    pub collect: Vec<MetricGroup>,
+    /// The largest age histogram bucket to export, in weeks; older files
+    /// only count in `+Inf`.
+    pub max_age_weeks: Option<f64>,
+}
+
+impl ScrapeOptions {
+    /// Parses the `collect[]` parameters, in the style of node_exporter,
+    /// and `max_age_weeks` from a URL query string; other parameters are
+    /// ignored.
+    /// Example:
+    /// ```
+    /// use photo_backlog_exporter::prometheus::{MetricGroup, ScrapeOptions};
+    /// let options = ScrapeOptions::from_query("collect[]=counts&collect%5B%5D=ages&max_age_weeks=52").unwrap();
+    /// assert_eq!(options.collect, vec![MetricGroup::Counts, MetricGroup::Ages]);
+    /// assert_eq!(options.max_age_weeks, Some(52.0));
+    /// assert!(ScrapeOptions::from_query("collect[]=foo").is_err());
+    /// assert!(ScrapeOptions::from_query("max_age_weeks=-1").is_err());
+    /// assert_eq!(ScrapeOptions::from_query(""), Ok(ScrapeOptions::default()));
+    /// ```
+    pub fn from_query(query: &str) -> Result<Self, String> {
+        let mut options = ScrapeOptions::default();
+        for param in query.split('&') {
+            let Some((key, value)) = param.split_once('=') else {
+                continue;
+            };
+            let (key, value) = (percent_decode(key)?, percent_decode(value)?);
+            match key.as_str() {
+                "collect[]" => options.collect.push(value.parse()?),
+                "max_age_weeks" => {
+                    let weeks = value
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|weeks| weeks.is_finite() && *weeks > 0.0)
+                        .ok_or_else(|| format!("Invalid max_age_weeks '{}'", value))?;
+                    options.max_age_weeks = Some(weeks);
+                }
+                _ => {}
+            }
+        }
+        Ok(options)
+    }
+
+    fn enabled(&self, group: MetricGroup) -> bool {
+        self.collect.is_empty() || self.collect.contains(&group)
+    }
+}
+
+/// Decodes a URL query component: `+` as a space and `%XX` escapes.
+fn percent_decode(s: &str) -> Result<String, String> {
+    let invalid = || format!("Invalid escape in query parameter '{}'", s);
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut input = s.bytes();
+    while let Some(b) = input.next() {
+        match b {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let digits = [input.next(), input.next()];
+                let value = match digits {
+                    [Some(high), Some(low)] => char::from(high)
+                        .to_digit(16)
+                        .zip(char::from(low).to_digit(16))
+                        .map(|(high, low)| (high * 16 + low) as u8),
+                    _ => None,
+                };
+                bytes.push(value.ok_or_else(invalid)?);
+            }
+            _ => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+/// Collector for a single scrape, with specific options.
+#[derive(Debug)]
+pub struct ScrapeCollector {
+    pub collector: Arc<PhotoBacklogCollector>,
+    pub options: ScrapeOptions,
+}
+
+impl Collector for ScrapeCollector {
+    fn encode(&self, encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
+        self.collector.encode_with(encoder, &self.options)
+    }
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
@@ -51,228 +434,2892 @@ impl EncodeLabelValue for ItemType {
         EncodeLabelValue::encode(&s, encoder)
     }
 }
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct WorkerLabels {
+    worker: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct PhaseLabels {
+    phase: Phase,
+}
+
+/// Phases of a scrape, for the processing time.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+enum Phase {
+    /// Walking the tree, i.e. mostly waiting on the filesystem.
+    Walk,
+    /// Classifying and checking the entries.
+    Checks,
+    /// Aggregating the backlog files into totals, folders and histograms.
+    Aggregation,
+    /// Encoding the metrics.
+    Encode,
+}
+
+impl EncodeLabelValue for Phase {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> Result<(), std::fmt::Error> {
+        let s = match self {
+            Phase::Walk => "walk",
+            Phase::Checks => "checks",
+            Phase::Aggregation => "aggregation",
+            Phase::Encode => "encode",
+        };
+        EncodeLabelValue::encode(&s, encoder)
+    }
+}
+
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
 struct ErrorLabels {
     kind: super::ErrorType,
 }
 
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct SkipLabels {
+    reason: super::SkipReason,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct CheckErrorLabels {
+    kind: super::ErrorType,
+    check: super::Check,
+    target: super::CheckTarget,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct CacheLabels {
+    result: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct CameraLabels {
+    serial: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ExtLabels {
+    ext: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct FolderOwnerLabels {
+    path: String,
+    owner: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct FolderBucketLabels {
+    path: String,
+    le: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct OldestFileLabels {
+    path: String,
+    folder: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct QuantileLabels {
+    quantile: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ClassLabels {
+    class: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct PriorityLabels {
+    priority: String,
+}
+
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
 struct FolderLabels {
     path: String,
 }
 
 impl Collector for PhotoBacklogCollector {
-    fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
-        let instant = Instant::now(); // for this processor's execution time.
-        let now = SystemTime::now(); // for file age, which is seconds.
+    fn encode(&self, encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
+        self.encode_with(encoder, &ScrapeOptions::default())
+    }
+}
 
-        let config = super::Config {
-            root_path: &self.scan_path,
-            ignored_exts: &self.ignored_exts,
-            raw_exts: &self.raw_exts,
-            editable_exts: &self.editable_exts,
-            owner: self.owner,
-            group: self.group,
-            dir_mode: self.dir_mode,
-            raw_file_mode: self.raw_file_mode,
-            editable_file_mode: self.editable_file_mode,
+impl PhotoBacklogCollector {
+    /// Scans and encodes the metrics of the enabled sub-collectors,
+    /// restricted to the groups selected in the given options.
+    pub fn encode_with(
+        &self,
+        encoder: DescriptorEncoder,
+        options: &ScrapeOptions,
+    ) -> Result<(), std::fmt::Error> {
+        let (scheduled, scheduler_running) = self.wait_scheduled(FIRST_SCAN_WAIT);
+        let scrape = match scheduled {
+            Some(scrape) => ScrapeData {
+                encode_started: Instant::now(),
+                ..scrape
+            },
+            // The first background scan is still running: no data yet.
+            None if scheduler_running => self.last_scan_data(),
+            // Not scanning in the background.
+            None if self.needs_scan(options) => self.scan_data(options),
+            None => self.last_scan_data(),
         };
+        self.encode_scrape(encoder, options, &scrape)
+    }
 
-        let mut backlog = super::Backlog::new(self.age_buckets.iter().copied());
+    /// Returns the background scan's data, waiting up to the given time for
+    /// the first one, and whether scanning in the background at all.
+    fn wait_scheduled(&self, timeout: Duration) -> (Option<ScrapeData>, bool) {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self
+            .scheduled_done
+            .wait_timeout_while(state, timeout, |state| {
+                state.scheduler_running && state.scheduled.is_none()
+            })
+            .unwrap();
+        (state.scheduled.clone(), state.scheduler_running)
+    }
 
-        backlog.scan(&config, now);
+    /// Whether the scrape exports any group besides the self-metrics,
+    /// which don't need a new scan.
+    fn needs_scan(&self, options: &ScrapeOptions) -> bool {
+        MetricGroup::ALL.into_iter().any(|group| {
+            group != MetricGroup::SelfMetrics
+                && !self.disabled_collectors.contains(&group)
+                && options.enabled(group)
+        })
+    }
 
-        let totals_fam = Family::<TotalLabels, Gauge>::default();
-        let errors_fam = Family::<ErrorLabels, Gauge>::default();
-        let folder_sizes_fam = Family::<FolderLabels, Gauge>::default();
-        let folder_ages_fam = Family::<FolderLabels, Gauge<f64, AtomicU64>>::default();
+    /// Returns the data of the last good scan, or of an empty backlog
+    /// before the first one, without scanning.
+    fn last_scan_data(&self) -> ScrapeData {
+        let now = SystemTime::now();
+        let last_good = self.state.lock().unwrap().last_good.clone();
+        let (backlog, scan_time) =
+            last_good.unwrap_or_else(|| (Arc::new(self.new_backlog(now)), now));
+        ScrapeData {
+            backlog,
+            root_available: self.mock_data.is_some() || self.scan_path.is_dir(),
+            stale_age: None,
+            scan_time,
+            scan_aborted: false,
+            walk_time: Duration::ZERO,
+            checks_time: Duration::ZERO,
+            aggregation_time: Duration::ZERO,
+            io_wait_time: Duration::ZERO,
+            cache: self.cache_stats(),
+            encode_started: Instant::now(),
+            next_scan: None,
+        }
+    }
 
-        totals_fam
-            .get_or_create(&TotalLabels {
-                kind: ItemType::Photos,
-            })
-            .set(backlog.total_files);
-        totals_fam
-            .get_or_create(&TotalLabels {
-                kind: ItemType::Folders,
-            })
-            .set(
-                backlog
-                    .folders
-                    .len()
-                    .try_into()
-                    .expect("More than 2^63 entries in the map?!"),
-            );
+    /// Scans every `scan_interval`, varied by the jitter, keeping the
+    /// results for scrapes to serve; doesn't return if scanning on a
+    /// schedule.
+    pub fn run_scheduled_scans(&self) {
+        let Some(interval) = self.scan_interval else {
+            return;
+        };
+        self.state.lock().unwrap().scheduler_running = true;
+        loop {
+            let started = Instant::now();
+            let mut scrape = self.scan_data(&ScrapeOptions::default());
+            let duration = started.elapsed();
+            let delay = jittered(interval, self.scan_jitter, random_fraction());
+            // Not exported if out of the clock's range.
+            scrape.next_scan = SystemTime::now().checked_add(delay);
+            // Served before being announced, so that subscribers reacting
+            // to the announcement get the new data.
+            self.state.lock().unwrap().scheduled = Some(scrape.clone());
+            self.scheduled_done.notify_all();
+            self.announce_scan(&scrape, duration);
+            std::thread::sleep(delay);
+        }
+    }
 
-        for (kind, count) in &backlog.total_errors {
-            let labels = ErrorLabels { kind: *kind };
-            errors_fam.get_or_create(&labels).set(*count);
+    /// Scans now, outside of scrapes, also updating the data served if
+    /// scanning on a schedule; returns the served backlog.
+    pub fn scan_now(&self) -> Arc<super::Backlog> {
+        let started = Instant::now();
+        let mut scrape = self.scan_data(&ScrapeOptions::default());
+        let duration = started.elapsed();
+        {
+            let mut state = self.state.lock().unwrap();
+            if let Some(scheduled) = &state.scheduled {
+                scrape.next_scan = scheduled.next_scan;
+                state.scheduled = Some(scrape.clone());
+            }
         }
+        self.announce_scan(&scrape, duration);
+        scrape.backlog
+    }
 
-        for (path, (cnt, age)) in backlog.folders.drain() {
-            let labels = FolderLabels { path };
-            folder_sizes_fam.get_or_create(&labels).set(cnt);
-            folder_ages_fam.get_or_create(&labels).set(age);
+    /// Announces a background scan to the subscribers, if any.
+    #[cfg(feature = "daemon")]
+    fn announce_scan(&self, scrape: &ScrapeData, duration: Duration) {
+        if let Some(completions) = &self.scan_completions {
+            // Fails only if nobody is subscribed.
+            let _ = completions.send(ScanCompletion {
+                root: self.scan_path.clone(),
+                backlog: Arc::clone(&scrape.backlog),
+                failed: scrape.stale_age.is_some() || scrape.backlog.scan_failure.is_some(),
+                duration,
+            });
         }
+    }
 
-        let totals_encoder = encoder
-            .encode_descriptor(
-                "photo_backlog_counts",
-                "Number of items in the photo backlog",
-                None,
-                totals_fam.metric_type(),
-            )
-            .expect("create totals_encoder");
-
-        totals_fam
-            .encode(totals_encoder)
-            .expect("encode totals family");
-
-        let errors_encoder = encoder
-            .encode_descriptor(
-                "photo_backlog_errors",
-                "Number of errors in the photo backlog",
-                None,
-                totals_fam.metric_type(),
-            )
-            .expect("create errors_encoder");
-
-        errors_fam
-            .encode(errors_encoder)
-            .expect("encode errors family");
-
-        let folder_sizes_encoder = encoder
-            .encode_descriptor(
-                "photo_backlog_folder_sizes",
-                "Size of folders in the backlog",
-                None,
-                folder_sizes_fam.metric_type(),
-            )
-            .expect("create totals_encoder");
-
-        folder_sizes_fam
-            .encode(folder_sizes_encoder)
-            .expect("encode folder sizes");
-
-        let folder_ages_encoder = encoder
-            .encode_descriptor(
-                "photo_backlog_folder_ages",
-                "Per-folder picture-seconds backlog",
-                None,
-                folder_ages_fam.metric_type(),
-            )
-            .expect("create totals_encoder");
-
-        folder_ages_fam
-            .encode(folder_ages_encoder)
-            .expect("encode folder sizes");
-
-        let ages_histogram_encoder = encoder
-            .encode_descriptor(
-                "photo_backlog_ages",
-                "Age of files in the backlog",
-                None,
-                backlog.ages_histogram.metric_type(),
-            )
-            .expect("create ages_histogram_encoderr");
+    #[cfg(not(feature = "daemon"))]
+    fn announce_scan(&self, _scrape: &ScrapeData, _duration: Duration) {}
+
+    /// Scans (or reuses the last good scan) and returns the data to
+    /// encode for the given options.
+    fn scan_data(&self, options: &ScrapeOptions) -> ScrapeData {
+        // Monotonic, so that durations aren't affected by wall-clock jumps.
+        let started = Instant::now();
+        let now = SystemTime::now(); // for file age, which is seconds.
+
+        let tree_mtimes = if self.skip_unchanged && self.mock_data.is_none() {
+            super::tree_mtimes(&self.scan_path).ok()
+        } else {
+            None
+        };
+        if let Some(backlog) = self.unchanged_backlog(tree_mtimes.as_ref(), now) {
+            return ScrapeData {
+                backlog,
+                root_available: true,
+                stale_age: None,
+                scan_time: now,
+                scan_aborted: false,
+                walk_time: started.elapsed(),
+                checks_time: Duration::ZERO,
+                aggregation_time: Duration::ZERO,
+                io_wait_time: Duration::ZERO,
+                cache: self.cache_stats(),
+                encode_started: Instant::now(),
+                next_scan: None,
+            };
+        }
 
+        if let Some((backlog, scan_time)) = self.locked_out_backlog() {
+            return ScrapeData {
+                backlog,
+                root_available: true,
+                stale_age: None,
+                scan_time,
+                scan_aborted: false,
+                walk_time: Duration::ZERO,
+                checks_time: Duration::ZERO,
+                aggregation_time: Duration::ZERO,
+                io_wait_time: Duration::ZERO,
+                cache: self.cache_stats(),
+                encode_started: Instant::now(),
+                next_scan: None,
+            };
+        }
+
+        let root_available = self.mock_data.is_some() || self.scan_path.is_dir();
+        let backlog = if self.removable_root {
+            let mut state = self.state.lock().unwrap();
+            if state.root_missing == root_available {
+                if root_available {
+                    info!("Root '{}' is available again", self.scan_path.display());
+                } else {
+                    info!(
+                        "Root '{}' is not available, assuming media is detached",
+                        self.scan_path.display()
+                    );
+                }
+                state.root_missing = !root_available;
+            }
+            drop(state);
+            if root_available {
+                self.watched_scan(now, options)
+            } else {
+                // Not an error, there's just nothing to report.
+                self.new_backlog(now)
+            }
+        } else {
+            self.watched_scan(now, options)
+        };
+        // Timed before possibly switching to stale data, as it's this
+        // scrape's work that counts.
+        let scan_aborted = backlog.scan_aborted;
+        let checks_time = backlog.checks_time;
+        let aggregation_time = backlog.aggregation_time;
+        let io_wait_time = backlog.stat_time;
+        let walk_time = started
+            .elapsed()
+            .saturating_sub(checks_time + aggregation_time);
+
+        // If the scan failed as a whole (e.g. unmounted root), serve the
+        // last good data instead, flagged as stale.
+        let mut state = self.state.lock().unwrap();
+        let (backlog, stale_age, scan_time) = match (&backlog.scan_failure, &state.last_good) {
+            (None, _) => {
+                let backlog = Arc::new(backlog);
+                // Sampled scans can miss small folders, and detached media
+                // aren't cleared.
+                if root_available && self.sample.is_none() {
+                    let cleared = state
+                        .last_good
+                        .as_ref()
+                        .map_or(0, |(previous, _)| cleared_folders(previous, &backlog));
+                    state.folders_cleared += cleared;
+                }
+                // Scans without recently modified files don't tell
+                // anything either way.
+                if backlog.clock_skewed() {
+                    state.skewed_scans = state.skewed_scans.saturating_add(1);
+                } else if backlog.recent_files > 0 {
+                    state.skewed_scans = 0;
+                }
+                state.last_good = Some((Arc::clone(&backlog), now));
+                state.tree_mtimes = tree_mtimes;
+                (backlog, None, now)
+            }
+            (Some(failure), Some((last_good, scan_time))) => {
+                let age = now.duration_since(*scan_time).unwrap_or(Duration::ZERO);
+                warn!(
+                    "[scan {}] Scan failed ({}), serving stale data from {}s ago",
+                    backlog.scan_id,
+                    failure,
+                    age.as_secs()
+                );
+                (Arc::clone(last_good), Some(age), *scan_time)
+            }
+            (Some(_), None) => (Arc::new(backlog), None, now),
+        };
+        drop(state);
+
+        ScrapeData {
+            backlog,
+            root_available,
+            stale_age,
+            scan_time,
+            scan_aborted,
+            walk_time,
+            checks_time,
+            aggregation_time,
+            io_wait_time,
+            cache: self.cache_stats(),
+            encode_started: Instant::now(),
+            next_scan: None,
+        }
+    }
+
+    /// Returns the last good scan if the tree didn't change since, marking
+    /// it as current.
+    fn unchanged_backlog(
+        &self,
+        tree_mtimes: Option<&Vec<(OsString, SystemTime)>>,
+        now: SystemTime,
+    ) -> Option<Arc<super::Backlog>> {
+        let tree_mtimes = tree_mtimes?;
+        let mut state = self.state.lock().unwrap();
+        let unchanged = state.tree_mtimes.as_ref() == Some(tree_mtimes);
+        let backlog = match (unchanged, &mut state.last_good) {
+            (true, Some((backlog, scan_time))) => {
+                *scan_time = now;
+                Some(Arc::clone(backlog))
+            }
+            _ => None,
+        };
+        if backlog.is_some() {
+            state.cache_hits += 1;
+        } else {
+            state.cache_misses += 1;
+        }
         backlog
-            .ages_histogram
-            .encode(ages_histogram_encoder)
-            .expect("encode ages_histogram");
-
-        let elapsed_gauge = ConstGauge::new(instant.elapsed().as_secs_f64());
-        let elapsed_encoder = encoder
-            .encode_descriptor(
-                PROCESSING_TIME_NAME,
-                PROCESSING_TIME_HELP,
-                None,
-                elapsed_gauge.metric_type(),
-            )
-            .expect("register gauge");
-        elapsed_gauge
-            .encode(elapsed_encoder)
-            .expect("encode elapsed");
+    }
+
+    /// Returns the scan cache statistics, if skipping unchanged scans.
+    fn cache_stats(&self) -> Option<CacheStats> {
+        if !self.skip_unchanged {
+            return None;
+        }
+        let state = self.state.lock().unwrap();
+        let tree_mtimes = state.tree_mtimes.as_deref().unwrap_or_default();
+        let mtimes_memory: usize = tree_mtimes
+            .iter()
+            .map(|(name, _)| name.len() + std::mem::size_of::<(OsString, SystemTime)>())
+            .sum();
+        let backlog_memory = state
+            .last_good
+            .as_ref()
+            .map_or(0, |(backlog, _)| backlog.approx_memory());
+        Some(CacheStats {
+            hits: state.cache_hits,
+            misses: state.cache_misses,
+            directories: tree_mtimes.len(),
+            memory: mtimes_memory + backlog_memory,
+        })
+    }
+
+    /// Encodes the metrics of the enabled and selected sub-collectors.
+    fn encode_scrape(
+        &self,
+        encoder: DescriptorEncoder,
+        options: &ScrapeOptions,
+        scrape: &ScrapeData,
+    ) -> Result<(), std::fmt::Error> {
+        let mut encoder = MetricEncoder {
+            inner: encoder,
+            aliases: self.metric_aliases,
+            max_age: options.max_age_weeks.map(|weeks| weeks * buckets::WEEK),
+        };
+        encode_metric(
+            &mut encoder,
+            "photo_backlog_schema_version",
+            "Version of the exporter's metric names and labels, bumped on incompatible changes",
+            &ConstGauge::new(SCHEMA_VERSION),
+        )?;
+        for group in MetricGroup::ALL {
+            if self.disabled_collectors.contains(&group) || !options.enabled(group) {
+                continue;
+            }
+            group.sub_collector()(self, scrape, &mut encoder)?;
+        }
         Ok(())
     }
-}
 
-pub fn encode_to_text(collector: PhotoBacklogCollector) -> Result<String, std::fmt::Error> {
-    let mut registry = Registry::default();
-    registry.register_collector(Box::new(collector));
-    let mut buffer = String::new();
-    encode(&mut buffer, &registry).and(Ok(buffer))
-}
+    /// Returns a collector for another root, with the same settings but
+    /// its own state, e.g. its own last good scan.
+    pub fn for_root(&self, root: PathBuf) -> Self {
+        PhotoBacklogCollector {
+            scan_path: root,
+            state: Default::default(),
+            scheduled_done: Default::default(),
+            ..self.clone()
+        }
+    }
 
-#[cfg(test)]
-mod tests {
-    use std::ffi::OsString;
+    /// Returns the last good scan and its time if another scanner
+    /// currently holds the scan lock, instead of waiting for it.
+    fn locked_out_backlog(&self) -> Option<(Arc<super::Backlog>, SystemTime)> {
+        let path = self.scan_lock.as_ref()?;
+        let last_good = self.state.lock().unwrap().last_good.clone()?;
+        match super::scan_lock::ScanLock::try_acquire(path) {
+            Ok(None) => {
+                info!(
+                    "Another scan holds '{}', serving the last good scan",
+                    path.display()
+                );
+                Some(last_good)
+            }
+            // Released right away, scan_root takes it again.
+            Ok(Some(_)) | Err(_) => None,
+        }
+    }
 
-    use rstest::rstest;
-    use tempfile::tempdir;
+    /// Takes the scan lock, if any, waiting for other scanners; if it
+    /// can't be used, scans without it.
+    fn lock_scan(&self) -> Option<super::scan_lock::ScanLock> {
+        let path = self.scan_lock.as_ref()?;
+        let result = match super::scan_lock::ScanLock::try_acquire(path) {
+            Ok(None) => {
+                info!("Waiting for another scan holding '{}'", path.display());
+                super::scan_lock::ScanLock::acquire(path)
+            }
+            Ok(Some(lock)) => Ok(lock),
+            Err(e) => Err(e),
+        };
+        result.map_err(|e| warn!("{}, scanning without it", e)).ok()
+    }
 
-    extern crate speculoos;
-    use speculoos::prelude::*;
+    /// Scans the given root with this collector's settings.
+    pub fn scan_root(&self, root: &Path, now: SystemTime) -> super::Backlog {
+        self.scan_root_with(root, now, None, &ScrapeOptions::default())
+    }
 
-    /// Runs the collector with a variety of folder configurations and
-    /// checks that the encoding contains a few expected values.
-    /// Note not all encoded values are tested.
-    #[rstest]
-    #[case::empty_dir([0].to_vec())]
-    #[case::one_dir_one_file([1].to_vec())]
-    #[case::two_dirs_one_two([1, 2].to_vec())]
-    #[case::three_dirs_one_zero_two([1, 0, 2].to_vec())]
-    fn test_backlog_encoding(#[case] folders_config: Vec<i32>) {
-        fn format_dir(pos: usize) -> String {
-            format!("dir-{}", pos)
+    /// Scans the root in a separate thread, cancelling it if it exceeds
+    /// the watchdog; a cancelled scan only stops at the next entry, so
+    /// a scan blocked in the kernel keeps its thread until it returns.
+    fn watched_scan(&self, now: SystemTime, options: &ScrapeOptions) -> super::Backlog {
+        let Some(limit) = self.scan_watchdog else {
+            return self.scan_root_with(&self.scan_path, now, None, options);
+        };
+        let failed = |message: String| {
+            let mut backlog = self.new_backlog(now);
+            backlog.scan_failure = Some(message);
+            backlog
+        };
+        if self.state.lock().unwrap().scan_stuck {
+            return failed(format!(
+                "A cancelled scan of '{}' is still running",
+                self.scan_path.display()
+            ));
         }
-        let temp_dir = tempdir().unwrap();
-        for (pos, folder_size) in folders_config.iter().enumerate() {
-            let folder = temp_dir.path().join(format_dir(pos));
-            std::fs::create_dir(&folder).unwrap();
-            for i in 0..*folder_size {
-                let file = folder.join(format!("{}.nef", i));
-                std::fs::File::create(&file).unwrap();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = mpsc::channel();
+        let collector = self.clone();
+        let thread_cancel = Arc::clone(&cancel);
+        let options = options.clone();
+        std::thread::spawn(move || {
+            let backlog =
+                collector.scan_root_with(&collector.scan_path, now, Some(&thread_cancel), &options);
+            // Sent under the lock, so that the watchdog either gets the
+            // result or has already marked the scan as stuck.
+            let mut state = collector.state.lock().unwrap();
+            state.scan_stuck = false;
+            let _ = sender.send(backlog);
+        });
+        if let Ok(backlog) = receiver.recv_timeout(limit) {
+            return backlog;
+        }
+        let mut state = self.state.lock().unwrap();
+        if let Ok(backlog) = receiver.try_recv() {
+            return backlog;
+        }
+        cancel.store(true, Ordering::Relaxed);
+        state.scan_stuck = true;
+        state.watchdog_fired += 1;
+        drop(state);
+        let message = format!(
+            "Scan of '{}' exceeded the watchdog of {:?}, cancelling it",
+            self.scan_path.display(),
+            limit
+        );
+        warn!("{}", message);
+        failed(message)
+    }
+
+    /// Scans the given root, stopping early once the cancel flag is set.
+    fn scan_root_with(
+        &self,
+        root: &Path,
+        now: SystemTime,
+        cancel: Option<&AtomicBool>,
+        options: &ScrapeOptions,
+    ) -> super::Backlog {
+        let _lock = self.lock_scan();
+        let scan_id = self.next_scan_id(now);
+        let mut backlog = self.with_config(root, scan_id, cancel, options, |config| {
+            let mut backlog = self.new_backlog(now);
+            backlog.scan_id = scan_id;
+            match self.mock_data {
+                Some(mock) => backlog.fill_mock(mock),
+                None => backlog.scan(config, now),
             }
+            backlog
+        });
+        if let Some(log) = &self.violations_log {
+            log.submit(scan_id, &backlog.violations);
         }
-        let collector = super::PhotoBacklogCollector {
-            scan_path: temp_dir.path().to_path_buf(),
-            ignored_exts: vec![],
-            raw_exts: vec![OsString::from("nef")],
-            editable_exts: vec![],
-            age_buckets: vec![1.0],
-            owner: None,
-            group: None,
-            dir_mode: None,
-            raw_file_mode: None,
-            editable_file_mode: None,
+        if !self.keep_violations {
+            backlog.violations = Vec::new();
+        }
+        backlog
+    }
+
+    /// Calls the given function with the scan configuration for the given
+    /// root, as per this collector's options and extension lists, and the
+    /// groups the scrape exports.
+    fn with_config<R>(
+        &self,
+        root: &Path,
+        scan_id: u64,
+        cancel: Option<&AtomicBool>,
+        options: &ScrapeOptions,
+        f: impl FnOnce(&super::Config) -> R,
+    ) -> R {
+        let exts = self.extension_lists();
+        let ignored_exts = super::exts::or_default(&exts.ignored, &self.ignored_exts);
+        let raw_exts = super::exts::or_default(&exts.raw, &self.raw_exts);
+        let editable_exts = super::exts::or_default(&exts.editable, &self.editable_exts);
+        let enabled = |check| !self.disabled_checks.contains(&check);
+        // The ages of the files are only kept if the scrape exports them,
+        // unless the scan can be served to later scrapes.
+        let reused = self.skip_unchanged || self.scan_lock.is_some();
+        let keep = |group| reused || options.enabled(group);
+        let mode_classes = if enabled(super::Check::ClassModes) {
+            exts.mode_classes()
+        } else {
+            Vec::new()
         };
-        let buffer = super::encode_to_text(collector).unwrap();
+        let config = super::Config {
+            root_path: root,
+            ignored_exts: &ignored_exts,
+            raw_exts: &raw_exts,
+            editable_exts: &editable_exts,
+            companion_exts: &self.companion_exts,
+            archive_exts: &self.archive_exts,
+            owner: self.owner.filter(|_| enabled(super::Check::Owner)),
+            group: self.group.filter(|_| enabled(super::Check::Group)),
+            dir_mode: self.dir_mode.filter(|_| enabled(super::Check::DirMode)),
+            raw_file_mode: self
+                .raw_file_mode
+                .filter(|_| enabled(super::Check::RawMode)),
+            editable_file_mode: self
+                .editable_file_mode
+                .filter(|_| enabled(super::Check::EditableMode)),
+            mode_classes: &mode_classes,
+            folder_memory_budget: self.folder_memory_budget,
+            no_extension: self.no_extension,
+            special_files_as_errors: self.special_files_as_errors,
+            symlinks: self.symlinks,
+            max_path_length: self.max_path_length,
+            max_depth: self.max_depth,
+            scan_threads: self.scan_threads,
+            required_subdirs: if enabled(super::Check::Structure) {
+                &self.required_subdirs
+            } else {
+                &[]
+            },
+            immutable_subpath: self
+                .immutable_subpath
+                .as_deref()
+                .filter(|_| enabled(super::Check::Immutable)),
+            max_files: self.max_files,
+            track_owners: self.folder_owner_metrics,
+            camera_serials: self.camera_serials,
+            sample: self.sample,
+            record_violations: self.violations_log.is_some() || self.keep_violations,
+            scan_id,
+            largest_files: self.largest_files,
+            cancel,
+            folder_tag_keys: &self.folder_tag_keys,
+            clock_skew_tolerance: self
+                .clock_skew_tolerance
+                .unwrap_or(super::DEFAULT_CLOCK_SKEW_TOLERANCE),
+            age_calendar: self.age_calendar.as_ref(),
+            folder_details: self.folder_details,
+            keep_ages: self.age_metrics != AgeMetrics::Histogram && keep(MetricGroup::Ages),
+            keep_folder_ages: keep(MetricGroup::Folders),
+        };
+        f(&config)
+    }
 
-        // Now check the encoded values.
-        let total_photos = folders_config.iter().sum::<i32>();
-        let photos_string = format!("photo_backlog_counts{{kind=\"photos\"}} {}", total_photos);
-        assert_that(&buffer).contains(&photos_string);
-        let folder_string = format!(
-            "photo_backlog_counts{{kind=\"folders\"}} {}",
-            folders_config.iter().filter(|x| **x > 0).count()
+    /// Applies files added or removed under the root since the last
+    /// background scan to the data served, until the next scan replaces
+    /// it; see `Backlog::apply_changes`.
+    pub fn apply_changes(&self, changes: &[super::FileChange]) {
+        let now = SystemTime::now();
+        self.with_config(
+            &self.scan_path,
+            0,
+            None,
+            &ScrapeOptions::default(),
+            |config| {
+                // Before the first scan, there's nothing to update.
+                let Some(served) = self
+                    .state
+                    .lock()
+                    .unwrap()
+                    .scheduled
+                    .as_ref()
+                    .map(|s| Arc::clone(&s.backlog))
+                else {
+                    return;
+                };
+                // Copying a large backlog takes a while, so it's done
+                // without holding the lock that the scrapes need.
+                let mut backlog = super::Backlog::clone(&served);
+                backlog.apply_changes(config, now, changes);
+                let mut state = self.state.lock().unwrap();
+                match state.scheduled.as_mut() {
+                    Some(scheduled) if Arc::ptr_eq(&scheduled.backlog, &served) => {
+                        scheduled.backlog = Arc::new(backlog);
+                    }
+                    // A scan replaced the data meanwhile, maybe already
+                    // with these changes.
+                    _ => info!("Dropping {} changes, superseded by a scan", changes.len()),
+                }
+            },
         );
-        assert!(buffer.contains(&folder_string));
-        for (pos, folder_size) in folders_config.iter().enumerate() {
-            if *folder_size == 0 {
-                continue;
+    }
+
+    /// Returns a new scan id: the scan's start time in Unix milliseconds,
+    /// bumped if needed so that ids are unique and increasing, also
+    /// across restarts.
+    fn next_scan_id(&self, now: SystemTime) -> u64 {
+        let millis = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_millis() as u64;
+        let mut state = self.state.lock().unwrap();
+        state.last_scan_id = millis.max(state.last_scan_id + 1);
+        state.last_scan_id
+    }
+
+    /// Returns the extension lists from the extensions file, if any,
+    /// falling back to the last good ones if it can't be loaded.
+    fn extension_lists(&self) -> super::exts::ExtensionLists {
+        let Some(path) = &self.exts_file else {
+            return Default::default();
+        };
+        let mut state = self.state.lock().unwrap();
+        match super::exts::ExtensionLists::load(path) {
+            Ok(lists) => {
+                state.exts = lists.clone();
+                lists
+            }
+            Err(e) => {
+                warn!("{}, using the last good extension lists", e);
+                state.exts.clone()
             }
-            let folder_string = format!(
-                "photo_backlog_folder_sizes{{path=\"{}\"}} {}",
-                format_dir(pos),
-                folder_size
-            );
-            assert_that(&buffer).contains(&folder_string);
         }
-        assert_that!(buffer).contains("photo_backlog_processing_time_seconds ");
-        let ages_string = format!("photo_backlog_ages_count {}", total_photos);
-        assert_that!(buffer).contains(ages_string);
-        assert_that!(buffer).contains("photo_backlog_errors{kind=\"scan\"} 0");
-        assert_that!(buffer).contains("photo_backlog_errors{kind=\"ownership\"} 0");
+    }
+
+    /// Returns an empty backlog with this collector's histogram buckets,
+    /// calendar-aligned ones being relative to the given time.
+    fn new_backlog(&self, now: SystemTime) -> super::Backlog {
+        let raw_buckets = self.raw_age_buckets.as_ref().unwrap_or(&self.age_buckets);
+        let editable_buckets = self
+            .editable_age_buckets
+            .as_ref()
+            .unwrap_or(&self.age_buckets);
+        super::Backlog::new(buckets::nominal(&self.age_buckets).into_iter())
+            .with_class_buckets(
+                buckets::nominal(raw_buckets).into_iter(),
+                buckets::nominal(editable_buckets).into_iter(),
+            )
+            .with_age_scales(
+                AgeScale::new(&self.age_buckets, now),
+                AgeScale::new(raw_buckets, now),
+                AgeScale::new(editable_buckets, now),
+            )
+    }
+
+    /// Whether folders are grouped by priority, from their tags or the
+    /// priorities rules.
+    fn priorities_enabled(&self) -> bool {
+        self.folder_priorities.is_some() || self.folder_tag_keys.iter().any(|k| k == PRIORITY_TAG)
+    }
+
+    /// Returns the priority of the given folder: its priority tag if any,
+    /// else the first matching priorities rule, else `NO_PRIORITY`.
+    fn folder_priority(&self, backlog: &super::Backlog, folder: &str) -> String {
+        if let Some(priority) = backlog
+            .folder_tags
+            .get(folder)
+            .and_then(|tags| tags.get(PRIORITY_TAG))
+        {
+            return priority.clone();
+        }
+        self.folder_priorities
+            .as_ref()
+            .and_then(|rules| rules.matching(folder))
+            .map_or_else(|| NO_PRIORITY.to_string(), |p| p.into_owned())
+    }
+
+    /// Whether the given folder gets its own labeled series; filtered out
+    /// folders are still accounted in the totals.
+    fn folder_exported(&self, folder: &str) -> bool {
+        self.folder_allow
+            .as_ref()
+            .is_none_or(|re| re.is_match(folder))
+            && !self
+                .folder_deny
+                .as_ref()
+                .is_some_and(|re| re.is_match(folder))
+    }
+}
+
+/// The data of a single scrape, shared by the sub-collectors.
+#[derive(Clone, Debug)]
+struct ScrapeData {
+    backlog: Arc<super::Backlog>,
+    root_available: bool,
+    /// The age of the served data, if the scan failed and it's stale.
+    stale_age: Option<Duration>,
+    /// When the scan of the served data started.
+    scan_time: SystemTime,
+    /// Whether this scrape's scan was aborted due to too many entries.
+    scan_aborted: bool,
+    /// How long walking the tree took, dominated by the filesystem.
+    walk_time: Duration,
+    /// How long the walk waited on stat calls, summed over its threads.
+    io_wait_time: Duration,
+    /// How long classifying and checking the entries took.
+    checks_time: Duration,
+    /// How long aggregating the backlog files took.
+    aggregation_time: Duration,
+    /// The scan cache statistics, if skipping unchanged scans.
+    cache: Option<CacheStats>,
+    /// When encoding the metrics started.
+    encode_started: Instant,
+    /// When the next background scan is due, if scanning on a schedule.
+    next_scan: Option<SystemTime>,
+}
+
+/// Varies the interval by up to the given fraction of it, up or down,
+/// based on a random number in `[0, 1)`; the interval is kept as is if
+/// the varied one would overflow.
+/// Example:
+/// ```
+/// use std::time::Duration;
+/// use photo_backlog_exporter::prometheus::jittered;
+/// let interval = Duration::from_secs(600);
+/// assert_eq!(jittered(interval, 0.1, 0.0), Duration::from_secs(540));
+/// assert_eq!(jittered(interval, 0.1, 0.5), interval);
+/// assert_eq!(jittered(interval, 0.0, 0.9), interval);
+/// assert_eq!(jittered(Duration::MAX, 1.0, 0.9), Duration::MAX);
+/// ```
+pub fn jittered(interval: Duration, jitter: f64, random: f64) -> Duration {
+    let factor = 1.0 + jitter * (2.0 * random - 1.0);
+    Duration::try_from_secs_f64(interval.as_secs_f64() * factor).unwrap_or(interval)
+}
+
+/// Returns a random number in `[0, 1)`, from the randomly keyed std hasher,
+/// which is plenty for spreading scans.
+fn random_fraction() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Statistics of reusing the last good scan for unchanged trees.
+#[derive(Clone, Copy, Debug)]
+struct CacheStats {
+    hits: u64,
+    misses: u64,
+    /// Number of directories whose mtimes are tracked.
+    directories: usize,
+    /// Approximate memory used by the tracked mtimes and the cached scan.
+    memory: usize,
+}
+
+type SubCollector =
+    fn(&PhotoBacklogCollector, &ScrapeData, &mut MetricEncoder) -> Result<(), std::fmt::Error>;
+
+/// The descriptor encoder, with the metric aliases to emit and the
+/// scrape's largest age bucket, in seconds.
+struct MetricEncoder<'a> {
+    inner: DescriptorEncoder<'a>,
+    aliases: &'static [(&'static str, &'static str)],
+    max_age: Option<f64>,
+}
+
+fn encode_counts(
+    collector: &PhotoBacklogCollector,
+    scrape: &ScrapeData,
+    encoder: &mut MetricEncoder,
+) -> Result<(), std::fmt::Error> {
+    let totals_fam = Family::<TotalLabels, Gauge>::default();
+    totals_fam
+        .get_or_create(&TotalLabels {
+            kind: ItemType::Photos,
+        })
+        .set(scrape.backlog.total_files);
+    totals_fam
+        .get_or_create(&TotalLabels {
+            kind: ItemType::Folders,
+        })
+        .set(
+            scrape
+                .backlog
+                .folder_count()
+                .try_into()
+                .expect("More than 2^63 entries in the map?!"),
+        );
+    encode_metric(
+        encoder,
+        "photo_backlog_counts",
+        "Number of items in the photo backlog",
+        &totals_fam,
+    )?;
+    let sizes_fam = Family::<ClassLabels, Gauge<f64, AtomicU64>>::default();
+    for (class, (cnt, bytes)) in &scrape.backlog.class_sizes {
+        if *cnt > 0 {
+            sizes_fam
+                .get_or_create(&ClassLabels {
+                    class: class.clone(),
+                })
+                .set(*bytes as f64 / *cnt as f64);
+        }
+    }
+    encode_metric(
+        encoder,
+        "photo_backlog_file_size_mean_bytes",
+        "Mean size of backlog files per class: the mode class if any (e.g. video), else raw or editable",
+        &sizes_fam,
+    )?;
+    let folders_cleared = collector.state.lock().unwrap().folders_cleared;
+    encode_metric(
+        encoder,
+        "photo_backlog_folders_cleared",
+        "Number of folders with files in a scan that were empty or gone in the next one",
+        &ConstCounter::new(folders_cleared),
+    )?;
+    encode_metric(
+        encoder,
+        "photo_backlog_sample_ratio",
+        "Fraction of the files sampled, with the counts scaled up accordingly, or 1 if not sampling",
+        &ConstGauge::new(scrape.backlog.sample_ratio.unwrap_or(1.0)),
+    )?;
+    encode_metric(
+        encoder,
+        "photo_backlog_ignored_files",
+        "Number of files skipped due to the ignored extensions (or lack of extension, if ignored)",
+        &ConstGauge::new(scrape.backlog.ignored_files.values().sum::<i64>()),
+    )?;
+    if collector.ignored_files_per_ext {
+        let ignored_fam = Family::<ExtLabels, Gauge>::default();
+        for (ext, count) in &scrape.backlog.ignored_files {
+            ignored_fam
+                .get_or_create(&ExtLabels { ext: ext.clone() })
+                .set(*count);
+        }
+        encode_metric(
+            encoder,
+            "photo_backlog_ignored_files_per_ext",
+            "Number of files skipped due to the ignore rules, per extension (empty if none)",
+            &ignored_fam,
+        )?;
+    }
+    if collector.priorities_enabled() {
+        let files_fam = Family::<PriorityLabels, Gauge>::default();
+        let ages_fam = Family::<PriorityLabels, Gauge<f64, AtomicU64>>::default();
+        for (folder, (cnt, age)) in &scrape.backlog.folders {
+            let labels = PriorityLabels {
+                priority: collector.folder_priority(&scrape.backlog, folder),
+            };
+            files_fam.get_or_create(&labels).inc_by(*cnt);
+            ages_fam.get_or_create(&labels).inc_by(*age);
+        }
+        encode_metric(
+            encoder,
+            "photo_backlog_files_by_priority",
+            "Number of files in the backlog per folder priority (tag or --folder-priorities-file)",
+            &files_fam,
+        )?;
+        encode_metric(
+            encoder,
+            "photo_backlog_ages_by_priority",
+            "Picture-seconds backlog per folder priority",
+            &ages_fam,
+        )?;
+    }
+    if collector.camera_serials {
+        let cameras_fam = Family::<CameraLabels, Gauge>::default();
+        for (serial, count) in &scrape.backlog.camera_files {
+            cameras_fam
+                .get_or_create(&CameraLabels {
+                    serial: serial.clone(),
+                })
+                .set(*count);
+        }
+        encode_metric(
+            encoder,
+            "photo_backlog_camera_files",
+            "Number of files in the backlog per camera serial number",
+            &cameras_fam,
+        )?;
+    }
+    Ok(())
+}
+
+fn encode_errors(
+    _collector: &PhotoBacklogCollector,
+    scrape: &ScrapeData,
+    encoder: &mut MetricEncoder,
+) -> Result<(), std::fmt::Error> {
+    let errors_fam = Family::<ErrorLabels, Gauge>::default();
+    for (kind, count) in &scrape.backlog.total_errors {
+        let labels = ErrorLabels { kind: *kind };
+        errors_fam.get_or_create(&labels).set(*count);
+    }
+    encode_metric(
+        encoder,
+        "photo_backlog_errors",
+        "Number of errors in the photo backlog",
+        &errors_fam,
+    )?;
+    let check_errors_fam = Family::<CheckErrorLabels, Gauge>::default();
+    for ((check, target), count) in &scrape.backlog.check_errors {
+        let labels = CheckErrorLabels {
+            kind: check.error_type(),
+            check: *check,
+            target: *target,
+        };
+        check_errors_fam.get_or_create(&labels).set(*count);
+    }
+    encode_metric(
+        encoder,
+        "photo_backlog_future_dated_files",
+        "Number of backlog files modified in the future, e.g. due to a wrong camera clock",
+        &ConstGauge::new(scrape.backlog.future_dated_files),
+    )?;
+    encode_metric(
+        encoder,
+        "photo_backlog_unreadable_dirs",
+        "Number of directories that couldn't be read due to permissions, each counted as a single scan error",
+        &ConstGauge::new(scrape.backlog.unreadable_dirs.len() as i64),
+    )?;
+    encode_metric(
+        encoder,
+        "photo_backlog_check_errors",
+        "Number of ownership and permissions errors, by failed check and directory or file target",
+        &check_errors_fam,
+    )
+}
+
+fn encode_folders(
+    collector: &PhotoBacklogCollector,
+    scrape: &ScrapeData,
+    encoder: &mut MetricEncoder,
+) -> Result<(), std::fmt::Error> {
+    // Per-folder families expose folder names, so they can be turned off.
+    if collector.no_folder_metrics {
+        return Ok(());
+    }
+    let folder_sizes_fam = Family::<FolderLabels, Gauge>::default();
+    let folder_ages_fam = Family::<FolderLabels, Gauge<f64, AtomicU64>>::default();
+    let folder_special_fam = Family::<FolderLabels, Gauge>::default();
+    let folder_medians_fam = Family::<FolderLabels, Gauge<f64, AtomicU64>>::default();
+    let folder_means_fam = Family::<FolderLabels, Gauge<f64, AtomicU64>>::default();
+    let folder_ranks_fam = Family::<FolderLabels, Gauge>::default();
+    let labels = |path: &str| FolderLabels {
+        path: collector.folder_relabel.apply(path).into_owned(),
+    };
+
+    // Relabeling can map several folders to the same label, so sum them.
+    let mut label_totals: HashMap<FolderLabels, (i64, f64)> = HashMap::new();
+    for (path, (cnt, age)) in &scrape.backlog.folders {
+        if !collector.folder_exported(path) {
+            continue;
+        }
+        let totals = label_totals.entry(labels(path)).or_default();
+        totals.0 += cnt;
+        totals.1 += age;
+    }
+    for (labels, (cnt, age)) in &label_totals {
+        folder_sizes_fam.get_or_create(labels).set(*cnt);
+        folder_ages_fam.get_or_create(labels).set(*age);
+        if *cnt > 0 {
+            folder_means_fam
+                .get_or_create(labels)
+                .set(*age / *cnt as f64);
+        }
+    }
+    // Medians can't be summed, so compute them over the merged ages.
+    let mut label_ages: HashMap<FolderLabels, Vec<f64>> = HashMap::new();
+    for (path, ages) in &scrape.backlog.folder_ages {
+        if collector.folder_exported(path) {
+            label_ages.entry(labels(path)).or_default().extend(ages);
+        }
+    }
+    let mut oldest = Vec::with_capacity(label_ages.len());
+    for (labels, mut ages) in label_ages {
+        ages.sort_by(f64::total_cmp);
+        if let Some(median) = super::quantile(&ages, 0.5) {
+            folder_medians_fam.get_or_create(&labels).set(median);
+        }
+        if let Some(age) = ages.last() {
+            oldest.push((*age, labels));
+        }
+    }
+    // First in, first out: the folder with the oldest file first, ties
+    // broken by name so that the order doesn't change between scrapes.
+    oldest
+        .sort_by(|(a_age, a), (b_age, b)| b_age.total_cmp(a_age).then_with(|| a.path.cmp(&b.path)));
+    for (rank, (_, labels)) in oldest.iter().enumerate() {
+        folder_ranks_fam.get_or_create(labels).set(rank as i64 + 1);
+    }
+    for (path, cnt) in &scrape.backlog.special_files {
+        if collector.folder_exported(path) {
+            folder_special_fam.get_or_create(&labels(path)).inc_by(*cnt);
+        }
+    }
+
+    encode_metric(
+        encoder,
+        "photo_backlog_folder_sizes",
+        "Size of folders in the backlog",
+        &folder_sizes_fam,
+    )?;
+    encode_metric(
+        encoder,
+        "photo_backlog_folder_ages",
+        "Per-folder picture-seconds backlog",
+        &folder_ages_fam,
+    )?;
+    encode_metric(
+        encoder,
+        "photo_backlog_folder_completeness",
+        "Per-folder ratio of editable to raw files, capped at 1, as an approximation of progress",
+        &folder_completeness_family(collector, &scrape.backlog),
+    )?;
+    encode_metric(
+        encoder,
+        "photo_backlog_folder_age_median_seconds",
+        "Per-folder median age of files",
+        &folder_medians_fam,
+    )?;
+    encode_metric(
+        encoder,
+        "photo_backlog_folder_age_mean_seconds",
+        "Per-folder mean age of files, i.e. folder ages divided by folder sizes",
+        &folder_means_fam,
+    )?;
+    encode_metric(
+        encoder,
+        "photo_backlog_folder_created_rank",
+        "Per-folder position in the backlog queue by oldest file, the folder with the oldest file being 1",
+        &folder_ranks_fam,
+    )?;
+    if collector.folder_owner_metrics {
+        encode_metric(
+            encoder,
+            "photo_backlog_folder_owner_files",
+            "Per-folder and owner number of files",
+            &folder_owner_family(collector, &scrape.backlog),
+        )?;
+    }
+    if collector.folder_age_bucket_metrics {
+        encode_metric(
+            encoder,
+            "photo_backlog_folder_age_bucket_files",
+            "Per-folder number of files at most as old as the age bucket's upper bound",
+            &folder_age_bucket_family(collector, &scrape.backlog),
+        )?;
+    }
+    if collector.oldest_file_metric {
+        let oldest_fam = Family::<OldestFileLabels, Gauge>::default();
+        if let Some((path, folder, _)) = &scrape.backlog.oldest_file {
+            if collector.folder_exported(folder) {
+                let labels = OldestFileLabels {
+                    path: path.to_string_lossy().into_owned(),
+                    folder: collector.folder_relabel.apply(folder).into_owned(),
+                };
+                oldest_fam.get_or_create(&labels).set(1);
+            }
+        }
+        encode_metric(
+            encoder,
+            "photo_backlog_oldest_file_info",
+            "The oldest file in the backlog, relative to the root",
+            &oldest_fam,
+        )?;
+    }
+    if !collector.folder_tag_keys.is_empty() {
+        let tags_fam = Family::<Vec<(String, String)>, Gauge>::default();
+        for (folder, tags) in &scrape.backlog.folder_tags {
+            if !scrape.backlog.folders.contains_key(folder) || !collector.folder_exported(folder) {
+                continue;
+            }
+            let mut labels = vec![(
+                "path".to_string(),
+                collector.folder_relabel.apply(folder).into_owned(),
+            )];
+            labels.extend(tags.iter().map(|(k, v)| (k.clone(), v.clone())));
+            tags_fam.get_or_create(&labels).set(1);
+        }
+        encode_metric(
+            encoder,
+            "photo_backlog_folder_tags_info",
+            "Tags of backlog folders, from their .backlog-tags file, for joining on path",
+            &tags_fam,
+        )?;
+    }
+    if collector.special_files_as_errors {
+        encode_metric(
+            encoder,
+            "photo_backlog_folder_special_files",
+            "Per-folder number of special files (FIFOs, sockets, devices)",
+            &folder_special_fam,
+        )?;
+    }
+    Ok(())
+}
+
+/// Returns the number of folders with files in the previous scan which
+/// are empty or gone in the current one; none if either scan exceeded the
+/// folder memory budget, as folders missing from it can still have files.
+fn cleared_folders(previous: &super::Backlog, current: &super::Backlog) -> u64 {
+    if previous.untracked_files > 0 || current.untracked_files > 0 {
+        return 0;
+    }
+    previous
+        .folders
+        .iter()
+        .filter(|(folder, (cnt, _))| {
+            *cnt > 0
+                && current
+                    .folders
+                    .get(*folder)
+                    .is_none_or(|(cnt, _)| *cnt == 0)
+        })
+        .count() as u64
+}
+
+/// Builds the per-folder completeness family, from the raw and editable
+/// counts summed per label; folders without raw files are complete.
+fn folder_completeness_family(
+    collector: &PhotoBacklogCollector,
+    backlog: &super::Backlog,
+) -> Family<FolderLabels, Gauge<f64, AtomicU64>> {
+    let mut counts: HashMap<FolderLabels, (i64, i64)> = HashMap::new();
+    for (path, (raw, editable)) in &backlog.folder_kinds {
+        if !collector.folder_exported(path) {
+            continue;
+        }
+        let labels = FolderLabels {
+            path: collector.folder_relabel.apply(path).into_owned(),
+        };
+        let entry = counts.entry(labels).or_insert((0, 0));
+        entry.0 += raw;
+        entry.1 += editable;
+    }
+    let family = Family::<FolderLabels, Gauge<f64, AtomicU64>>::default();
+    for (labels, (raw, editable)) in counts {
+        let ratio = if raw == 0 {
+            1.0
+        } else {
+            (editable as f64 / raw as f64).min(1.0)
+        };
+        family.get_or_create(&labels).set(ratio);
+    }
+    family
+}
+
+/// Builds the per-folder and owner family, keeping only the largest
+/// series if over the limit.
+fn folder_owner_family(
+    collector: &PhotoBacklogCollector,
+    backlog: &super::Backlog,
+) -> Family<FolderOwnerLabels, Gauge> {
+    let names = user_names();
+    let mut counts: HashMap<FolderOwnerLabels, i64> = HashMap::new();
+    for ((path, uid), count) in &backlog.folder_owners {
+        if !collector.folder_exported(path) {
+            continue;
+        }
+        let labels = FolderOwnerLabels {
+            path: collector.folder_relabel.apply(path).into_owned(),
+            owner: names.get(uid).cloned().unwrap_or_else(|| uid.to_string()),
+        };
+        *counts.entry(labels).or_insert(0) += count;
+    }
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    if counts.len() > collector.folder_owner_max_series {
+        info!(
+            "Exporting only the largest {} of {} folder/owner series",
+            collector.folder_owner_max_series,
+            counts.len()
+        );
+        counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        counts.truncate(collector.folder_owner_max_series);
+    }
+    let family = Family::<FolderOwnerLabels, Gauge>::default();
+    for (labels, count) in counts {
+        family.get_or_create(&labels).set(count);
+    }
+    family
+}
+
+/// Builds the per-folder age buckets family, cumulative as the age
+/// histogram, keeping only the largest folders if over the limit.
+fn folder_age_bucket_family(
+    collector: &PhotoBacklogCollector,
+    backlog: &super::Backlog,
+) -> Family<FolderBucketLabels, Gauge> {
+    let bounds = buckets::nominal(&collector.age_buckets);
+    // The ages as observed in the histogram, i.e. mapped onto the nominal
+    // bounds for calendar-aligned buckets.
+    let mut label_ages: HashMap<String, Vec<f64>> = HashMap::new();
+    for (path, ages) in &backlog.folder_ages {
+        if collector.folder_exported(path) {
+            label_ages
+                .entry(collector.folder_relabel.apply(path).into_owned())
+                .or_default()
+                .extend(ages.iter().map(|age| backlog.ages_scale.apply(*age)));
+        }
+    }
+    let mut folders: Vec<_> = label_ages.into_iter().collect();
+    // A series per bucket, plus +Inf.
+    let max_folders = collector.folder_age_bucket_max_series / (bounds.len() + 1);
+    if folders.len() > max_folders {
+        info!(
+            "Exporting the age buckets of only the largest {} of {} folders",
+            max_folders,
+            folders.len()
+        );
+        folders.sort_by(|(a_path, a), (b_path, b)| {
+            b.len().cmp(&a.len()).then_with(|| a_path.cmp(b_path))
+        });
+        folders.truncate(max_folders);
+    }
+    let ratio = backlog.sample_ratio.unwrap_or(1.0);
+    let family = Family::<FolderBucketLabels, Gauge>::default();
+    for (path, ages) in folders {
+        let les = bounds
+            .iter()
+            .map(|bound| (format!("{:?}", bound), *bound))
+            .chain([("+Inf".to_string(), f64::INFINITY)]);
+        for (le, bound) in les {
+            let count = ages.iter().filter(|age| **age <= bound).count();
+            let labels = FolderBucketLabels {
+                path: path.clone(),
+                le,
+            };
+            family
+                .get_or_create(&labels)
+                .set((count as f64 / ratio).round() as i64);
+        }
+    }
+    family
+}
+
+/// Returns the user names by uid, from `/etc/passwd`, if readable; read
+/// directly rather than via NSS, which static (musl) binaries can't load.
+fn user_names() -> HashMap<u32, String> {
+    let passwd = std::fs::read_to_string("/etc/passwd").unwrap_or_default();
+    passwd
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let uid = fields.nth(1)?.parse().ok()?;
+            Some((uid, name.to_string()))
+        })
+        .collect()
+}
+
+fn encode_ages(
+    collector: &PhotoBacklogCollector,
+    scrape: &ScrapeData,
+    encoder: &mut MetricEncoder,
+) -> Result<(), std::fmt::Error> {
+    if collector.age_metrics != AgeMetrics::Quantiles {
+        let max_age = encoder.max_age;
+        let histogram = |histogram: &buckets::AgeHistogram| match max_age {
+            Some(max) => histogram.truncated(max),
+            None => histogram.clone(),
+        };
+        encode_metric(
+            encoder,
+            "photo_backlog_ages",
+            "Age of files in the backlog",
+            &histogram(&scrape.backlog.ages_histogram),
+        )?;
+        encode_metric(
+            encoder,
+            "photo_backlog_ages_raw",
+            "Age of raw files in the backlog",
+            &histogram(&scrape.backlog.raw_ages_histogram),
+        )?;
+        encode_metric(
+            encoder,
+            "photo_backlog_ages_editable",
+            "Age of editable files in the backlog",
+            &histogram(&scrape.backlog.editable_ages_histogram),
+        )?;
+    }
+    if collector.age_metrics != AgeMetrics::Histogram {
+        let quantiles_fam = Family::<QuantileLabels, Gauge<f64, AtomicU64>>::default();
+        for q in AGE_QUANTILES {
+            if let Some(age) = super::quantile(&scrape.backlog.ages, q) {
+                let labels = QuantileLabels {
+                    quantile: q.to_string(),
+                };
+                quantiles_fam.get_or_create(&labels).set(age);
+            }
+        }
+        encode_metric(
+            encoder,
+            "photo_backlog_age_quantiles",
+            "Exact quantiles of the age of files in the backlog",
+            &quantiles_fam,
+        )?;
+        // The sum of ages is dominated by outliers, the median much less so.
+        if let Some(median) = super::quantile(&scrape.backlog.ages, 0.5) {
+            encode_metric(
+                encoder,
+                "photo_backlog_age_median_seconds",
+                "Median age of files in the backlog",
+                &ConstGauge::new(median),
+            )?;
+        }
+    }
+    let oldest_ext_fam = Family::<ExtLabels, Gauge<f64, AtomicU64>>::default();
+    for (ext, age) in &scrape.backlog.oldest_by_ext {
+        oldest_ext_fam
+            .get_or_create(&ExtLabels { ext: ext.clone() })
+            .set(*age);
+    }
+    encode_metric(
+        encoder,
+        "photo_backlog_oldest_age_by_extension",
+        "Age of the oldest file in the backlog, per extension (empty if none)",
+        &oldest_ext_fam,
+    )?;
+    Ok(())
+}
+
+fn encode_fs_stats(
+    _collector: &PhotoBacklogCollector,
+    scrape: &ScrapeData,
+    encoder: &mut MetricEncoder,
+) -> Result<(), std::fmt::Error> {
+    let backlog = &scrape.backlog;
+    encode_metric(
+        encoder,
+        "photo_backlog_symlinks",
+        "Number of symlinks encountered while scanning",
+        &ConstGauge::new(backlog.symlinks),
+    )?;
+    encode_metric(
+        encoder,
+        "photo_backlog_dirs_total",
+        "Number of directories walked, including the root",
+        &ConstGauge::new(backlog.dirs),
+    )?;
+    let skipped_fam = Family::<SkipLabels, Gauge>::default();
+    for reason in super::SkipReason::ALL {
+        let count = backlog.skipped_entries.get(&reason).copied().unwrap_or(0);
+        skipped_fam.get_or_create(&SkipLabels { reason }).set(count);
+    }
+    encode_metric(
+        encoder,
+        "photo_backlog_skipped_entries_total",
+        "Number of entries skipped while scanning, and thus not counted, per reason",
+        &skipped_fam,
+    )?;
+    encode_metric(
+        encoder,
+        "photo_backlog_max_depth",
+        "Deepest nesting level seen below the root, files in the root being at depth 1",
+        &ConstGauge::new(backlog.max_depth as i64),
+    )?;
+    encode_metric(
+        encoder,
+        "photo_backlog_files_per_dir",
+        "Average number of regular files per walked directory, backlog or not",
+        &ConstGauge::new(backlog.walked_files as f64 / backlog.dirs.max(1) as f64),
+    )?;
+    encode_metric(
+        encoder,
+        "photo_backlog_stat_duration_seconds",
+        "Latency of the stat calls done while scanning",
+        &backlog.stat_histogram,
+    )?;
+    encode_metric(
+        encoder,
+        "photo_backlog_walk_workers",
+        "Number of threads walking the tree, see --scan-threads",
+        &ConstGauge::new(backlog.walk_workers as i64),
+    )?;
+    encode_metric(
+        encoder,
+        "photo_backlog_walk_queue_peak_entries",
+        "Peak number of walked entries waiting to be processed by the scan, for the parallel walker",
+        &ConstGauge::new(backlog.walk_queue_peak),
+    )?;
+    if !backlog.walk_worker_busy.is_empty() {
+        let busy_fam = Family::<WorkerLabels, Gauge<f64, AtomicU64>>::default();
+        for (worker, busy) in backlog.walk_worker_busy.iter().enumerate() {
+            busy_fam
+                .get_or_create(&WorkerLabels {
+                    worker: worker.to_string(),
+                })
+                .set(busy.as_secs_f64());
+        }
+        encode_metric(
+            encoder,
+            "photo_backlog_walk_worker_busy_seconds",
+            "Time each walker thread spent stat-ing and queueing entries, for the parallel walker",
+            &busy_fam,
+        )?;
+    }
+    encode_metric(
+        encoder,
+        "photo_backlog_memory_budget_exceeded",
+        "Whether the folder memory budget was exceeded, making folder metrics partial or dropping the file ages",
+        &ConstGauge::new(i64::from(
+            backlog.untracked_files > 0 || backlog.ages_dropped || backlog.folder_ages_dropped,
+        )),
+    )?;
+    encode_metric(
+        encoder,
+        "photo_backlog_untracked_files",
+        "Files only accounted in the totals, due to the folder memory budget",
+        &ConstGauge::new(backlog.untracked_files),
+    )
+}
+
+fn encode_self_metrics(
+    collector: &PhotoBacklogCollector,
+    scrape: &ScrapeData,
+    encoder: &mut MetricEncoder,
+) -> Result<(), std::fmt::Error> {
+    encode_metric(
+        encoder,
+        "photo_backlog_root_available",
+        "Whether the root directory exists, e.g. the removable media is attached",
+        &ConstGauge::new(i64::from(scrape.root_available)),
+    )?;
+    encode_metric(
+        encoder,
+        "photo_backlog_serving_stale",
+        "Whether the last scan failed and the previous good data is served instead",
+        &ConstGauge::new(i64::from(scrape.stale_age.is_some())),
+    )?;
+    encode_metric(
+        encoder,
+        "photo_backlog_scan_aborted",
+        "Whether the last scan was aborted due to too many entries (see --max-files)",
+        &ConstGauge::new(i64::from(scrape.scan_aborted)),
+    )?;
+    encode_metric(
+        encoder,
+        "photo_backlog_stale_data_age_seconds",
+        "Age of the served data if stale, zero otherwise",
+        &ConstGauge::new(scrape.stale_age.unwrap_or(Duration::ZERO).as_secs_f64()),
+    )?;
+    encode_metric(
+        encoder,
+        "photo_backlog_last_scan_id",
+        "Id of the scan producing the served data, as found in the logs and the violations log",
+        &ConstGauge::new(scrape.backlog.scan_id as i64),
+    )?;
+    encode_metric(
+        encoder,
+        "photo_backlog_snapshot_timestamp_seconds",
+        "Unix time at which the scan of the served data started",
+        &ConstGauge::new(
+            scrape
+                .scan_time
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or(Duration::ZERO)
+                .as_secs_f64(),
+        ),
+    )?;
+    if let Some(next_scan) = scrape.next_scan {
+        encode_metric(
+            encoder,
+            "photo_backlog_next_scan_timestamp_seconds",
+            "Unix time at which the next background scan is due, including the jitter",
+            &ConstGauge::new(
+                next_scan
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or(Duration::ZERO)
+                    .as_secs_f64(),
+            ),
+        )?;
+    }
+    let skewed_scans = collector.state.lock().unwrap().skewed_scans;
+    encode_metric(
+        encoder,
+        "photo_backlog_clock_skew_detected",
+        "Whether the last scans all found the files modified around their start dated in the future, i.e. the host's clock is behind the file server's",
+        &ConstGauge::new(i64::from(skewed_scans >= CLOCK_SKEW_SCANS)),
+    )?;
+    if collector.scan_watchdog.is_some() {
+        let (scan_stuck, watchdog_fired) = {
+            let state = collector.state.lock().unwrap();
+            (state.scan_stuck, state.watchdog_fired)
+        };
+        encode_metric(
+            encoder,
+            "photo_backlog_root_healthy",
+            "Whether no scan cancelled by the watchdog (see --scan-watchdog) is still hanging",
+            &ConstGauge::new(i64::from(!scan_stuck)),
+        )?;
+        encode_metric(
+            encoder,
+            "photo_backlog_scan_watchdog_fired",
+            "Number of scans cancelled for exceeding the watchdog",
+            &ConstCounter::new(watchdog_fired),
+        )?;
+    }
+    let processing_fam = Family::<PhaseLabels, Gauge<f64, AtomicU64>>::default();
+    for (phase, duration) in [
+        (Phase::Walk, scrape.walk_time),
+        (Phase::Checks, scrape.checks_time),
+        (Phase::Aggregation, scrape.aggregation_time),
+        (Phase::Encode, scrape.encode_started.elapsed()),
+    ] {
+        processing_fam
+            .get_or_create(&PhaseLabels { phase })
+            .set(duration.as_secs_f64());
+    }
+    encode_metric(
+        encoder,
+        PROCESSING_TIME_NAME,
+        PROCESSING_TIME_HELP,
+        &processing_fam,
+    )?;
+    encode_metric(
+        encoder,
+        "photo_backlog_walk_io_wait_seconds",
+        "Time the walk spent waiting on stat calls, summed over the walker threads, out of the walk phase's wall-clock time",
+        &ConstGauge::new(scrape.io_wait_time.as_secs_f64()),
+    )?;
+    if let Some(cache) = &scrape.cache {
+        let requests_fam = Family::<CacheLabels, Counter>::default();
+        for (result, count) in [("hit", cache.hits), ("miss", cache.misses)] {
+            requests_fam
+                .get_or_create(&CacheLabels { result })
+                .inc_by(count);
+        }
+        encode_metric(
+            encoder,
+            "photo_backlog_scan_cache_requests",
+            "Scrapes served from the last scan (hit) or needing a new scan (miss), see --skip-unchanged",
+            &requests_fam,
+        )?;
+        encode_metric(
+            encoder,
+            "photo_backlog_scan_cache_directories",
+            "Number of directories whose mtimes are tracked to detect changes",
+            &ConstGauge::new(cache.directories as i64),
+        )?;
+        encode_metric(
+            encoder,
+            "photo_backlog_scan_cache_memory_bytes",
+            "Approximate memory used by the cached scan and tracked mtimes",
+            &ConstGauge::new(cache.memory as i64),
+        )?;
+    }
+    Ok(())
+}
+
+fn encode_metric(
+    encoder: &mut MetricEncoder,
+    name: &str,
+    help: &str,
+    metric: &impl EncodeMetric,
+) -> Result<(), std::fmt::Error> {
+    let metric_encoder = encoder
+        .inner
+        .encode_descriptor(name, help, None, metric.metric_type())?;
+    metric.encode(metric_encoder)?;
+    for (old_name, _) in encoder.aliases.iter().filter(|(_, new)| *new == name) {
+        let help = format!("Deprecated alias of {}", name);
+        let metric_encoder =
+            encoder
+                .inner
+                .encode_descriptor(old_name, &help, None, metric.metric_type())?;
+        metric.encode(metric_encoder)?;
+    }
+    Ok(())
+}
+
+pub fn encode_to_text(collector: PhotoBacklogCollector) -> Result<String, std::fmt::Error> {
+    let mut registry = Registry::default();
+    registry.register_collector(Box::new(collector));
+    let mut buffer = String::new();
+    encode(&mut buffer, &registry).and(Ok(buffer))
+}
+
+/// Registers the collector into an existing registry, e.g. next to the
+/// application's own metrics. Scrapes of the registry then scan, as with
+/// the daemon, exporting all enabled groups.
+/// Example:
+/// ```
+/// use std::sync::Arc;
+/// use prometheus_client::encoding::text::encode;
+/// use prometheus_client::metrics::counter::Counter;
+/// use prometheus_client::registry::Registry;
+/// use photo_backlog_exporter::buckets::AgeBound;
+/// use photo_backlog_exporter::prometheus::{register_into, PhotoBacklogCollector};
+/// use photo_backlog_exporter::MockData;
+///
+/// let mut registry = Registry::default();
+/// registry.register("uploads", "Uploaded photos", Counter::<u64>::default());
+/// let collector = PhotoBacklogCollector {
+///     mock_data: Some(MockData { folders: 1, files: 2 }),
+///     age_buckets: vec![AgeBound::Seconds(1.0)],
+///     ..Default::default()
+/// };
+/// register_into(&mut registry, Arc::new(collector));
+/// let mut buffer = String::new();
+/// encode(&mut buffer, &registry).unwrap();
+/// assert!(buffer.contains("uploads_total 0"));
+/// assert!(buffer.contains("photo_backlog_counts{kind=\"photos\"} 2"));
+/// ```
+pub fn register_into(registry: &mut Registry, collector: Arc<PhotoBacklogCollector>) {
+    registry.register_collector(Box::new(ScrapeCollector {
+        collector,
+        options: ScrapeOptions::default(),
+    }));
+}
+
+/// Encodes the metrics of the given roots' collectors; with several roots,
+/// all metrics are labeled with their root. Each root is scanned and fails
+/// independently.
+pub fn encode_roots(
+    collectors: &[Arc<PhotoBacklogCollector>],
+    options: &ScrapeOptions,
+) -> Result<String, std::fmt::Error> {
+    let mut registry = Registry::default();
+    match collectors {
+        [collector] => registry.register_collector(Box::new(ScrapeCollector {
+            collector: Arc::clone(collector),
+            options: options.clone(),
+        })),
+        _ => {
+            for collector in collectors {
+                let root = collector.scan_path.to_string_lossy().into_owned();
+                registry
+                    .sub_registry_with_label(("root".into(), root.into()))
+                    .register_collector(Box::new(ScrapeCollector {
+                        collector: Arc::clone(collector),
+                        options: options.clone(),
+                    }));
+            }
+        }
+    }
+    let mut buffer = String::new();
+    encode(&mut buffer, &registry)?;
+    Ok(if collectors.len() > 1 {
+        merge_families(&buffer)
+    } else {
+        buffer
+    })
+}
+
+/// Merges the repeated blocks of the same metric families, as encoded by
+/// each root's collector, as a family must only be described once.
+fn merge_families(text: &str) -> String {
+    // Per family name, in order of appearance: the descriptor lines, and
+    // the samples of all blocks.
+    let mut families: Vec<(&str, Vec<&str>, Vec<&str>)> = Vec::new();
+    let mut current = None;
+    for line in text.lines() {
+        if line == "# EOF" {
+            break;
+        }
+        if let Some(rest) = line.strip_prefix("# HELP ") {
+            let name = rest.split(' ').next().unwrap_or_default();
+            current = match families.iter().position(|(n, _, _)| *n == name) {
+                Some(pos) => Some((pos, false)),
+                None => {
+                    families.push((name, Vec::new(), Vec::new()));
+                    Some((families.len() - 1, true))
+                }
+            };
+        }
+        let Some((pos, first)) = current else {
+            continue;
+        };
+        if line.starts_with('#') {
+            if first {
+                families[pos].1.push(line);
+            }
+        } else {
+            families[pos].2.push(line);
+        }
+    }
+    let mut merged = String::new();
+    for (_, descriptor, samples) in families {
+        for line in descriptor.iter().chain(samples.iter()) {
+            merged.push_str(line);
+            merged.push('\n');
+        }
+    }
+    merged.push_str("# EOF\n");
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    use prometheus_client::encoding::text::encode;
+    use prometheus_client::registry::Registry;
+    use rstest::rstest;
+    use tempfile::tempdir;
+
+    use super::AgeMetrics;
+    use crate::buckets::AgeBound;
+
+    extern crate speculoos;
+    use speculoos::prelude::*;
+
+    // A collector counting .nef files, with no checks enabled.
+    fn nef_collector(scan_path: PathBuf) -> super::PhotoBacklogCollector {
+        super::PhotoBacklogCollector {
+            scan_path,
+            raw_exts: vec![OsString::from("nef")],
+            age_buckets: vec![AgeBound::Seconds(1.0)],
+            ..Default::default()
+        }
+    }
+
+    /// Runs the collector with a variety of folder configurations and
+    /// checks that the encoding contains a few expected values.
+    /// Note not all encoded values are tested.
+    #[rstest]
+    #[case::empty_dir([0].to_vec())]
+    #[case::one_dir_one_file([1].to_vec())]
+    #[case::two_dirs_one_two([1, 2].to_vec())]
+    #[case::three_dirs_one_zero_two([1, 0, 2].to_vec())]
+    fn test_backlog_encoding(#[case] folders_config: Vec<i32>) {
+        fn format_dir(pos: usize) -> String {
+            format!("dir-{}", pos)
+        }
+        let temp_dir = tempdir().unwrap();
+        for (pos, folder_size) in folders_config.iter().enumerate() {
+            let folder = temp_dir.path().join(format_dir(pos));
+            std::fs::create_dir(&folder).unwrap();
+            for i in 0..*folder_size {
+                let file = folder.join(format!("{}.nef", i));
+                std::fs::File::create(&file).unwrap();
+            }
+        }
+        let collector = nef_collector(temp_dir.path().to_path_buf());
+        let buffer = super::encode_to_text(collector).unwrap();
+
+        // Now check the encoded values.
+        let total_photos = folders_config.iter().sum::<i32>();
+        let photos_string = format!("photo_backlog_counts{{kind=\"photos\"}} {}", total_photos);
+        assert_that(&buffer).contains(&photos_string);
+        let folder_string = format!(
+            "photo_backlog_counts{{kind=\"folders\"}} {}",
+            folders_config.iter().filter(|x| **x > 0).count()
+        );
+        assert!(buffer.contains(&folder_string));
+        for (pos, folder_size) in folders_config.iter().enumerate() {
+            if *folder_size == 0 {
+                continue;
+            }
+            let folder_string = format!(
+                "photo_backlog_folder_sizes{{path=\"{}\"}} {}",
+                format_dir(pos),
+                folder_size
+            );
+            assert_that(&buffer).contains(&folder_string);
+        }
+        for phase in ["walk", "checks", "aggregation", "encode"] {
+            assert_that!(buffer).contains(format!(
+                "photo_backlog_processing_time_seconds{{phase=\"{}\"}} ",
+                phase
+            ));
+        }
+        assert_that!(buffer).contains("photo_backlog_walk_io_wait_seconds ");
+        let ages_string = format!("photo_backlog_ages_count {}", total_photos);
+        assert_that!(buffer).contains(ages_string);
+        // One stat per file and directory, including the root.
+        let stat_string = format!(
+            "photo_backlog_stat_duration_seconds_count {}",
+            total_photos as usize + folders_config.len() + 1
+        );
+        assert_that!(buffer).contains(stat_string);
+        assert_that!(buffer).contains("photo_backlog_errors{kind=\"scan\"} 0");
+        assert_that!(buffer).contains("photo_backlog_errors{kind=\"ownership\"} 0");
+        assert_that!(buffer).contains(
+            "photo_backlog_check_errors{kind=\"permissions\",check=\"dir_mode\",target=\"dir\"} 0",
+        );
+    }
+
+    #[test]
+    fn test_stale_snapshot() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::File::create(root.join("1.nef")).unwrap();
+        let collector = nef_collector(root.clone());
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(collector));
+        let scrape = || {
+            let mut buffer = String::new();
+            encode(&mut buffer, &registry).unwrap();
+            buffer
+        };
+        let timestamp_line = |buffer: &str| {
+            buffer
+                .lines()
+                .find(|l| l.starts_with("photo_backlog_snapshot_timestamp_seconds "))
+                .expect("snapshot timestamp present")
+                .to_string()
+        };
+
+        let buffer = scrape();
+        assert_that!(buffer).contains("photo_backlog_serving_stale 0");
+        assert_that!(buffer).contains("photo_backlog_counts{kind=\"photos\"} 1");
+        let good_timestamp = timestamp_line(&buffer);
+
+        // Root disappears, so the previous data is served.
+        std::fs::remove_dir_all(&root).unwrap();
+        let buffer = scrape();
+        assert_that!(buffer).contains("photo_backlog_serving_stale 1");
+        assert_that!(buffer).contains("photo_backlog_counts{kind=\"photos\"} 1");
+        assert_that!(buffer).contains("photo_backlog_errors{kind=\"scan\"} 0");
+        // The timestamp is the one of the served scan.
+        assert_that!(timestamp_line(&buffer)).is_equal_to(good_timestamp);
+
+        // And recovers once it's back.
+        std::fs::create_dir(&root).unwrap();
+        let buffer = scrape();
+        assert_that!(buffer).contains("photo_backlog_serving_stale 0");
+        assert_that!(buffer).contains("photo_backlog_counts{kind=\"photos\"} 0");
+    }
+
+    #[test]
+    fn test_no_folder_metrics() {
+        let temp_dir = tempdir().unwrap();
+        let folder = temp_dir.path().join("secret-client");
+        std::fs::create_dir(&folder).unwrap();
+        std::fs::File::create(folder.join("1.nef")).unwrap();
+        let collector = super::PhotoBacklogCollector {
+            no_folder_metrics: true,
+            ..nef_collector(temp_dir.path().to_path_buf())
+        };
+        let buffer = super::encode_to_text(collector).unwrap();
+        assert_that!(buffer).contains("photo_backlog_counts{kind=\"photos\"} 1");
+        assert_that!(buffer).contains("photo_backlog_counts{kind=\"folders\"} 1");
+        assert_that!(buffer).contains("photo_backlog_ages_count 1");
+        assert_that!(buffer).does_not_contain("secret-client");
+    }
+
+    #[test]
+    fn test_folder_relabel() {
+        let temp_dir = tempdir().unwrap();
+        for (folder, files) in [
+            ("2024-06-15 Smith", 1),
+            ("2024-06-20 Jones", 2),
+            ("misc", 1),
+        ] {
+            let folder = temp_dir.path().join(folder);
+            std::fs::create_dir(&folder).unwrap();
+            for i in 0..files {
+                std::fs::File::create(folder.join(format!("{}.nef", i))).unwrap();
+            }
+        }
+        let rules = crate::relabel::RelabelRules::parse(
+            "- regex: '(\\d{4}-\\d{2})-.*'\n  replacement: '$1'\n",
+        )
+        .unwrap();
+        let collector = super::PhotoBacklogCollector {
+            folder_relabel: rules,
+            ..nef_collector(temp_dir.path().to_path_buf())
+        };
+        let buffer = super::encode_to_text(collector).unwrap();
+        assert_that!(buffer).contains("photo_backlog_folder_sizes{path=\"2024-06\"} 3");
+        assert_that!(buffer).contains("photo_backlog_folder_sizes{path=\"misc\"} 1");
+        assert_that!(buffer).does_not_contain("Smith");
+    }
+
+    #[test]
+    fn test_median_ages() {
+        // Folder 0 has files of 0, 1 and 2 days, folder 1 of 7, 8 and 9.
+        let mock = crate::MockData {
+            folders: 2,
+            files: 3,
+        };
+        let collector = super::PhotoBacklogCollector {
+            mock_data: Some(mock),
+            age_metrics: AgeMetrics::Both,
+            ..nef_collector(PathBuf::new())
+        };
+        let buffer = super::encode_to_text(collector).unwrap();
+        assert_that!(buffer).contains("photo_backlog_age_median_seconds 172800.0");
+        assert_that!(buffer)
+            .contains("photo_backlog_folder_age_median_seconds{path=\"mock-folder-0000\"} 86400.0");
+        assert_that!(buffer).contains(
+            "photo_backlog_folder_age_median_seconds{path=\"mock-folder-0001\"} 691200.0",
+        );
+        assert_that!(buffer)
+            .contains("photo_backlog_folder_age_mean_seconds{path=\"mock-folder-0000\"} 86400.0");
+        // Folder 1 has the oldest file.
+        assert_that!(buffer)
+            .contains("photo_backlog_folder_created_rank{path=\"mock-folder-0001\"} 1");
+        assert_that!(buffer)
+            .contains("photo_backlog_folder_created_rank{path=\"mock-folder-0000\"} 2");
+
+        // Relabeled folders get the median and mean of their merged ages.
+        let collector = super::PhotoBacklogCollector {
+            mock_data: Some(mock),
+            folder_relabel: crate::relabel::RelabelRules::parse(
+                "- regex: 'mock-.*'\n  replacement: 'all'\n",
+            )
+            .unwrap(),
+            ..nef_collector(PathBuf::new())
+        };
+        let buffer = super::encode_to_text(collector).unwrap();
+        assert_that!(buffer)
+            .contains("photo_backlog_folder_age_median_seconds{path=\"all\"} 172800.0");
+        assert_that!(buffer)
+            .contains("photo_backlog_folder_age_mean_seconds{path=\"all\"} 388800.0");
+        assert_that!(buffer).contains("photo_backlog_folder_created_rank{path=\"all\"} 1");
+    }
+
+    #[test]
+    fn test_folder_allow_deny() {
+        let temp_dir = tempdir().unwrap();
+        for folder in ["2023-old", "2024-keep", "2024-private"] {
+            let folder = temp_dir.path().join(folder);
+            std::fs::create_dir(&folder).unwrap();
+            std::fs::File::create(folder.join("1.nef")).unwrap();
+        }
+        let collector = super::PhotoBacklogCollector {
+            folder_allow: Some(crate::relabel::anchored_regex("2024-.*").unwrap()),
+            folder_deny: Some(crate::relabel::anchored_regex(".*private").unwrap()),
+            ..nef_collector(temp_dir.path().to_path_buf())
+        };
+        let buffer = super::encode_to_text(collector).unwrap();
+        assert_that!(buffer).contains("photo_backlog_folder_sizes{path=\"2024-keep\"} 1");
+        assert_that!(buffer).does_not_contain("2023-old");
+        assert_that!(buffer).does_not_contain("2024-private");
+        assert_that!(buffer).contains("photo_backlog_counts{kind=\"photos\"} 3");
+        assert_that!(buffer).contains("photo_backlog_counts{kind=\"folders\"} 3");
+    }
+
+    #[test]
+    fn test_class_age_buckets() {
+        let temp_dir = tempdir().unwrap();
+        std::fs::File::create(temp_dir.path().join("1.nef")).unwrap();
+        std::fs::File::create(temp_dir.path().join("2.mov")).unwrap();
+        std::fs::File::create(temp_dir.path().join("3.mov")).unwrap();
+        let collector = super::PhotoBacklogCollector {
+            editable_exts: vec![OsString::from("mov")],
+            editable_age_buckets: Some(vec![AgeBound::Seconds(10.0), AgeBound::Seconds(20.0)]),
+            ..nef_collector(temp_dir.path().to_path_buf())
+        };
+        let buffer = super::encode_to_text(collector).unwrap();
+        assert_that!(buffer).contains("photo_backlog_ages_count 3");
+        assert_that!(buffer).contains("photo_backlog_ages_raw_count 1");
+        assert_that!(buffer).contains("photo_backlog_ages_raw_bucket{le=\"1.0\"} 1");
+        assert_that!(buffer).contains("photo_backlog_ages_editable_count 2");
+        assert_that!(buffer).contains("photo_backlog_ages_editable_bucket{le=\"20.0\"} 2");
+        assert_that!(buffer).does_not_contain("photo_backlog_ages_editable_bucket{le=\"1.0\"}");
+    }
+
+    #[test]
+    fn test_calendar_age_buckets() {
+        let temp_dir = tempdir().unwrap();
+        let now = std::time::SystemTime::now();
+        for (name, days) in [("1.nef", 20), ("2.nef", 40)] {
+            std::fs::File::create(temp_dir.path().join(name))
+                .unwrap()
+                .set_modified(now - Duration::from_secs(days * 86400))
+                .unwrap();
+        }
+        let collector = super::PhotoBacklogCollector {
+            age_buckets: crate::buckets::parse_age_buckets("2w,1M").unwrap(),
+            ..nef_collector(temp_dir.path().to_path_buf())
+        };
+        let buffer = super::encode_to_text(collector).unwrap();
+        // Labeled with the average month length.
+        assert_that!(buffer).contains("photo_backlog_ages_bucket{le=\"1209600.0\"} 0");
+        assert_that!(buffer).contains("photo_backlog_ages_bucket{le=\"2629746.0\"} 1");
+        assert_that!(buffer).contains("photo_backlog_ages_count 2");
+        // The sum is of the actual ages, not the mapped ones.
+        let sum = buffer
+            .lines()
+            .find_map(|line| line.strip_prefix("photo_backlog_ages_sum "))
+            .and_then(|sum| sum.parse::<f64>().ok())
+            .unwrap();
+        assert_that!(sum).is_close_to(60.0 * 86400.0, 60.0);
+    }
+
+    #[test]
+    fn test_removable_root() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path().join("drive");
+        let collector = super::PhotoBacklogCollector {
+            removable_root: true,
+            ..nef_collector(root.clone())
+        };
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(collector));
+        let scrape = || {
+            let mut buffer = String::new();
+            encode(&mut buffer, &registry).unwrap();
+            buffer
+        };
+
+        let buffer = scrape();
+        assert_that!(buffer).contains("photo_backlog_root_available 0");
+        assert_that!(buffer).contains("photo_backlog_errors{kind=\"scan\"} 0");
+        assert_that!(buffer).contains("photo_backlog_counts{kind=\"photos\"} 0");
+
+        // Drive gets plugged in.
+        std::fs::create_dir(&root).unwrap();
+        std::fs::File::create(root.join("1.nef")).unwrap();
+        let buffer = scrape();
+        assert_that!(buffer).contains("photo_backlog_root_available 1");
+        assert_that!(buffer).contains("photo_backlog_counts{kind=\"photos\"} 1");
+
+        // And unplugged again, which isn't treated as a failure.
+        std::fs::remove_dir_all(&root).unwrap();
+        let buffer = scrape();
+        assert_that!(buffer).contains("photo_backlog_root_available 0");
+        assert_that!(buffer).contains("photo_backlog_serving_stale 0");
+        assert_that!(buffer).contains("photo_backlog_counts{kind=\"photos\"} 0");
+    }
+
+    #[test]
+    fn test_scrape_options() {
+        let temp_dir = tempdir().unwrap();
+        std::fs::File::create(temp_dir.path().join("1.nef")).unwrap();
+        let collector = std::sync::Arc::new(nef_collector(temp_dir.path().to_path_buf()));
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(super::ScrapeCollector {
+            collector,
+            options: super::ScrapeOptions::from_query("collect[]=counts&collect[]=ages").unwrap(),
+        }));
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert_that!(buffer).contains("photo_backlog_counts{kind=\"photos\"} 1");
+        assert_that!(buffer).contains("photo_backlog_ages_count 1");
+        assert_that!(buffer).does_not_contain("photo_backlog_errors");
+        assert_that!(buffer).does_not_contain("photo_backlog_folder_sizes");
+        assert_that!(buffer).does_not_contain("photo_backlog_stat_duration_seconds");
+        assert_that!(buffer).does_not_contain("photo_backlog_processing_time_seconds");
+    }
+
+    #[test]
+    fn test_scrape_options_max_age() {
+        let temp_dir = tempdir().unwrap();
+        let now = std::time::SystemTime::now();
+        for (i, days) in [1, 10].iter().enumerate() {
+            let path = temp_dir.path().join(format!("{}.nef", i));
+            std::fs::File::create(&path)
+                .and_then(|f| f.set_modified(now - std::time::Duration::from_secs(days * 86400)))
+                .unwrap();
+        }
+        let collector = std::sync::Arc::new(super::PhotoBacklogCollector {
+            age_buckets: vec![
+                AgeBound::Seconds(2.0 * 86400.0),
+                AgeBound::Seconds(14.0 * 86400.0),
+            ],
+            age_metrics: AgeMetrics::Both,
+            ..nef_collector(temp_dir.path().to_path_buf())
+        });
+        let options =
+            super::ScrapeOptions::from_query("collect%5B%5D=ages&max_age_weeks=1").unwrap();
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(super::ScrapeCollector {
+            collector: std::sync::Arc::clone(&collector),
+            options: options.clone(),
+        }));
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert_that!(buffer).contains("photo_backlog_ages_bucket{le=\"172800.0\"} 1");
+        assert_that!(buffer).does_not_contain("photo_backlog_ages_bucket{le=\"1209600.0\"}");
+        assert_that!(buffer).contains("photo_backlog_ages_bucket{le=\"+Inf\"} 2");
+        assert_that!(buffer).contains("photo_backlog_ages_count 2");
+        // Only the exported groups' ages are kept.
+        assert_that!(collector.scan_data(&options).backlog.folder_ages).is_empty();
+        let options = super::ScrapeOptions::from_query("collect[]=counts").unwrap();
+        assert_that!(collector.scan_data(&options).backlog.ages).is_empty();
+        assert_that!(collector.scan_data(&Default::default()).backlog.ages).has_length(2);
+    }
+
+    #[test]
+    fn test_scrape_options_self_only() {
+        let temp_dir = tempdir().unwrap();
+        std::fs::File::create(temp_dir.path().join("1.nef")).unwrap();
+        let collector = std::sync::Arc::new(nef_collector(temp_dir.path().to_path_buf()));
+        let scrape = |query| {
+            let mut registry = Registry::default();
+            registry.register_collector(Box::new(super::ScrapeCollector {
+                collector: std::sync::Arc::clone(&collector),
+                options: super::ScrapeOptions::from_query(query).unwrap(),
+            }));
+            let mut buffer = String::new();
+            encode(&mut buffer, &registry).unwrap();
+            buffer
+        };
+        // No scan until one is needed.
+        assert_that!(scrape("collect[]=self")).contains("photo_backlog_last_scan_id 0");
+        let scan_id = collector.state.lock().unwrap().last_scan_id;
+        assert_that!(scan_id).is_equal_to(0);
+        scrape("collect[]=counts");
+        let scan_id = collector.state.lock().unwrap().last_scan_id;
+        assert_that!(scrape("collect[]=self"))
+            .contains(format!("photo_backlog_last_scan_id {}", scan_id).as_str());
+        assert_that!(collector.state.lock().unwrap().last_scan_id).is_equal_to(scan_id);
+    }
+
+    #[rstest]
+    #[case::plus("max_age_weeks=1+", false)]
+    #[case::escape("collect%5b%5d=counts", true)]
+    #[case::short_escape("collect[]=count%7", false)]
+    #[case::bad_escape("collect[]=%zzcounts", false)]
+    #[case::bad_utf8("collect[]=%ff", false)]
+    #[case::zero_weeks("max_age_weeks=0", false)]
+    #[case::unknown("foo=%zz&collect[]=counts", false)]
+    #[case::no_value("foo&collect[]=counts", true)]
+    fn test_scrape_options_parsing(#[case] query: &str, #[case] valid: bool) {
+        assert_that!(super::ScrapeOptions::from_query(query).is_ok()).is_equal_to(valid);
+    }
+
+    #[rstest]
+    #[case::histogram(AgeMetrics::Histogram, true, false)]
+    #[case::quantiles(AgeMetrics::Quantiles, false, true)]
+    #[case::both(AgeMetrics::Both, true, true)]
+    fn test_age_metrics(
+        #[case] age_metrics: AgeMetrics,
+        #[case] histogram: bool,
+        #[case] quantiles: bool,
+    ) {
+        let temp_dir = tempdir().unwrap();
+        let now = std::time::SystemTime::now();
+        for (i, days) in [1, 2, 3, 10].iter().enumerate() {
+            let path = temp_dir.path().join(format!("{}.nef", i));
+            std::fs::File::create(&path)
+                .and_then(|f| f.set_modified(now - std::time::Duration::from_secs(days * 86400)))
+                .unwrap();
+        }
+        let collector = super::PhotoBacklogCollector {
+            age_metrics,
+            ..nef_collector(temp_dir.path().to_path_buf())
+        };
+        let buffer = super::encode_to_text(collector).unwrap();
+        assert_that!(buffer.contains("photo_backlog_ages_count 4")).is_equal_to(histogram);
+        assert_that!(buffer.contains("photo_backlog_age_quantiles{quantile=\"0.5\"} 17")) // 2 days.
+            .is_equal_to(quantiles);
+        assert_that!(buffer.contains("photo_backlog_age_quantiles{quantile=\"0.99\"} 86")) // 10 days.
+            .is_equal_to(quantiles);
+    }
+
+    #[test]
+    fn test_skip_unchanged() {
+        let temp_dir = tempdir().unwrap();
+        let folder = temp_dir.path().join("a");
+        std::fs::create_dir_all(folder.join("b")).unwrap();
+        std::fs::File::create(folder.join("1.nef")).unwrap();
+        let collector = std::sync::Arc::new(super::PhotoBacklogCollector {
+            skip_unchanged: true,
+            ..nef_collector(temp_dir.path().to_path_buf())
+        });
+        let scrape = || {
+            let mut registry = Registry::default();
+            registry.register_collector(Box::new(super::ScrapeCollector {
+                collector: std::sync::Arc::clone(&collector),
+                options: Default::default(),
+            }));
+            let mut buffer = String::new();
+            encode(&mut buffer, &registry).unwrap();
+            buffer
+        };
+        assert_that!(scrape()).contains("photo_backlog_counts{kind=\"photos\"} 1");
+        // Deeper changes don't touch the top-level mtimes, so aren't seen.
+        std::fs::File::create(folder.join("b").join("2.nef")).unwrap();
+        assert_that!(scrape()).contains("photo_backlog_counts{kind=\"photos\"} 1");
+        std::fs::File::create(folder.join("3.nef")).unwrap();
+        let buffer = scrape();
+        assert_that!(buffer).contains("photo_backlog_counts{kind=\"photos\"} 3");
+        assert_that!(buffer).contains("photo_backlog_scan_cache_requests_total{result=\"hit\"} 1");
+        assert_that!(buffer).contains("photo_backlog_scan_cache_requests_total{result=\"miss\"} 2");
+        // The root and the single top-level folder.
+        assert_that!(buffer).contains("photo_backlog_scan_cache_directories 2");
+    }
+
+    #[test]
+    fn test_scheduled_first_scrape() {
+        let temp_dir = tempdir().unwrap();
+        std::fs::File::create(temp_dir.path().join("1.nef")).unwrap();
+        let collector = std::sync::Arc::new(super::PhotoBacklogCollector {
+            scan_interval: Some(Duration::from_secs(3600)),
+            ..nef_collector(temp_dir.path().to_path_buf())
+        });
+        let background = std::sync::Arc::clone(&collector);
+        std::thread::spawn(move || background.run_scheduled_scans());
+        while !collector.state.lock().unwrap().scheduler_running {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        // Waits for the first background scan instead of walking the tree
+        // itself.
+        let buffer = super::encode_to_text(collector.as_ref().clone()).unwrap();
+        assert_that!(buffer).contains("photo_backlog_next_scan_timestamp_seconds");
+        assert_that!(buffer).contains("photo_backlog_counts{kind=\"photos\"} 1");
+    }
+
+    #[test]
+    fn test_clock_skew_metric() {
+        let temp_dir = tempdir().unwrap();
+        let file = temp_dir.path().join("1.nef");
+        std::fs::File::create(&file)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(30))
+            .unwrap();
+        // The default tolerance applies.
+        let collector = nef_collector(temp_dir.path().to_path_buf());
+        let skew = |detected| format!("photo_backlog_clock_skew_detected {}", detected);
+        for _ in 1..super::CLOCK_SKEW_SCANS {
+            let buffer = super::encode_to_text(collector.clone()).unwrap();
+            assert_that!(buffer).contains(skew(0).as_str());
+        }
+        let buffer = super::encode_to_text(collector.clone()).unwrap();
+        assert_that!(buffer).contains(skew(1).as_str());
+        // Files modified long before don't tell anything either way.
+        let set_modified = |time| {
+            std::fs::File::options()
+                .write(true)
+                .open(&file)
+                .unwrap()
+                .set_modified(time)
+                .unwrap()
+        };
+        set_modified(SystemTime::now() - Duration::from_secs(3600));
+        let buffer = super::encode_to_text(collector.clone()).unwrap();
+        assert_that!(buffer).contains(skew(1).as_str());
+        // Recent files within the tolerance reset the streak.
+        set_modified(SystemTime::now());
+        let buffer = super::encode_to_text(collector).unwrap();
+        assert_that!(buffer).contains(skew(0).as_str());
+    }
+
+    #[test]
+    fn test_applied_changes() {
+        let temp_dir = tempdir().unwrap();
+        std::fs::File::create(temp_dir.path().join("1.nef")).unwrap();
+        let collector = std::sync::Arc::new(super::PhotoBacklogCollector {
+            scan_interval: Some(Duration::from_secs(3600)),
+            ..nef_collector(temp_dir.path().to_path_buf())
+        });
+        let added = temp_dir.path().join("2.nef");
+        // Nothing to update before the first scan.
+        collector.apply_changes(&[crate::FileChange::Added(added.clone())]);
+        let background = std::sync::Arc::clone(&collector);
+        std::thread::spawn(move || background.run_scheduled_scans());
+        while collector.state.lock().unwrap().scheduled.is_none() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        std::fs::File::create(&added).unwrap();
+        collector.apply_changes(&[crate::FileChange::Added(added)]);
+        let buffer = super::encode_to_text(collector.as_ref().clone()).unwrap();
+        assert_that!(buffer).contains("photo_backlog_counts{kind=\"photos\"} 2");
+    }
+
+    #[test]
+    fn test_scheduled_scans() {
+        let temp_dir = tempdir().unwrap();
+        let folder = temp_dir.path().join("a");
+        std::fs::create_dir(&folder).unwrap();
+        std::fs::File::create(folder.join("1.nef")).unwrap();
+        let collector = std::sync::Arc::new(super::PhotoBacklogCollector {
+            scan_interval: Some(Duration::from_secs(3600)),
+            scan_jitter: 0.1,
+            ..nef_collector(temp_dir.path().to_path_buf())
+        });
+        let background = std::sync::Arc::clone(&collector);
+        std::thread::spawn(move || background.run_scheduled_scans());
+        while collector.state.lock().unwrap().scheduled.is_none() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        // Served from the background scan, until the next one.
+        std::fs::File::create(folder.join("2.nef")).unwrap();
+        let scrape = || {
+            let mut registry = Registry::default();
+            registry.register_collector(Box::new(super::ScrapeCollector {
+                collector: std::sync::Arc::clone(&collector),
+                options: Default::default(),
+            }));
+            let mut buffer = String::new();
+            encode(&mut buffer, &registry).unwrap();
+            buffer
+        };
+        let buffer = scrape();
+        assert_that!(buffer).contains("photo_backlog_counts{kind=\"photos\"} 1");
+        assert_that!(buffer).contains("photo_backlog_next_scan_timestamp_seconds");
+        // Scrapes don't scan, so the scan id stays the background scan's.
+        let scan_id = collector.state.lock().unwrap().last_scan_id;
+        let served_id = scrape()
+            .lines()
+            .find_map(|line| line.strip_prefix("photo_backlog_last_scan_id "))
+            .and_then(|id| id.parse::<u64>().ok());
+        assert_that!(served_id).is_equal_to(Some(scan_id));
+    }
+
+    #[cfg(feature = "daemon")]
+    #[test]
+    fn test_scan_announced_after_serving() {
+        let temp_dir = tempdir().unwrap();
+        let (completions, mut events) = tokio::sync::broadcast::channel(4);
+        let collector = std::sync::Arc::new(super::PhotoBacklogCollector {
+            scan_interval: Some(Duration::from_secs(3600)),
+            scan_completions: Some(completions),
+            ..nef_collector(temp_dir.path().to_path_buf())
+        });
+        let background = std::sync::Arc::clone(&collector);
+        std::thread::spawn(move || background.run_scheduled_scans());
+        // Subscribers see the announced scan being served.
+        let served = || {
+            let state = collector.state.lock().unwrap();
+            state
+                .scheduled
+                .as_ref()
+                .map(|s| std::sync::Arc::clone(&s.backlog))
+        };
+        let completion = events.blocking_recv().unwrap();
+        assert_that!(served().is_some_and(|b| std::sync::Arc::ptr_eq(&b, &completion.backlog)))
+            .is_true();
+        let backlog = collector.scan_now();
+        let completion = events.blocking_recv().unwrap();
+        assert_that!(std::sync::Arc::ptr_eq(&backlog, &completion.backlog)).is_true();
+        assert_that!(served().is_some_and(|b| std::sync::Arc::ptr_eq(&b, &completion.backlog)))
+            .is_true();
+    }
+
+    #[test]
+    fn test_first_scheduled_scan_wait() {
+        let collector = std::sync::Arc::new(nef_collector(PathBuf::new()));
+        collector.state.lock().unwrap().scheduler_running = true;
+        // Times out while the first scan is running.
+        let (scheduled, running) = collector.wait_scheduled(Duration::from_millis(10));
+        assert_that!(scheduled.is_none()).is_true();
+        assert_that!(running).is_true();
+        // And is woken up once it's done.
+        let background = std::sync::Arc::clone(&collector);
+        std::thread::spawn(move || {
+            let scrape = background.last_scan_data();
+            background.state.lock().unwrap().scheduled = Some(scrape);
+            background.scheduled_done.notify_all();
+        });
+        let (scheduled, _) = collector.wait_scheduled(Duration::from_secs(60));
+        assert_that!(scheduled.is_some()).is_true();
+    }
+
+    #[test]
+    fn test_scan_lock() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        std::fs::create_dir_all(root.join("a")).unwrap();
+        std::fs::File::create(root.join("a").join("1.nef")).unwrap();
+        let lock_path = temp_dir.path().join("scan.lock");
+        let collector = std::sync::Arc::new(super::PhotoBacklogCollector {
+            scan_lock: Some(lock_path.clone()),
+            ..nef_collector(root.clone())
+        });
+        let scrape = || {
+            let mut registry = Registry::default();
+            registry.register_collector(Box::new(super::ScrapeCollector {
+                collector: std::sync::Arc::clone(&collector),
+                options: Default::default(),
+            }));
+            let mut buffer = String::new();
+            encode(&mut buffer, &registry).unwrap();
+            buffer
+        };
+        assert_that!(scrape()).contains("photo_backlog_counts{kind=\"photos\"} 1");
+        std::fs::File::create(root.join("a").join("2.nef")).unwrap();
+        // While another scanner holds the lock, the last scan is served.
+        let lock = crate::scan_lock::ScanLock::acquire(&lock_path).unwrap();
+        assert_that!(scrape()).contains("photo_backlog_counts{kind=\"photos\"} 1");
+        drop(lock);
+        assert_that!(scrape()).contains("photo_backlog_counts{kind=\"photos\"} 2");
+    }
+
+    #[test]
+    fn test_scan_watchdog() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        std::fs::create_dir_all(root.join("a")).unwrap();
+        std::fs::File::create(root.join("a").join("1.nef")).unwrap();
+        let lock_path = temp_dir.path().join("scan.lock");
+        let collector = std::sync::Arc::new(super::PhotoBacklogCollector {
+            scan_lock: Some(lock_path.clone()),
+            scan_watchdog: Some(Duration::from_millis(50)),
+            ..nef_collector(root.clone())
+        });
+        let scrape = || {
+            let mut registry = Registry::default();
+            registry.register_collector(Box::new(super::ScrapeCollector {
+                collector: std::sync::Arc::clone(&collector),
+                options: Default::default(),
+            }));
+            let mut buffer = String::new();
+            encode(&mut buffer, &registry).unwrap();
+            buffer
+        };
+        // Without a last good scan, the scan waits for the lock, standing
+        // in for a hung filesystem.
+        let lock = crate::scan_lock::ScanLock::acquire(&lock_path).unwrap();
+        let buffer = scrape();
+        assert_that!(buffer).contains("photo_backlog_scan_watchdog_fired_total 1");
+        assert_that!(buffer).contains("photo_backlog_root_healthy 0");
+        assert_that!(buffer).contains("photo_backlog_counts{kind=\"photos\"} 0");
+        // No new scan is started while the cancelled one hangs.
+        assert_that!(scrape()).contains("photo_backlog_scan_watchdog_fired_total 1");
+        drop(lock);
+        let mut buffer = scrape();
+        for _ in 0..100 {
+            if buffer.contains("photo_backlog_counts{kind=\"photos\"} 1") {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+            buffer = scrape();
+        }
+        assert_that!(buffer).contains("photo_backlog_root_healthy 1");
+        assert_that!(buffer).contains("photo_backlog_scan_watchdog_fired_total 1");
+        assert_that!(buffer).contains("photo_backlog_counts{kind=\"photos\"} 1");
+    }
+
+    #[test]
+    fn test_merge_families() {
+        let text = "# HELP a A.\n# TYPE a gauge\na{root=\"x\"} 1\n\
+                    # HELP b B.\n# TYPE b counter\nb_total{root=\"x\"} 2\n\
+                    # HELP a A.\n# TYPE a gauge\na{root=\"y\"} 3\n# EOF\n";
+        assert_that!(super::merge_families(text).as_str()).is_equal_to(
+            "# HELP a A.\n# TYPE a gauge\na{root=\"x\"} 1\na{root=\"y\"} 3\n\
+             # HELP b B.\n# TYPE b counter\nb_total{root=\"x\"} 2\n# EOF\n",
+        );
+    }
+
+    #[test]
+    fn test_folder_completeness() {
+        let temp_dir = tempdir().unwrap();
+        for (folder, files) in [
+            (
+                "pending",
+                &["1.nef", "2.nef", "3.nef", "4.nef", "1.jpg"][..],
+            ),
+            ("done", &["1.jpg", "2.jpg"]),
+        ] {
+            let folder = temp_dir.path().join(folder);
+            std::fs::create_dir(&folder).unwrap();
+            for file in files {
+                std::fs::File::create(folder.join(file)).unwrap();
+            }
+        }
+        let collector = super::PhotoBacklogCollector {
+            editable_exts: vec![OsString::from("jpg")],
+            ..nef_collector(temp_dir.path().to_path_buf())
+        };
+        let buffer = super::encode_to_text(collector).unwrap();
+        assert_that!(buffer).contains("photo_backlog_folder_completeness{path=\"pending\"} 0.25");
+        assert_that!(buffer).contains("photo_backlog_folder_completeness{path=\"done\"} 1.0");
+    }
+
+    #[test]
+    fn test_ignored_files() {
+        let temp_dir = tempdir().unwrap();
+        for file in ["1.nef", "1.xmp", "2.xmp", "README"] {
+            std::fs::File::create(temp_dir.path().join(file)).unwrap();
+        }
+        let collector = |ignored_files_per_ext| super::PhotoBacklogCollector {
+            ignored_exts: vec![OsString::from("xmp")],
+            ignored_files_per_ext,
+            ..nef_collector(temp_dir.path().to_path_buf())
+        };
+        let buffer = super::encode_to_text(collector(false)).unwrap();
+        assert_that!(buffer).contains("photo_backlog_ignored_files 3\n");
+        assert_that!(buffer).does_not_contain("photo_backlog_ignored_files_per_ext");
+        let buffer = super::encode_to_text(collector(true)).unwrap();
+        assert_that!(buffer).contains("photo_backlog_ignored_files_per_ext{ext=\"xmp\"} 2\n");
+        assert_that!(buffer).contains("photo_backlog_ignored_files_per_ext{ext=\"\"} 1\n");
+    }
+
+    #[test]
+    fn test_metric_aliases() {
+        let temp_dir = tempdir().unwrap();
+        std::fs::File::create(temp_dir.path().join("1.nef")).unwrap();
+        let collector = super::PhotoBacklogCollector {
+            metric_aliases: &[("photo_backlog_files", "photo_backlog_counts")],
+            ..nef_collector(temp_dir.path().to_path_buf())
+        };
+        let buffer = super::encode_to_text(collector).unwrap();
+        assert_that!(buffer).contains(format!(
+            "photo_backlog_schema_version {}\n",
+            super::SCHEMA_VERSION
+        ));
+        assert_that!(buffer).contains("photo_backlog_counts{kind=\"photos\"} 1\n");
+        assert_that!(buffer).contains(
+            "# HELP photo_backlog_files Deprecated alias of photo_backlog_counts\n\
+             # TYPE photo_backlog_files gauge\n",
+        );
+        // The samples' order follows the family's, which isn't fixed.
+        assert_that!(buffer).contains("photo_backlog_files{kind=\"photos\"} 1\n");
+    }
+
+    #[test]
+    fn test_scan_ids() {
+        let temp_dir = tempdir().unwrap();
+        let collector = nef_collector(temp_dir.path().to_path_buf());
+        let now = std::time::SystemTime::now();
+        let first = collector.scan_root(&collector.scan_path, now);
+        let second = collector.scan_root(&collector.scan_path, now);
+        assert_that!(second.scan_id).is_equal_to(first.scan_id + 1);
+        let buffer = super::encode_to_text(collector).unwrap();
+        let expected = format!("photo_backlog_last_scan_id {}", first.scan_id + 2);
+        assert_that!(buffer).contains(expected.as_str());
+    }
+
+    #[test]
+    fn test_exts_file_reload() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::File::create(root.join("1.nef")).unwrap();
+        std::fs::File::create(root.join("1.cr3")).unwrap();
+        let exts_file = temp_dir.path().join("exts.yaml");
+        std::fs::write(&exts_file, "raw: [nef]\nignored: [cr3]\n").unwrap();
+        let collector = std::sync::Arc::new(super::PhotoBacklogCollector {
+            exts_file: Some(exts_file.clone()),
+            ..nef_collector(root)
+        });
+        let scan = || collector.scan_root(&collector.scan_path, std::time::SystemTime::now());
+        assert_that!(scan().total_files).is_equal_to(1);
+        std::fs::write(&exts_file, "raw: [nef, cr3]\n").unwrap();
+        assert_that!(scan().total_files).is_equal_to(2);
+        // Broken edits keep the last good lists.
+        std::fs::write(&exts_file, "raw: [").unwrap();
+        assert_that!(scan().total_files).is_equal_to(2);
+    }
+
+    #[test]
+    fn test_folder_owner_metrics() {
+        let temp_dir = tempdir().unwrap();
+        for (folder, files) in [("big", 3), ("small", 1)] {
+            let folder = temp_dir.path().join(folder);
+            std::fs::create_dir(&folder).unwrap();
+            for i in 0..files {
+                std::fs::File::create(folder.join(format!("{}.nef", i))).unwrap();
+            }
+        }
+        let owner = super::user_names()
+            .remove(&std::os::unix::fs::MetadataExt::uid(
+                &std::fs::metadata(temp_dir.path()).unwrap(),
+            ))
+            .unwrap_or_default();
+        let collector = super::PhotoBacklogCollector {
+            folder_owner_metrics: true,
+            folder_owner_max_series: 1,
+            ..nef_collector(temp_dir.path().to_path_buf())
+        };
+        let buffer = super::encode_to_text(collector).unwrap();
+        assert_that!(buffer).contains(format!(
+            "photo_backlog_folder_owner_files{{path=\"big\",owner=\"{}\"}} 3",
+            owner
+        ));
+        assert_that!(buffer).does_not_contain("path=\"small\",owner");
+    }
+
+    #[test]
+    fn test_folder_age_bucket_metrics() {
+        let temp_dir = tempdir().unwrap();
+        let now = std::time::SystemTime::now();
+        let day = Duration::from_secs(86400);
+        // Ages in days per file.
+        for (folder, ages) in [("big", vec![2, 10, 40]), ("small", vec![1])] {
+            let folder = temp_dir.path().join(folder);
+            std::fs::create_dir(&folder).unwrap();
+            for (i, age) in ages.into_iter().enumerate() {
+                std::fs::File::create(folder.join(format!("{}.nef", i)))
+                    .and_then(|f| f.set_modified(now - day * age))
+                    .unwrap();
+            }
+        }
+        let collector = super::PhotoBacklogCollector {
+            age_buckets: vec![AgeBound::Seconds(7.0 * 86400.0), AgeBound::Months(1)],
+            folder_age_bucket_metrics: true,
+            folder_age_bucket_max_series: 100,
+            ..nef_collector(temp_dir.path().to_path_buf())
+        };
+        let buffer = super::encode_to_text(collector).unwrap();
+        // Calendar buckets are labeled with their nominal bound.
+        for (labels, count) in [
+            ("path=\"big\",le=\"604800.0\"", 1),
+            ("path=\"big\",le=\"2629746.0\"", 2),
+            ("path=\"big\",le=\"+Inf\"", 3),
+            ("path=\"small\",le=\"604800.0\"", 1),
+            ("path=\"small\",le=\"+Inf\"", 1),
+        ] {
+            assert_that!(buffer).contains(format!(
+                "photo_backlog_folder_age_bucket_files{{{}}} {}\n",
+                labels, count
+            ));
+        }
+        // Whole folders are dropped over the limit, the smallest first.
+        let collector = super::PhotoBacklogCollector {
+            folder_age_bucket_metrics: true,
+            folder_age_bucket_max_series: 3,
+            ..nef_collector(temp_dir.path().to_path_buf())
+        };
+        let buffer = super::encode_to_text(collector).unwrap();
+        assert_that!(buffer)
+            .contains("photo_backlog_folder_age_bucket_files{path=\"big\",le=\"+Inf\"} 3");
+        assert_that!(buffer).does_not_contain("path=\"small\",le");
+    }
+
+    #[test]
+    fn test_oldest_file_metric() {
+        let temp_dir = tempdir().unwrap();
+        let folder = temp_dir.path().join("2024-06-15 Smith");
+        std::fs::create_dir(&folder).unwrap();
+        std::fs::File::create(folder.join("1.nef"))
+            .and_then(|f| f.set_modified(std::time::UNIX_EPOCH))
+            .unwrap();
+        std::fs::File::create(folder.join("2.nef")).unwrap();
+        let collector = nef_collector(temp_dir.path().to_path_buf());
+        let buffer = super::encode_to_text(collector).unwrap();
+        assert_that!(buffer).does_not_contain("photo_backlog_oldest_file_info");
+        let collector = super::PhotoBacklogCollector {
+            oldest_file_metric: true,
+            ..nef_collector(temp_dir.path().to_path_buf())
+        };
+        let buffer = super::encode_to_text(collector).unwrap();
+        assert_that!(buffer).contains(
+            "photo_backlog_oldest_file_info{path=\"2024-06-15 Smith/1.nef\",folder=\"2024-06-15 Smith\"} 1",
+        );
+    }
+
+    #[test]
+    fn test_oldest_age_by_extension() {
+        let temp_dir = tempdir().unwrap();
+        let folder = temp_dir.path().join("folder");
+        std::fs::create_dir(&folder).unwrap();
+        std::fs::File::create(folder.join("1.nef"))
+            .and_then(|f| f.set_modified(std::time::UNIX_EPOCH))
+            .unwrap();
+        std::fs::File::create(folder.join("2.nef")).unwrap();
+        let collector = nef_collector(temp_dir.path().to_path_buf());
+        let buffer = super::encode_to_text(collector).unwrap();
+        let age: f64 = buffer
+            .lines()
+            .find_map(|l| l.strip_prefix("photo_backlog_oldest_age_by_extension{ext=\"nef\"} "))
+            .expect("oldest age of the nef files")
+            .parse()
+            .unwrap();
+        // The age of the first file, since the epoch.
+        assert_that!(age).is_greater_than(50.0 * 365.0 * 86400.0);
+        assert_that!(buffer).does_not_contain("ext=\"jpg\"");
+    }
+
+    #[test]
+    fn test_folder_tags_metric() {
+        let temp_dir = tempdir().unwrap();
+        for folder in ["tagged", "untagged"] {
+            std::fs::create_dir(temp_dir.path().join(folder)).unwrap();
+            std::fs::File::create(temp_dir.path().join(folder).join("1.nef")).unwrap();
+        }
+        std::fs::write(
+            temp_dir.path().join("tagged").join(crate::TAGS_FILE),
+            "client=smith,priority=high\nsecret=x\n",
+        )
+        .unwrap();
+        let collector = super::PhotoBacklogCollector {
+            folder_tag_keys: vec!["client".to_string(), "priority".to_string()],
+            ..nef_collector(temp_dir.path().to_path_buf())
+        };
+        let buffer = super::encode_to_text(collector).unwrap();
+        assert_that!(buffer).contains(
+            "photo_backlog_folder_tags_info{path=\"tagged\",client=\"smith\",priority=\"high\"} 1",
+        );
+        assert_that!(buffer).does_not_contain("secret");
+        assert_that!(buffer).does_not_contain("path=\"untagged\",client");
+        assert_that!(buffer).contains("photo_backlog_folder_sizes{path=\"tagged\"} 1");
+    }
+
+    #[test]
+    fn test_priority_metrics() {
+        let temp_dir = tempdir().unwrap();
+        for (folder, files) in [("Client a", 2), ("Client b", 1), ("misc", 1)] {
+            let dir = temp_dir.path().join(folder);
+            std::fs::create_dir(&dir).unwrap();
+            for i in 0..files {
+                std::fs::File::create(dir.join(format!("{}.nef", i))).unwrap();
+            }
+        }
+        // The tag wins over the rules.
+        std::fs::write(
+            temp_dir.path().join("Client a").join(crate::TAGS_FILE),
+            "priority=high",
+        )
+        .unwrap();
+        let rules =
+            crate::relabel::RelabelRules::parse("- regex: 'Client .*'\n  replacement: normal\n")
+                .unwrap();
+        let collector = super::PhotoBacklogCollector {
+            folder_tag_keys: vec!["priority".to_string()],
+            folder_priorities: Some(rules),
+            ..nef_collector(temp_dir.path().to_path_buf())
+        };
+        let buffer = super::encode_to_text(collector).unwrap();
+        assert_that!(buffer).contains("photo_backlog_files_by_priority{priority=\"high\"} 2");
+        assert_that!(buffer).contains("photo_backlog_files_by_priority{priority=\"normal\"} 1");
+        assert_that!(buffer).contains("photo_backlog_files_by_priority{priority=\"none\"} 1");
+        assert_that!(buffer).contains("photo_backlog_ages_by_priority{priority=\"high\"} ");
+        let collector = nef_collector(temp_dir.path().to_path_buf());
+        let buffer = super::encode_to_text(collector).unwrap();
+        assert_that!(buffer).does_not_contain("by_priority");
+    }
+
+    #[test]
+    fn test_file_size_metric() {
+        let temp_dir = tempdir().unwrap();
+        std::fs::write(temp_dir.path().join("1.nef"), [0u8; 10]).unwrap();
+        std::fs::write(temp_dir.path().join("2.nef"), [0u8; 20]).unwrap();
+        let buffer = super::encode_to_text(nef_collector(temp_dir.path().to_path_buf())).unwrap();
+        assert_that!(buffer).contains("photo_backlog_file_size_mean_bytes{class=\"raw\"} 15.0");
+        assert_that!(buffer).does_not_contain("class=\"editable\"");
+    }
+
+    #[test]
+    fn test_folders_cleared() {
+        let temp_dir = tempdir().unwrap();
+        for folder in ["a", "b", "c"] {
+            std::fs::create_dir(temp_dir.path().join(folder)).unwrap();
+            std::fs::File::create(temp_dir.path().join(folder).join("1.nef")).unwrap();
+        }
+        let collector = std::sync::Arc::new(nef_collector(temp_dir.path().to_path_buf()));
+        let scrape = || {
+            let mut registry = Registry::default();
+            registry.register_collector(Box::new(super::ScrapeCollector {
+                collector: std::sync::Arc::clone(&collector),
+                options: Default::default(),
+            }));
+            let mut buffer = String::new();
+            encode(&mut buffer, &registry).unwrap();
+            buffer
+        };
+        assert_that!(scrape()).contains("photo_backlog_folders_cleared_total 0");
+        // Emptied and removed folders count, once.
+        std::fs::remove_file(temp_dir.path().join("a").join("1.nef")).unwrap();
+        std::fs::remove_dir_all(temp_dir.path().join("b")).unwrap();
+        assert_that!(scrape()).contains("photo_backlog_folders_cleared_total 2");
+        assert_that!(scrape()).contains("photo_backlog_folders_cleared_total 2");
+    }
+
+    #[test]
+    fn test_cleared_folders_truncated() {
+        let backlog = |folders: &[&str], untracked_files| {
+            let mut backlog = crate::Backlog::new(std::iter::empty());
+            for folder in folders {
+                backlog.folders.insert(folder.to_string(), (1, 0.0));
+            }
+            backlog.untracked_files = untracked_files;
+            backlog
+        };
+        assert_that!(super::cleared_folders(
+            &backlog(&["a", "b"], 0),
+            &backlog(&["a"], 0)
+        ))
+        .is_equal_to(1);
+        // Folders beyond the memory budget aren't tracked, whether in the
+        // previous or in the current scan.
+        assert_that!(super::cleared_folders(
+            &backlog(&["a", "b"], 0),
+            &backlog(&["a"], 1)
+        ))
+        .is_equal_to(0);
+        assert_that!(super::cleared_folders(
+            &backlog(&["a", "b"], 1),
+            &backlog(&["a"], 0)
+        ))
+        .is_equal_to(0);
+    }
+
+    #[test]
+    fn test_disabled_collectors() {
+        let temp_dir = tempdir().unwrap();
+        std::fs::File::create(temp_dir.path().join("1.nef")).unwrap();
+        let collector = super::PhotoBacklogCollector {
+            disabled_collectors: vec![super::MetricGroup::Folders, super::MetricGroup::Ages],
+            ..nef_collector(temp_dir.path().to_path_buf())
+        };
+        let collector = std::sync::Arc::new(collector);
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(super::ScrapeCollector {
+            collector,
+            // Selecting a disabled group doesn't re-enable it.
+            options: super::ScrapeOptions::from_query("collect[]=counts&collect[]=ages").unwrap(),
+        }));
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert_that!(buffer).contains("photo_backlog_counts{kind=\"photos\"} 1");
+        assert_that!(buffer).does_not_contain("photo_backlog_ages");
+        assert_that!(buffer).does_not_contain("photo_backlog_folder_sizes");
     }
 }
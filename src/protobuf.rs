@@ -0,0 +1,459 @@
+//! The Prometheus protobuf exposition format, i.e. length-delimited
+//! `io.prometheus.client.MetricFamily` messages, served on `/metrics` when
+//! the scraper prefers it. Metric names, and the type and help, are then
+//! sent once per family rather than on every sample line; label names are
+//! still repeated in each sample's label pairs, so the per-folder
+//! families of large trees mostly save on parsing rather than size.
+//!
+//! The metrics are converted from the text format, as encoded by the
+//! collectors, so both formats always carry the same data; the messages
+//! are small enough to be encoded by hand.
+
+/// The content type of the delimited protobuf format.
+pub const CONTENT_TYPE: &str =
+    "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited";
+
+const MEDIA_TYPE: &str = "application/vnd.google.protobuf";
+const PROTO: &str = "io.prometheus.client.MetricFamily";
+
+// The `MetricType` enum values.
+const COUNTER: u64 = 0;
+const GAUGE: u64 = 1;
+const UNTYPED: u64 = 3;
+const HISTOGRAM: u64 = 4;
+
+/// Whether the given `Accept` header prefers (or ties with) the protobuf
+/// format over the text ones.
+/// Example:
+/// ```
+/// use photo_backlog_exporter::protobuf::negotiated;
+/// assert!(negotiated("application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited;q=0.7,text/plain;version=0.0.4;q=0.3,*/*;q=0.2"));
+/// assert!(!negotiated("application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5,*/*;q=0.1"));
+/// assert!(!negotiated("application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=text"));
+/// ```
+pub fn negotiated(accept: &str) -> bool {
+    let mut protobuf: Option<f64> = None;
+    let mut other = 0.0f64;
+    for range in accept.split(',') {
+        let mut params = range.split(';').map(str::trim);
+        let media_type = params.next().unwrap_or_default();
+        let (mut q, mut proto, mut encoding) = (1.0, None, None);
+        for param in params {
+            match param.split_once('=') {
+                Some(("q", value)) => q = value.parse().unwrap_or(0.0),
+                Some(("proto", value)) => proto = Some(value),
+                Some(("encoding", value)) => encoding = Some(value),
+                _ => {}
+            }
+        }
+        if media_type.eq_ignore_ascii_case(MEDIA_TYPE)
+            && proto == Some(PROTO)
+            && encoding == Some("delimited")
+        {
+            protobuf = Some(protobuf.unwrap_or(0.0).max(q));
+        } else {
+            other = other.max(q);
+        }
+    }
+    protobuf.is_some_and(|q| q > 0.0 && q >= other)
+}
+
+/// A protobuf message being encoded.
+#[derive(Default)]
+pub(crate) struct Message(pub(crate) Vec<u8>);
+
+impl Message {
+    pub(crate) fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    pub(crate) fn uint64(&mut self, field: u64, value: u64) {
+        self.varint(field << 3);
+        self.varint(value);
+    }
+
+    pub(crate) fn double(&mut self, field: u64, value: f64) {
+        self.varint(field << 3 | 1);
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn bytes(&mut self, field: u64, value: &[u8]) {
+        self.varint(field << 3 | 2);
+        self.varint(value.len() as u64);
+        self.0.extend_from_slice(value);
+    }
+
+    pub(crate) fn string(&mut self, field: u64, value: &str) {
+        if !value.is_empty() {
+            self.bytes(field, value.as_bytes());
+        }
+    }
+
+    pub(crate) fn message(&mut self, field: u64, message: Message) {
+        self.bytes(field, &message.0);
+    }
+}
+
+type Labels = Vec<(String, String)>;
+
+/// A sample line: name, labels and value.
+pub(crate) struct Sample {
+    pub(crate) name: String,
+    pub(crate) labels: Labels,
+    pub(crate) value: f64,
+}
+
+/// Parses a sample line, ignoring any timestamp or exemplar.
+pub(crate) fn parse_sample(line: &str) -> Result<Sample, String> {
+    let error = || format!("Invalid sample line '{}'", line);
+    let name_end = line.find(['{', ' ']).ok_or_else(error)?;
+    let (name, mut rest) = line.split_at(name_end);
+    let mut labels = Vec::new();
+    if let Some(mut chars) = rest.strip_prefix('{') {
+        loop {
+            chars = chars.trim_start_matches(',');
+            if let Some(after) = chars.strip_prefix('}') {
+                rest = after;
+                break;
+            }
+            let (label, after) = chars.split_once("=\"").ok_or_else(error)?;
+            let mut value = String::new();
+            let mut iter = after.char_indices();
+            let end = loop {
+                match iter.next().ok_or_else(error)? {
+                    (i, '"') => break i,
+                    (_, '\\') => match iter.next().ok_or_else(error)?.1 {
+                        'n' => value.push('\n'),
+                        c => value.push(c),
+                    },
+                    (_, c) => value.push(c),
+                }
+            };
+            labels.push((label.to_string(), value));
+            chars = &after[end + 1..];
+        }
+    }
+    let value = rest.split_whitespace().next().ok_or_else(error)?;
+    let value = value.parse().map_err(|_| error())?;
+    Ok(Sample {
+        name: name.to_string(),
+        labels,
+        value,
+    })
+}
+
+/// A metric family as described in the text format, with its samples.
+struct Family<'a> {
+    name: &'a str,
+    help: &'a str,
+    kind: &'a str,
+    unit: &'a str,
+    samples: Vec<Sample>,
+}
+
+fn label_pairs(metric: &mut Message, labels: &[(String, String)]) {
+    for (name, value) in labels {
+        let mut pair = Message::default();
+        pair.string(1, name);
+        pair.string(2, value);
+        metric.message(1, pair);
+    }
+}
+
+/// Encodes a metric with a single value, in the given `Metric` field.
+fn single_value(labels: &[(String, String)], field: u64, value: f64) -> Message {
+    let mut metric = Message::default();
+    label_pairs(&mut metric, labels);
+    let mut inner = Message::default();
+    inner.double(1, value);
+    metric.message(field, inner);
+    metric
+}
+
+#[derive(Default)]
+struct HistogramData {
+    count: f64,
+    sum: f64,
+    buckets: Vec<(f64, f64)>,
+}
+
+impl Family<'_> {
+    /// Returns the family's name, type and `Metric` messages.
+    fn metrics(&self) -> Result<(String, u64, Vec<Message>), String> {
+        match self.kind {
+            "counter" => {
+                let name = format!("{}_total", self.name);
+                let metrics = self
+                    .samples
+                    .iter()
+                    .filter(|s| s.name == name)
+                    .map(|s| single_value(&s.labels, 3, s.value))
+                    .collect();
+                Ok((name, COUNTER, metrics))
+            }
+            "histogram" => Ok((self.name.to_string(), HISTOGRAM, self.histograms()?)),
+            "gauge" | "info" => {
+                // Info metrics become a gauge of their single sample.
+                let name = match self.kind {
+                    "info" => format!("{}_info", self.name),
+                    _ => self.name.to_string(),
+                };
+                let metrics = self
+                    .samples
+                    .iter()
+                    .map(|s| single_value(&s.labels, 2, s.value))
+                    .collect();
+                Ok((name, GAUGE, metrics))
+            }
+            _ => {
+                let metrics = self
+                    .samples
+                    .iter()
+                    .map(|s| single_value(&s.labels, 5, s.value))
+                    .collect();
+                Ok((self.name.to_string(), UNTYPED, metrics))
+            }
+        }
+    }
+
+    fn histograms(&self) -> Result<Vec<Message>, String> {
+        // Per label set, in order of appearance.
+        let mut series: Vec<(Labels, HistogramData)> = Vec::new();
+        for sample in &self.samples {
+            let suffix = sample.name.strip_prefix(self.name).unwrap_or_default();
+            let mut labels = sample.labels.clone();
+            let le = labels.iter().position(|(name, _)| name == "le");
+            let le = le.map(|pos| labels.remove(pos).1);
+            let data = match series.iter().position(|(l, _)| *l == labels) {
+                Some(pos) => &mut series[pos].1,
+                None => {
+                    series.push((labels, HistogramData::default()));
+                    &mut series.last_mut().unwrap().1
+                }
+            };
+            match (suffix, le) {
+                ("_count", _) => data.count = sample.value,
+                ("_sum", _) => data.sum = sample.value,
+                ("_bucket", Some(le)) => {
+                    let bound: f64 = le
+                        .parse()
+                        .map_err(|_| format!("Invalid bucket bound '{}' of {}", le, self.name))?;
+                    // Implied by the sample count.
+                    if bound.is_finite() {
+                        data.buckets.push((bound, sample.value));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(series
+            .into_iter()
+            .map(|(labels, data)| {
+                let mut histogram = Message::default();
+                histogram.uint64(1, data.count as u64);
+                histogram.double(2, data.sum);
+                for (bound, count) in data.buckets {
+                    let mut bucket = Message::default();
+                    bucket.uint64(1, count as u64);
+                    bucket.double(2, bound);
+                    histogram.message(3, bucket);
+                }
+                let mut metric = Message::default();
+                label_pairs(&mut metric, &labels);
+                metric.message(7, histogram);
+                metric
+            })
+            .collect())
+    }
+
+    fn encode(&self, out: &mut Message) -> Result<(), String> {
+        let (name, kind, metrics) = self.metrics()?;
+        let mut family = Message::default();
+        family.string(1, &name);
+        family.string(2, self.help);
+        family.uint64(3, kind);
+        for metric in metrics {
+            family.message(4, metric);
+        }
+        family.string(5, self.unit);
+        out.varint(family.0.len() as u64);
+        out.0.extend_from_slice(&family.0);
+        Ok(())
+    }
+}
+
+/// Converts metrics from the text format to the delimited protobuf one.
+pub fn from_text(text: &str) -> Result<Vec<u8>, String> {
+    let mut out = Message::default();
+    let mut family: Option<Family> = None;
+    for line in text.lines() {
+        if line == "# EOF" {
+            break;
+        }
+        if let Some(rest) = line.strip_prefix("# HELP ") {
+            if let Some(family) = family.take() {
+                family.encode(&mut out)?;
+            }
+            let (name, help) = rest.split_once(' ').unwrap_or((rest, ""));
+            family = Some(Family {
+                name,
+                help,
+                kind: "unknown",
+                unit: "",
+                samples: Vec::new(),
+            });
+            continue;
+        }
+        let Some(current) = family.as_mut() else {
+            continue;
+        };
+        if let Some(rest) = line.strip_prefix("# TYPE ") {
+            current.kind = rest.rsplit(' ').next().unwrap_or_default();
+        } else if let Some(rest) = line.strip_prefix("# UNIT ") {
+            current.unit = rest.rsplit(' ').next().unwrap_or_default();
+        } else if !line.is_empty() && !line.starts_with('#') {
+            current.samples.push(parse_sample(line)?);
+        }
+    }
+    if let Some(family) = family {
+        family.encode(&mut out)?;
+    }
+    Ok(out.0)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use speculoos::prelude::*;
+
+    use super::from_text;
+
+    #[derive(Debug, PartialEq)]
+    pub(crate) enum Value {
+        Varint(u64),
+        Double(f64),
+        Bytes(Vec<u8>),
+    }
+
+    fn varint(data: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for shift in (0..).step_by(7) {
+            let byte = data[0];
+            *data = &data[1..];
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte < 0x80 {
+                break;
+            }
+        }
+        value
+    }
+
+    /// Decodes the fields of a message, enough for the tests.
+    pub(crate) fn fields(mut data: &[u8]) -> Vec<(u64, Value)> {
+        let mut fields = Vec::new();
+        while !data.is_empty() {
+            let key = varint(&mut data);
+            let value = match key & 7 {
+                0 => Value::Varint(varint(&mut data)),
+                1 => {
+                    let (value, rest) = data.split_at(8);
+                    data = rest;
+                    Value::Double(f64::from_le_bytes(value.try_into().unwrap()))
+                }
+                2 => {
+                    let len = varint(&mut data) as usize;
+                    let (value, rest) = data.split_at(len);
+                    data = rest;
+                    Value::Bytes(value.to_vec())
+                }
+                wire => panic!("Unexpected wire type {}", wire),
+            };
+            fields.push((key >> 3, value));
+        }
+        fields
+    }
+
+    pub(crate) fn bytes(value: &Value) -> &[u8] {
+        match value {
+            Value::Bytes(bytes) => bytes,
+            other => panic!("Expected bytes, got {:?}", other),
+        }
+    }
+
+    fn families(mut data: &[u8]) -> Vec<Vec<(u64, Value)>> {
+        let mut families = Vec::new();
+        while !data.is_empty() {
+            let len = varint(&mut data) as usize;
+            let (family, rest) = data.split_at(len);
+            families.push(fields(family));
+            data = rest;
+        }
+        families
+    }
+
+    fn string(s: &str) -> Value {
+        Value::Bytes(s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_gauge_and_counter() {
+        let text = "# HELP photo_backlog_counts Backlog size.\n\
+                    # TYPE photo_backlog_counts gauge\n\
+                    photo_backlog_counts{kind=\"photos\",path=\"a \\\"b\\\" {c}\"} 3\n\
+                    # HELP scrapes Scrapes.\n\
+                    # TYPE scrapes counter\n\
+                    scrapes_total 2 # {trace=\"x\"} 1.0\n\
+                    # EOF\n";
+        let families = families(&from_text(text).unwrap());
+        assert_that!(families).has_length(2);
+        let gauge = &families[0];
+        assert_that!(gauge[0]).is_equal_to((1, string("photo_backlog_counts")));
+        assert_that!(gauge[1]).is_equal_to((2, string("Backlog size.")));
+        assert_that!(gauge[2]).is_equal_to((3, Value::Varint(1)));
+        let metric = fields(bytes(&gauge[3].1));
+        let labels: Vec<_> = metric[..2].iter().map(|(_, l)| fields(bytes(l))).collect();
+        assert_that!(labels[1]).is_equal_to(vec![(1, string("path")), (2, string("a \"b\" {c}"))]);
+        assert_that!(fields(bytes(&metric[2].1))).is_equal_to(vec![(1, Value::Double(3.0))]);
+
+        let counter = &families[1];
+        assert_that!(counter[0]).is_equal_to((1, string("scrapes_total")));
+        assert_that!(counter[2]).is_equal_to((3, Value::Varint(0)));
+        let metric = fields(bytes(&counter[3].1));
+        assert_that!(metric[0].0).is_equal_to(3);
+        assert_that!(fields(bytes(&metric[0].1))).is_equal_to(vec![(1, Value::Double(2.0))]);
+    }
+
+    #[test]
+    fn test_histogram() {
+        let text = "# HELP ages_seconds Ages.\n\
+                    # TYPE ages_seconds histogram\n\
+                    # UNIT ages_seconds seconds\n\
+                    ages_seconds_sum{kind=\"raw\"} 5.5\n\
+                    ages_seconds_count{kind=\"raw\"} 3\n\
+                    ages_seconds_bucket{le=\"1.0\",kind=\"raw\"} 1\n\
+                    ages_seconds_bucket{le=\"+Inf\",kind=\"raw\"} 3\n";
+        let families = families(&from_text(text).unwrap());
+        let family = &families[0];
+        assert_that!(family[2]).is_equal_to((3, Value::Varint(4)));
+        assert_that!(family[4]).is_equal_to((5, string("seconds")));
+        let metric = fields(bytes(&family[3].1));
+        assert_that!(fields(bytes(&metric[0].1)))
+            .is_equal_to(vec![(1, string("kind")), (2, string("raw"))]);
+        let histogram = fields(bytes(&metric[1].1));
+        assert_that!(histogram[0]).is_equal_to((1, Value::Varint(3)));
+        assert_that!(histogram[1]).is_equal_to((2, Value::Double(5.5)));
+        // The +Inf bucket is implied.
+        assert_that!(histogram).has_length(3);
+        assert_that!(fields(bytes(&histogram[2].1)))
+            .is_equal_to(vec![(1, Value::Varint(1)), (2, Value::Double(1.0))]);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_that!(from_text("# HELP a A.\n# TYPE a gauge\na{x=\"1} 1\n")).is_err();
+        assert_that!(from_text("# HELP a A.\n# TYPE a gauge\na one\n")).is_err();
+    }
+}
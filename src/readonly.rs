@@ -0,0 +1,162 @@
+//! Support for `--assert-readonly`: asserting at startup that the process
+//! can't modify the photo tree, by dropping the capabilities allowing
+//! writes regardless of permissions and checking that the roots aren't
+//! writable otherwise. The exporter itself has no actions modifying the
+//! tree (`oneshot --fix-plan` only prints commands), so this guards
+//! against bugs and misconfiguration, e.g. running it as the tree's owner.
+
+use std::path::{Path, PathBuf};
+
+#[cfg(target_os = "linux")]
+use rustix::thread::{Capability, CapabilityFlags};
+
+/// Capabilities bypassing file permissions or allowing to change them,
+/// directly or by becoming another user; `CAP_SETPCAP`, needed for
+/// removing capabilities from the bounding set, last.
+#[cfg(target_os = "linux")]
+const WRITE_CAPABILITIES: &[(Capability, CapabilityFlags)] = &[
+    (Capability::ChangeOwnership, CapabilityFlags::CHOWN),
+    (Capability::DACOverride, CapabilityFlags::DAC_OVERRIDE),
+    (Capability::FileOwner, CapabilityFlags::FOWNER),
+    (Capability::FileSetID, CapabilityFlags::FSETID),
+    (Capability::LinuxImmutable, CapabilityFlags::LINUX_IMMUTABLE),
+    (Capability::MakeNode, CapabilityFlags::MKNOD),
+    (Capability::SetUserID, CapabilityFlags::SETUID),
+    (Capability::SetGroupID, CapabilityFlags::SETGID),
+    (Capability::SetFileCapabilities, CapabilityFlags::SETFCAP),
+    (Capability::SystemAdmin, CapabilityFlags::SYS_ADMIN),
+    (
+        Capability::SetPermittedCapabilities,
+        CapabilityFlags::SETPCAP,
+    ),
+];
+
+/// Drops the write capabilities (see `WRITE_CAPABILITIES`) of the calling
+/// thread for good, so it must be called before any other threads are
+/// started, which then inherit this.
+#[cfg(target_os = "linux")]
+pub fn drop_write_capabilities() -> Result<(), String> {
+    use rustix::io::Errno;
+    use rustix::thread;
+
+    let error = |e: Errno| format!("Can't drop the write capabilities: {}", e);
+    // No regaining them by executing setuid or file capabilities binaries.
+    thread::set_no_new_privs(true).map_err(error)?;
+    for (capability, _) in WRITE_CAPABILITIES {
+        match thread::remove_capability_from_bounding_set(*capability) {
+            // Without CAP_SETPCAP, they can't be regained anyway.
+            Ok(()) | Err(Errno::PERM) => {}
+            Err(e) => return Err(error(e)),
+        }
+    }
+    let dropped = WRITE_CAPABILITIES
+        .iter()
+        .fold(CapabilityFlags::empty(), |flags, (_, flag)| flags | *flag);
+    let mut sets = thread::capabilities(None).map_err(error)?;
+    sets.effective.remove(dropped);
+    sets.permitted.remove(dropped);
+    sets.inheritable.remove(dropped);
+    thread::set_capabilities(None, sets).map_err(error)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn drop_write_capabilities() -> Result<(), String> {
+    Err("--assert-readonly is only supported on Linux".to_string())
+}
+
+/// Checks that each root can be opened as a read-only directory and isn't
+/// writable by the process, and that none of the files the process writes
+/// is under a root.
+pub fn check_roots<'a>(
+    roots: &[PathBuf],
+    outputs: impl IntoIterator<Item = &'a Path>,
+) -> Result<(), String> {
+    let outputs: Vec<_> = outputs.into_iter().map(absolute).collect();
+    for root in roots {
+        check_root(root)?;
+        let absolute_root = absolute(root);
+        if let Some(output) = outputs.iter().find(|o| o.starts_with(&absolute_root)) {
+            return Err(format!(
+                "'{}' would be written under the read-only root '{}'",
+                output.display(),
+                root.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Resolves symlinks as far as the path exists, e.g. for files not yet
+// created.
+fn absolute(path: &Path) -> PathBuf {
+    if let Ok(path) = path.canonicalize() {
+        return path;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if !parent.as_os_str().is_empty() => absolute(parent).join(name),
+        _ => std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn check_root(root: &Path) -> Result<(), String> {
+    use rustix::fs::{self, Access, AtFlags, Mode, OFlags};
+
+    let dir = fs::open(root, OFlags::RDONLY | OFlags::DIRECTORY, Mode::empty())
+        .map_err(|e| format!("Can't open root '{}' read-only: {}", root.display(), e))?;
+    // As per the effective ids and capabilities.
+    if fs::accessat(&dir, ".", Access::WRITE_OK, AtFlags::EACCESS).is_ok() {
+        return Err(format!(
+            "Root '{}' is writable by the exporter, despite --assert-readonly",
+            root.display()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn check_root(_root: &Path) -> Result<(), String> {
+    Err("--assert-readonly is only supported on Linux".to_string())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use rustix::thread::{self, CapabilityFlags};
+    use speculoos::prelude::*;
+    use tempfile::tempdir;
+
+    use super::{check_roots, drop_write_capabilities};
+
+    #[test]
+    fn test_assert_readonly() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path().join("photos");
+        std::fs::create_dir(&root).unwrap();
+        let roots = [root.clone()];
+        // Capabilities are per thread, so don't affect the other tests.
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                drop_write_capabilities().unwrap();
+                let effective = thread::capabilities(None).unwrap().effective;
+                assert_that!(effective.contains(CapabilityFlags::DAC_OVERRIDE)).is_false();
+                assert_that!(check_roots(&roots, []))
+                    .is_err()
+                    .contains("is writable");
+                std::fs::set_permissions(&root, std::fs::Permissions::from_mode(0o555)).unwrap();
+                assert_that!(check_roots(&roots, [])).is_ok();
+                let log = root.join("violations.jsonl");
+                assert_that!(check_roots(&roots, [log.as_path()]))
+                    .is_err()
+                    .contains("under the read-only root");
+                let log = temp_dir.path().join("violations.jsonl");
+                assert_that!(check_roots(&roots, [log.as_path()])).is_ok();
+                assert_that!(check_roots(&[temp_dir.path().join("missing")], []))
+                    .is_err()
+                    .contains("Can't open root");
+            });
+        });
+        std::fs::set_permissions(&root, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+//! Relabeling of folder names before they are exported, so that folders
+//! can be grouped (e.g. by month or client) without renaming them on disk.
+
+use std::borrow::Cow;
+use std::path::Path;
+
+use regex::Regex;
+use serde::Deserialize;
+
+/// Compiles a regex anchored on both ends, so that it must match the whole
+/// folder name.
+/// Example:
+/// ```
+/// use photo_backlog_exporter::relabel::anchored_regex;
+/// let re = anchored_regex("2024-.*").unwrap();
+/// assert!(re.is_match("2024-06-15 Smith Wedding"));
+/// assert!(!re.is_match("old 2024-06"));
+/// assert!(anchored_regex("(").is_err());
+/// ```
+pub fn anchored_regex(re: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{})$", re))
+}
+
+#[derive(Debug, Deserialize)]
+struct RawRule {
+    regex: String,
+    replacement: String,
+}
+
+/// A single relabel rule. Like in Prometheus, the regex is anchored on both
+/// ends, and the replacement can refer to capture groups as `$1`, `${name}`.
+#[derive(Clone, Debug)]
+pub struct RelabelRule {
+    regex: Regex,
+    replacement: String,
+}
+
+/// An ordered list of relabel rules; the first matching rule wins, and
+/// folders not matching any rule keep their name.
+#[derive(Clone, Debug, Default)]
+pub struct RelabelRules(Vec<RelabelRule>);
+
+impl RelabelRules {
+    /// Parses a YAML list of `regex`/`replacement` pairs.
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let raw: Vec<RawRule> = serde_yaml::from_str(contents).map_err(|e| e.to_string())?;
+        raw.into_iter()
+            .map(|r| {
+                anchored_regex(&r.regex)
+                    .map(|regex| RelabelRule {
+                        regex,
+                        replacement: r.replacement,
+                    })
+                    .map_err(|e| format!("Invalid regex '{}': {}", r.regex, e))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(RelabelRules)
+    }
+
+    /// Loads and parses the given relabel rules file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Can't read relabel rules '{}': {}", path.display(), e))?;
+        Self::parse(&contents)
+            .map_err(|e| format!("Can't parse relabel rules '{}': {}", path.display(), e))
+    }
+
+    /// Returns the exported label for the given folder.
+    pub fn apply<'a>(&self, folder: &'a str) -> Cow<'a, str> {
+        self.matching(folder).unwrap_or(Cow::Borrowed(folder))
+    }
+
+    /// Returns the replacement of the first matching rule, if any.
+    pub fn matching<'a>(&self, folder: &'a str) -> Option<Cow<'a, str>> {
+        self.0
+            .iter()
+            .find(|r| r.regex.is_match(folder))
+            .map(|r| r.regex.replace(folder, r.replacement.as_str()))
+    }
+}
+
+/// Wrapper for use as a command line option parser.
+pub fn load_relabel_rules(path: &str) -> Result<RelabelRules, String> {
+    RelabelRules::load(Path::new(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use speculoos::prelude::*;
+
+    use super::RelabelRules;
+
+    #[test]
+    fn test_relabel() {
+        let rules = RelabelRules::parse(
+            r#"
+- regex: '(\d{4}-\d{2})-\d{2} .*'
+  replacement: '$1'
+- regex: '.*(Smith|Jones).*'
+  replacement: 'client-${1}'
+"#,
+        )
+        .expect("parse relabel rules");
+        assert_that!(rules.apply("2024-06-15 Smith Wedding").as_ref()).is_equal_to("2024-06");
+        assert_that!(rules.apply("Jones portraits").as_ref()).is_equal_to("client-Jones");
+        // Anchored, so partial matches don't count.
+        assert_that!(rules.apply("x2024-06-15 y").as_ref()).is_equal_to("x2024-06-15 y");
+        assert_that!(rules.apply("misc").as_ref()).is_equal_to("misc");
+        assert_that!(rules.matching("misc")).is_none();
+    }
+
+    #[test]
+    fn test_relabel_invalid() {
+        assert_that!(RelabelRules::parse("- regex: '('\n  replacement: x\n"))
+            .is_err()
+            .contains("Invalid regex");
+        assert_that!(RelabelRules::parse("foo: bar")).is_err();
+    }
+}
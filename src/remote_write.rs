@@ -0,0 +1,476 @@
+//! Pushing the metrics to a Prometheus remote write endpoint (Prometheus
+//! with `--web.enable-remote-write-receiver`, VictoriaMetrics, Mimir, ...),
+//! for setups without anything scraping the exporter.
+//!
+//! The daemon scans all roots every interval, as a scrape would, and sends
+//! the resulting samples as a snappy-compressed `WriteRequest` (remote
+//! write 1.0), retrying failed requests with exponential backoff until
+//! the next push is due; pushes are never queued, as the next one carries
+//! the current state anyway.
+
+use std::time::{Duration, SystemTime};
+
+use axum::body::Bytes;
+use base64::Engine;
+use http::header::{self, HeaderMap, HeaderValue};
+use http::uri::{Authority, Uri};
+use http::{Request, StatusCode};
+use http_body_util::{BodyExt, Full};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use tokio::time::{Instant, MissedTickBehavior};
+
+use crate::cli::ResponseHeader;
+use crate::daemon::AppHandle;
+use crate::protobuf::{parse_sample, Message};
+
+/// Upper bound of the retry delay.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Delay before the first retry, doubling on each further one.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// Timeout of a single request, unless the interval is shorter.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Settings of the remote write sender.
+#[derive(Clone, Debug)]
+pub struct RemoteWriteConfig {
+    /// The endpoint, with optional `user:password@` credentials for basic
+    /// auth.
+    pub url: Uri,
+    /// How often to scan and push.
+    pub interval: Duration,
+    /// Labels added to all series, unless already set.
+    pub labels: Vec<(String, String)>,
+    /// Extra request headers, e.g. `Authorization`.
+    pub headers: Vec<ResponseHeader>,
+}
+
+/// Converts metrics from the text format to a `WriteRequest` message,
+/// with a series per sample line, all at the given time.
+pub fn write_request(
+    text: &str,
+    labels: &[(String, String)],
+    timestamp_ms: i64,
+) -> Result<Vec<u8>, String> {
+    let mut request = Message::default();
+    for line in text.lines() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let sample = parse_sample(line)?;
+        let mut series_labels = sample.labels;
+        for (name, value) in labels {
+            if !series_labels.iter().any(|(n, _)| n == name) {
+                series_labels.push((name.clone(), value.clone()));
+            }
+        }
+        series_labels.push(("__name__".to_string(), sample.name));
+        // Required by the protocol.
+        series_labels.sort();
+        let mut series = Message::default();
+        for (name, value) in &series_labels {
+            let mut label = Message::default();
+            label.string(1, name);
+            label.string(2, value);
+            series.message(1, label);
+        }
+        let mut point = Message::default();
+        point.double(1, sample.value);
+        point.uint64(2, timestamp_ms as u64);
+        series.message(2, point);
+        request.message(1, series);
+    }
+    Ok(request.0)
+}
+
+/// Why a request failed, and whether to retry it: per the protocol,
+/// client errors other than 429 (Too Many Requests) are final.
+#[derive(Debug)]
+enum SendError {
+    Retryable(String),
+    Final(String),
+}
+
+type HttpsClient = Client<HttpsConnector<HttpConnector>, Full<Bytes>>;
+
+struct Sender {
+    client: HttpsClient,
+    url: Uri,
+    headers: HeaderMap,
+    timeout: Duration,
+}
+
+impl Sender {
+    fn new(config: &RemoteWriteConfig) -> Result<Self, String> {
+        let connector = HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .map_err(|e| format!("Can't load the TLS root certificates: {}", e))?
+            .https_or_http()
+            .enable_http1()
+            .build();
+        let client = Client::builder(TokioExecutor::new()).build(connector);
+        let (url, authorization) = split_credentials(&config.url)?;
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/x-protobuf"),
+        );
+        headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("snappy"));
+        headers.insert(
+            "x-prometheus-remote-write-version",
+            HeaderValue::from_static("0.1.0"),
+        );
+        headers.insert(
+            header::USER_AGENT,
+            HeaderValue::from_static(concat!(
+                "photo-backlog-exporter/",
+                env!("CARGO_PKG_VERSION")
+            )),
+        );
+        if let Some(authorization) = authorization {
+            headers.insert(header::AUTHORIZATION, authorization);
+        }
+        for h in &config.headers {
+            headers.insert(h.name.clone(), h.value.clone());
+        }
+        Ok(Sender {
+            client,
+            url,
+            headers,
+            timeout: REQUEST_TIMEOUT.min(config.interval),
+        })
+    }
+
+    async fn send(&self, body: Bytes) -> Result<(), SendError> {
+        let mut request = Request::post(self.url.clone())
+            .body(Full::new(body))
+            .map_err(|e| SendError::Final(e.to_string()))?;
+        request.headers_mut().extend(self.headers.clone());
+        let response = tokio::time::timeout(self.timeout, self.client.request(request))
+            .await
+            .map_err(|_| SendError::Retryable(format!("timed out after {:?}", self.timeout)))?
+            .map_err(|e| SendError::Retryable(e.to_string()))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .map(|body| body.to_bytes())
+            .unwrap_or_default();
+        let message = format!(
+            "{}: {}",
+            status,
+            String::from_utf8_lossy(&body[..body.len().min(200)]).trim()
+        );
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            Err(SendError::Retryable(message))
+        } else {
+            Err(SendError::Final(message))
+        }
+    }
+
+    /// Sends the body, retrying with exponential backoff as long as the
+    /// retry would start before the deadline. Returns whether it was sent.
+    async fn push(&self, body: Bytes, deadline: Instant, mut backoff: Duration) -> bool {
+        loop {
+            match self.send(body.clone()).await {
+                Ok(()) => return true,
+                Err(SendError::Final(e)) => {
+                    log::error!("Remote write to {} rejected: {}", self.url, e);
+                    return false;
+                }
+                Err(SendError::Retryable(e)) if Instant::now() + backoff >= deadline => {
+                    log::error!("Remote write to {} failed, giving up: {}", self.url, e);
+                    return false;
+                }
+                Err(SendError::Retryable(e)) => {
+                    log::warn!(
+                        "Remote write to {} failed, retrying in {:?}: {}",
+                        self.url,
+                        backoff,
+                        e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+}
+
+// Moves `user:password@` credentials from the URL into a basic auth
+// header, as the HTTP client doesn't.
+fn split_credentials(url: &Uri) -> Result<(Uri, Option<HeaderValue>), String> {
+    let invalid = |e: &dyn std::fmt::Display| format!("Invalid remote write URL '{}': {}", url, e);
+    let Some((credentials, host)) = url.authority().and_then(|a| a.as_str().rsplit_once('@'))
+    else {
+        return Ok((url.clone(), None));
+    };
+    let mut parts = url.clone().into_parts();
+    parts.authority = Some(Authority::try_from(host).map_err(|e| invalid(&e))?);
+    let url = Uri::from_parts(parts).map_err(|e| invalid(&e))?;
+    let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+    let mut value = HeaderValue::try_from(format!("Basic {}", encoded)).map_err(|e| invalid(&e))?;
+    value.set_sensitive(true);
+    Ok((url, Some(value)))
+}
+
+/// Scans and pushes the metrics every interval, forever.
+pub async fn run(config: RemoteWriteConfig, handle: AppHandle) -> Result<(), String> {
+    let sender = Sender::new(&config)?;
+    let mut interval = tokio::time::interval(config.interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let labels = std::sync::Arc::new(config.labels);
+    loop {
+        interval.tick().await;
+        let deadline = Instant::now() + config.interval;
+        let handle = handle.clone();
+        let labels = std::sync::Arc::clone(&labels);
+        let body = tokio::task::spawn_blocking(move || {
+            let text = handle.encode()?;
+            let timestamp = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            let request = write_request(&text, &labels, timestamp.as_millis() as i64)?;
+            snap::raw::Encoder::new()
+                .compress_vec(&request)
+                .map_err(|e| e.to_string())
+        })
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|body| body);
+        match body {
+            Ok(body) => {
+                if sender.push(body.into(), deadline, INITIAL_BACKOFF).await {
+                    log::debug!("Pushed the metrics to {}", sender.url);
+                }
+            }
+            Err(e) => log::error!("Can't encode the metrics for remote write: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use axum::body::Bytes;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use axum::Router;
+    use speculoos::prelude::*;
+    use tokio::net::TcpListener;
+    use tokio::time::Instant;
+
+    use super::{split_credentials, write_request, RemoteWriteConfig, Sender};
+    use crate::protobuf::tests::{bytes, fields, Value};
+
+    type Series = (Vec<(String, String)>, f64, u64);
+
+    /// Decodes the series of a request: labels, value and timestamp.
+    fn series(request: &[u8]) -> Vec<Series> {
+        fields(request)
+            .iter()
+            .map(|(_, series)| {
+                let series = fields(bytes(series));
+                let labels = series
+                    .iter()
+                    .filter(|(field, _)| *field == 1)
+                    .map(|(_, label)| {
+                        let label = fields(bytes(label));
+                        let text = |v| String::from_utf8(bytes(v).to_vec()).unwrap();
+                        (text(&label[0].1), text(&label[1].1))
+                    })
+                    .collect();
+                let point = fields(bytes(&series.last().unwrap().1));
+                let (Value::Double(value), Value::Varint(timestamp)) = (&point[0].1, &point[1].1)
+                else {
+                    panic!("Unexpected sample {:?}", point);
+                };
+                (labels, *value, *timestamp)
+            })
+            .collect()
+    }
+
+    fn pairs(labels: &[(&str, &str)]) -> Vec<(String, String)> {
+        labels
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_write_request() {
+        let text = "# HELP photo_backlog_counts Backlog size.\n\
+                    # TYPE photo_backlog_counts gauge\n\
+                    photo_backlog_counts{kind=\"photos\",path=\"a \\\"b\\\"\"} 3\n\
+                    # HELP scrapes Scrapes.\n\
+                    # TYPE scrapes counter\n\
+                    scrapes_total 2\n\
+                    # EOF\n";
+        let labels = pairs(&[("instance", "nas"), ("kind", "ignored")]);
+        let request = write_request(text, &labels, 1_700_000_000_000).unwrap();
+        let series = series(&request);
+        assert_that!(series).is_equal_to(vec![
+            (
+                pairs(&[
+                    ("__name__", "photo_backlog_counts"),
+                    ("instance", "nas"),
+                    ("kind", "photos"),
+                    ("path", "a \"b\""),
+                ]),
+                3.0,
+                1_700_000_000_000,
+            ),
+            (
+                pairs(&[
+                    ("__name__", "scrapes_total"),
+                    ("instance", "nas"),
+                    ("kind", "ignored"),
+                ]),
+                2.0,
+                1_700_000_000_000,
+            ),
+        ]);
+        assert_that!(write_request("a{x=\"1} 1\n", &[], 0)).is_err();
+    }
+
+    #[test]
+    fn test_split_credentials() {
+        let (url, auth) =
+            split_credentials(&"https://u:p@example.com/write".parse().unwrap()).unwrap();
+        assert_that!(url.to_string()).is_equal_to("https://example.com/write".to_string());
+        assert_that!(auth.unwrap()).is_equal_to(axum::http::HeaderValue::from_static("Basic dTpw"));
+        let (url, auth) =
+            split_credentials(&"http://localhost:8428/api/v1/write".parse().unwrap()).unwrap();
+        assert_that!(url.to_string()).is_equal_to("http://localhost:8428/api/v1/write".to_string());
+        assert_that!(auth).is_none();
+    }
+
+    /// Serves an endpoint answering with the given statuses in turn, then
+    /// 204, and recording the request headers and decompressed bodies.
+    async fn endpoint(
+        statuses: Vec<StatusCode>,
+    ) -> (String, Arc<Mutex<Vec<(HeaderMap, Vec<u8>)>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let statuses = Arc::new(Mutex::new(statuses));
+        let app = Router::new().route("/api/v1/write", {
+            let received = Arc::clone(&received);
+            post(move |headers: HeaderMap, body: Bytes| async move {
+                let body = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+                received.lock().unwrap().push((headers, body));
+                let mut statuses = statuses.lock().unwrap();
+                match statuses.is_empty() {
+                    true => StatusCode::NO_CONTENT,
+                    false => statuses.remove(0),
+                }
+            })
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://user:pass@{}/api/v1/write",
+            listener.local_addr().unwrap()
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, received)
+    }
+
+    fn compressed(data: &[u8]) -> Bytes {
+        snap::raw::Encoder::new().compress_vec(data).unwrap().into()
+    }
+
+    fn config(url: &str) -> RemoteWriteConfig {
+        RemoteWriteConfig {
+            url: url.parse().unwrap(),
+            interval: Duration::from_secs(10),
+            labels: Vec::new(),
+            headers: vec![crate::cli::parse_header("X-Scope-OrgID: photos").unwrap()],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_push_retries() {
+        let (url, received) = endpoint(vec![
+            StatusCode::SERVICE_UNAVAILABLE,
+            StatusCode::TOO_MANY_REQUESTS,
+        ])
+        .await;
+        let sender = Sender::new(&config(&url)).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let sent = sender
+            .push(compressed(b"request"), deadline, Duration::from_millis(10))
+            .await;
+        assert_that!(sent).is_true();
+        let received = received.lock().unwrap();
+        assert_that!(*received).has_length(3);
+        let (headers, body) = &received[2];
+        assert_that!(body.as_slice()).is_equal_to(b"request".as_slice());
+        assert_that!(headers["content-encoding"].to_str().unwrap()).is_equal_to("snappy");
+        assert_that!(headers["x-prometheus-remote-write-version"]
+            .to_str()
+            .unwrap())
+        .is_equal_to("0.1.0");
+        assert_that!(headers["x-scope-orgid"].to_str().unwrap()).is_equal_to("photos");
+        assert_that!(headers["authorization"].to_str().unwrap()).is_equal_to("Basic dXNlcjpwYXNz");
+    }
+
+    #[tokio::test]
+    async fn test_push_gives_up() {
+        // Client errors are final.
+        let (url, received) = endpoint(vec![StatusCode::BAD_REQUEST]).await;
+        let sender = Sender::new(&config(&url)).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let sent = sender
+            .push(compressed(b""), deadline, Duration::from_millis(10))
+            .await;
+        assert_that!(sent).is_false();
+        assert_that!(*received.lock().unwrap()).has_length(1);
+        // And retries stop at the deadline.
+        let (url, received) = endpoint(vec![StatusCode::BAD_GATEWAY; 10]).await;
+        let sender = Sender::new(&config(&url)).unwrap();
+        let deadline = Instant::now() + Duration::from_millis(100);
+        let sent = sender
+            .push(compressed(b""), deadline, Duration::from_millis(40))
+            .await;
+        assert_that!(sent).is_false();
+        assert_that!(*received.lock().unwrap()).has_length(2);
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let (url, received) = endpoint(Vec::new()).await;
+        let collector = crate::prometheus::PhotoBacklogCollector {
+            mock_data: Some(crate::MockData {
+                folders: 1,
+                files: 2,
+            }),
+            ..Default::default()
+        };
+        let (_, handle) = crate::daemon::AppBuilder::new(vec![collector]).build();
+        let mut config = config(&url);
+        config.labels = vec![("instance".to_string(), "nas".to_string())];
+        let task = tokio::spawn(super::run(config, handle));
+        for _ in 0..100 {
+            if !received.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        task.abort();
+        let received = received.lock().unwrap();
+        let photos = pairs(&[
+            ("__name__", "photo_backlog_counts"),
+            ("instance", "nas"),
+            ("kind", "photos"),
+        ]);
+        let series = series(&received[0].1);
+        let (_, value, _) = series.iter().find(|(l, _, _)| *l == photos).unwrap();
+        assert_that!(*value).is_equal_to(2.0);
+    }
+}
@@ -0,0 +1,62 @@
+//! Advisory lock file serialising scans of the same tree across processes
+//! (e.g. the daemon and a `oneshot` cron job), via `flock`.
+
+use std::fs::{File, TryLockError};
+use std::path::Path;
+
+/// A held scan lock, released when dropped.
+#[derive(Debug)]
+pub struct ScanLock {
+    _file: File,
+}
+
+fn open(path: &Path) -> Result<File, String> {
+    File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .map_err(|e| format!("Can't open scan lock '{}': {}", path.display(), e))
+}
+
+impl ScanLock {
+    /// Acquires the lock, waiting for another scanner to finish.
+    pub fn acquire(path: &Path) -> Result<ScanLock, String> {
+        let file = open(path)?;
+        file.lock()
+            .map_err(|e| format!("Can't lock '{}': {}", path.display(), e))?;
+        Ok(ScanLock { _file: file })
+    }
+
+    /// Acquires the lock if free, returning none if another scanner
+    /// holds it.
+    pub fn try_acquire(path: &Path) -> Result<Option<ScanLock>, String> {
+        let file = open(path)?;
+        match file.try_lock() {
+            Ok(()) => Ok(Some(ScanLock { _file: file })),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Error(e)) => Err(format!("Can't lock '{}': {}", path.display(), e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use speculoos::prelude::*;
+    use tempfile::tempdir;
+
+    use super::ScanLock;
+
+    #[test]
+    fn test_scan_lock() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("scan.lock");
+        let lock = ScanLock::acquire(&path).unwrap();
+        assert_that!(ScanLock::try_acquire(&path).unwrap()).is_none();
+        drop(lock);
+        assert_that!(ScanLock::try_acquire(&path).unwrap()).is_some();
+        assert_that!(ScanLock::acquire(&temp_dir.path().join("none/scan.lock")))
+            .is_err()
+            .contains("Can't open scan lock");
+    }
+}
@@ -0,0 +1,182 @@
+//! Serialisation of scan results as JSON snapshots, and comparison of two
+//! snapshots to see how the backlog evolved.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+
+use crate::collation::{self, FolderOrder};
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Id of the scan, zero in older snapshots.
+    #[serde(default)]
+    pub scan_id: u64,
+    pub total_files: i64,
+    pub folders: BTreeMap<String, FolderSnapshot>,
+    /// Error counts, per kind.
+    #[serde(default)]
+    pub errors: BTreeMap<String, i64>,
+    /// Individual violations, if they were recorded by the scan.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<ViolationSnapshot>,
+    /// Directories that couldn't be read, whose contents are unaccounted.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unreadable_dirs: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FolderSnapshot {
+    pub files: i64,
+    /// Sum of file ages, in seconds.
+    pub age: f64,
+    /// Allowed tags, from the folder's tags file.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ViolationSnapshot {
+    pub path: String,
+    pub kind: String,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+}
+
+impl From<&super::Violation> for ViolationSnapshot {
+    fn from(v: &super::Violation) -> Self {
+        ViolationSnapshot {
+            path: v.path.to_string_lossy().into_owned(),
+            kind: v.kind.name().to_string(),
+            expected: v.expected.clone(),
+            actual: v.actual.clone(),
+        }
+    }
+}
+
+impl From<&super::Backlog> for Snapshot {
+    fn from(backlog: &super::Backlog) -> Self {
+        Snapshot {
+            scan_id: backlog.scan_id,
+            total_files: backlog.total_files,
+            folders: backlog
+                .folders
+                .iter()
+                .map(|(path, (files, age))| {
+                    (
+                        path.clone(),
+                        FolderSnapshot {
+                            files: *files,
+                            age: *age,
+                            tags: backlog.folder_tags.get(path).cloned().unwrap_or_default(),
+                        },
+                    )
+                })
+                .collect(),
+            errors: backlog
+                .total_errors
+                .iter()
+                .map(|(kind, count)| (kind.name().to_string(), *count))
+                .collect(),
+            violations: backlog
+                .violations
+                .iter()
+                .map(ViolationSnapshot::from)
+                .collect(),
+            unreadable_dirs: backlog
+                .unreadable_dirs
+                .iter()
+                .map(|p| p.to_string_lossy().into_owned())
+                .collect(),
+        }
+    }
+}
+
+impl Snapshot {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Can't read snapshot '{}': {}", path.display(), e))?;
+        serde_json::from_str(&contents)
+            .map_err(|e| format!("Can't parse snapshot '{}': {}", path.display(), e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let contents = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        std::fs::write(path, contents)
+            .map_err(|e| format!("Can't write snapshot '{}': {}", path.display(), e))
+    }
+
+    /// Returns the snapshot as gzip-compressed JSON.
+    pub fn to_gzip_json(&self) -> std::io::Result<Vec<u8>> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        serde_json::to_writer(&mut encoder, self)?;
+        encoder.finish()
+    }
+}
+
+/// Describes, one line per changed folder, how the backlog went from `old`
+/// to `new`, in the given folder order, followed by the change in the
+/// total.
+/// Example:
+/// ```
+/// use photo_backlog_exporter::collation::FolderOrder;
+/// use photo_backlog_exporter::snapshot::{compare, FolderSnapshot, Snapshot};
+/// let folder = |files| FolderSnapshot { files, ..Default::default() };
+/// let old = Snapshot {
+///     total_files: 5,
+///     folders: [("a".into(), folder(2)), ("b".into(), folder(3))].into(),
+///     ..Default::default()
+/// };
+/// let new = Snapshot {
+///     total_files: 4,
+///     folders: [("b".into(), folder(1)), ("c".into(), folder(3))].into(),
+///     ..Default::default()
+/// };
+/// assert_eq!(compare(&old, &new, &FolderOrder::default()), vec![
+///     "- a: 2 files (disappeared)",
+///     "- b: 3 -> 1 files (shrank by 2)",
+///     "+ c: 3 files (appeared)",
+///     "total: 5 -> 4 files (-1)",
+/// ]);
+/// ```
+pub fn compare(old: &Snapshot, new: &Snapshot, order: &FolderOrder) -> Vec<String> {
+    let mut names: Vec<&String> = old.folders.keys().chain(new.folders.keys()).collect();
+    names.sort_by(|a, b| order.compare(a, b));
+    names.dedup();
+    let mut lines: Vec<String> = names
+        .into_iter()
+        .filter_map(|name| {
+            let (old, new) = (old.folders.get(name), new.folders.get(name));
+            let name = collation::display(name);
+            match (old.map(|f| f.files), new.map(|f| f.files)) {
+                (None, Some(n)) => Some(format!("+ {}: {} files (appeared)", name, n)),
+                (Some(o), None) => Some(format!("- {}: {} files (disappeared)", name, o)),
+                (Some(o), Some(n)) if n > o => Some(format!(
+                    "+ {}: {} -> {} files (grew by {})",
+                    name,
+                    o,
+                    n,
+                    n - o
+                )),
+                (Some(o), Some(n)) if n < o => Some(format!(
+                    "- {}: {} -> {} files (shrank by {})",
+                    name,
+                    o,
+                    n,
+                    o - n
+                )),
+                _ => None,
+            }
+        })
+        .collect();
+    lines.push(format!(
+        "total: {} -> {} files ({:+})",
+        old.total_files,
+        new.total_files,
+        new.total_files - old.total_files
+    ));
+    lines
+}
@@ -0,0 +1,111 @@
+//! Machine-readable summary of a `oneshot` run, written as JSON for
+//! wrapper scripts and systemd units, independently of the output format.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::Backlog;
+
+/// Why the run ended.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitReason {
+    #[default]
+    Ok,
+    /// A root couldn't be scanned at all.
+    ScanFailed,
+    /// The metrics differ from the golden file.
+    GoldenMismatch,
+    /// Any other error, e.g. writing the snapshot failed.
+    Error,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Summary {
+    pub exit_reason: ExitReason,
+    /// The error message, if the run failed.
+    pub error: Option<String>,
+    pub duration_seconds: f64,
+    /// Id of the last scan done.
+    pub scan_id: Option<u64>,
+    pub roots: usize,
+    pub total_files: i64,
+    pub folders: usize,
+    /// Error counts, per kind, summed over the scanned roots.
+    pub errors: BTreeMap<&'static str, i64>,
+}
+
+impl Summary {
+    /// Adds the results of a scan, which failed as a whole if it has a
+    /// scan failure.
+    pub fn record(&mut self, backlog: &Backlog) {
+        self.roots += 1;
+        self.scan_id = Some(backlog.scan_id);
+        if backlog.scan_failure.is_some() {
+            self.exit_reason = ExitReason::ScanFailed;
+        }
+        self.total_files += backlog.total_files;
+        self.folders += backlog.folder_count();
+        for (kind, count) in &backlog.total_errors {
+            *self.errors.entry(kind.name()).or_insert(0) += count;
+        }
+    }
+
+    /// Records the outcome of the run; errors not otherwise classified
+    /// are generic ones.
+    pub fn finish(&mut self, duration: Duration, result: &Result<(), String>) {
+        self.duration_seconds = duration.as_secs_f64();
+        if let Err(e) = result {
+            self.error = Some(e.clone());
+            if self.exit_reason == ExitReason::Ok {
+                self.exit_reason = ExitReason::Error;
+            }
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let contents = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        std::fs::write(path, contents)
+            .map_err(|e| format!("Can't write summary '{}': {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use speculoos::prelude::*;
+
+    use super::{ExitReason, Summary};
+    use crate::{Backlog, ErrorType, MockData};
+
+    #[test]
+    fn test_summary() {
+        let mut backlog = Backlog::new([1.0].into_iter());
+        backlog.fill_mock(MockData {
+            folders: 2,
+            files: 3,
+        });
+        backlog.record_error(ErrorType::Ownership);
+        let mut summary = Summary::default();
+        summary.record(&backlog);
+        summary.record(&backlog);
+        summary.finish(Duration::from_millis(1500), &Ok(()));
+        assert_that!(summary.exit_reason).is_equal_to(ExitReason::Ok);
+        assert_that!(summary.total_files).is_equal_to(12);
+        assert_that!(summary.folders).is_equal_to(4);
+        assert_that!(summary.errors.get("ownership")).is_equal_to(Some(&2));
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_that!(json["exit_reason"]).is_equal_to(serde_json::json!("ok"));
+        assert_that!(json["duration_seconds"]).is_equal_to(serde_json::json!(1.5));
+
+        backlog.scan_failure = Some("gone".to_string());
+        summary.record(&backlog);
+        summary.finish(Duration::ZERO, &Err("failed".to_string()));
+        assert_that!(summary.exit_reason).is_equal_to(ExitReason::ScanFailed);
+        assert_that!(summary.error).is_equal_to(Some("failed".to_string()));
+    }
+}
@@ -0,0 +1,148 @@
+//! Output for node_exporter's textfile collector (`oneshot
+//! --textfile-output`), so that e.g. a cron job can export the backlog via
+//! the node_exporter already running on the host, without the daemon.
+//!
+//! The collector reads the classic Prometheus text format, with a stricter
+//! parser than Prometheus' own, so the OpenMetrics specifics of the
+//! encoded metrics (counter families named without `_total`, info
+//! metrics, units and the EOF marker) are converted or dropped. The file
+//! is replaced atomically, so the collector never reads a partial one.
+
+use std::io::Write;
+use std::path::Path;
+
+/// Converts metrics from the OpenMetrics text format to the classic one.
+/// Example:
+/// ```
+/// use photo_backlog_exporter::textfile::from_openmetrics;
+/// let text = "# HELP scans Scans.\n# TYPE scans counter\nscans_total 2\n# EOF\n";
+/// assert_eq!(from_openmetrics(text), "# HELP scans_total Scans.\n# TYPE scans_total counter\nscans_total 2\n");
+/// ```
+pub fn from_openmetrics(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    // The help line of the family, held until its type gives the name.
+    let mut help: Option<(&str, &str)> = None;
+    let push_help = |out: &mut String, name: &str, help: &str| {
+        out.push_str(&format!("# HELP {} {}\n", name, help));
+    };
+    for line in text.lines() {
+        if line == "# EOF" {
+            break;
+        }
+        if let Some(rest) = line.strip_prefix("# HELP ") {
+            if let Some((name, text)) = help.take() {
+                push_help(&mut out, name, text);
+            }
+            help = Some(rest.split_once(' ').unwrap_or((rest, "")));
+        } else if let Some(rest) = line.strip_prefix("# TYPE ") {
+            let (name, kind) = rest.rsplit_once(' ').unwrap_or((rest, "unknown"));
+            let (classic, kind) = match kind {
+                "counter" => (format!("{}_total", name), "counter"),
+                "info" => (format!("{}_info", name), "gauge"),
+                "gauge" | "histogram" | "summary" => (name.to_string(), kind),
+                _ => (name.to_string(), "untyped"),
+            };
+            match help.take() {
+                Some((help_name, text)) if help_name == name => push_help(&mut out, &classic, text),
+                Some((help_name, text)) => push_help(&mut out, help_name, text),
+                None => {}
+            }
+            out.push_str(&format!("# TYPE {} {}\n", classic, kind));
+        } else {
+            if let Some((name, text)) = help.take() {
+                push_help(&mut out, name, text);
+            }
+            // Dropping other comments, e.g. the units, which the classic
+            // format lacks.
+            if !line.starts_with('#') {
+                out.push_str(line);
+                out.push('\n');
+            }
+        }
+    }
+    if let Some((name, text)) = help {
+        push_help(&mut out, name, text);
+    }
+    out
+}
+
+/// Writes the contents to a temporary file next to the path, then renames
+/// it over the path.
+pub fn write(path: &Path, contents: &str) -> Result<(), String> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| format!("Invalid textfile path '{}'", path.display()))?;
+    // Not ending in `.prom`, so ignored by the collector.
+    let temp = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    let written = std::fs::File::create(&temp).and_then(|mut file| {
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|()| std::fs::rename(&temp, path)) {
+        let _ = std::fs::remove_file(&temp);
+        return Err(format!("Can't write textfile '{}': {}", path.display(), e));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use speculoos::prelude::*;
+    use tempfile::tempdir;
+
+    use super::{from_openmetrics, write};
+
+    #[test]
+    fn test_from_openmetrics() {
+        let text = "# HELP photo_backlog_counts Backlog size.\n\
+                    # TYPE photo_backlog_counts gauge\n\
+                    photo_backlog_counts{kind=\"photos\"} 3\n\
+                    # HELP photo_backlog_oldest_file Oldest file.\n\
+                    # TYPE photo_backlog_oldest_file info\n\
+                    photo_backlog_oldest_file_info{path=\"a/b.nef\"} 1\n\
+                    # HELP ages_seconds Ages.\n\
+                    # TYPE ages_seconds histogram\n\
+                    # UNIT ages_seconds seconds\n\
+                    ages_seconds_sum 5.5\n\
+                    ages_seconds_count 3\n\
+                    ages_seconds_bucket{le=\"+Inf\"} 3\n\
+                    # HELP other Other.\n\
+                    # TYPE other stateset\n\
+                    other{other=\"a\"} 1\n\
+                    # EOF\n";
+        let expected = "# HELP photo_backlog_counts Backlog size.\n\
+                        # TYPE photo_backlog_counts gauge\n\
+                        photo_backlog_counts{kind=\"photos\"} 3\n\
+                        # HELP photo_backlog_oldest_file_info Oldest file.\n\
+                        # TYPE photo_backlog_oldest_file_info gauge\n\
+                        photo_backlog_oldest_file_info{path=\"a/b.nef\"} 1\n\
+                        # HELP ages_seconds Ages.\n\
+                        # TYPE ages_seconds histogram\n\
+                        ages_seconds_sum 5.5\n\
+                        ages_seconds_count 3\n\
+                        ages_seconds_bucket{le=\"+Inf\"} 3\n\
+                        # HELP other Other.\n\
+                        # TYPE other untyped\n\
+                        other{other=\"a\"} 1\n";
+        assert_that!(from_openmetrics(text).as_str()).is_equal_to(expected);
+    }
+
+    #[test]
+    fn test_write() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("photo_backlog.prom");
+        write(&path, "old\n").unwrap();
+        write(&path, "new\n").unwrap();
+        assert_that!(std::fs::read_to_string(&path).unwrap()).is_equal_to("new\n".to_string());
+        // No temporary file left behind.
+        assert_that!(std::fs::read_dir(temp_dir.path()).unwrap().count()).is_equal_to(1);
+        let missing = temp_dir.path().join("missing").join("photo_backlog.prom");
+        assert_that!(write(&missing, "new\n"))
+            .is_err()
+            .contains("Can't write textfile");
+    }
+}
@@ -0,0 +1,276 @@
+//! Watching of the tree via inotify (through the `notify` crate), so that
+//! files added or removed between the daemon's background scans show up in
+//! the served data without walking the whole tree. Changes that can't be
+//! applied file by file (new or moved-in directories, ignore and tags
+//! files, lost events) trigger a full scan instead, and the scheduled scans
+//! still correct anything missed.
+
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use notify::event::{CreateKind, ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+
+use crate::prometheus::PhotoBacklogCollector;
+use crate::{FileChange, IGNORE_FILE, TAGS_FILE};
+
+/// How long to wait for more events after one, so that e.g. a folder
+/// being copied is applied at once.
+const BATCH_WINDOW: Duration = Duration::from_secs(1);
+
+/// The changes from a batch of events.
+#[derive(Debug, Default, PartialEq)]
+struct Batch {
+    changes: Vec<FileChange>,
+    /// Whether a full scan is needed instead.
+    rescan: bool,
+    /// Paths moved away, unless the batch shows where to; they no longer
+    /// exist, so whether they were directories isn't known.
+    moved_away: Vec<PathBuf>,
+}
+
+impl Batch {
+    fn add(&mut self, root: &Path, event: notify::Result<Event>) {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("Error watching '{}': {}", root.display(), e);
+                self.rescan = true;
+                return;
+            }
+        };
+        if event.need_rescan() {
+            self.rescan = true;
+            return;
+        }
+        // Renames within the tree follow their separate from/to events;
+        // the destination tells whether a directory was moved.
+        if let EventKind::Modify(ModifyKind::Name(RenameMode::Both)) = event.kind {
+            if let [from, to] = event.paths.as_slice() {
+                self.moved_away.retain(|path| path != from);
+                if to.is_dir() {
+                    self.rescan = true;
+                } else {
+                    self.changes.push(FileChange::Removed(from.clone()));
+                }
+            }
+            return;
+        }
+        for path in &event.paths {
+            let name = path.file_name().unwrap_or_default();
+            if name == IGNORE_FILE || name == TAGS_FILE {
+                self.rescan = true;
+                continue;
+            }
+            match event.kind {
+                // New directories can be filled before being watched.
+                EventKind::Create(CreateKind::Folder) => self.rescan = true,
+                EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                    if path.is_dir() {
+                        self.rescan = true;
+                    } else {
+                        self.changes.push(FileChange::Added(path.clone()));
+                    }
+                }
+                EventKind::Remove(_) => self.changes.push(FileChange::Removed(path.clone())),
+                EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                    self.moved_away.push(path.clone());
+                }
+                EventKind::Modify(ModifyKind::Name(_)) => self.rescan = true,
+                _ => {}
+            }
+        }
+    }
+
+    /// Resolves the paths moved out of the tree, once no more events are
+    /// expected: top-level folders are removed as a whole, while anything
+    /// deeper might have been a directory with unknown counts.
+    fn finish(&mut self, root: &Path) {
+        for path in std::mem::take(&mut self.moved_away) {
+            if path.parent() == Some(root) {
+                self.changes.push(FileChange::Removed(path));
+            } else {
+                self.rescan = true;
+            }
+        }
+    }
+}
+
+/// Watches the collector's root, applying the changes to the data it
+/// serves; returns only if the root can't be watched. Full scans are
+/// started at most once per `min_rescan_interval`, deferring the others.
+pub fn run(collector: &PhotoBacklogCollector, min_rescan_interval: Duration) {
+    let root = collector.scan_path.as_path();
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = match notify::recommended_watcher(tx) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            warn!("Can't watch '{}': {}", root.display(), e);
+            return;
+        }
+    };
+    // E.g. too many directories for the inotify watches limit.
+    if let Err(e) = watcher.watch(root, RecursiveMode::Recursive) {
+        warn!(
+            "Can't watch '{}', relying on the scheduled scans only: {}",
+            root.display(),
+            e
+        );
+        return;
+    }
+    info!("Watching '{}' for changes", root.display());
+    let mut batch = Batch::default();
+    let mut last_rescan: Option<Instant> = None;
+    loop {
+        // A deferred rescan waits for more events only until it's due.
+        let event = if batch.rescan {
+            let due = last_rescan.map_or_else(Instant::now, |last| last + min_rescan_interval);
+            match rx.recv_timeout(due.saturating_duration_since(Instant::now())) {
+                Ok(event) => Some(event),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        } else {
+            match rx.recv() {
+                Ok(event) => Some(event),
+                Err(_) => break,
+            }
+        };
+        if let Some(event) = event {
+            batch.add(root, event);
+            let deadline = Instant::now() + BATCH_WINDOW;
+            while let Ok(event) =
+                rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                batch.add(root, event);
+            }
+            batch.finish(root);
+        }
+        if batch.rescan {
+            if last_rescan.is_some_and(|last| last.elapsed() < min_rescan_interval) {
+                continue;
+            }
+            debug!("Rescanning '{}' after changes", root.display());
+            collector.scan_now();
+            last_rescan = Some(Instant::now());
+        } else if !batch.changes.is_empty() {
+            debug!(
+                "Applying {} changes under '{}'",
+                batch.changes.len(),
+                root.display()
+            );
+            collector.apply_changes(&batch.changes);
+        }
+        batch = Batch::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use notify::event::{CreateKind, ModifyKind, RemoveKind, RenameMode};
+    use notify::{Event, EventKind};
+    use rstest::rstest;
+    use speculoos::prelude::*;
+    use tempfile::tempdir;
+
+    use super::Batch;
+    use crate::FileChange;
+
+    fn event(kind: EventKind, path: &Path) -> notify::Result<Event> {
+        Ok(Event::new(kind).add_path(path.to_path_buf()))
+    }
+
+    #[test]
+    fn test_file_changes() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        let added = root.join("a/1.nef");
+        let mut batch = Batch::default();
+        batch.add(root, event(EventKind::Create(CreateKind::File), &added));
+        batch.add(
+            root,
+            event(EventKind::Remove(RemoveKind::File), &root.join("a/2.nef")),
+        );
+        // A file renamed within the tree, without an extension.
+        std::fs::create_dir(root.join("a")).unwrap();
+        let (from, to) = (root.join("a/3.nef"), root.join("a/3"));
+        std::fs::write(&to, "").unwrap();
+        batch.add(
+            root,
+            event(EventKind::Modify(ModifyKind::Name(RenameMode::From)), &from),
+        );
+        batch.add(
+            root,
+            event(EventKind::Modify(ModifyKind::Name(RenameMode::To)), &to),
+        );
+        batch.add(
+            root,
+            Ok(
+                Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+                    .add_path(from.clone())
+                    .add_path(to.clone()),
+            ),
+        );
+        // Top-level folders moved away are removed as a whole.
+        batch.add(
+            root,
+            event(
+                EventKind::Modify(ModifyKind::Name(RenameMode::From)),
+                &root.join("b"),
+            ),
+        );
+        batch.finish(root);
+        assert_that!(batch).is_equal_to(Batch {
+            changes: vec![
+                FileChange::Added(added),
+                FileChange::Removed(root.join("a/2.nef")),
+                FileChange::Added(to),
+                FileChange::Removed(from),
+                FileChange::Removed(root.join("b")),
+            ],
+            rescan: false,
+            moved_away: vec![],
+        });
+    }
+
+    #[rstest]
+    #[case::new_dir(EventKind::Create(CreateKind::Folder), "a/new")]
+    #[case::moved_in_dir(EventKind::Modify(ModifyKind::Name(RenameMode::To)), "a")]
+    #[case::moved_out(EventKind::Modify(ModifyKind::Name(RenameMode::From)), "a/sub.d")]
+    #[case::ignore_file(EventKind::Create(CreateKind::File), crate::IGNORE_FILE)]
+    #[case::tags_file(EventKind::Remove(RemoveKind::File), "a/.backlog-tags")]
+    fn test_rescan(#[case] kind: EventKind, #[case] path: &str) {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        std::fs::create_dir(root.join("a")).unwrap();
+        let mut batch = Batch::default();
+        batch.add(root, event(kind, &root.join(path)));
+        batch.finish(root);
+        assert_that!(batch.rescan).is_true();
+    }
+
+    #[test]
+    fn test_renamed_dir() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        // Directories can have what looks like an extension.
+        let (from, to) = (root.join("a/old"), root.join("a/2023.01"));
+        std::fs::create_dir_all(&to).unwrap();
+        let mut batch = Batch::default();
+        batch.add(
+            root,
+            Ok(
+                Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+                    .add_path(from)
+                    .add_path(to),
+            ),
+        );
+        batch.finish(root);
+        assert_that!(batch.rescan).is_true();
+        assert_that!(batch.changes).is_empty();
+    }
+}
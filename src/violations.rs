@@ -0,0 +1,186 @@
+//! Logging of individual violations to a JSON lines file, separate from
+//! the general log, so that exact offender lists can be post-processed.
+//!
+//! Writing is done by a background thread, fed via a bounded queue; if the
+//! writer falls behind (e.g. slow disk), whole scans are dropped rather
+//! than blocking the scrapes.
+
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+
+use log::warn;
+use serde::Serialize;
+
+use crate::Violation;
+
+/// Number of scans that can be queued for writing.
+const QUEUE_SIZE: usize = 4;
+
+#[derive(Serialize)]
+struct Record<'a> {
+    scan_id: u64,
+    path: &'a str,
+    kind: &'static str,
+    expected: Option<&'a str>,
+    actual: Option<&'a str>,
+}
+
+#[derive(Debug)]
+pub struct ViolationsLog {
+    sender: Option<SyncSender<String>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl ViolationsLog {
+    /// Starts logging to the given file, which is rotated (to `FILE.1`)
+    /// once it would exceed `max_size` bytes.
+    pub fn new(path: PathBuf, max_size: u64) -> Self {
+        let (sender, receiver) = sync_channel(QUEUE_SIZE);
+        let writer = std::thread::spawn(move || write_loop(&path, max_size, receiver));
+        ViolationsLog {
+            sender: Some(sender),
+            writer: Some(writer),
+        }
+    }
+
+    /// Queues the violations of a scan for writing.
+    pub fn submit(&self, scan_id: u64, violations: &[Violation]) {
+        if violations.is_empty() {
+            return;
+        }
+        let mut batch = String::new();
+        for v in violations {
+            let path = v.path.to_string_lossy();
+            let record = Record {
+                scan_id,
+                path: &path,
+                kind: v.kind.name(),
+                expected: v.expected.as_deref(),
+                actual: v.actual.as_deref(),
+            };
+            batch.push_str(&serde_json::to_string(&record).expect("serialize violation"));
+            batch.push('\n');
+        }
+        let sender = self.sender.as_ref().expect("sender is only taken on drop");
+        match sender.try_send(batch) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => warn!(
+                "Violations log is falling behind, dropping {} violations of scan {}",
+                violations.len(),
+                scan_id
+            ),
+            Err(TrySendError::Disconnected(_)) => {
+                warn!("Violations log writer is gone, dropping violations")
+            }
+        }
+    }
+}
+
+impl Drop for ViolationsLog {
+    // Flushes the queued scans, e.g. before oneshot exits.
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Checks that the given log file can be opened for appending, creating
+/// it if needed, so that a bad path fails at startup rather than in the
+/// writer thread.
+pub fn check_writable(path: &Path) -> Result<(), String> {
+    open(path)
+        .map(drop)
+        .map_err(|e| format!("Can't open violations log '{}': {}", path.display(), e))
+}
+
+fn open(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn write_loop(path: &Path, max_size: u64, receiver: Receiver<String>) {
+    let mut rotated = OsString::from(path.as_os_str());
+    rotated.push(".1");
+    for batch in receiver {
+        let result = open(path).and_then(|mut file| {
+            let size = file.metadata()?.len();
+            if size > 0 && size + batch.len() as u64 > max_size {
+                std::fs::rename(path, &rotated)?;
+                file = open(path)?;
+            }
+            file.write_all(batch.as_bytes())
+        });
+        if let Err(e) = result {
+            warn!("Can't write violations log '{}': {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use speculoos::prelude::*;
+    use tempfile::tempdir;
+
+    use super::{check_writable, ViolationsLog};
+    use crate::{ErrorType, Violation};
+
+    fn violation(path: &str) -> Violation {
+        Violation {
+            path: PathBuf::from(path),
+            kind: ErrorType::Permissions,
+            expected: Some("640".to_string()),
+            actual: Some("600".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_violations_log() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("violations.jsonl");
+        let log = ViolationsLog::new(path.clone(), 1 << 20);
+        log.submit(1, &[violation("a/1.nef"), violation("a/2.nef")]);
+        log.submit(2, &[]);
+        drop(log);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = contents.lines().collect();
+        assert_that!(lines).has_length(2);
+        assert_that!(lines[0]).is_equal_to(
+            r#"{"scan_id":1,"path":"a/1.nef","kind":"permissions","expected":"640","actual":"600"}"#,
+        );
+    }
+
+    #[test]
+    fn test_violations_log_rotation() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("violations.jsonl");
+        let log = ViolationsLog::new(path.clone(), 100);
+        log.submit(1, &[violation("a/1.nef")]);
+        log.submit(2, &[violation("a/2.nef")]);
+        drop(log);
+        let current = std::fs::read_to_string(&path).unwrap();
+        let rotated = std::fs::read_to_string(temp_dir.path().join("violations.jsonl.1")).unwrap();
+        assert_that!(rotated).contains("\"scan_id\":1");
+        assert_that!(current).contains("\"scan_id\":2");
+        assert_that!(current).does_not_contain("\"scan_id\":1");
+    }
+
+    #[test]
+    fn test_check_writable() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("violations.jsonl");
+        assert_that!(check_writable(&path)).is_ok();
+        assert_that!(path.exists()).is_true();
+        assert_that!(check_writable(
+            &temp_dir.path().join("missing").join("violations.jsonl")
+        ))
+        .is_err()
+        .contains("Can't open violations log");
+    }
+}
@@ -0,0 +1,376 @@
+//! Walking of the photo tree, either sequentially (via walkdir) or in
+//! parallel (via the `ignore` crate), producing the same entries for the
+//! scan to process.
+
+use std::ffi::OsStr;
+use std::fs::Metadata;
+use std::ops::ControlFlow;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::{WalkBuilder, WalkState};
+use walkdir::WalkDir;
+
+use crate::{Config, SkipReason, Symlinks, IGNORE_FILE, TAGS_FILE};
+
+/// An entry found while walking.
+pub(crate) struct WalkedEntry {
+    pub path: PathBuf,
+    pub depth: usize,
+    pub is_symlink: bool,
+    /// The entry's metadata, or none for symlinks that are not followed nor
+    /// counted, and are thus not stat-ed.
+    pub metadata: Option<std::io::Result<Metadata>>,
+    /// How long the stat call took.
+    pub stat_time: Duration,
+}
+
+pub(crate) enum WalkItem {
+    Entry(Box<WalkedEntry>),
+    Error {
+        message: String,
+        /// Whether the root itself couldn't be read.
+        root: bool,
+        /// Whether this is a followed symlink pointing to an ancestor.
+        is_loop: bool,
+        /// The path that couldn't be read, if it was permission denied.
+        denied: Option<PathBuf>,
+    },
+}
+
+impl WalkedEntry {
+    fn new(
+        config: &Config,
+        path: PathBuf,
+        depth: usize,
+        is_symlink: bool,
+        walker_metadata: impl FnOnce() -> std::io::Result<Metadata>,
+    ) -> Self {
+        let stat_start = Instant::now();
+        let metadata = match (is_symlink, config.symlinks) {
+            (true, Symlinks::Ignore | Symlinks::Error) => None,
+            // When counting (but not following) symlinks, look at the target.
+            (true, Symlinks::Count) => Some(std::fs::metadata(&path)),
+            _ => Some(walker_metadata()),
+        };
+        WalkedEntry {
+            path,
+            depth,
+            is_symlink,
+            metadata,
+            stat_time: stat_start.elapsed(),
+        }
+    }
+}
+
+/// Loads the ignore file from the given root, if present.
+pub(crate) fn load_ignore_file(config: &Config) -> Option<Gitignore> {
+    let root = config.root_path;
+    let path = root.join(IGNORE_FILE);
+    if !path.is_file() {
+        return None;
+    }
+    let mut builder = GitignoreBuilder::new(root);
+    if let Some(e) = builder.add(&path) {
+        scan_log!(warn, config, "Error reading '{}': {}", path.display(), e);
+    }
+    match builder.build() {
+        Ok(gitignore) => Some(gitignore),
+        Err(e) => {
+            scan_log!(
+                warn,
+                config,
+                "Can't use ignore file '{}': {}",
+                path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Returns why the entry is skipped by the walk, if it is.
+fn is_ignored(
+    ignore: Option<&Gitignore>,
+    depth: usize,
+    name: &OsStr,
+    path: &Path,
+    is_dir: bool,
+) -> Option<SkipReason> {
+    if depth == 0 {
+        return None;
+    }
+    if (depth == 1 && name == IGNORE_FILE) || (depth == 2 && name == TAGS_FILE) {
+        return Some(SkipReason::Marker);
+    }
+    ignore
+        .is_some_and(|i| i.matched(path, is_dir).is_ignore())
+        .then_some(SkipReason::ExcludedGlob)
+}
+
+/// Counts of the entries skipped by the walk itself, shared between the
+/// parallel walker's threads.
+#[derive(Default)]
+struct SkipCounts {
+    marker: AtomicI64,
+    excluded_glob: AtomicI64,
+}
+
+impl SkipCounts {
+    /// Records the skip reason, if any, returning whether to keep the entry.
+    fn keep(&self, reason: Option<SkipReason>) -> bool {
+        let counter = match reason {
+            None => return true,
+            Some(SkipReason::Marker) => &self.marker,
+            Some(_) => &self.excluded_glob,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+
+    fn counts(&self) -> Vec<(SkipReason, i64)> {
+        vec![
+            (SkipReason::Marker, self.marker.load(Ordering::Relaxed)),
+            (
+                SkipReason::ExcludedGlob,
+                self.excluded_glob.load(Ordering::Relaxed),
+            ),
+        ]
+    }
+}
+
+/// Statistics of a walk, besides the entries it produced.
+#[derive(Default)]
+pub(crate) struct WalkStats {
+    /// Number of entries skipped by the walk itself, per reason.
+    pub skipped: Vec<(SkipReason, i64)>,
+    /// Number of threads walking the tree.
+    pub workers: usize,
+    /// Peak number of entries walked but not yet processed by the scan.
+    pub queue_peak: i64,
+    /// Time each walker thread spent handling entries (stat-ing and
+    /// queueing them), for the parallel walker only.
+    pub worker_busy: Vec<Duration>,
+}
+
+/// Whether a file found outside of a walk (e.g. by the tree watcher)
+/// would have been skipped by one, being ignored or under an ignored
+/// directory.
+pub(crate) fn is_ignored_file(config: &Config, ignore: Option<&Gitignore>, path: &Path) -> bool {
+    let Ok(relative) = path.strip_prefix(config.root_path) else {
+        return true;
+    };
+    let name = path.file_name().unwrap_or_default();
+    is_ignored(None, relative.components().count(), name, path, false).is_some()
+        || ignore.is_some_and(|i| i.matched_path_or_any_parents(path, false).is_ignore())
+}
+
+/// Whether the file is left out of the sample, if sampling. The choice
+/// hashes the relative path without extension, so that it's stable across
+/// scans and e.g. raw and editable files or companions of a shot are
+/// sampled together.
+fn sampled_out(config: &Config, path: &Path, is_file: bool) -> bool {
+    let Some(ratio) = config.sample else {
+        return false;
+    };
+    if !is_file {
+        return false;
+    }
+    let stem = path
+        .strip_prefix(config.root_path)
+        .unwrap_or(path)
+        .with_extension("");
+    // FNV-1a, as the std hasher isn't guaranteed stable across versions.
+    let hash = stem
+        .as_os_str()
+        .as_bytes()
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325u64, |hash, b| {
+            (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+        });
+    (hash % 1_000_000) as f64 >= ratio * 1_000_000.0
+}
+
+/// Returns the path of a permission denied error, if any.
+fn denied_path(e: &ignore::Error) -> Option<PathBuf> {
+    match e {
+        ignore::Error::WithPath { path, err } => {
+            let io = err.io_error()?;
+            (io.kind() == std::io::ErrorKind::PermissionDenied).then(|| path.clone())
+        }
+        ignore::Error::WithDepth { err, .. } | ignore::Error::WithLineNumber { err, .. } => {
+            denied_path(err)
+        }
+        _ => None,
+    }
+}
+
+fn is_loop(e: &ignore::Error) -> bool {
+    match e {
+        ignore::Error::Loop { .. } => true,
+        ignore::Error::WithPath { err, .. }
+        | ignore::Error::WithDepth { err, .. }
+        | ignore::Error::WithLineNumber { err, .. } => is_loop(err),
+        ignore::Error::Partial(errs) => errs.iter().any(is_loop),
+        _ => false,
+    }
+}
+
+/// Walks the tree under the configured root, calling `sink` for each item,
+/// always from the calling thread, until it breaks.
+pub(crate) fn walk(
+    config: &Config,
+    mut sink: impl FnMut(WalkItem) -> ControlFlow<()>,
+) -> WalkStats {
+    let ignore = load_ignore_file(config);
+    if config.scan_threads > 1 {
+        return walk_parallel(config, ignore, sink);
+    }
+    let skipped = SkipCounts::default();
+    // Skipping ignored directories here avoids descending into them.
+    let walker = WalkDir::new(config.root_path)
+        .follow_links(config.symlinks == Symlinks::Follow)
+        .into_iter()
+        .filter_entry(|e| {
+            skipped.keep(is_ignored(
+                ignore.as_ref(),
+                e.depth(),
+                e.file_name(),
+                e.path(),
+                e.file_type().is_dir(),
+            ))
+        });
+    for maybe_entry in walker {
+        let item = match maybe_entry {
+            Err(e) => WalkItem::Error {
+                message: e.to_string(),
+                root: e.depth() == 0,
+                is_loop: e.loop_ancestor().is_some(),
+                denied: e
+                    .io_error()
+                    .filter(|io| io.kind() == std::io::ErrorKind::PermissionDenied)
+                    .and(e.path())
+                    .map(Path::to_path_buf),
+            },
+            Ok(entry) if sampled_out(config, entry.path(), entry.file_type().is_file()) => {
+                continue;
+            }
+            Ok(entry) => WalkItem::Entry(Box::new(WalkedEntry::new(
+                config,
+                entry.path().to_path_buf(),
+                entry.depth(),
+                entry.path_is_symlink(),
+                || entry.metadata().map_err(std::io::Error::from),
+            ))),
+        };
+        if sink(item).is_break() {
+            break;
+        }
+    }
+    WalkStats {
+        skipped: skipped.counts(),
+        workers: 1,
+        ..Default::default()
+    }
+}
+
+// The `ignore` walker doesn't expose its queue of directories to read, so
+// the queue measured is that of the entries handed to the scan, which
+// grows if the walker threads outpace the (sequential) processing.
+fn walk_parallel(
+    config: &Config,
+    ignore: Option<Gitignore>,
+    mut sink: impl FnMut(WalkItem) -> ControlFlow<()>,
+) -> WalkStats {
+    let ignore = Arc::new(ignore);
+    let skipped = Arc::new(SkipCounts::default());
+    let filter_skipped = skipped.clone();
+    let walker = WalkBuilder::new(config.root_path)
+        // Keep the same semantics as the sequential walker.
+        .standard_filters(false)
+        .follow_links(config.symlinks == Symlinks::Follow)
+        .threads(config.scan_threads)
+        .filter_entry(move |e| {
+            filter_skipped.keep(is_ignored(
+                ignore.as_ref().as_ref(),
+                e.depth(),
+                e.file_name(),
+                e.path(),
+                e.file_type().is_some_and(|t| t.is_dir()),
+            ))
+        })
+        .build_parallel();
+    let queued = AtomicI64::new(0);
+    let queue_peak = AtomicI64::new(0);
+    // Busy time per walker thread, in nanoseconds.
+    let busy: Vec<AtomicU64> = (0..config.scan_threads)
+        .map(|_| AtomicU64::new(0))
+        .collect();
+    let next_worker = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel();
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            walker.run(|| {
+                let tx = tx.clone();
+                let worker_busy = &busy[next_worker.fetch_add(1, Ordering::Relaxed) % busy.len()];
+                let (queued, queue_peak) = (&queued, &queue_peak);
+                Box::new(move |maybe_entry| {
+                    let started = Instant::now();
+                    let item = match maybe_entry {
+                        Err(e) => WalkItem::Error {
+                            message: e.to_string(),
+                            root: e.depth().unwrap_or(0) == 0,
+                            is_loop: is_loop(&e),
+                            denied: denied_path(&e),
+                        },
+                        Ok(entry)
+                            if sampled_out(
+                                config,
+                                entry.path(),
+                                entry.file_type().is_some_and(|t| t.is_file()),
+                            ) =>
+                        {
+                            return WalkState::Continue;
+                        }
+                        Ok(entry) => WalkItem::Entry(Box::new(WalkedEntry::new(
+                            config,
+                            entry.path().to_path_buf(),
+                            entry.depth(),
+                            entry.path_is_symlink(),
+                            || entry.metadata().map_err(std::io::Error::other),
+                        ))),
+                    };
+                    let depth = queued.fetch_add(1, Ordering::Relaxed) + 1;
+                    queue_peak.fetch_max(depth, Ordering::Relaxed);
+                    let state = match tx.send(item) {
+                        Ok(()) => WalkState::Continue,
+                        Err(_) => WalkState::Quit,
+                    };
+                    worker_busy.fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+                    state
+                })
+            });
+            drop(tx);
+        });
+        // Once the receiver is dropped, the walker threads quit.
+        for item in rx {
+            queued.fetch_sub(1, Ordering::Relaxed);
+            if sink(item).is_break() {
+                break;
+            }
+        }
+    });
+    WalkStats {
+        skipped: skipped.counts(),
+        workers: config.scan_threads,
+        queue_peak: queue_peak.into_inner(),
+        worker_busy: busy
+            .into_iter()
+            .map(|nanos| Duration::from_nanos(nanos.into_inner()))
+            .collect(),
+    }
+}
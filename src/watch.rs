@@ -0,0 +1,109 @@
+//! Compact console table of the backlog, re-rendered periodically by
+//! `oneshot --watch` while actively clearing the backlog.
+
+use std::fmt::Write;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::collation::{self, FolderOrder};
+use crate::Backlog;
+
+/// ANSI sequence clearing the terminal and moving the cursor home.
+pub const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+/// Formats an age in seconds compactly, in the largest sensible unit.
+/// Example:
+/// ```
+/// use photo_backlog_exporter::watch::format_age;
+/// assert_eq!(format_age(90.0), "1m");
+/// assert_eq!(format_age(7200.0), "2h");
+/// assert_eq!(format_age(86400.0 * 10.5), "10d");
+/// ```
+pub fn format_age(seconds: f64) -> String {
+    const MINUTE: f64 = 60.0;
+    const HOUR: f64 = 60.0 * MINUTE;
+    const DAY: f64 = 24.0 * HOUR;
+    if seconds < HOUR {
+        format!("{}m", (seconds / MINUTE) as u64)
+    } else if seconds < 2.0 * DAY {
+        format!("{}h", (seconds / HOUR) as u64)
+    } else {
+        format!("{}d", (seconds / DAY) as u64)
+    }
+}
+
+/// Renders the backlog as a table of folders, largest first, then in the
+/// given order.
+pub fn render(backlog: &Backlog, root: &Path, interval: Duration, order: &FolderOrder) -> String {
+    let mut out = String::new();
+    let errors: i64 = backlog.total_errors.values().sum();
+    // Writing to a String can't fail.
+    let _ = writeln!(
+        out,
+        "{}: {} files in {} folders, {} errors (scan {}, every {}s)",
+        root.display(),
+        backlog.total_files,
+        backlog.folder_count(),
+        errors,
+        backlog.scan_id,
+        interval.as_secs()
+    );
+    if let Some(failure) = &backlog.scan_failure {
+        let _ = writeln!(out, "Scan failed: {}", failure);
+        return out;
+    }
+    let _ = writeln!(out, "{:>8} {:>6} {:>6}  FOLDER", "FILES", "MEAN", "MEDIAN");
+    let mut folders: Vec<_> = backlog.folders.iter().collect();
+    folders.sort_by(|(a, (a_count, _)), (b, (b_count, _))| {
+        b_count.cmp(a_count).then_with(|| order.compare(a, b))
+    });
+    for (folder, (count, ages)) in folders {
+        let median = backlog
+            .folder_ages
+            .get(folder)
+            .and_then(|ages| crate::quantile(ages, 0.5))
+            .map_or("-".to_string(), format_age);
+        let _ = writeln!(
+            out,
+            "{:>8} {:>6} {:>6}  {}",
+            count,
+            format_age(ages / *count as f64),
+            median,
+            collation::display(folder)
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::Duration;
+
+    use speculoos::prelude::*;
+
+    use super::render;
+    use crate::collation::FolderOrder;
+    use crate::{Backlog, MockData};
+
+    #[test]
+    fn test_render() {
+        let mut backlog = Backlog::new([1.0].into_iter());
+        backlog.fill_mock(MockData {
+            folders: 2,
+            files: 3,
+        });
+        let table = render(
+            &backlog,
+            Path::new("/photos"),
+            Duration::from_secs(30),
+            &FolderOrder::default(),
+        );
+        assert_that!(table.lines().collect::<Vec<_>>()).is_equal_to(vec![
+            "/photos: 6 files in 2 folders, 0 errors (scan 0, every 30s)",
+            "   FILES   MEAN MEDIAN  FOLDER",
+            "       3    24h    24h  mock-folder-0000",
+            "       3     8d     8d  mock-folder-0001",
+        ]);
+    }
+}
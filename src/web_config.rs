@@ -0,0 +1,500 @@
+//! Support for the web configuration file used by the official Prometheus
+//! exporters (see the exporter-toolkit documentation), allowing TLS and
+//! basic authentication to be configured the same way as for them.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderName, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use base64::prelude::*;
+use serde::Deserialize;
+use tokio_rustls::rustls;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::TlsAcceptor;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct WebConfig {
+    pub tls_server_config: Option<TlsServerConfig>,
+    pub http_server_config: Option<HttpServerConfig>,
+    #[serde(default)]
+    pub basic_auth_users: HashMap<String, String>,
+}
+
+/// The TLS settings; certificates and keys can be given as files or
+/// inline, as PEM.
+#[derive(Clone, Debug, Deserialize)]
+pub struct TlsServerConfig {
+    pub cert_file: Option<String>,
+    pub cert: Option<String>,
+    pub key_file: Option<String>,
+    pub key: Option<String>,
+    #[serde(default)]
+    pub client_auth_type: ClientAuthType,
+    pub client_ca_file: Option<String>,
+    pub client_ca: Option<String>,
+    #[serde(default = "default_min_version")]
+    pub min_version: TlsVersion,
+    pub max_version: Option<TlsVersion>,
+    /// Settings of the official exporters not supported here (e.g.
+    /// `cipher_suites`, as rustls only has secure ones), which are
+    /// ignored with a warning.
+    #[serde(flatten)]
+    pub unsupported: BTreeMap<String, serde_yaml::Value>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    TLS10,
+    TLS11,
+    TLS12,
+    TLS13,
+}
+
+fn default_min_version() -> TlsVersion {
+    TlsVersion::TLS12
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+pub enum ClientAuthType {
+    #[default]
+    NoClientCert,
+    RequestClientCert,
+    RequireAnyClientCert,
+    VerifyClientCertIfGiven,
+    RequireAndVerifyClientCert,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct HttpServerConfig {
+    #[serde(default = "default_http2")]
+    pub http2: bool,
+    /// Headers added to all responses.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+fn default_http2() -> bool {
+    true
+}
+
+impl WebConfig {
+    /// Loads and parses the given web configuration file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Can't read web config '{}': {}", path.display(), e))?;
+        Self::parse(&contents)
+            .map_err(|e| format!("Can't parse web config '{}': {}", path.display(), e))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        // An empty file is a valid (empty) configuration.
+        if contents.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        let config: Self = serde_yaml::from_str(contents).map_err(|e| e.to_string())?;
+        if let Some(tls) = &config.tls_server_config {
+            for name in tls.unsupported.keys() {
+                log::warn!("Ignoring unsupported TLS setting '{}'", name);
+            }
+        }
+        config.headers()?;
+        Ok(config)
+    }
+
+    /// The headers to add to all responses, validated.
+    pub fn headers(&self) -> Result<Vec<(HeaderName, HeaderValue)>, String> {
+        let Some(http) = &self.http_server_config else {
+            return Ok(vec![]);
+        };
+        http.headers
+            .iter()
+            .map(|(name, value)| {
+                let name = HeaderName::try_from(name)
+                    .map_err(|e| format!("Invalid header name '{}': {}", name, e))?;
+                let value = HeaderValue::try_from(value)
+                    .map_err(|e| format!("Invalid value for header '{}': {}", name, e))?;
+                Ok((name, value))
+            })
+            .collect()
+    }
+
+    /// Whether HTTP/2 should be offered, defaulting to yes as the official
+    /// exporters do.
+    pub fn http2(&self) -> bool {
+        self.http_server_config.as_ref().is_none_or(|c| c.http2)
+    }
+
+    /// Builds the TLS acceptor, if TLS is configured.
+    pub fn tls_acceptor(&self) -> Result<Option<TlsAcceptor>, String> {
+        let tls = match &self.tls_server_config {
+            None => return Ok(None),
+            Some(tls) => tls,
+        };
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = rustls::ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_protocol_versions(&tls.protocol_versions()?)
+            .map_err(|e| format!("Can't initialise TLS: {}", e))?;
+        let builder = match tls.client_auth_type {
+            ClientAuthType::NoClientCert => builder.with_no_client_auth(),
+            ClientAuthType::VerifyClientCertIfGiven
+            | ClientAuthType::RequireAndVerifyClientCert => {
+                let (ca_source, mut ca) =
+                    pem_source(&tls.client_ca_file, &tls.client_ca, "client_ca").map_err(|_| {
+                        format!(
+                            "Client auth type {:?} requires a client_ca_file or client_ca",
+                            tls.client_auth_type
+                        )
+                    })?;
+                let mut roots = rustls::RootCertStore::empty();
+                for cert in load_certs(&ca_source, &mut ca)? {
+                    roots
+                        .add(cert)
+                        .map_err(|e| format!("Invalid client CA in {}: {}", ca_source, e))?;
+                }
+                let mut verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+                if tls.client_auth_type == ClientAuthType::VerifyClientCertIfGiven {
+                    verifier = verifier.allow_unauthenticated();
+                }
+                let verifier = verifier
+                    .build()
+                    .map_err(|e| format!("Can't build client verifier: {}", e))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            other => return Err(format!("Unsupported client auth type {:?}", other)),
+        };
+        let (cert_source, mut cert) = pem_source(&tls.cert_file, &tls.cert, "cert")?;
+        let certs = load_certs(&cert_source, &mut cert)?;
+        let (key_source, mut key) = pem_source(&tls.key_file, &tls.key, "key")?;
+        let key = load_key(&key_source, &mut key)?;
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .map_err(|e| format!("Invalid TLS certificate/key: {}", e))?;
+        config.alpn_protocols = if self.http2() {
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        } else {
+            vec![b"http/1.1".to_vec()]
+        };
+        Ok(Some(TlsAcceptor::from(Arc::new(config))))
+    }
+}
+
+impl TlsServerConfig {
+    /// The TLS versions allowed, of those supported by rustls (1.2 and
+    /// 1.3), which older minimum versions thus imply.
+    fn protocol_versions(&self) -> Result<Vec<&'static rustls::SupportedProtocolVersion>, String> {
+        let versions: Vec<_> = [
+            (TlsVersion::TLS12, &rustls::version::TLS12),
+            (TlsVersion::TLS13, &rustls::version::TLS13),
+        ]
+        .into_iter()
+        .filter(|(version, _)| {
+            *version >= self.min_version && self.max_version.is_none_or(|max| *version <= max)
+        })
+        .map(|(_, supported)| supported)
+        .collect();
+        if versions.is_empty() {
+            return Err(format!(
+                "No supported TLS version between {:?} and {:?}",
+                self.min_version,
+                self.max_version.unwrap_or(TlsVersion::TLS13)
+            ));
+        }
+        Ok(versions)
+    }
+}
+
+/// Opens the PEM data given either as file or inline, returning it with a
+/// description for errors.
+fn pem_source(
+    file: &Option<String>,
+    inline: &Option<String>,
+    name: &str,
+) -> Result<(String, Box<dyn BufRead>), String> {
+    match (file, inline) {
+        (Some(path), None) => File::open(path)
+            .map(|f| {
+                (
+                    format!("'{}'", path),
+                    Box::new(BufReader::new(f)) as Box<dyn BufRead>,
+                )
+            })
+            .map_err(|e| format!("Can't open '{}': {}", path, e)),
+        (None, Some(pem)) => Ok((
+            format!("inline {}", name),
+            Box::new(std::io::Cursor::new(pem.clone().into_bytes())),
+        )),
+        (Some(_), Some(_)) => Err(format!(
+            "Only one of {}_file and {} can be given",
+            name, name
+        )),
+        (None, None) => Err(format!("One of {}_file and {} is required", name, name)),
+    }
+}
+
+fn load_certs(source: &str, pem: &mut dyn BufRead) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = rustls_pemfile::certs(pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Can't read certificates from {}: {}", source, e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", source));
+    }
+    Ok(certs)
+}
+
+fn load_key(source: &str, pem: &mut dyn BufRead) -> Result<PrivateKeyDer<'static>, String> {
+    rustls_pemfile::private_key(pem)
+        .map_err(|e| format!("Can't read private key from {}: {}", source, e))?
+        .ok_or_else(|| format!("No private key found in {}", source))
+}
+
+/// Checks an `Authorization` header value against the configured users,
+/// whose passwords are bcrypt hashes.
+pub fn check_basic_auth(users: &HashMap<String, String>, authorization: &str) -> bool {
+    let decoded = match authorization
+        .strip_prefix("Basic ")
+        .and_then(|creds| BASE64_STANDARD.decode(creds).ok())
+        .and_then(|creds| String::from_utf8(creds).ok())
+    {
+        Some(d) => d,
+        None => return false,
+    };
+    let (user, password) = match decoded.split_once(':') {
+        Some(pair) => pair,
+        None => return false,
+    };
+    users
+        .get(user)
+        .is_some_and(|hash| bcrypt::verify(password, hash).unwrap_or(false))
+}
+
+/// Checks an `Authorization` header value against the expected bearer
+/// token, in constant time for tokens of the same length.
+pub fn check_bearer_token(token: &str, authorization: &str) -> bool {
+    authorization.strip_prefix("Bearer ").is_some_and(|given| {
+        given.len() == token.len()
+            && given
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    })
+}
+
+/// The credentials accepted by the `authenticate` middleware: basic auth
+/// users (from the web config) and a bearer token, either of which is
+/// enough.
+#[derive(Debug, Default)]
+pub struct Credentials {
+    pub users: HashMap<String, String>,
+    pub token: Option<String>,
+}
+
+impl Credentials {
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.token.is_none()
+    }
+
+    /// Checks the credentials, verifying basic auth passwords on the
+    /// blocking threads, as bcrypt is deliberately slow.
+    async fn check(self: &Arc<Self>, authorization: &str) -> bool {
+        if self
+            .token
+            .as_ref()
+            .is_some_and(|token| check_bearer_token(token, authorization))
+        {
+            return true;
+        }
+        if self.users.is_empty() || !authorization.starts_with("Basic ") {
+            return false;
+        }
+        let credentials = self.clone();
+        let authorization = authorization.to_string();
+        tokio::task::spawn_blocking(move || check_basic_auth(&credentials.users, &authorization))
+            .await
+            .unwrap_or(false)
+    }
+}
+
+/// Middleware rejecting requests without valid credentials.
+pub async fn authenticate(
+    State(credentials): State<Arc<Credentials>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok());
+    let authorized = match authorization {
+        Some(authorization) => credentials.check(authorization).await,
+        None => false,
+    };
+    if !authorized {
+        let scheme = if credentials.users.is_empty() {
+            "Bearer"
+        } else {
+            "Basic"
+        };
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, scheme)],
+            "Unauthorized\n",
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use base64::prelude::*;
+    use speculoos::prelude::*;
+
+    use tokio_rustls::rustls;
+
+    use super::{
+        check_basic_auth, check_bearer_token, ClientAuthType, Credentials, TlsVersion, WebConfig,
+    };
+
+    #[test]
+    fn test_parse_full() {
+        let config = WebConfig::parse(
+            r#"
+tls_server_config:
+  cert_file: server.crt
+  key_file: server.key
+  client_auth_type: RequireAndVerifyClientCert
+  client_ca_file: ca.crt
+  min_version: TLS13
+  cipher_suites: [TLS_AES_128_GCM_SHA256]
+http_server_config:
+  http2: false
+  headers:
+    X-Frame-Options: deny
+basic_auth_users:
+  alice: $2y$10$mDwo.lAisC94iLAyP81MCesa29IzH37oigHC/42V2pdJlUprsJPze
+"#,
+        )
+        .expect("parse web config");
+        let tls = config.tls_server_config.as_ref().expect("tls config");
+        assert_that!(tls.cert_file.as_deref()).is_equal_to(Some("server.crt"));
+        assert_that!(tls.min_version).is_equal_to(TlsVersion::TLS13);
+        assert_that!(tls.unsupported.contains_key("cipher_suites")).is_true();
+        assert_that!(tls.client_auth_type).is_equal_to(ClientAuthType::RequireAndVerifyClientCert);
+        assert_that!(config.http2()).is_false();
+        assert_that!(config.basic_auth_users).contains_key("alice".to_string());
+        assert_that!(config.headers().unwrap()).has_length(1);
+    }
+
+    #[test]
+    fn test_tls_versions() {
+        let tls = |versions: &str| {
+            let config = WebConfig::parse(&format!(
+                "tls_server_config:\n  cert: x\n  key: y\n{}",
+                versions
+            ))
+            .unwrap();
+            config.tls_server_config.unwrap().protocol_versions()
+        };
+        assert_that!(tls("").map(|v| v.len())).is_equal_to(Ok(2));
+        assert_that!(tls("  min_version: TLS10\n  max_version: TLS12\n"))
+            .is_equal_to(Ok(vec![&rustls::version::TLS12]));
+        assert_that!(tls("  max_version: TLS11\n"))
+            .is_err()
+            .contains("No supported TLS version");
+        assert_that!(WebConfig::parse(
+            "tls_server_config:\n  min_version: SSL3\n"
+        ))
+        .is_err();
+    }
+
+    #[test]
+    fn test_inline_pem() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let indent = |pem: String| pem.replace('\n', "\n    ");
+        let config = WebConfig::parse(&format!(
+            "tls_server_config:\n  cert: |\n    {}\n  key: |\n    {}\n",
+            indent(cert.cert.pem()),
+            indent(cert.key_pair.serialize_pem())
+        ))
+        .unwrap();
+        assert_that!(config.tls_acceptor().map(|a| a.is_some())).is_equal_to(Ok(true));
+        let config = WebConfig::parse("tls_server_config:\n  key: y\n").unwrap();
+        assert_that!(config.tls_acceptor().err())
+            .is_some()
+            .contains("One of cert_file and cert is required");
+        let config = WebConfig::parse("http_server_config:\n  headers:\n    'a b': c\n");
+        assert_that!(config)
+            .is_err()
+            .contains("Invalid header name");
+    }
+
+    #[test]
+    fn test_parse_empty() {
+        let config = WebConfig::parse("").expect("parse empty web config");
+        assert_that!(config.tls_server_config.is_none()).is_true();
+        assert_that!(config.http2()).is_true();
+        assert_that!(config.tls_acceptor().map(|a| a.is_none())).is_equal_to(Ok(true));
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert_that!(WebConfig::parse("tls_server_config: [1, 2]")).is_err();
+    }
+
+    #[test]
+    fn test_missing_cert() {
+        let config = WebConfig::parse(
+            "tls_server_config:\n  cert_file: /no/such/cert\n  key_file: /no/such/key\n",
+        )
+        .unwrap();
+        assert_that!(config.tls_acceptor().err())
+            .is_some()
+            .contains("Can't open '/no/such/cert'");
+    }
+
+    #[test]
+    fn test_basic_auth() {
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        let users = HashMap::from([("alice".to_string(), hash)]);
+        let header = |creds: &str| format!("Basic {}", BASE64_STANDARD.encode(creds));
+        assert_that!(check_basic_auth(&users, &header("alice:secret"))).is_true();
+        assert_that!(check_basic_auth(&users, &header("alice:wrong"))).is_false();
+        assert_that!(check_basic_auth(&users, &header("bob:secret"))).is_false();
+        assert_that!(check_basic_auth(&users, &header("alice"))).is_false();
+        assert_that!(check_basic_auth(&users, "Bearer alice:secret")).is_false();
+    }
+
+    #[tokio::test]
+    async fn test_credentials_check() {
+        let credentials = std::sync::Arc::new(Credentials {
+            users: HashMap::from([("alice".to_string(), bcrypt::hash("secret", 4).unwrap())]),
+            token: Some("s3cret".to_string()),
+        });
+        let basic = format!("Basic {}", BASE64_STANDARD.encode("alice:secret"));
+        assert_that!(credentials.check(&basic).await).is_true();
+        assert_that!(credentials.check("Bearer s3cret").await).is_true();
+        assert_that!(credentials.check("Bearer wrong").await).is_false();
+        let wrong = format!("Basic {}", BASE64_STANDARD.encode("alice:wrong"));
+        assert_that!(credentials.check(&wrong).await).is_false();
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        assert_that!(check_bearer_token("s3cret", "Bearer s3cret")).is_true();
+        assert_that!(check_bearer_token("s3cret", "Bearer s3cre")).is_false();
+        assert_that!(check_bearer_token("s3cret", "Bearer s3creT")).is_false();
+        assert_that!(check_bearer_token("s3cret", "s3cret")).is_false();
+        assert_that!(check_bearer_token("s3cret", "Basic s3cret")).is_false();
+    }
+}
@@ -0,0 +1,250 @@
+//! Weekly working-time calendars, for measuring the age of backlog files
+//! in business days or editing hours rather than wall-clock time, so that
+//! the ages (and any alerting on them) reflect the actual editing capacity.
+//!
+//! A file's age is then the working time elapsed between its modification
+//! time and the reference time. Calendars use a fixed UTC offset, so the
+//! working hours shift by an hour across daylight saving time changes.
+
+use std::time::{Duration, SystemTime};
+
+const HOUR: f64 = 3600.0;
+const DAY: f64 = 24.0 * HOUR;
+const WEEK: f64 = 7.0 * DAY;
+/// The Unix epoch was a Thursday, three days into the week.
+const EPOCH_WEEKDAY: f64 = 3.0;
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// Alias for counting only whole weekdays.
+pub const BUSINESS_DAYS: &str = "business-days";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorkCalendar {
+    /// Working intervals, in seconds since the start of the week (Monday
+    /// midnight), sorted and not overlapping.
+    intervals: Vec<(f64, f64)>,
+    /// Offset of the calendar's times from UTC, in seconds.
+    utc_offset: f64,
+}
+
+impl WorkCalendar {
+    /// Parses a calendar given as comma-separated `DAYS[@HH:MM-HH:MM]`
+    /// entries, where `DAYS` is a day or a range of days, with whole days
+    /// if no hours are given; `business-days` is the same as `mon-fri`.
+    /// Example:
+    /// ```
+    /// use photo_backlog_exporter::work_calendar::WorkCalendar;
+    /// let evenings = WorkCalendar::parse("mon-fri@19:00-22:00,sat@10:00-18:00").unwrap();
+    /// assert_eq!(evenings.weekly_hours(), 23.0);
+    /// assert_eq!(WorkCalendar::parse("business-days").unwrap().weekly_hours(), 120.0);
+    /// assert!(WorkCalendar::parse("mon@22:00-02:00").is_err());
+    /// assert!(WorkCalendar::parse("someday").is_err());
+    /// ```
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let spec = if s == BUSINESS_DAYS { "mon-fri" } else { s };
+        let mut intervals = Vec::new();
+        for entry in spec.split(',').filter(|e| !e.is_empty()) {
+            let (days, hours) = match entry.split_once('@') {
+                Some((days, hours)) => (days, Some(hours)),
+                None => (entry, None),
+            };
+            let (start, end) = match hours {
+                None => (0.0, DAY),
+                Some(hours) => {
+                    let (start, end) = hours
+                        .split_once('-')
+                        .ok_or_else(|| format!("Invalid working hours '{}'", hours))?;
+                    (parse_time(start)?, parse_time(end)?)
+                }
+            };
+            if start >= end {
+                return Err(format!(
+                    "Invalid working hours in '{}', they must end after they start on the same day",
+                    entry
+                ));
+            }
+            let (first, last) = match days.split_once('-') {
+                Some((first, last)) => (parse_day(first)?, parse_day(last)?),
+                None => (parse_day(days)?, parse_day(days)?),
+            };
+            // Ranges can wrap around the week's end, e.g. `sat-mon`.
+            let mut day = first;
+            loop {
+                let offset = day as f64 * DAY;
+                intervals.push((offset + start, offset + end));
+                if day == last {
+                    break;
+                }
+                day = (day + 1) % DAYS.len();
+            }
+        }
+        if intervals.is_empty() {
+            return Err(format!("Calendar '{}' has no working time", s));
+        }
+        intervals.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let mut merged: Vec<(f64, f64)> = Vec::with_capacity(intervals.len());
+        for (start, end) in intervals {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        Ok(WorkCalendar {
+            intervals: merged,
+            utc_offset: 0.0,
+        })
+    }
+
+    /// Sets the offset from UTC, in seconds, of the working hours.
+    pub fn with_utc_offset(self, utc_offset: i64) -> Self {
+        WorkCalendar {
+            utc_offset: utc_offset as f64,
+            ..self
+        }
+    }
+
+    /// The working time per week, in hours.
+    pub fn weekly_hours(&self) -> f64 {
+        self.week_total() / HOUR
+    }
+
+    fn week_total(&self) -> f64 {
+        self.intervals.iter().map(|(start, end)| end - start).sum()
+    }
+
+    /// The working time between a fixed point (the Monday before the
+    /// epoch) and the given time, in seconds.
+    fn worked_until(&self, time: SystemTime) -> f64 {
+        let since_epoch = match time.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(d) => d.as_secs_f64(),
+            Err(e) => -e.duration().as_secs_f64(),
+        };
+        let local = since_epoch + self.utc_offset + EPOCH_WEEKDAY * DAY;
+        let weeks = (local / WEEK).floor();
+        let in_week = local - weeks * WEEK;
+        let partial: f64 = self
+            .intervals
+            .iter()
+            .map(|(start, end)| (in_week - start).clamp(0.0, end - start))
+            .sum();
+        weeks * self.week_total() + partial
+    }
+
+    /// Returns the working time between the modification and reference
+    /// times, or zero if the file is newer.
+    pub fn age(&self, reference: SystemTime, modified: SystemTime) -> Duration {
+        let worked = self.worked_until(reference) - self.worked_until(modified);
+        Duration::from_secs_f64(worked.max(0.0))
+    }
+}
+
+fn parse_day(s: &str) -> Result<usize, String> {
+    DAYS.iter()
+        .position(|d| d.eq_ignore_ascii_case(s))
+        .ok_or_else(|| format!("Invalid day '{}', expected one of {}", s, DAYS.join(", ")))
+}
+
+// Parses `HH:MM`, up to `24:00`, into seconds since midnight.
+fn parse_time(s: &str) -> Result<f64, String> {
+    let error = || format!("Invalid time '{}', expected HH:MM", s);
+    let (hours, minutes) = s.split_once(':').ok_or_else(error)?;
+    let hours: u32 = hours.parse().map_err(|_| error())?;
+    let minutes: u32 = minutes.parse().map_err(|_| error())?;
+    if minutes >= 60 || hours * 60 + minutes > 24 * 60 {
+        return Err(error());
+    }
+    Ok(f64::from(hours) * HOUR + f64::from(minutes) * 60.0)
+}
+
+/// Parses a UTC offset given as `[+-]HH:MM`, into seconds.
+/// Example:
+/// ```
+/// use photo_backlog_exporter::work_calendar::parse_utc_offset;
+/// assert_eq!(parse_utc_offset("+02:00"), Ok(7200));
+/// assert_eq!(parse_utc_offset("-05:30"), Ok(-19800));
+/// assert!(parse_utc_offset("2").is_err());
+/// ```
+pub fn parse_utc_offset(s: &str) -> Result<i64, String> {
+    let error = || format!("Invalid UTC offset '{}', expected e.g. +02:00", s);
+    let (sign, time) = match s.split_at_checked(1) {
+        Some(("+", time)) => (1, time),
+        Some(("-", time)) => (-1, time),
+        _ => return Err(error()),
+    };
+    let seconds = parse_time(time).map_err(|_| error())?;
+    if seconds > 14.0 * HOUR {
+        return Err(error());
+    }
+    Ok(sign * seconds as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use rstest::rstest;
+    use speculoos::prelude::*;
+
+    use super::WorkCalendar;
+
+    const HOUR: u64 = 3600;
+    const DAY: u64 = 24 * HOUR;
+
+    // Monday, 2024-01-01 00:00 UTC.
+    fn monday() -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_704_067_200)
+    }
+
+    #[rstest]
+    // From Friday noon to the next Monday noon: half of Friday and Monday.
+    #[case::weekend("business-days", 4 * DAY + 12 * HOUR, 7 * DAY + 12 * HOUR, DAY)]
+    #[case::same_day("business-days", HOUR, 3 * HOUR, 2 * HOUR)]
+    #[case::two_weeks("business-days", 0, 14 * DAY, 10 * DAY)]
+    #[case::weekend_only("business-days", 5 * DAY, 6 * DAY, 0)]
+    #[case::newer("business-days", 2 * DAY, DAY, 0)]
+    // From Monday 20:00 to Wednesday 21:00: 2h, 3h, 2h.
+    #[case::evenings("mon-fri@19:00-22:00", 20 * HOUR, 2 * DAY + 21 * HOUR, 7 * HOUR)]
+    #[case::wrapping("sat-mon@10:00-12:00", 0, 7 * DAY, 6 * HOUR)]
+    fn test_age(
+        #[case] spec: &str,
+        #[case] modified: u64,
+        #[case] reference: u64,
+        #[case] expected: u64,
+    ) {
+        let calendar = WorkCalendar::parse(spec).unwrap();
+        let at = |offset| monday() + Duration::from_secs(offset);
+        assert_that!(calendar.age(at(reference), at(modified)))
+            .is_equal_to(Duration::from_secs(expected));
+    }
+
+    #[test]
+    fn test_utc_offset() {
+        // At UTC+02:00, Monday 00:00 UTC is already two hours into Monday.
+        let calendar = WorkCalendar::parse("mon").unwrap().with_utc_offset(7200);
+        let reference = monday() + Duration::from_secs(DAY);
+        assert_that!(calendar.age(reference, monday())).is_equal_to(Duration::from_secs(22 * HOUR));
+        // And before the epoch.
+        let reference = SystemTime::UNIX_EPOCH;
+        let modified = reference - Duration::from_secs(14 * DAY);
+        assert_that!(calendar.age(reference, modified)).is_equal_to(Duration::from_secs(2 * DAY));
+    }
+
+    #[test]
+    fn test_parse() {
+        let overlapping = WorkCalendar::parse("mon@09:00-12:00,mon@11:00-13:00,mon").unwrap();
+        assert_that!(overlapping.weekly_hours()).is_equal_to(24.0);
+        assert_that!(WorkCalendar::parse("mon@12:00-24:00")
+            .unwrap()
+            .weekly_hours())
+        .is_equal_to(12.0);
+        for invalid in [
+            "",
+            "mon@09:00",
+            "mon@9-10",
+            "mon@10:00-10:00",
+            "mon@00:00-25:00",
+        ] {
+            assert_that!(WorkCalendar::parse(invalid)).is_err();
+        }
+    }
+}
@@ -1,19 +1,34 @@
 use assert_cmd::cargo::CommandCargoExt;
 use assert_cmd::prelude::*;
+use photo_backlog_exporter::fixture;
 use predicates::prelude::*;
+#[cfg(feature = "daemon")]
 use result::ResultAssertions;
 use rstest::rstest;
 use speculoos::*;
+#[cfg(feature = "daemon")]
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::fs::MetadataExt;
 use std::path::PathBuf;
 use std::process::Command;
 use tempfile::tempdir;
+#[cfg(feature = "daemon")]
 use tokio::net::TcpListener;
 
+// Returns the command for the binary, or none for the daemon if built
+// without it.
+fn cargo_bin(name: &str) -> Option<Command> {
+    if name == "photo-backlog-exporter" && !cfg!(feature = "daemon") {
+        return None;
+    }
+    Some(Command::cargo_bin(name).unwrap())
+}
+
 #[rstest]
 fn test_help(#[values("oneshot", "photo-backlog-exporter")] cmd_name: &str) {
-    let mut cmd = Command::cargo_bin(cmd_name).unwrap();
+    let Some(mut cmd) = cargo_bin(cmd_name) else {
+        return;
+    };
     cmd.arg("--help");
 
     cmd.assert()
@@ -22,9 +37,31 @@ fn test_help(#[values("oneshot", "photo-backlog-exporter")] cmd_name: &str) {
         .stderr(predicate::str::contains("--age-buckets AGE-BUCKETS"));
 }
 
+#[rstest]
+fn test_print_features(#[values("oneshot", "photo-backlog-exporter")] cmd_name: &str) {
+    let Some(mut cmd) = cargo_bin(cmd_name) else {
+        return;
+    };
+    // No --path needed.
+    cmd.args(["--no-config", "--print-features"]);
+
+    let collation = if cfg!(feature = "collation") {
+        "+collation"
+    } else {
+        "-collation"
+    };
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::starts_with("photo-backlog-exporter "))
+        .stdout(predicate::str::contains(collation))
+        .stdout(predicate::str::contains("linked)\n"));
+}
+
 #[rstest]
 fn test_missing_path(#[values("oneshot", "photo-backlog-exporter")] cmd_name: &str) {
-    let mut cmd = Command::cargo_bin(cmd_name).unwrap();
+    let Some(mut cmd) = cargo_bin(cmd_name) else {
+        return;
+    };
 
     cmd.assert()
         .failure()
@@ -36,15 +73,13 @@ fn test_permissions_check() {
     // Setup the test environment. Note that this tests/assumes what the
     // raw/editable file extensions are.
     let temp_dir = tempdir().unwrap();
-    let mut fname = PathBuf::from(temp_dir.path());
-    fname.push("file1.nef");
-    std::fs::write(&fname, b"").expect("Can't create file");
-    std::fs::set_permissions(&fname, std::fs::Permissions::from_mode(0o600))
-        .expect("Can't set permissions");
-    let m = std::fs::metadata(&fname).expect("Can't stat just created file!");
-
-    std::fs::write(temp_dir.path().join("file2.zip"), b"").expect("Can't create file");
-    std::fs::write(temp_dir.path().join("file3.jpg"), b"").expect("Can't create file");
+    fixture::build_tree(
+        temp_dir.path(),
+        "file1.nef mode=600\nfile2.zip\nfile3.jpg\n",
+    )
+    .expect("Can't build tree");
+    let m = std::fs::metadata(temp_dir.path().join("file1.nef"))
+        .expect("Can't stat just created file!");
 
     let mut cmd = Command::cargo_bin("oneshot").unwrap();
     cmd.args(["--path", temp_dir.path().to_str().unwrap()])
@@ -73,12 +108,38 @@ fn test_permissions_check() {
         ));
 }
 
+#[test]
+fn test_fix_plan() {
+    let temp_dir = tempdir().unwrap();
+    fixture::build_tree(temp_dir.path(), "a/file1.nef mode=600\na/file2.nef\n")
+        .expect("Can't build tree");
+    let file1 = temp_dir.path().join("a/file1.nef");
+    let owner = std::fs::metadata(&file1).unwrap().uid() + 1;
+
+    let mut cmd = Command::cargo_bin("oneshot").unwrap();
+    cmd.args(["--path", temp_dir.path().to_str().unwrap()])
+        .args(["--owner", &owner.to_string()])
+        .args(["--raw-file-mode", "644", "--fix-plan"]);
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("\n# a\n"))
+        .stdout(predicate::str::contains(format!(
+            "chmod 644 {}\n",
+            file1.display()
+        )))
+        .stdout(predicate::str::contains(format!(
+            "chown {} {}\n",
+            owner,
+            file1.display()
+        )))
+        .stdout(predicate::str::contains("photo_backlog_counts").not());
+}
+
 #[test]
 fn test_relative_dir() {
     let temp_dir = tempdir().unwrap();
-    let mut fname = PathBuf::from(temp_dir.path());
-    fname.push("file1.nef");
-    std::fs::write(&fname, b"").expect("Can't create file");
+    fixture::build_tree(temp_dir.path(), "file1.nef").expect("Can't build tree");
 
     let mut cmd = Command::cargo_bin("oneshot").unwrap();
 
@@ -94,12 +155,44 @@ fn test_relative_dir() {
         ));
 }
 
+#[test]
+#[cfg(target_os = "linux")]
+fn test_assert_readonly() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = tempdir().unwrap();
+    fixture::build_tree(temp_dir.path(), "file1.nef").expect("Can't build tree");
+    let path = temp_dir.path().to_str().unwrap();
+
+    let mut cmd = Command::cargo_bin("oneshot").unwrap();
+    cmd.args(["--path", path, "--assert-readonly"]);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("is writable by the exporter"));
+
+    let permissions = std::fs::Permissions::from_mode(0o555);
+    std::fs::set_permissions(temp_dir.path(), permissions).unwrap();
+    let mut cmd = Command::cargo_bin("oneshot").unwrap();
+    cmd.args(["--path", path, "--assert-readonly"]);
+    cmd.assert().success().stdout(predicate::str::contains(
+        "photo_backlog_counts{kind=\"photos\"} 1",
+    ));
+    let textfile = temp_dir.path().join("backlog.prom");
+    let mut cmd = Command::cargo_bin("oneshot").unwrap();
+    cmd.args(["--path", path, "--assert-readonly", "--textfile-output"]);
+    cmd.arg(&textfile);
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "would be written under the read-only root",
+    ));
+    std::fs::set_permissions(temp_dir.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+}
+
 #[test]
 fn test_ignores_fifo() {
     let temp_dir = tempdir().unwrap();
     let mut fname = PathBuf::from(temp_dir.path());
     fname.push("fifo.nef");
-    Command::new("mkfifo").arg(&fname).spawn().unwrap();
+    Command::new("mkfifo").arg(&fname).status().unwrap();
 
     let mut cmd = Command::cargo_bin("oneshot").unwrap();
 
@@ -117,7 +210,9 @@ fn test_ignores_fifo() {
 
 #[rstest]
 fn test_daemon_systemd_logging(#[values("oneshot", "photo-backlog-exporter")] cmd_name: &str) {
-    let mut cmd = Command::cargo_bin(cmd_name).unwrap();
+    let Some(mut cmd) = cargo_bin(cmd_name) else {
+        return;
+    };
     cmd.env("RUST_LOG_SYSTEMD", "yes");
     cmd.env("RUST_LOG", "debug");
     cmd.arg("--help");
@@ -128,6 +223,7 @@ fn test_daemon_systemd_logging(#[values("oneshot", "photo-backlog-exporter")] cm
 }
 
 #[tokio::test]
+#[cfg(feature = "daemon")]
 async fn test_daemon_fail_port() {
     let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0);
     let listener = TcpListener::bind(&socket).await;
@@ -145,6 +241,162 @@ async fn test_daemon_fail_port() {
         .stderr(predicate::str::contains("Failed to bind"));
 }
 
+#[tokio::test]
+#[cfg(feature = "daemon")]
+async fn test_daemon_sigterm() {
+    let temp_dir = tempdir().unwrap();
+    let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0);
+    let port = TcpListener::bind(&socket)
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let mut cmd = Command::cargo_bin("photo-backlog-exporter").unwrap();
+    cmd.args(["--listen", "127.0.0.1", "--port", &port.to_string()]);
+    cmd.args([
+        "--path",
+        temp_dir.path().to_str().unwrap(),
+        "--in-container",
+    ]);
+    let mut child = cmd.spawn().unwrap();
+    let addr = SocketAddr::new(socket.ip(), port);
+    for _ in 0..100 {
+        if tokio::net::TcpStream::connect(addr).await.is_ok() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    let status = Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    for _ in 0..100 {
+        if let Some(status) = child.try_wait().unwrap() {
+            assert!(status.success());
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    child.kill().unwrap();
+    panic!("The daemon didn't exit on SIGTERM");
+}
+
+#[tokio::test]
+#[cfg(feature = "daemon")]
+async fn test_daemon_sigterm_mid_scan() {
+    use photo_backlog_exporter::scan_lock::ScanLock;
+    use tokio::io::AsyncWriteExt;
+
+    let temp_dir = tempdir().unwrap();
+    // Held here, so that the daemon's first scan blocks waiting for it.
+    let lock_path = temp_dir.path().join("scan.lock");
+    let _lock = ScanLock::acquire(&lock_path).unwrap();
+    let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0);
+    let port = TcpListener::bind(&socket)
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let mut cmd = Command::cargo_bin("photo-backlog-exporter").unwrap();
+    cmd.args(["--listen", "127.0.0.1", "--port", &port.to_string()]);
+    cmd.args([
+        "--path",
+        temp_dir.path().to_str().unwrap(),
+        "--scan-lock",
+        lock_path.to_str().unwrap(),
+        "--in-container",
+        "--shutdown-grace-period",
+        "1s",
+    ]);
+    let mut child = cmd.spawn().unwrap();
+    let addr = SocketAddr::new(socket.ip(), port);
+    let mut stream = None;
+    for _ in 0..100 {
+        if let Ok(connected) = tokio::net::TcpStream::connect(addr).await {
+            stream = Some(connected);
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    let mut stream = stream.expect("The daemon didn't start listening");
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    let status = Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    // Exits after the grace period, without waiting for the scan.
+    for _ in 0..100 {
+        if child.try_wait().unwrap().is_some() {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    child.kill().unwrap();
+    panic!("The daemon didn't exit on SIGTERM mid-scan");
+}
+
+#[tokio::test]
+#[cfg(feature = "remote-write")]
+async fn test_daemon_remote_write() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let temp_dir = tempdir().unwrap();
+    let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0);
+    let receiver = TcpListener::bind(&socket).await.unwrap();
+    let url = format!("http://{}/api/v1/write", receiver.local_addr().unwrap());
+
+    let mut cmd = Command::cargo_bin("photo-backlog-exporter").unwrap();
+    cmd.args(["--listen", "127.0.0.1", "--port", "0"]);
+    cmd.args(["--path", temp_dir.path().to_str().unwrap()]);
+    cmd.args(["--remote-write-url", &url, "--remote-write-interval", "1s"]);
+    cmd.args(["--remote-write-label", "instance=test"]);
+    let mut child = cmd.spawn().unwrap();
+    let accepted =
+        tokio::time::timeout(std::time::Duration::from_secs(10), receiver.accept()).await;
+    let request = match accepted {
+        Ok(Ok((mut stream, _))) => {
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buffer).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buffer[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&request).to_lowercase()
+        }
+        _ => String::new(),
+    };
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert!(
+        request.starts_with("post /api/v1/write http/1.1"),
+        "{}",
+        request
+    );
+    assert!(request.contains("content-encoding: snappy"), "{}", request);
+    assert!(
+        request.contains("content-type: application/x-protobuf"),
+        "{}",
+        request
+    );
+}
+
 #[test]
 fn test_oneshot_systemd_logging() {
     let temp_dir = tempdir().unwrap();
@@ -167,3 +419,178 @@ fn test_oneshot_systemd_logging() {
             "<6>photo_backlog_exporter::cli: Starting up with the following options",
         ));
 }
+
+#[test]
+fn test_snapshot_compare() {
+    let temp_dir = tempdir().unwrap();
+    let root = temp_dir.path().join("root");
+    let folder = root.join("2024-06-15 Smith");
+    std::fs::create_dir_all(&folder).unwrap();
+    std::fs::write(folder.join("1.nef"), b"").unwrap();
+    std::fs::write(folder.join("2.nef"), b"").unwrap();
+    let old = temp_dir.path().join("old.json");
+    let new = temp_dir.path().join("new.json");
+
+    let snapshot = |path: &PathBuf| {
+        Command::cargo_bin("oneshot")
+            .unwrap()
+            .args(["--path", root.to_str().unwrap()])
+            .args(["--snapshot-file", path.to_str().unwrap()])
+            .assert()
+            .success();
+    };
+    snapshot(&old);
+    std::fs::remove_file(folder.join("2.nef")).unwrap();
+    std::fs::create_dir(root.join("misc")).unwrap();
+    std::fs::write(root.join("misc").join("1.jpg"), b"").unwrap();
+    snapshot(&new);
+
+    Command::cargo_bin("oneshot")
+        .unwrap()
+        .args(["compare", old.to_str().unwrap(), new.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "- 2024-06-15 Smith: 2 -> 1 files (shrank by 1)",
+        ))
+        .stdout(predicate::str::contains("+ misc: 1 files (appeared)"))
+        .stdout(predicate::str::contains("total: 2 -> 2 files (+0)"));
+}
+
+#[test]
+fn test_paths_from_stdin() {
+    let temp_dir = tempdir().unwrap();
+    fixture::build_tree(temp_dir.path(), "drive1/a/1.nef\ndrive1/a/2.jpg\n").unwrap();
+    let drive1 = temp_dir.path().join("drive1");
+    let drive2 = temp_dir.path().join("drive2");
+    let stdin = format!("{}\n\n{}\n", drive1.display(), drive2.display());
+
+    assert_cmd::Command::cargo_bin("oneshot")
+        .unwrap()
+        .args(["--paths-from", "-"])
+        .write_stdin(stdin)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!(
+            "       2        1        0  {}\n",
+            drive1.display()
+        )))
+        .stdout(predicate::str::contains(format!(
+            "       -        -        -  {} (",
+            drive2.display()
+        )));
+}
+
+#[test]
+fn test_mock_data() {
+    let mut cmd = Command::cargo_bin("oneshot").unwrap();
+    cmd.args(["--mock-data", "3,5"]);
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "photo_backlog_counts{kind=\"photos\"} 15",
+        ))
+        .stdout(predicate::str::contains(
+            "photo_backlog_folder_sizes{path=\"mock-folder-0000\"} 5",
+        ));
+}
+
+#[test]
+fn test_textfile_output() {
+    let temp_dir = tempdir().unwrap();
+    let textfile = temp_dir.path().join("photo_backlog.prom");
+    let mut cmd = Command::cargo_bin("oneshot").unwrap();
+    cmd.args(["--no-config", "--mock-data", "3,4", "--textfile-output"]);
+    cmd.arg(&textfile);
+    cmd.assert().success().stdout(predicate::str::is_empty());
+
+    let contents = std::fs::read_to_string(&textfile).unwrap();
+    assert!(contents.contains("photo_backlog_counts{kind=\"photos\"} 12\n"));
+    assert!(contents.contains("# TYPE photo_backlog_folders_cleared_total counter\n"));
+    assert!(!contents.contains("# EOF"));
+    assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+
+    let mut cmd = Command::cargo_bin("oneshot").unwrap();
+    cmd.args(["--no-config", "--mock-data", "3,4", "--golden"]);
+    cmd.arg("--textfile-output").arg(&textfile);
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "--textfile-output can't be combined",
+    ));
+}
+
+#[test]
+fn test_golden_file() {
+    let golden = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden/mock-3-4.prom");
+    let mut cmd = Command::cargo_bin("oneshot").unwrap();
+    cmd.args([
+        "--mock-data",
+        "3,4",
+        "--golden-file",
+        golden.to_str().unwrap(),
+    ]);
+    cmd.assert().success().stdout(predicate::str::is_empty());
+
+    // A renamed metric shows up as removed and added lines.
+    let temp_dir = tempdir().unwrap();
+    let renamed = temp_dir.path().join("renamed.prom");
+    let contents = std::fs::read_to_string(&golden).unwrap();
+    std::fs::write(
+        &renamed,
+        contents.replace("photo_backlog_counts{", "photo_backlog_count{"),
+    )
+    .unwrap();
+    let mut cmd = Command::cargo_bin("oneshot").unwrap();
+    cmd.args([
+        "--mock-data",
+        "3,4",
+        "--golden-file",
+        renamed.to_str().unwrap(),
+    ]);
+    cmd.assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "-photo_backlog_count{kind=\"photos\"} 12",
+        ))
+        .stdout(predicate::str::contains(
+            "+photo_backlog_counts{kind=\"photos\"} 12",
+        ));
+}
+
+#[test]
+fn test_summary_file() {
+    let temp_dir = tempdir().unwrap();
+    let summary_file = temp_dir.path().join("summary.json");
+    let read_summary = || -> serde_json::Value {
+        serde_json::from_str(&std::fs::read_to_string(&summary_file).unwrap()).unwrap()
+    };
+    let mut cmd = Command::cargo_bin("oneshot").unwrap();
+    cmd.args([
+        "--mock-data",
+        "3,4",
+        "--summary-file",
+        summary_file.to_str().unwrap(),
+    ]);
+    cmd.assert().success();
+    let summary = read_summary();
+    assert_that!(summary["exit_reason"]).is_equal_to(serde_json::json!("ok"));
+    assert_that!(summary["total_files"]).is_equal_to(serde_json::json!(12));
+    assert_that!(summary["folders"]).is_equal_to(serde_json::json!(3));
+
+    // Failures are recorded as well.
+    let missing = temp_dir.path().join("missing.prom");
+    let mut cmd = Command::cargo_bin("oneshot").unwrap();
+    cmd.args([
+        "--mock-data",
+        "3,4",
+        "--golden-file",
+        missing.to_str().unwrap(),
+        "--summary-file",
+        summary_file.to_str().unwrap(),
+    ]);
+    cmd.assert().failure();
+    let summary = read_summary();
+    assert_that!(summary["exit_reason"]).is_equal_to(serde_json::json!("error"));
+    let error = summary["error"].as_str().unwrap();
+    assert!(error.contains("Can't read golden file"), "{}", error);
+}
@@ -1,41 +1,30 @@
 use std::ffi::OsString;
-use std::os::unix::fs::{MetadataExt, PermissionsExt};
-use std::path::Path;
-use std::path::PathBuf;
+use std::os::unix::fs::MetadataExt;
 use std::time::SystemTime;
 
 use speculoos::prelude::*;
 use tempfile::tempdir;
 
-use photo_backlog_exporter::{Backlog, Config, ErrorType};
-
-fn create_file(path: &Path, name: &str, mode: u32) -> PathBuf {
-    let mut fname = path.to_path_buf();
-    fname.push(name);
-    std::fs::write(&fname, b"").expect("Can't create file");
-    std::fs::set_permissions(&fname, std::fs::Permissions::from_mode(mode))
-        .expect("Can't set permissions");
-    fname
-}
+use photo_backlog_exporter::{fixture, Backlog, Config, ErrorType};
 
 #[test]
 fn test_ownership_logs() {
     testing_logger::setup();
     let temp_dir = tempdir().unwrap();
-    let fname = create_file(temp_dir.path(), "file1.nef", 0o600);
-    let m = std::fs::metadata(&fname).expect("Can't stat just created file!");
-    let _ = create_file(temp_dir.path(), "file2.jpg", 0o644);
-    let _ = create_file(temp_dir.path(), "file3.jpg", 0o664);
+    fixture::build_tree(
+        temp_dir.path(),
+        "file1.nef mode=600\nfile2.jpg mode=644\nfile3.jpg mode=664\n",
+    )
+    .expect("Can't build tree");
+    let m = std::fs::metadata(temp_dir.path().join("file1.nef"))
+        .expect("Can't stat just created file!");
     let config = Config {
-        root_path: temp_dir.path(),
-        ignored_exts: &[],
         raw_exts: &[OsString::from("nef")],
         editable_exts: &[OsString::from("jpg")],
         owner: Some(m.uid() + 1),
-        group: None,
         raw_file_mode: Some(0o644),
         editable_file_mode: Some(0o664),
-        dir_mode: None,
+        ..Config::new(temp_dir.path())
     };
     let mut backlog = Backlog::new([].into_iter());
     let now = SystemTime::now();